@@ -0,0 +1,216 @@
+use actix_web::{ get, post, patch, delete, web, HttpResponse, Error };
+use serde::Deserialize;
+use serde_json::json;
+use log::{ error, info };
+use crate::database::db_config::run_db;
+use super::authorization::{ generate_api_key, hash_api_key, require_admin, User };
+
+pub fn register_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(whoami)
+        .service(list_users)
+        .service(create_user)
+        .service(update_user_quotas)
+        .service(list_api_keys)
+        .service(create_api_key)
+        .service(revoke_api_key);
+}
+
+#[derive(Deserialize)]
+struct CreateUserRequest {
+    name: String,
+    is_admin: Option<bool>,
+}
+
+// Missing or null resets the quota to the server default
+#[derive(Deserialize)]
+struct UpdateQuotasRequest {
+    daily_token_quota: Option<i64>,
+    monthly_token_quota: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct CreateApiKeyRequest {
+    label: Option<String>,
+    // Admins can issue keys for other users
+    user_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListApiKeysParams {
+    user_id: Option<String>,
+    all: Option<bool>,
+}
+
+#[get("/auth/me")]
+async fn whoami(user: User) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(user))
+}
+
+#[get("/auth/users")]
+async fn list_users(user: User) -> Result<HttpResponse, Error> {
+    if let Some(response) = require_admin(&user) {
+        return Ok(response);
+    }
+
+    match run_db(|db| db.list_users()).await? {
+        Ok(users) => Ok(HttpResponse::Ok().json(json!({"result": users, "total": users.len()}))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()}))),
+    }
+}
+
+#[post("/auth/users")]
+async fn create_user(user: User, data: web::Json<CreateUserRequest>) -> Result<HttpResponse, Error> {
+    if let Some(response) = require_admin(&user) {
+        return Ok(response);
+    }
+    if data.name.trim().is_empty() {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "name is required"})));
+    }
+
+    let (name, is_admin) = (data.name.trim().to_string(), data.is_admin.unwrap_or(false));
+    match run_db(move |db| db.create_user(&name, is_admin)).await? {
+        Ok(created) => {
+            info!("User {} created by {}", created["id"], user.user_id);
+            Ok(HttpResponse::Created().json(created))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()}))),
+    }
+}
+
+// A quota below 0 would reject every request. 0 is allowed and, like null, falls back to
+// the server default.
+fn invalid_quota(daily: Option<i64>, monthly: Option<i64>) -> Option<String> {
+    [("daily_token_quota", daily), ("monthly_token_quota", monthly)]
+        .into_iter()
+        .find(|(_, quota)| quota.is_some_and(|quota| quota < 0))
+        .map(|(name, _)| format!("{} must not be negative", name))
+}
+
+#[patch("/auth/users/{user_id}")]
+async fn update_user_quotas(
+    user: User,
+    path: web::Path<String>,
+    data: web::Json<UpdateQuotasRequest>
+) -> Result<HttpResponse, Error> {
+    if let Some(response) = require_admin(&user) {
+        return Ok(response);
+    }
+    let user_id = path.into_inner();
+    if let Some(message) = invalid_quota(data.daily_token_quota, data.monthly_token_quota) {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": message})));
+    }
+
+    let (quota_user_id, daily, monthly) = (user_id.clone(), data.daily_token_quota, data.monthly_token_quota);
+    match run_db(move |db| db.set_user_quotas(&quota_user_id, daily, monthly)).await? {
+        Ok(true) => {
+            info!("Token quotas of user {} updated by {}", user_id, user.user_id);
+            let updated_user_id = user_id.clone();
+            match run_db(move |db| db.get_user(&updated_user_id)).await? {
+                Ok(updated) => Ok(HttpResponse::Ok().json(updated)),
+                Err(e) => Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()}))),
+            }
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({"error": format!("User {} not found", user_id)}))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()}))),
+    }
+}
+
+// Own keys, admins can look at someone else's with ?user_id= or everybody's with ?all=true
+#[get("/auth/keys")]
+async fn list_api_keys(user: User, query: web::Query<ListApiKeysParams>) -> Result<HttpResponse, Error> {
+    let owner = match (&query.user_id, query.all.unwrap_or(false)) {
+        (None, false) => Some(user.user_id.clone()),
+        (requested, all) => {
+            if let Some(response) = require_admin(&user) {
+                return Ok(response);
+            }
+            if all { None } else { requested.clone() }
+        }
+    };
+
+    match run_db(move |db| db.list_api_keys(owner.as_deref())).await? {
+        Ok(keys) => Ok(HttpResponse::Ok().json(json!({"result": keys, "total": keys.len()}))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()}))),
+    }
+}
+
+// The plain key is only ever part of this response
+#[post("/auth/keys")]
+async fn create_api_key(
+    user: User,
+    data: Option<web::Json<CreateApiKeyRequest>>
+) -> Result<HttpResponse, Error> {
+    let data = data.map(|d| d.into_inner());
+    let label = data.as_ref().and_then(|d| d.label.clone());
+    let owner = match data.and_then(|d| d.user_id) {
+        Some(owner) if owner != user.user_id => {
+            if let Some(response) = require_admin(&user) {
+                return Ok(response);
+            }
+            owner
+        }
+        _ => user.user_id.clone(),
+    };
+
+    // The local user isn't a row in `users`, keys have to belong to a real user
+    let key_owner = owner.clone();
+    match run_db(move |db| db.get_user(&key_owner)).await? {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return Ok(
+                HttpResponse::NotFound().json(
+                    json!({"error": format!("User {} not found, create it with POST /auth/users first", owner)})
+                )
+            );
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()})));
+        }
+    }
+
+    let api_key = generate_api_key();
+    let (key_hash, prefix) = (hash_api_key(&api_key), api_key[..12].to_string());
+    match run_db(move |db| db.create_api_key(&owner, &key_hash, &prefix, label.as_deref())).await? {
+        Ok(mut created) => {
+            created["key"] = json!(api_key);
+            Ok(HttpResponse::Created().json(created))
+        }
+        Err(e) => {
+            error!("Failed to create API key: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()})))
+        }
+    }
+}
+
+#[delete("/auth/keys/{key_id}")]
+async fn revoke_api_key(user: User, path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let key_id = path.into_inner();
+    // Admins can revoke anybody's key, everyone else only their own
+    let owner = if user.is_admin { None } else { Some(user.user_id.clone()) };
+
+    let revoked_key_id = key_id.clone();
+    match run_db(move |db| db.revoke_api_key(&revoked_key_id, owner.as_deref())).await? {
+        Ok(true) => Ok(HttpResponse::Ok().json(json!({"message": "API key revoked", "id": key_id}))),
+        Ok(false) =>
+            Ok(HttpResponse::NotFound().json(json!({"error": format!("API key {} not found", key_id)}))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()}))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::invalid_quota;
+
+    #[test]
+    fn accepts_unset_zero_and_positive_quotas() {
+        assert_eq!(invalid_quota(None, None), None);
+        assert_eq!(invalid_quota(Some(0), Some(0)), None);
+        assert_eq!(invalid_quota(Some(1000), Some(30000)), None);
+    }
+
+    #[test]
+    fn rejects_negative_quotas() {
+        assert_eq!(invalid_quota(Some(-1), None), Some("daily_token_quota must not be negative".to_string()));
+        assert_eq!(invalid_quota(Some(10), Some(-1)), Some("monthly_token_quota must not be negative".to_string()));
+    }
+}
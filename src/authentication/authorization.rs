@@ -1,92 +1,234 @@
-use actix_web::{HttpRequest, HttpResponse};
+use actix_web::{ dev::Payload, FromRequest, HttpMessage, HttpRequest, HttpResponse };
+use serde::Serialize;
 use serde_json::json;
-use std::collections::HashMap;
-use crate::utils::is_cloud_execution_mode;
-use log::debug;
-use actix_web::HttpMessage;  // <-- Bring the HttpMessage trait into scope
-// Structs to represent the user and subscription details
-#[derive(Clone)]
+use sha2::{ Digest, Sha256 };
+use rand::RngCore;
+use std::future::{ ready, Ready };
+use log::{ error, warn };
+use crate::database::db_config::{ run_db, DB_INSTANCE };
+use chrono::{ Datelike, TimeZone, Utc };
+use crate::utils::{ is_api_key_required, get_daily_token_quota, get_monthly_token_quota };
+
+// Owner of everything created without an API key, i.e. single user local mode
+pub const DEFAULT_USER_ID: &str = "user_id";
+
+const API_KEY_PREFIX: &str = "pyano_";
+
+// Routes that answer without a key, so load balancers and clients can probe the server
+const PUBLIC_ROUTES: &[&str] = &["/", "/health", "/version"];
+
+/// The caller of a request, put in the request extensions by the auth middleware.
+/// Handlers take it as an extractor argument.
+#[derive(Clone, Debug, Serialize)]
 pub struct User {
     pub user_id: String,
-    pub subscription: Subscription,
+    pub name: String,
+    pub is_admin: bool,
 }
 
-#[derive(Clone)]
-pub struct Subscription {
-    pub is_trial_active: bool,
-    pub pro: ProSubscription,
+impl User {
+    /// Requests without a key when keys aren't required. Owns the machine, so it's an admin.
+    pub fn local() -> Self {
+        User {
+            user_id: DEFAULT_USER_ID.to_string(),
+            name: "local".to_string(),
+            is_admin: true,
+        }
+    }
 }
 
-#[derive(Clone)]
-pub struct ProSubscription {
-    pub active_till: i64,
-    pub tokens_left: i64,
+impl FromRequest for User {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match req.extensions().get::<User>() {
+            Some(user) => ready(Ok(user.clone())),
+            None => ready(Err(actix_web::error::ErrorUnauthorized(json!({"error": "Not authenticated"})))),
+        }
+    }
 }
 
+pub fn is_public_route(path: &str) -> bool {
+    PUBLIC_ROUTES.contains(&path)
+}
 
-/// Function that mimics the request validation
-pub async fn is_request_allowed(req: HttpRequest) -> Result<Option<User>, HttpResponse> {
-    if is_cloud_execution_mode() {        
-        log::debug!("Cloud EXECUTION MODE IS ON");
+pub fn hash_api_key(api_key: &str) -> String {
+    hex::encode(Sha256::digest(api_key.as_bytes()))
+}
 
-        let headers: HashMap<_, _> = req.headers().iter()
-            .map(|(k, v)| (k.as_str(), v.to_str().unwrap_or("")))
-            .collect();
+/// A new random key. Only its hash is stored, the caller has to hand the key out right away.
+pub fn generate_api_key() -> String {
+    let mut bytes = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("{}{}", API_KEY_PREFIX, hex::encode(bytes))
+}
 
-        log::debug!("{:?}", headers);
+// Accepts `Authorization: Bearer <key>`, `X-API-Key: <key>` and the older `api_key` header
+fn api_key_from_request(req: &HttpRequest) -> Option<String> {
+    let headers = req.headers();
+    let bearer = headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
 
-        // Check if API key is present
-        if let Some(api_key) = headers.get("api_key") {
-            // Simulate database lookup (replace this with actual DB code)
-            if let Some(user) = find_user_by_api_key(api_key).await {
-                // Check if the trial period is over
-                if !user.subscription.is_trial_active {
-                    let current_time = current_timestamp(); // Function to get the current time as timestamp
+    bearer
+        .or_else(|| headers.get("X-API-Key").and_then(|v| v.to_str().ok()))
+        .or_else(|| headers.get("api_key").and_then(|v| v.to_str().ok()))
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+}
 
-                    // Check subscription validity
-                    if current_time > user.subscription.pro.active_till {
-                        return Err(HttpResponse::InternalServerError().json(json!({"error": "Please renew your premium subscription"})));
-                    }
-                    
-                    // Check tokens limit
-                    if user.subscription.pro.tokens_left < 0 {
-                        return Err(HttpResponse::InternalServerError().json(json!({"error": "Please refill your premium subscription, tokens limit has been reached"})));
-                    }
-                }
+/// Resolves the caller from the API key on the request and stores it in the request extensions
+pub async fn authenticate(req: &HttpRequest) -> Result<User, HttpResponse> {
+    let api_key = match api_key_from_request(req) {
+        Some(api_key) => api_key,
+        None if is_api_key_required() => {
+            return Err(
+                HttpResponse::Unauthorized().json(json!({"error": "No API key present in the headers"}))
+            );
+        }
+        None => {
+            let user = User::local();
+            req.extensions_mut().insert(user.clone());
+            return Ok(user);
+        }
+    };
 
-                // Store user in the request state (you can use extensions for this)
-                req.extensions_mut().insert(user.clone());
-                return Ok(Some(user));
-            } else {
-                return Err(HttpResponse::InternalServerError().json(json!({"error": "API key is not valid"})));
-            }
-        } else {
-            return Err(HttpResponse::InternalServerError().json(json!({"error": "No API key present in the headers"})));
+    let key_hash = hash_api_key(&api_key);
+    let found = run_db(move |db| db.find_user_by_api_key(&key_hash).map_err(|e| e.to_string())).await;
+    match found {
+        Ok(Ok(Some(user))) => {
+            req.extensions_mut().insert(user.clone());
+            Ok(user)
         }
+        Ok(Ok(None)) => Err(HttpResponse::Unauthorized().json(json!({"error": "API key is not valid"}))),
+        Ok(Err(e)) => {
+            error!("Failed to look up API key: {}", e);
+            Err(HttpResponse::InternalServerError().json(json!({"error": "Failed to validate API key"})))
+        }
+        Err(e) => {
+            error!("Failed to look up API key: {}", e);
+            Err(HttpResponse::InternalServerError().json(json!({"error": "Failed to validate API key"})))
+        }
+    }
+}
+
+/// The 403 to answer with when `user` isn't an admin
+pub fn require_admin(user: &User) -> Option<HttpResponse> {
+    if user.is_admin {
+        None
+    } else {
+        Some(HttpResponse::Forbidden().json(json!({"error": "Admin API key required"})))
     }
+}
+
+/// (daily, monthly) token quota of a user, its own override or the server default
+pub fn token_quotas(user_id: &str) -> Result<(Option<i64>, Option<i64>), rusqlite::Error> {
+    let (daily, monthly) = DB_INSTANCE.get_user_quotas(user_id)?;
+    Ok((effective_quota(daily, get_daily_token_quota()), effective_quota(monthly, get_monthly_token_quota())))
+}
+
+// Like the server default, an override of 0 or less isn't a limit. Rows stored before
+// overrides were validated can hold one, they fall back to the default.
+fn effective_quota(own: Option<i64>, default: Option<i64>) -> Option<i64> {
+    own.filter(|quota| *quota > 0).or(default)
+}
+
+/// Start of the current UTC day and month, the windows quotas are counted over
+pub fn quota_windows() -> (String, String) {
+    let now = Utc::now();
+    let day = now.date_naive().and_hms_opt(0, 0, 0).unwrap();
+    let month = day.with_day(1).unwrap();
+    (Utc.from_utc_datetime(&day).to_rfc3339(), Utc.from_utc_datetime(&month).to_rfc3339())
+}
+
+/// (period, used, quota) of the first quota the user spent, daily before monthly
+fn spent_quota(user_id: &str) -> Result<Option<(&'static str, i64, i64)>, rusqlite::Error> {
+    let (daily_quota, monthly_quota) = token_quotas(user_id)?;
+    let (day_start, month_start) = quota_windows();
 
-    // If not in cloud mode, assign a default user
+    for (period, quota, since) in [("daily", daily_quota, day_start), ("monthly", monthly_quota, month_start)] {
+        let quota = match quota {
+            Some(quota) => quota,
+            None => {
+                continue;
+            }
+        };
+        let used = DB_INSTANCE.tokens_used_since(user_id, &since)?;
+        if used >= quota {
+            return Ok(Some((period, used, quota)));
+        }
+    }
     Ok(None)
 }
 
-// Mock database lookup (replace with actual DB access)
-async fn find_user_by_api_key(api_key: &str) -> Option<User> {
-    // Simulate finding the user by API key
-    debug!("Implement mongodb retrivela for cloud execution mode for {}", api_key);
-    Some(User {
-        user_id: "some_user_id".to_string(),
-        subscription: Subscription {
-            is_trial_active: false,
-            pro: ProSubscription {
-                active_till: 1700000000,
-                tokens_left: 10,
-            },
-        },
-    })
-}
-
-// Function to get the current timestamp
-fn current_timestamp() -> i64 {
-    // Replace with actual timestamp logic
-    1600000000
-}
\ No newline at end of file
+/// Checked before every LLM call, rejects with 429 once the user spent the daily or monthly quota
+pub async fn is_request_allowed(user_id: &str) -> Result<(), actix_web::Error> {
+    let owner = user_id.to_string();
+    let spent = run_db(move |_| spent_quota(&owner).map_err(|e| e.to_string())).await?.map_err(
+        actix_web::error::ErrorInternalServerError
+    )?;
+
+    if let Some((period, used, quota)) = spent {
+        warn!("User {} is over the {} token quota ({} of {})", user_id, period, used, quota);
+        let message = format!("The {} token quota of {} is used up", period, quota);
+        return Err(
+            actix_web::error::InternalError::from_response(
+                message.clone(),
+                HttpResponse::TooManyRequests().json(json!({"error": message, "used": used, "quota": quota}))
+            ).into()
+        );
+    }
+    Ok(())
+}
+
+/// When keys are required but nobody can issue one yet, create an admin and print its key once
+pub fn bootstrap_admin_key() {
+    if !is_api_key_required() {
+        return;
+    }
+    match DB_INSTANCE.count_active_admin_keys() {
+        Ok(0) => {}
+        Ok(_) => {
+            return;
+        }
+        Err(e) => {
+            error!("Failed to check for admin API keys: {}", e);
+            return;
+        }
+    }
+
+    let api_key = generate_api_key();
+    let created = DB_INSTANCE.create_user("admin", true).and_then(|user| {
+        let user_id = user["id"].as_str().unwrap_or_default().to_string();
+        DB_INSTANCE.create_api_key(&user_id, &hash_api_key(&api_key), &api_key[..12], Some("bootstrap"))
+    });
+    match created {
+        Ok(_) => {
+            // Straight to stdout, a key in the log would stay in every log file
+            warn!("No admin API key found, created one and printed it to stdout");
+            println!("Admin API key, store it now, it won't be shown again: {}", api_key);
+        }
+        Err(e) => error!("Failed to create the bootstrap admin API key: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::effective_quota;
+
+    #[test]
+    fn own_quota_wins_over_the_default() {
+        assert_eq!(effective_quota(Some(500), Some(1000)), Some(500));
+        assert_eq!(effective_quota(Some(500), None), Some(500));
+    }
+
+    #[test]
+    fn missing_or_non_positive_quota_falls_back_to_the_default() {
+        assert_eq!(effective_quota(None, Some(1000)), Some(1000));
+        assert_eq!(effective_quota(Some(0), Some(1000)), Some(1000));
+        assert_eq!(effective_quota(Some(-5), Some(1000)), Some(1000));
+        assert_eq!(effective_quota(Some(0), None), None);
+    }
+}
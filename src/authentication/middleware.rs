@@ -0,0 +1,23 @@
+use actix_web::{ body::MessageBody, dev::{ ServiceRequest, ServiceResponse }, middleware::Next, Error };
+use actix_web::body::EitherBody;
+use log::debug;
+use super::authorization::{ authenticate, is_public_route };
+
+/// Wrapped around the whole app, every route except the public ones needs a valid API key
+/// (or, in local mode, no key at all). The caller ends up in the request extensions.
+pub async fn require_api_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    if is_public_route(req.path()) {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    match authenticate(req.request()).await {
+        Ok(user) => {
+            debug!("{} {} as {}", req.method(), req.path(), user.user_id);
+            Ok(next.call(req).await?.map_into_left_body())
+        }
+        Err(response) => Ok(req.into_response(response).map_into_right_body()),
+    }
+}
@@ -1 +1,4 @@
 pub mod authorization;
+pub mod middleware;
+pub mod auth_api;
+pub mod usage_api;
@@ -0,0 +1,73 @@
+use actix_web::{ get, web, HttpResponse, Error };
+use serde::Deserialize;
+use serde_json::{ json, Value };
+use crate::database::db_config::{ run_db, DB_INSTANCE };
+use super::authorization::{ quota_windows, require_admin, token_quotas, User };
+
+pub fn register_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_usage);
+}
+
+#[derive(Deserialize)]
+struct UsageParams {
+    user_id: Option<String>,
+    all: Option<bool>,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+fn quota_status(user_id: &str) -> Result<Value, rusqlite::Error> {
+    let (daily_quota, monthly_quota) = token_quotas(user_id)?;
+    let (day_start, month_start) = quota_windows();
+    Ok(
+        json!({
+        "daily": {
+            "used": DB_INSTANCE.tokens_used_since(user_id, &day_start)?,
+            "limit": daily_quota,
+        },
+        "monthly": {
+            "used": DB_INSTANCE.tokens_used_since(user_id, &month_start)?,
+            "limit": monthly_quota,
+        },
+    })
+    )
+}
+
+// Own usage, admins can look at someone else's with ?user_id= or everybody's with ?all=true
+#[get("/usage")]
+async fn get_usage(user: User, query: web::Query<UsageParams>) -> Result<HttpResponse, Error> {
+    let owner = match (&query.user_id, query.all.unwrap_or(false)) {
+        (None, false) => Some(user.user_id.clone()),
+        (requested, all) => {
+            if let Some(response) = require_admin(&user) {
+                return Ok(response);
+            }
+            if all { None } else { requested.clone() }
+        }
+    };
+
+    let (summary_owner, from, to) = (owner.clone(), query.from.clone(), query.to.clone());
+    let summary = run_db(move |db| db.usage_summary(summary_owner.as_deref(), from.as_deref(), to.as_deref())).await?;
+    let mut summary = match summary {
+        Ok(summary) => summary,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()})));
+        }
+    };
+
+    summary["user_id"] = json!(owner);
+    summary["from"] = json!(query.from);
+    summary["to"] = json!(query.to);
+    if let Some(owner) = &owner {
+        let quota_owner = owner.clone();
+        match run_db(move |_| quota_status(&quota_owner)).await? {
+            Ok(quota) => {
+                summary["quota"] = quota;
+            }
+            Err(e) => {
+                return Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()})));
+            }
+        }
+    }
+    Ok(HttpResponse::Ok().json(summary))
+}
@@ -0,0 +1,26 @@
+use actix_web::{ post, web, HttpResponse, Error };
+use serde_json::json;
+use crate::llm_stream::cancel::cancel_request;
+use crate::authentication::authorization::User;
+
+pub fn register_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(cancel_chat);
+}
+
+/// Stops a running generation. The id comes from the `X-Request-ID` response header
+/// (or the one the client sent with the request). Whatever was generated so far is
+/// saved to the chat history flagged as cancelled. Generations of other users look missing.
+#[post("/chat/cancel/{request_id}")]
+pub async fn cancel_chat(path: web::Path<String>, user: User) -> Result<HttpResponse, Error> {
+    let request_id = path.into_inner();
+
+    if cancel_request(&request_id, &user.user_id) {
+        Ok(HttpResponse::Ok().json(json!({ "request_id": request_id, "cancelled": true })))
+    } else {
+        Ok(
+            HttpResponse::NotFound().json(
+                json!({ "error": format!("No running request with id {}", request_id) })
+            )
+        )
+    }
+}
@@ -0,0 +1,435 @@
+use actix_web::{ get, post, web, HttpRequest, HttpResponse, Error };
+use crate::llm_stream::handle::{
+    handle_request,
+    context_sources_header,
+    CONTEXT_SOURCES_HEADER,
+    CONTEXT_SOURCES_TOTAL_HEADER,
+};
+use crate::llm_stream::usage::UsageContext;
+use crate::llm_stream::backend::{ requested_backend, resolve_backend_name };
+use crate::llm_stream::generation::GenerationParams;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use super::chat_types::RequestType;
+use std::sync::{ Arc, Mutex };
+use crate::session_manager::check_session;
+use crate::authentication::authorization::User;
+use super::utils::handle_stream_completion;
+use crate::context::make_context::make_context;
+use crate::context::token_budget::context_budget;
+use crate::context::memory_scope::MemoryScope;
+use crate::model_state::model_process::get_app_config;
+use async_stream::stream;
+use futures::StreamExt;
+use chrono::Utc;
+use uuid::Uuid;
+use reqwest::Client;
+use crate::llm_stream::cancel::{
+    register_request,
+    request_id_for,
+    CompletionSignal,
+    StreamOutcome,
+    REQUEST_ID_HEADER,
+};
+
+const DEFAULT_MODEL_NAME: &str = "pyano";
+
+const DEFAULT_SYSTEM_PROMPT: &str =
+    r#"
+        You are an AI programming assistant. Follow the user's requirements carefully and to the letter.
+        Take context into account if relevant.
+        Context will include sections separated by '----------CONTEXT----------', which may contain code snippets, user chats, or uploaded files.
+
+        Formatting:
+        - Use GFM when required.
+        - For multi-line code block conventions include language.
+        "#;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatCompletionMessage {
+    pub role: String,
+    // OpenAI clients send either a plain string or an array of content parts
+    pub content: Value,
+}
+
+impl ChatCompletionMessage {
+    pub fn text(&self) -> String {
+        match &self.content {
+            Value::String(text) => text.clone(),
+            Value::Array(parts) =>
+                parts
+                    .iter()
+                    .filter_map(|part| part.get("text").and_then(|t| t.as_str()))
+                    .collect::<Vec<&str>>()
+                    .join("\n"),
+            _ => String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatCompletionRequest {
+    pub model: Option<String>,
+    pub messages: Vec<ChatCompletionMessage>,
+    pub stream: Option<bool>,
+    // Not part of the OpenAI protocol, lets pyano clients keep chats in one session
+    pub session_id: Option<String>,
+    // OpenAI sampling fields, mapped onto our generation params
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<i64>,
+    pub stop: Option<Value>,
+    pub seed: Option<i64>,
+    // Extra knobs OpenAI doesn't have
+    pub generation: Option<GenerationParams>,
+    #[serde(default)]
+    pub memory_scope: MemoryScope,
+}
+
+impl ChatCompletionRequest {
+    fn generation_params(&self) -> GenerationParams {
+        // `stop` can be a single string or a list in the OpenAI protocol
+        let stop = match &self.stop {
+            Some(Value::String(stop)) => Some(vec![stop.clone()]),
+            Some(Value::Array(stops)) =>
+                Some(
+                    stops
+                        .iter()
+                        .filter_map(|s| s.as_str().map(String::from))
+                        .collect()
+                ),
+            _ => None,
+        };
+        GenerationParams {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            stop,
+            seed: self.seed,
+            ..Default::default()
+        }.or(self.generation.clone().unwrap_or_default())
+    }
+}
+
+pub fn register_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(chat_completions).service(list_models);
+}
+
+fn openai_error(message: &str, error_type: &str) -> Value {
+    json!({
+        "error": {
+            "message": message,
+            "type": error_type,
+        }
+    })
+}
+
+/// Splits the OpenAI `messages` array into the system prompt, the earlier turns
+/// of the conversation and the latest user message.
+fn split_messages(messages: &[ChatCompletionMessage]) -> (String, String, String) {
+    let system_prompt = messages
+        .iter()
+        .filter(|message| message.role == "system")
+        .map(|message| message.text())
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let last_user_index = messages.iter().rposition(|message| message.role == "user");
+
+    let user_prompt = last_user_index
+        .map(|index| messages[index].text())
+        .unwrap_or_default();
+
+    let conversation = messages
+        .iter()
+        .enumerate()
+        .filter(|(index, message)| message.role != "system" && Some(*index) != last_user_index)
+        .map(|(_, message)| format!("{}: {}", message.role, message.text()))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    (system_prompt, conversation, user_prompt)
+}
+
+#[post("/v1/chat/completions")]
+pub async fn chat_completions(
+    data: web::Json<ChatCompletionRequest>,
+    client: web::Data<Client>,
+    req: HttpRequest,
+    user: User
+) -> Result<HttpResponse, Error> {
+    let (system_prompt, conversation, user_prompt) = split_messages(&data.messages);
+    if user_prompt.is_empty() {
+        return Ok(
+            HttpResponse::BadRequest().json(
+                openai_error("messages must contain at least one user message", "invalid_request_error")
+            )
+        );
+    }
+
+    // Session can come from the body or from the header we hand out on every chat response
+    let requested_session_id = data.session_id.clone().or_else(|| {
+        req.headers()
+            .get("X-Session-ID")
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    });
+    let session_id = match check_session(requested_session_id, &user.user_id).await {
+        Ok(id) => id,
+        Err(e) => {
+            let status = e.as_response_error().status_code();
+            let error_type = if status.is_client_error() { "invalid_request_error" } else { "server_error" };
+            return Ok(HttpResponse::build(status).json(openai_error(&e.to_string(), error_type)));
+        }
+    };
+
+    let memory_scope = match data.memory_scope.authorize(&user) {
+        Ok(scope) => scope,
+        Err(e) => {
+            let status = e.as_response_error().status_code();
+            return Ok(HttpResponse::build(status).json(openai_error(&e.to_string(), "invalid_request_error")));
+        }
+    };
+
+    let model = data.model.clone().unwrap_or_else(|| DEFAULT_MODEL_NAME.to_string());
+    let system_prompt = if system_prompt.is_empty() {
+        DEFAULT_SYSTEM_PROMPT.to_string()
+    } else {
+        system_prompt
+    };
+
+    // The conversation so far is part of the prompt too, the context gets what's left
+    let llm_backend = resolve_backend_name(RequestType::Chat.to_string(), requested_backend(&req).as_deref());
+    let budget = context_budget(
+        &client,
+        &llm_backend,
+        RequestType::Chat.to_string(),
+        &system_prompt,
+        &format!("{}\n{}", conversation, user_prompt),
+        Some(&data.generation_params())
+    ).await;
+    let context = make_context(&client, &llm_backend, &session_id, &user.user_id, &user_prompt, budget, memory_scope).await?;
+
+    let prompt_with_context = format!(
+        r#"
+        Context from prior conversations and uploaded files (separated by '----------CONTEXT----------'):
+        {context}
+        Conversation so far:
+        {conversation}
+        New question or coding request: {user_prompt}
+        "#,
+        context = context.text,
+        conversation = conversation,
+        user_prompt = &user_prompt
+    );
+
+    let accumulated_content = Arc::new(Mutex::new(String::new()));
+    let accumulated_content_clone = Arc::clone(&accumulated_content);
+    let shared_session_id = Arc::new(Mutex::new(session_id.clone()));
+    let shared_prompt = Arc::new(Mutex::new(user_prompt.clone()));
+
+    let (tx, rx) = tokio::sync::oneshot::channel::<StreamOutcome>();
+
+    // Lets /chat/cancel/{request_id} stop this completion too
+    let active_request = register_request(&request_id_for(&req), &user.user_id);
+    let request_id = active_request.request_id.clone();
+    let backend_name = requested_backend(&req);
+    let generation = data.generation_params();
+    let mut stream = match
+        handle_request(
+            RequestType::Chat,
+            &client,
+            backend_name.as_deref(),
+            Some(&generation),
+            &system_prompt,
+            &prompt_with_context,
+            UsageContext::new(&user.user_id, Some(request_id.clone()))
+        ).await
+    {
+        Ok(s) => s,
+        Err(e) if e.as_response_error().status_code() == actix_web::http::StatusCode::TOO_MANY_REQUESTS => {
+            return Ok(
+                HttpResponse::TooManyRequests().json(openai_error(&e.to_string(), "rate_limit_exceeded"))
+            );
+        }
+        Err(e) => {
+            return Ok(
+                HttpResponse::InternalServerError().json(
+                    openai_error(&format!("Local LLM response error: {}", e), "server_error")
+                )
+            );
+        }
+    };
+
+    // Which files and chats the answer draws on, besides the OpenAI fields
+    let sources_header = context_sources_header(&context.sources);
+    let sources_total = context.sources.len();
+    let sources_json = json!(context.sources);
+
+    // Persist the exchange (and its embeddings) once the whole answer has been produced
+    tokio::spawn(async move {
+        handle_stream_completion(
+            rx,
+            accumulated_content,
+            shared_session_id,
+            shared_prompt,
+            RequestType::Chat,
+            context.sources
+        ).await;
+    });
+
+    let completion_id = format!("chatcmpl-{}", Uuid::new_v4());
+    let created = Utc::now().timestamp();
+
+    let mut completion = CompletionSignal::new(tx);
+
+    if !data.stream.unwrap_or(false) {
+        let mut outcome = StreamOutcome::Completed;
+        loop {
+            let chunk_result = tokio::select! {
+                chunk = stream.next() => chunk,
+                _ = active_request.cancelled() => {
+                    outcome = StreamOutcome::Cancelled;
+                    None
+                }
+            };
+            let chunk_result = match chunk_result {
+                Some(chunk_result) => chunk_result,
+                None => break,
+            };
+            match chunk_result {
+                Ok(chunk) => {
+                    if let Ok(chunk_str) = std::str::from_utf8(&chunk) {
+                        accumulated_content_clone.lock().unwrap().push_str(chunk_str);
+                    }
+                }
+                Err(e) => {
+                    return Ok(
+                        HttpResponse::InternalServerError().json(
+                            openai_error(&format!("Error while streaming: {}", e), "server_error")
+                        )
+                    );
+                }
+            }
+        }
+        drop(stream);
+        let content = accumulated_content_clone.lock().unwrap().clone();
+        completion.send(outcome);
+        let finish_reason = if outcome == StreamOutcome::Cancelled { "cancelled" } else { "stop" };
+
+        return Ok(
+            HttpResponse::Ok()
+                .append_header(("X-Session-ID", session_id))
+                .append_header((REQUEST_ID_HEADER, request_id))
+                .append_header((CONTEXT_SOURCES_HEADER, sources_header))
+                .append_header((CONTEXT_SOURCES_TOTAL_HEADER, sources_total))
+                .json(
+                    json!({
+                "id": completion_id,
+                "object": "chat.completion",
+                "created": created,
+                "model": model,
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": content },
+                    "finish_reason": finish_reason
+                }],
+                "context_sources": sources_json
+            })
+                )
+        );
+    }
+
+    let chunk_event = move |delta: Value, finish_reason: Option<&str>| {
+        let chunk =
+            json!({
+            "id": completion_id,
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": model,
+            "choices": [{
+                "index": 0,
+                "delta": delta,
+                "finish_reason": finish_reason
+            }]
+        });
+        web::Bytes::from(format!("data: {}\n\n", chunk))
+    };
+
+    // Re-frame the plain text stream as OpenAI chat.completion.chunk SSE events
+    let response_stream = stream! {
+        yield Ok::<_, Error>(chunk_event(json!({ "role": "assistant" }), None));
+
+        let mut outcome = StreamOutcome::Completed;
+        loop {
+            let chunk_result = tokio::select! {
+                chunk = stream.next() => chunk,
+                _ = active_request.cancelled() => {
+                    outcome = StreamOutcome::Cancelled;
+                    None
+                }
+            };
+            let chunk_result = match chunk_result {
+                Some(chunk_result) => chunk_result,
+                None => break,
+            };
+            match chunk_result {
+                Ok(chunk) => {
+                    if let Ok(chunk_str) = std::str::from_utf8(&chunk) {
+                        if chunk_str.is_empty() {
+                            continue;
+                        }
+                        {
+                            let mut accumulated = accumulated_content_clone.lock().unwrap();
+                            accumulated.push_str(chunk_str);
+                        }
+                        yield Ok(chunk_event(json!({ "content": chunk_str }), None));
+                    }
+                }
+                Err(e) => {
+                    let error = openai_error(&format!("Error while streaming: {}", e), "server_error");
+                    yield Ok(web::Bytes::from(format!("data: {}\n\n", error)));
+                    break;
+                }
+            }
+        }
+
+        drop(stream);
+        let finish_reason = if outcome == StreamOutcome::Cancelled { "cancelled" } else { "stop" };
+        yield Ok(chunk_event(json!({}), Some(finish_reason)));
+        yield Ok(web::Bytes::from("data: [DONE]\n\n"));
+
+        // Notify that streaming is complete
+        completion.send(outcome);
+    };
+
+    Ok(
+        HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .append_header(("Cache-Control", "no-cache"))
+            .append_header(("X-Session-ID", session_id))
+            .append_header((REQUEST_ID_HEADER, request_id))
+            .append_header((CONTEXT_SOURCES_HEADER, sources_header))
+            .append_header((CONTEXT_SOURCES_TOTAL_HEADER, sources_total))
+            .streaming(response_stream)
+    )
+}
+
+#[get("/v1/models")]
+pub async fn list_models() -> Result<HttpResponse, Error> {
+    let config = get_app_config();
+
+    let mut models: Vec<Value> = config.models
+        .keys()
+        .map(|model_id| {
+            json!({
+                "id": model_id,
+                "object": "model",
+                "owned_by": DEFAULT_MODEL_NAME
+            })
+        })
+        .collect();
+    models.push(json!({ "id": DEFAULT_MODEL_NAME, "object": "model", "owned_by": DEFAULT_MODEL_NAME }));
+
+    Ok(HttpResponse::Ok().json(json!({ "object": "list", "data": models })))
+}
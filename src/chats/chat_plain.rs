@@ -4,15 +4,24 @@ use serde::{ Deserialize, Serialize };
 use super::chat_types::RequestType;
 use std::sync::{ Arc, Mutex };
 use crate::session_manager::check_session;
-use serde_json::json;
+use crate::authentication::authorization::User;
 use super::utils::handle_stream_completion;
 use crate::context::make_context::make_context;
+use crate::context::token_budget::context_budget;
+use crate::llm_stream::backend::{ requested_backend, resolve_backend_name };
+use crate::context::memory_scope::MemoryScope;
 use reqwest::Client;
+use crate::llm_stream::cancel::StreamOutcome;
+use crate::llm_stream::generation::GenerationParams;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatRequest {
     pub prompt: String,
     pub session_id: Option<String>,
+    pub generation: Option<GenerationParams>,
+    // session (default), user or global, see MemoryScope
+    #[serde(default)]
+    pub memory_scope: MemoryScope,
 }
 
 pub fn register_routes(cfg: &mut web::ServiceConfig) {
@@ -23,20 +32,11 @@ pub fn register_routes(cfg: &mut web::ServiceConfig) {
 pub async fn chat(
     data: web::Json<ChatRequest>,
     client: web::Data<Client>,
-    _req: HttpRequest
+    req: HttpRequest,
+    user: User
 ) -> Result<HttpResponse, Error> {
-    let session_id = match check_session(data.session_id.clone()) {
-        Ok(id) => id,
-        Err(e) => {
-            return Err(
-                actix_web::error::ErrorInternalServerError(
-                    json!({
-                "error": e.to_string()
-            })
-                )
-            );
-        }
-    };
+    let session_id = check_session(data.session_id.clone(), &user.user_id).await?;
+    let memory_scope = data.memory_scope.authorize(&user)?;
 
     let accumulated_content = Arc::new(Mutex::new(String::new()));
     let accumulated_content_clone = Arc::clone(&accumulated_content);
@@ -49,20 +49,7 @@ pub async fn chat(
     let shared_prompt = Arc::new(Mutex::new(data.prompt.clone()));
     let shared_prompt_clone = Arc::clone(&shared_prompt);
 
-    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
-    let context = make_context(&session_id, &data.prompt, 3).await?;
-
-    let prompt_with_context = format!(
-        r#"
-        Context from prior conversations and uploaded files (separated by '----------CONTEXT----------'): 
-        {context}
-        New question or coding request: {user_prompt}
-
-        Please provide your response following instruction-tuning principles.
-        "#,
-        context = context,
-        user_prompt = &data.prompt
-    );
+    let (tx, rx) = tokio::sync::oneshot::channel::<StreamOutcome>();
 
     let system_prompt: &str =
         r#"
@@ -95,12 +82,40 @@ pub async fn chat(
         - For multi-line code block conventions include language.
         "#;
 
+    // Retrieved context gets whatever the model's window leaves after the prompts and the answer
+    let backend_name = resolve_backend_name(RequestType::Chat.to_string(), requested_backend(&req).as_deref());
+    let budget = context_budget(
+        &client,
+        &backend_name,
+        RequestType::Chat.to_string(),
+        system_prompt,
+        &data.prompt,
+        data.generation.as_ref()
+    ).await;
+    let context = make_context(&client, &backend_name, &session_id, &user.user_id, &data.prompt, budget, memory_scope).await?;
+
+    let prompt_with_context = format!(
+        r#"
+        Context from prior conversations and uploaded files (separated by '----------CONTEXT----------'): 
+        {context}
+        New question or coding request: {user_prompt}
+
+        Please provide your response following instruction-tuning principles.
+        "#,
+        context = context.text,
+        user_prompt = &data.prompt
+    );
+
     let response = stream_to_chat_client(
         RequestType::Chat,
         &client,
+        &req,
         &session_id,
         system_prompt,
         &prompt_with_context,
+        &context.text,
+        &context.sources,
+        data.generation.as_ref(),
         accumulated_content_clone,
         tx
     ).await?;
@@ -112,7 +127,8 @@ pub async fn chat(
             accumulated_content,
             shared_session_id_clone,
             shared_prompt_clone,
-            RequestType::Chat
+            RequestType::Chat,
+            context.sources
         ).await;
     });
     Ok(response)
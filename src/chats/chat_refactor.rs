@@ -5,15 +5,24 @@ use serde::{ Deserialize, Serialize };
 use super::chat_types::RequestType;
 use std::sync::{Arc, Mutex};
 use crate::session_manager::check_session;
-use serde_json::json;
+use crate::authentication::authorization::User;
 use super::utils::handle_stream_completion;
 use crate::context::make_context::make_context;
+use crate::context::token_budget::context_budget;
+use crate::llm_stream::backend::{ requested_backend, resolve_backend_name };
+use crate::context::memory_scope::MemoryScope;
 use reqwest::Client;
+use crate::llm_stream::cancel::StreamOutcome;
+use crate::llm_stream::generation::GenerationParams;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RefactorRequest {
     pub prompt: String,
     pub session_id: Option<String>,
+    pub generation: Option<GenerationParams>,
+    // session (default), user or global, see MemoryScope
+    #[serde(default)]
+    pub memory_scope: MemoryScope,
 }
 
 pub fn register_routes(cfg: &mut web::ServiceConfig) {
@@ -21,19 +30,9 @@ pub fn register_routes(cfg: &mut web::ServiceConfig) {
 }
 
 #[post("/chat/refactor")]
-pub async fn chat_refactor(data: web::Json<RefactorRequest>, client: web::Data<Client>, _req: HttpRequest) -> Result<HttpResponse, Error> {
-    let session_id = match check_session(data.session_id.clone()) {
-        Ok(id) => id,
-        Err(e) => {
-            return Err(
-                actix_web::error::ErrorInternalServerError(
-                    json!({
-                "error": e.to_string()
-            })
-                )
-            );
-        }
-    };
+pub async fn chat_refactor(data: web::Json<RefactorRequest>, client: web::Data<Client>, req: HttpRequest, user: User) -> Result<HttpResponse, Error> {
+    let session_id = check_session(data.session_id.clone(), &user.user_id).await?;
+    let memory_scope = data.memory_scope.authorize(&user)?;
     
     let accumulated_content = Arc::new(Mutex::new(String::new()));
     let accumulated_content_clone = Arc::clone(&accumulated_content);
@@ -45,21 +44,8 @@ pub async fn chat_refactor(data: web::Json<RefactorRequest>, client: web::Data<C
     // Wrap your data in a Mutex or RwLock to ensure thread safety
     let shared_prompt = Arc::new(Mutex::new(data.prompt.clone()));
     let shared_prompt_clone = Arc::clone(&shared_prompt);
-    let context = make_context(&session_id, &data.prompt, 3).await?;
 
-    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
-    
-    
-    let prompt_with_context = format!(r#"
-        Context from prior conversations and uploaded files (separated by '----------CONTEXT----------'): 
-        {context}
-        New question or coding request: {user_prompt}
-
-        Please provide your response following instruction-tuning principles.
-        "#,
-            context = context,
-            user_prompt = &data.prompt
-        );
+    let (tx, rx) = tokio::sync::oneshot::channel::<StreamOutcome>();
 
     let system_prompt: &str = r#"
         You are an expert software engineer specializing in code refactoring. Your responses should improve code quality, readability, and efficiency. 
@@ -82,19 +68,46 @@ pub async fn chat_refactor(data: web::Json<RefactorRequest>, client: web::Data<C
         - Use proper indentation, comments, and single/multi-line code blocks.
     "#;
 
+    // Retrieved context gets whatever the model's window leaves after the prompts and the answer
+    let backend_name = resolve_backend_name(RequestType::Refactor.to_string(), requested_backend(&req).as_deref());
+    let budget = context_budget(
+        &client,
+        &backend_name,
+        RequestType::Refactor.to_string(),
+        system_prompt,
+        &data.prompt,
+        data.generation.as_ref()
+    ).await;
+    let context = make_context(&client, &backend_name, &session_id, &user.user_id, &data.prompt, budget, memory_scope).await?;
+
+    let prompt_with_context = format!(r#"
+        Context from prior conversations and uploaded files (separated by '----------CONTEXT----------'): 
+        {context}
+        New question or coding request: {user_prompt}
+
+        Please provide your response following instruction-tuning principles.
+        "#,
+            context = context.text,
+            user_prompt = &data.prompt
+        );
+
     let response = stream_to_chat_client(
         RequestType::Refactor,
         &client,
+        &req,
         &session_id,
         system_prompt,
         &prompt_with_context,
+        &context.text,
+        &context.sources,
+        data.generation.as_ref(),
         accumulated_content_clone,
         tx,
     ).await?;
     // Spawn a separate task to handle the stream completion
     // Ensure the main async task is spawned correctly
     tokio::spawn(async move {
-        handle_stream_completion(rx, accumulated_content, shared_session_id_clone, shared_prompt_clone, RequestType::Refactor).await;
+        handle_stream_completion(rx, accumulated_content, shared_session_id_clone, shared_prompt_clone, RequestType::Refactor, context.sources).await;
     });
     Ok(response)
 }
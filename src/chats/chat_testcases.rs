@@ -4,14 +4,17 @@ use serde::{ Deserialize, Serialize };
 use super::chat_types::RequestType;
 use std::sync::{Arc, Mutex};
 use crate::session_manager::check_session;
-use serde_json::json;
+use crate::authentication::authorization::User;
 use super::utils::handle_stream_completion;
 use reqwest::Client;
+use crate::llm_stream::cancel::StreamOutcome;
+use crate::llm_stream::generation::GenerationParams;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TestCasesRequest {
     pub prompt: String,
     pub session_id: Option<String>,
+    pub generation: Option<GenerationParams>,
 }
 
 pub fn register_routes(cfg: &mut web::ServiceConfig) {
@@ -19,19 +22,8 @@ pub fn register_routes(cfg: &mut web::ServiceConfig) {
 }
 
 #[post("/chat/tests-cases")]
-pub async fn chat_testcases(data: web::Json<TestCasesRequest>, client: web::Data<Client>, _req: HttpRequest) -> Result<HttpResponse, Error> {
-    let session_id = match check_session(data.session_id.clone()) {
-        Ok(id) => id,
-        Err(e) => {
-            return Err(
-                actix_web::error::ErrorInternalServerError(
-                    json!({
-                "error": e.to_string()
-            })
-                )
-            );
-        }
-    };
+pub async fn chat_testcases(data: web::Json<TestCasesRequest>, client: web::Data<Client>, req: HttpRequest, user: User) -> Result<HttpResponse, Error> {
+    let session_id = check_session(data.session_id.clone(), &user.user_id).await?;
     
     
     let accumulated_content = Arc::new(Mutex::new(String::new()));
@@ -47,7 +39,7 @@ pub async fn chat_testcases(data: web::Json<TestCasesRequest>, client: web::Data
 
 
 
-    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    let (tx, rx) = tokio::sync::oneshot::channel::<StreamOutcome>();
     
     
     //TODO: Add context
@@ -112,16 +104,20 @@ pub async fn chat_testcases(data: web::Json<TestCasesRequest>, client: web::Data
     let response = stream_to_chat_client(
         RequestType::TestCases,
         &client,
+        &req,
         &session_id,
         system_prompt,
         &prompt_with_context,
+        "",
+        &[],
+        data.generation.as_ref(),
         accumulated_content_clone,
         tx,
     ).await?;
     // Spawn a separate task to handle the stream completion
     // Ensure the main async task is spawned correctly
     tokio::spawn(async move {
-        handle_stream_completion(rx, accumulated_content, shared_session_id_clone, shared_prompt_clone, RequestType::TestCases).await;
+        handle_stream_completion(rx, accumulated_content, shared_session_id_clone, shared_prompt_clone, RequestType::TestCases, Vec::new()).await;
     });
     Ok(response)
 }
\ No newline at end of file
@@ -1,21 +1,31 @@
 use actix_web::{ get, web, HttpResponse, Error };
 use serde::Deserialize;
-use serde_json::json;
-use crate::database::db_config::DB_INSTANCE;
+use serde_json::{ json, Value };
+use std::collections::HashMap;
+use log::{ error, info };
+use crate::database::db_config::run_db;
+use crate::database::chat_db::ChatSearchFilters;
+use crate::embeddings::text_embeddings::generate_text_embedding;
+use crate::authentication::authorization::User;
+use crate::session_manager::owned_session;
+use crate::similarity_index::hybrid::RRF_K;
 
 pub fn register_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(session_chat_history)
         .service(request_type_chat_history)
+        .service(search_chat_history)
         .service(whole_chat_history); // Register the correct route handler
 }
 
 // The correct handler for GET steps
 #[get("/chat/history/all")]
-async fn whole_chat_history() -> Result<HttpResponse, Error> {
+async fn whole_chat_history(user: User) -> Result<HttpResponse, Error> {
     // Use into_inner to get the inner String from the Path extractors
 
     // Fetch the steps for the provided pair_programmer_id
-    let steps = DB_INSTANCE.fetch_chats_all();
+    let steps = run_db(move |db| db.fetch_chats_all(&user.user_id).map_err(|e| e.to_string()))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
 
     // Return the result as JSON
     Ok(HttpResponse::Ok().json(steps))
@@ -30,21 +40,43 @@ struct SessionParams {
 #[get("/chat/history/session_id/{session_id}")]
 async fn session_chat_history(
     path: web::Path<String>,
-    query: web::Query<SessionParams>
+    query: web::Query<SessionParams>,
+    user: User
 ) -> Result<HttpResponse, Error> {
     // Use into_inner to get the inner String from the Path extractors
     let session_id = path.into_inner();
 
+    // Human readable label and rolling summary of the session, if they were generated yet
+    let owner_session_id = session_id.clone();
+    let session = match run_db(move |_| owned_session(&owner_session_id, &user.user_id)).await? {
+        Ok(Some(session)) => session,
+        Ok(None) => {
+            return Ok(
+                HttpResponse::NotFound().json(json!({"error": format!("Session {} not found", session_id)}))
+            );
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()})));
+        }
+    };
+
     let page = query.page.unwrap_or(1);
     let page_size = query.page_size.unwrap_or(10);
 
     let skip = (page - 1) * page_size;
     // Fetch the steps for the provided pair_programmer_id
-    let history = DB_INSTANCE.fetch_chats_for_session(&session_id, skip, page_size);
+    let history_session_id = session_id.clone();
+    let history = run_db(move |db| {
+        db.fetch_chats_for_session(&history_session_id, skip, page_size).map_err(|e| e.to_string())
+    })
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
 
     let response =
         json!({
         "result" : history,
+        "title": session["title"],
+        "summary": session["summary"],
         "total": history.len(),
         "page": page,
         "page_size": page_size,
@@ -64,7 +96,8 @@ struct SessionTypeParams {
 #[get("/chat/history/request_type/{request_type}")]
 async fn request_type_chat_history(
     path: web::Path<String>,
-    query: web::Query<SessionTypeParams>
+    query: web::Query<SessionTypeParams>,
+    user: User
 ) -> Result<HttpResponse, Error> {
     // Use into_inner to get the inner String from the Path extractors
     let request_type = path.into_inner();
@@ -72,7 +105,11 @@ async fn request_type_chat_history(
     let page_size = query.page_size.unwrap_or(10);
 
     let skip = (page - 1) * page_size;
-    let history = DB_INSTANCE.fetch_chats_for_request_type(&request_type, skip, page_size);
+    let history = run_db(move |db|
+        db.fetch_chats_for_request_type(&user.user_id, &request_type, skip, page_size).map_err(|e| e.to_string())
+    )
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
 
     let response =
         json!({
@@ -84,4 +121,149 @@ async fn request_type_chat_history(
 
     // Return the result as JSON
     Ok(HttpResponse::Ok().json(response))
-}
\ No newline at end of file
+}
+#[derive(Deserialize)]
+struct SearchParams {
+    q: String,
+    session_id: Option<String>,
+    request_type: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Quotes every term so user input can't break the FTS5 query syntax, any term is enough to match
+pub fn fts_query(q: &str) -> String {
+    q.split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" OR ")
+}
+
+// A bare date as upper bound should include the whole day
+fn end_of_day(to: &str) -> String {
+    if to.len() == 10 { format!("{}T23:59:59Z", to) } else { to.to_string() }
+}
+
+// Hybrid search, BM25 over prompt/response and nearest chat embeddings merged with reciprocal rank fusion
+#[get("/chat/history/search")]
+async fn search_chat_history(query: web::Query<SearchParams>, user: User) -> Result<HttpResponse, Error> {
+    let query = query.into_inner();
+    let q = query.q.trim();
+    if q.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(json!({"error": "q is required"})));
+    }
+    let limit = query.limit.unwrap_or(10).clamp(1, 100);
+
+    let filters = ChatSearchFilters {
+        user_id: user.user_id,
+        session_id: query.session_id,
+        request_type: query.request_type,
+        from: query.from,
+        to: query.to.as_deref().map(end_of_day),
+    };
+
+    // Without an embedding model we still have the keyword results
+    let embeddings = match generate_text_embedding(q).await {
+        Ok(embeddings) => Some(embeddings),
+        Err(e) => {
+            error!("Failed to embed search query, using keyword search only: {}", e);
+            None
+        }
+    };
+
+    // Fetch more than we return from each side, so fusion has something to work with
+    let fts = fts_query(q);
+    let hits = run_db(move |db| {
+        let keyword_hits = db.keyword_search_chats(&fts, &filters, limit * 3).map_err(|e| e.to_string())?;
+        let semantic_hits = match embeddings {
+            Some(embeddings) =>
+                db.semantic_search_chats(embeddings, &filters, limit * 3).unwrap_or_else(|e| {
+                    error!("Semantic search failed: {}", e);
+                    Vec::new()
+                }),
+            None => Vec::new(),
+        };
+        Ok::<_, String>((keyword_hits, semantic_hits))
+    }).await?;
+    let (keyword_hits, semantic_hits) = match hits {
+        Ok(hits) => hits,
+        Err(e) => {
+            error!("Keyword search failed: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(json!({"error": e})));
+        }
+    };
+
+    // chat id -> (fused score, snippet, matched_by)
+    let mut fused: HashMap<String, (f64, Option<String>, Vec<&str>)> = HashMap::new();
+    for (rank, (id, _, snippet)) in keyword_hits.into_iter().enumerate() {
+        let entry = fused.entry(id).or_insert((0.0, None, Vec::new()));
+        entry.0 += 1.0 / (RRF_K + (rank as f64) + 1.0);
+        entry.1 = Some(snippet);
+        entry.2.push("keyword");
+    }
+    for (rank, (id, _)) in semantic_hits.into_iter().enumerate() {
+        let entry = fused.entry(id).or_insert((0.0, None, Vec::new()));
+        entry.0 += 1.0 / (RRF_K + (rank as f64) + 1.0);
+        entry.2.push("semantic");
+    }
+
+    let mut ranked: Vec<_> = fused.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.0.partial_cmp(&a.1.0).unwrap_or(std::cmp::Ordering::Equal));
+    ranked.truncate(limit);
+
+    let results = run_db(move |db| {
+        let mut results: Vec<Value> = Vec::new();
+        for (id, (score, snippet, matched_by)) in ranked {
+            match db.fetch_chat_by_id(&id) {
+                Ok(Some(mut chat)) => {
+                    chat["score"] = json!(score);
+                    chat["snippet"] = json!(snippet);
+                    chat["matched_by"] = json!(matched_by);
+                    results.push(chat);
+                }
+                Ok(None) => {}
+                Err(e) => error!("Failed to fetch chat {}: {}", id, e),
+            }
+        }
+        results
+    }).await?;
+    info!("Chat history search for {:?} returned {} results", q, results.len());
+
+    Ok(
+        HttpResponse::Ok().json(
+            json!({
+        "result": results,
+        "total": results.len(),
+        "query": q
+    })
+        )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fts_query;
+
+    #[test]
+    fn quotes_each_term() {
+        assert_eq!(fts_query("rerank  model"), "\"rerank\" OR \"model\"");
+    }
+
+    #[test]
+    fn escapes_double_quotes() {
+        assert_eq!(fts_query("say \"hi\""), "\"say\" OR \"\"\"hi\"\"\"");
+        assert_eq!(fts_query("a\"b"), "\"a\"\"b\"");
+    }
+
+    #[test]
+    fn star_and_operators_stay_literal() {
+        assert_eq!(fts_query("foo* NOT bar"), "\"foo*\" OR \"NOT\" OR \"bar\"");
+        assert_eq!(fts_query("*"), "\"*\"");
+    }
+
+    #[test]
+    fn blank_query_is_empty() {
+        assert_eq!(fts_query("   "), "");
+    }
+}
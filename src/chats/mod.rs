@@ -7,10 +7,14 @@ pub mod chat_findbugs;
 pub mod chat_docstring;
 pub mod chat_types;
 pub mod history;
+pub mod chat_completions;
+pub mod cancel;
 pub use chat_plain::register_routes as chat_plain_routes;
 pub use chat_explain::register_routes as chat_explain_routes;
 pub use chat_refactor::register_routes as chat_refactor_routes;
 pub use chat_testcases::register_routes as chat_testcases_routes;
 pub use chat_findbugs::register_routes as chat_findbugs_routes;
 pub use chat_docstring::register_routes as chat_docstring_routes;
-pub use history::register_routes as chat_history_routes;
\ No newline at end of file
+pub use history::register_routes as chat_history_routes;
+pub use chat_completions::register_routes as chat_completions_routes;
+pub use cancel::register_routes as chat_cancel_routes;
@@ -4,19 +4,30 @@ use super::chat_types::RequestType;
 use log::{error, debug};
 use crate::embeddings::text_embeddings::generate_text_embedding;
 use crate::prompt_compression::compress::get_attention_scores;
-use crate::database::db_config::DB_INSTANCE;
+use crate::database::db_config::run_db;
+use crate::llm_stream::cancel::StreamOutcome;
+use crate::session_manager::session_summary::update_session_metadata;
+use crate::authentication::authorization::DEFAULT_USER_ID;
+use crate::context::make_context::ContextSource;
 use std::time::{Duration, Instant};
 use std::future::Future;
 
 pub async fn handle_stream_completion(
-    rx: tokio::sync::oneshot::Receiver<()>,
+    rx: tokio::sync::oneshot::Receiver<StreamOutcome>,
     accumulated_content: Arc<Mutex<String>>,
     ts_session_id: Arc<Mutex<String>>,
     ts_prompt: Arc<Mutex<String>>,
     request_type: RequestType,
+    context_sources: Vec<ContextSource>,
 ) {
-    if let Ok(_) = rx.await {
+    if let Ok(outcome) = rx.await {
         let accumulated_content_final = accumulated_content.lock().unwrap().clone();
+        let cancelled = outcome == StreamOutcome::Cancelled;
+        // Nothing was generated before the cancel, nothing worth keeping
+        if cancelled && accumulated_content_final.is_empty() {
+            debug!("Request cancelled before any output, not storing chat");
+            return;
+        }
 
         // let summary = summarize_text(&accumulated_content_final).await.unwrap();
         let prompt = match ts_prompt.lock() {
@@ -61,29 +72,51 @@ pub async fn handle_stream_completion(
             }
         };
 
-        let db_response = DB_INSTANCE.store_chats(
-            "user_id",
-            &session_id,
-            &prompt,
-            &compressed_prompt_response,
-            &accumulated_content_final,
-            &embeddings,
-            request_type.to_string(),
-        );
+        // Both queries run on the blocking pool, the stream's worker keeps serving other requests
+        let store_session_id = session_id.clone();
+        let store_prompt = prompt.clone();
+        let response = accumulated_content_final.clone();
+        // Kept with the chat so the answer can be audited later
+        let context_sources = serde_json::to_string(&context_sources).unwrap_or_else(|_| "[]".to_string());
+        let stored = run_db(move |db| {
+            // Chats belong to whoever owns the session, check_session made sure that's the caller
+            let user_id = match db.get_session(&store_session_id) {
+                Ok(Some(session)) => session["user_id"].as_str().unwrap_or(DEFAULT_USER_ID).to_string(),
+                _ => DEFAULT_USER_ID.to_string(),
+            };
+            db.store_chats(
+                &user_id,
+                &store_session_id,
+                &store_prompt,
+                &compressed_prompt_response,
+                &response,
+                &embeddings,
+                request_type.to_string(),
+                cancelled,
+                &context_sources,
+            ).map_err(|e| e.to_string())
+        }).await;
 
-        match db_response {
+        let stored = match stored.map_err(|e| e.to_string()).and_then(|stored| stored) {
             Ok(_) => {
                 debug!(
                     "DB Update successful for chat for session_id {}",
                     session_id
                 );
+                true
             }
             Err(err) => {
                 error!(
                     "Error updating chat for session_id {}: {:?}",
                     session_id, err
                 );
+                false
             }
+        };
+
+        if stored {
+            // Title after the first exchange, rolling summary every few chats
+            update_session_metadata(&session_id, &prompt, &accumulated_content_final).await;
         }
     }
 }
@@ -0,0 +1,180 @@
+use std::path::{ Path, PathBuf };
+use globset::{ Glob, GlobSet, GlobSetBuilder };
+use ignore::gitignore::{ Gitignore, GitignoreBuilder };
+use log::warn;
+use serde::{ Deserialize, Serialize };
+
+// Project level ignore file, same syntax as .gitignore, for what git tracks but we don't want indexed
+pub const PYANO_IGNORE_FILE: &str = ".pyanoignore";
+
+// Ignore files at the root of an indexed directory, lowest precedence first like the walk has them
+pub const ROOT_IGNORE_FILES: [&str; 3] = [".gitignore", ".ignore", PYANO_IGNORE_FILE];
+
+/// How a path is indexed, sent with `POST /rags/index/code` and kept with the indexed path so
+/// re-indexing (and the watcher) use the same rules
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IndexOptions {
+    // Globs over paths relative to the indexed directory. When given, only matching files are
+    // indexed, docs and configs included.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    // Bytes, larger files are skipped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<u64>,
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern).map_err(|e| format!("Invalid glob '{}': {}", pattern, e))?;
+        builder.add(glob);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// `IndexOptions` compiled for one indexed directory
+pub struct FileFilter {
+    root: PathBuf,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    pub max_file_size: Option<u64>,
+}
+
+impl FileFilter {
+    pub fn new(root: &Path, options: &IndexOptions) -> Result<Self, String> {
+        let include = if options.include.is_empty() { None } else { Some(glob_set(&options.include)?) };
+        Ok(FileFilter {
+            root: root.to_path_buf(),
+            include,
+            exclude: glob_set(&options.exclude)?,
+            max_file_size: options.max_file_size,
+        })
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.root).unwrap_or(path)
+    }
+
+    /// Whether the include and exclude globs let the file in
+    pub fn accepts(&self, path: &Path) -> bool {
+        let relative = self.relative(path);
+        let included = match &self.include {
+            Some(include) => include.is_match(relative),
+            None => true,
+        };
+        included && !self.exclude.is_match(relative)
+    }
+
+    /// Whether an include glob names the file, which indexes it even if it's docs or config
+    pub fn asks_for(&self, path: &Path) -> bool {
+        match &self.include {
+            Some(include) => include.is_match(self.relative(path)),
+            None => false,
+        }
+    }
+
+    pub fn within_size(&self, path: &Path) -> bool {
+        match (self.max_file_size, path.metadata()) {
+            (Some(max_file_size), Ok(metadata)) => metadata.len() <= max_file_size,
+            _ => true,
+        }
+    }
+}
+
+/// The root ignore files of an indexed directory, plus `.git/info/exclude`, compiled into one
+/// matcher. The walk in `traverse_directory` also honours nested ignore files, this only has the
+/// root ones, enough for the watcher to skip writes in ignored build output such as `dist/`.
+pub fn root_ignore_matcher(root: &Path) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(root);
+    let mut ignore_files = vec![root.join(".git").join("info").join("exclude")];
+    ignore_files.extend(ROOT_IGNORE_FILES.iter().map(|name| root.join(name)));
+    for ignore_file in ignore_files {
+        if !ignore_file.is_file() {
+            continue;
+        }
+        if let Some(e) = builder.add(&ignore_file) {
+            warn!("Failed to read {}: {}", ignore_file.display(), e);
+        }
+    }
+    match builder.build() {
+        Ok(matcher) => matcher,
+        Err(e) => {
+            warn!("Failed to build the ignore rules of {}: {}", root.display(), e);
+            Gitignore::empty()
+        }
+    }
+}
+
+/// Whether the path is one of the root ignore files, after which the matcher is stale
+pub fn is_root_ignore_file(root: &Path, path: &Path) -> bool {
+    match path.strip_prefix(root) {
+        Ok(relative) => ROOT_IGNORE_FILES.iter().any(|name| relative == Path::new(name)),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::{ is_root_ignore_file, FileFilter, IndexOptions, PYANO_IGNORE_FILE };
+
+    fn filter(include: &[&str], exclude: &[&str]) -> FileFilter {
+        let options = IndexOptions {
+            include: include.iter().map(|glob| glob.to_string()).collect(),
+            exclude: exclude.iter().map(|glob| glob.to_string()).collect(),
+            max_file_size: None,
+        };
+        FileFilter::new(Path::new("/work/project"), &options).unwrap()
+    }
+
+    #[test]
+    fn accepts_everything_without_globs() {
+        let filter = filter(&[], &[]);
+        assert!(filter.accepts(Path::new("/work/project/src/main.rs")));
+        assert!(!filter.asks_for(Path::new("/work/project/src/main.rs")));
+    }
+
+    #[test]
+    fn include_globs_limit_and_ask_for_files() {
+        let filter = filter(&["src/**"], &[]);
+        assert!(filter.accepts(Path::new("/work/project/src/main.rs")));
+        assert!(filter.asks_for(Path::new("/work/project/src/main.rs")));
+        assert!(!filter.accepts(Path::new("/work/project/tests/api.rs")));
+        assert!(!filter.asks_for(Path::new("/work/project/tests/api.rs")));
+    }
+
+    #[test]
+    fn exclude_globs_win_over_include_globs() {
+        let filter = filter(&["src/**"], &["src/generated/**"]);
+        assert!(filter.accepts(Path::new("/work/project/src/lib.rs")));
+        assert!(!filter.accepts(Path::new("/work/project/src/generated/schema.rs")));
+        // Asked for, but still excluded
+        assert!(filter.asks_for(Path::new("/work/project/src/generated/schema.rs")));
+    }
+
+    #[test]
+    fn globs_match_paths_relative_to_the_root() {
+        let filter = filter(&[], &["vendor/**"]);
+        assert!(!filter.accepts(Path::new("/work/project/vendor/lib.rs")));
+        assert!(filter.accepts(Path::new("/work/project/src/vendor.rs")));
+        // A path outside the root is matched as given
+        assert!(!filter.accepts(Path::new("vendor/lib.rs")));
+    }
+
+    #[test]
+    fn rejects_invalid_globs() {
+        let options = IndexOptions { include: vec!["src/{a,b".to_string()], ..Default::default() };
+        assert!(FileFilter::new(Path::new("/work/project"), &options).is_err());
+    }
+
+    #[test]
+    fn recognises_root_ignore_files() {
+        let root = Path::new("/work/project");
+        assert!(is_root_ignore_file(root, &root.join(".gitignore")));
+        assert!(is_root_ignore_file(root, &root.join(PYANO_IGNORE_FILE)));
+        assert!(!is_root_ignore_file(root, &root.join("src").join(".gitignore")));
+        assert!(!is_root_ignore_file(root, Path::new("/elsewhere/.gitignore")));
+    }
+}
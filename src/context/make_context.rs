@@ -1,12 +1,131 @@
 use std::error::Error;
-use crate::database::db_config::DB_INSTANCE;
+use crate::database::db_config::run_db;
 use std::time::{ Duration, Instant };
 use std::future::Future;
 use crate::embeddings::text_embeddings::generate_text_embedding;
 use log::{ error, info };
 use crate::rerank::rerank::rerank_documents;
-use std::collections::HashSet;
-use crate::similarity_index::index::search_index;
+use std::collections::{ HashMap, HashSet };
+use crate::similarity_index::hybrid::hybrid_search_code;
+use crate::database::db_config::DBConfig;
+use crate::database::chat_db::ChatMemory;
+use crate::database::rag_db::ContextChunk;
+use serde::{ Deserialize, Serialize };
+use reqwest::Client;
+use super::memory_scope::{ MemoryScope, MAX_SCOPE_SESSIONS };
+use super::token_budget::{ count_tokens, count_tokens_batch, truncate_to_tokens, MIN_TRUNCATED_TOKENS };
+use super::symbol_context::{ definition_code, mentioned_definitions, MAX_MENTIONED_DEFINITIONS };
+use crate::database::symbol_db::SymbolDefinition;
+
+// Nearest earlier chats of the session handed to the reranker
+const CHAT_MEMORY_LIMIT: usize = 20;
+// Goes in front of every retrieved document, the prompts tell the model to look for it
+const CONTEXT_SEPARATOR: &str = "----------CONTEXT----------\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceKind {
+    Code,
+    Chat,
+    SessionSummary,
+    // Definition of a symbol the prompt mentions
+    Symbol,
+}
+
+/// One item an answer's context was built from. Sent to the client with the answer and
+/// stored with the chat row.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContextSource {
+    pub kind: SourceKind,
+    pub session_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_type: Option<String>,
+    // Zero based, as stored in context_children
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_line: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_line: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<String>,
+    // None for the prior chat and the session summary, which skip the reranker
+    pub rerank_score: Option<f32>,
+    // Cut short to fit the token budget
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+impl ContextSource {
+    fn code(chunk: &ContextChunk) -> Self {
+        ContextSource {
+            kind: SourceKind::Code,
+            session_id: chunk.session_id.clone(),
+            file_path: Some(chunk.file_path.clone()),
+            chunk_type: Some(chunk.chunk_type.clone()),
+            start_line: chunk.start_line,
+            end_line: chunk.end_line,
+            chat_id: None,
+            symbol: None,
+            rerank_score: None,
+            truncated: false,
+        }
+    }
+
+    fn symbol(definition: &SymbolDefinition) -> Self {
+        ContextSource {
+            kind: SourceKind::Symbol,
+            session_id: definition.session_id.clone(),
+            file_path: Some(definition.file_path.clone()),
+            chunk_type: Some(definition.kind.clone()),
+            start_line: Some(definition.start_line),
+            end_line: Some(definition.end_line),
+            chat_id: None,
+            symbol: Some(definition.name.clone()),
+            rerank_score: None,
+            truncated: false,
+        }
+    }
+
+    fn chat(session_id: &str, chat_id: &str) -> Self {
+        ContextSource {
+            kind: SourceKind::Chat,
+            session_id: session_id.to_string(),
+            file_path: None,
+            chunk_type: None,
+            start_line: None,
+            end_line: None,
+            chat_id: Some(chat_id.to_string()),
+            symbol: None,
+            rerank_score: None,
+            truncated: false,
+        }
+    }
+
+    fn session_summary(session_id: &str) -> Self {
+        ContextSource {
+            kind: SourceKind::SessionSummary,
+            session_id: session_id.to_string(),
+            file_path: None,
+            chunk_type: None,
+            start_line: None,
+            end_line: None,
+            chat_id: None,
+            symbol: None,
+            rerank_score: None,
+            truncated: false,
+        }
+    }
+}
+
+/// The context handed to the model and the items it was built from
+#[derive(Debug, Clone, Default)]
+pub struct RetrievedContext {
+    pub text: String,
+    pub sources: Vec<ContextSource>,
+}
 
 /// Retrieves the last `n` chats for a given session.
 ///
@@ -15,20 +134,21 @@ use crate::similarity_index::index::search_index;
 /// * `n` - The number of last chats to retrieve.
 ///
 /// # Returns
-/// A vector of chats or an error if the retrieval fails.
-async fn get_last_chats(session_id: &str, n: usize) -> Result<Vec<String>, Box<dyn Error>> {
+/// A vector of (chat id, compressed chat), newest first, or an error if the retrieval fails.
+async fn get_last_chats(session_id: &str, n: usize) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let session_id = session_id.to_string();
     let (chats, _duration) = measure_time_async(|| async {
-        DB_INSTANCE.get_last_n_chats(session_id, n)
+        run_db(move |db| db.get_last_n_chats(&session_id, n).map_err(|e| e.to_string())).await
     }).await;
 
-    match chats {
+    match chats? {
         Ok(chats) => {
             // info!("Time elapsed in getting last {} chats: {:?}", n, duration);
             Ok(chats)
         }
         Err(e) => {
-            error!("Failed to generate embeddings: {}", e);
-            Err(e)
+            error!("Failed to get the last chats: {}", e);
+            Err(e.into())
         }
     }
 }
@@ -57,23 +177,33 @@ async fn generate_prompt_embeddings(prompt: &str) -> Result<Vec<f32>, Box<dyn Er
     }
 }
 
-/// Queries the nearest embeddings based on the generated embeddings.
+/// Queries the nearest chats within the memory scope based on the generated embeddings.
 ///
 /// # Arguments
+/// * `session_id` - The current session.
+/// * `user_id` - The caller, whose sessions the `user` scope covers.
+/// * `scope` - Which chats are searched.
 /// * `embeddings` - The embeddings to query.
 /// * `limit` - The number of nearest embeddings to retrieve.
 ///
 /// # Returns
-/// A vector of tuples (rowid, distance, prompt, compressed_prompt_response), or an error.
+/// The nearest chats, or an error.
 async fn query_nearest_chat_embeddings(
+    session_id: &str,
+    user_id: &str,
+    scope: MemoryScope,
     embeddings: Vec<f32>,
     limit: usize
-) -> Result<Vec<(i64, f64, String, String, String)>, Box<dyn Error>> {
+) -> Result<Vec<ChatMemory>, Box<dyn Error>> {
+    let (session_id, user_id) = (session_id.to_string(), user_id.to_string());
     let (chats, duration) = measure_time_async(|| async {
-        DB_INSTANCE.query_nearest_embeddings(embeddings.clone(), limit)
+        run_db(move |db| {
+            let (user_filter, session_filter) = scope.chat_filters(&user_id, &session_id);
+            db.query_chat_memory(user_filter, session_filter, embeddings, limit).map_err(|e| e.to_string())
+        }).await
     }).await;
 
-    match chats {
+    match chats? {
         Ok(chats) => {
             info!(
                 "Time elapsed in getting last {} nearest embeddings to query: {:?} and got {} nearest embeddings",
@@ -84,25 +214,54 @@ async fn query_nearest_chat_embeddings(
             Ok(chats)
         }
         Err(e) => {
-            error!("Failed to generate embeddings: {}", e);
-            Err(e)
+            error!("Failed to query the nearest chat embeddings: {}", e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Sessions whose indexed code the memory scope covers, the current one included.
+///
+/// # Arguments
+/// * `session_id` - The current session.
+/// * `user_id` - The caller, whose sessions the `user` scope covers.
+/// * `scope` - Which sessions' indexes are searched.
+///
+/// # Returns
+/// The session ids, or an error.
+async fn indexed_sessions(
+    session_id: &str,
+    user_id: &str,
+    scope: MemoryScope
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut sessions = vec![session_id.to_string()];
+    if scope != MemoryScope::Session {
+        let owner = if scope == MemoryScope::User { Some(user_id.to_string()) } else { None };
+        sessions = run_db(move |db| db.fetch_indexed_sessions(owner.as_deref(), MAX_SCOPE_SESSIONS)).await??;
+        if !sessions.iter().any(|id| id == session_id) {
+            sessions.push(session_id.to_string());
         }
     }
+    Ok(sessions)
 }
 
-/// Queries the session context based on the embeddings.
+/// Queries the indexed code of the sessions, by embeddings and by keywords.
 ///
 /// # Arguments
+/// * `sessions` - The sessions whose indexes are searched, see `indexed_sessions`.
+/// * `prompt` - The user prompt, matched against identifiers, file paths and code.
 /// * `embeddings` - The embeddings to query.
 /// * `limit` - The number of session context items to retrieve.
 ///
 /// # Returns
-/// A vector of tuples (file_path, chunk_type, content), or an error.
+/// The matching chunks with their line ranges, or an error.
 async fn query_session_context(
-    session_id: &str,
+    sessions: Vec<String>,
+    prompt: &str,
     embeddings: Vec<f32>,
     limit: usize
-) -> Result<Vec<(String, String, String, String)>, Box<dyn Error>> {
+) -> Result<Vec<ContextChunk>, Box<dyn Error>> {
+
     // match DB_INSTANCE.query_session_context(embeddings, limit) {
     //     Ok(context) => {
     //         info!("Nearest embeddings from the database {:?}", context);
@@ -128,9 +287,9 @@ async fn query_session_context(
     //     }
     // }
 
-    let chunk_ids = search_index(session_id, embeddings, limit);
+    let chunk_ids = hybrid_search_code(sessions, prompt, embeddings, limit).await;
 
-    let entries = DB_INSTANCE.get_row_ids(chunk_ids).unwrap();
+    let entries = run_db(move |db| db.get_context_chunks(chunk_ids).map_err(|e| e.to_string())).await??;
     Ok(entries)
 }
 
@@ -138,60 +297,114 @@ async fn query_session_context(
 ///
 /// # Arguments
 /// * `last_chats` - The vector of last chats.
-/// * `rag_context` - The session context (file path, content, etc.).
+/// * `rag_context` - The session context chunks.
+/// * `definitions` - Definitions of the symbols the prompt mentions.
 /// * `query_context` - The nearest embeddings queries.
+/// * `session_id` - The current session, anything from another one is attributed to it.
+/// * `session_labels` - Titles of the other sessions, by id.
 ///
 ///
 /// # Returns
-/// A formatted string combining the context.
+/// The formatted documents without duplicates, each with the item it came from.
 fn combine_contexts(
-    last_chats: Vec<String>,
-    rag_context: Vec<(String, String, String, String)>,
-    query_context: Vec<(i64, f64, String, String, String)>
-) -> HashSet<String> {
-    // file_path, chunk_type, content, session_id
-    let formatted_context: Vec<String> = rag_context
-        .iter()
-        .map(|(file_path, _, content, _)| format!("file_path: {}\nContent: {}", file_path, content))
-        .collect();
+    last_chats: Vec<(String, String)>,
+    rag_context: Vec<ContextChunk>,
+    definitions: Vec<SymbolDefinition>,
+    query_context: Vec<ChatMemory>,
+    session_id: &str,
+    session_labels: &HashMap<String, String>
+) -> Vec<(String, ContextSource)> {
+    let source = |sid: &str| session_labels.get(sid).cloned().unwrap_or_else(|| sid.to_string());
+
+    let recent_chats = last_chats
+        .into_iter()
+        .map(|(chat_id, compressed)| (compressed, ContextSource::chat(session_id, &chat_id)));
+
+    let formatted_context = rag_context.iter().map(|chunk| {
+        let document = if chunk.session_id == session_id {
+            format!("file_path: {}\nContent: {}", chunk.file_path, chunk.content)
+        } else {
+            format!(
+                "file_path: {}\nsource_session: {}\nContent: {}",
+                chunk.file_path,
+                source(&chunk.session_id),
+                chunk.content
+            )
+        };
+        (document, ContextSource::code(chunk))
+    });
 
     // info!("Context from the files {:?}", formatted_context);
 
-    let nearest_queries: Vec<String> = query_context
+    // A definition whose chunk was retrieved already is left out
+    let symbol_context = definitions
         .iter()
-        .map(|(_, _, _, compressed_prompt_response, _)| compressed_prompt_response.clone())
-        .collect();
+        .filter(|definition| {
+            !rag_context
+                .iter()
+                .any(|chunk| {
+                    chunk.session_id == definition.session_id &&
+                        chunk.file_path == definition.file_path &&
+                        chunk.start_line == Some(definition.start_line)
+                })
+        })
+        .map(|definition| {
+            let mut document = format!(
+                "file_path: {}\nsymbol: {} {}\n",
+                definition.file_path,
+                definition.kind,
+                definition.name
+            );
+            if definition.session_id != session_id {
+                document.push_str(&format!("source_session: {}\n", source(&definition.session_id)));
+            }
+            document.push_str(&format!("Content: {}", definition_code(definition)));
+            (document, ContextSource::symbol(definition))
+        })
+        .collect::<Vec<_>>();
 
-    // info!("Context from the chat history {:?}", nearest_queries);
+    let nearest_queries = query_context.iter().map(|memory| {
+        let document = if memory.session_id == session_id {
+            memory.compressed_prompt_response.clone()
+        } else {
+            format!("source_session: {}\n{}", source(&memory.session_id), memory.compressed_prompt_response)
+        };
+        (document, ContextSource::chat(&memory.session_id, &memory.chat_id))
+    });
 
-    let mut all_context: HashSet<String> = last_chats.into_iter().collect(); // Remove duplicates
-    all_context.extend(formatted_context);
-    all_context.extend(nearest_queries);
+    // info!("Context from the chat history {:?}", nearest_queries);
 
-    all_context
+    // Remove duplicates, the first item a document came from is the one reported
+    let mut seen: HashSet<String> = HashSet::new();
+    recent_chats
+        .chain(formatted_context)
+        .chain(symbol_context)
+        .chain(nearest_queries)
+        .filter(|(document, _)| seen.insert(document.clone()))
+        .collect()
 }
 
-/// Filters and returns the top `n` reranked documents with positive scores.
+/// Reranks the documents against the prompt.
 ///
 /// # Arguments
 /// * `prompt` - The original prompt.
-/// * `all_context` - The combined context.
-/// * `top_n` - The number of top documents to return.
+/// * `all_context` - The combined context and where each document came from.
 ///
 /// # Returns
-/// A formatted string of the top `n` documents or an empty string if none are available.
+/// The documents, most relevant first, with their sources carrying the rerank scores.
 async fn filter_reranked_documents(
     prompt: &str,
-    all_context: Vec<String>,
-    top_n: usize
-) -> Result<String, Box<dyn Error>> {
+    all_context: Vec<(String, ContextSource)>
+) -> Result<Vec<(String, ContextSource)>, Box<dyn Error>> {
+    let (documents, sources): (Vec<String>, Vec<ContextSource>) = all_context.into_iter().unzip();
+
     // info!("RERANKED DOcuments process started");
 
     // let reranked_documents = rerank_documents(prompt, all_context).await;
     // info!("RERANKED DOcuments {:?}", reranked_documents);
 
     let (documents, duration) = measure_time_async(|| async {
-        rerank_documents(prompt, all_context).await
+        rerank_documents(prompt, documents).await
     }).await;
 
     info!("Rerank docs resulting length {:?}", documents);
@@ -200,15 +413,16 @@ async fn filter_reranked_documents(
             info!("Time elapsed in re ranking documents {:?}", duration);
             info!("Rerank docs resulting length {:?}", docs.len());
 
-            let formatted_docs = docs
+            // The index points back into the documents we passed in
+            let reranked = docs
                 .into_iter()
-                .take(top_n) // Take only top N
-                .map(|(document, _, _)| document) // Extract document
-                .collect::<Vec<String>>() // Collect into Vec<String>
-                .join("----------CONTEXT----------\n"); // Join with separator
-
-            // Return the formatted string or an empty string if no documents are available
-            Ok(if formatted_docs.is_empty() { String::new() } else { formatted_docs })
+                .filter_map(|(document, index, score)| {
+                    let mut source = sources.get(index)?.clone();
+                    source.rerank_score = Some(score);
+                    Some((document, source))
+                })
+                .collect();
+            Ok(reranked)
         }
         Err(e) => {
             error!("Failed to rerank docs: {:?}", e);
@@ -217,51 +431,181 @@ async fn filter_reranked_documents(
     }
 }
 
+/// Greedily packs the reranked documents, best first, into the token budget. See
+/// `pack_counted_documents`, this only counts their tokens.
+///
+/// # Arguments
+/// * `client` - Used to reach the model's tokenizer.
+/// * `backend_name` - The backend that will answer, see `token_budget::count_tokens`.
+/// * `reranked` - The documents, most relevant first.
+/// * `budget` - Tokens available for the documents.
+///
+/// # Returns
+/// The documents joined with the context separator and the sources of the ones included.
+async fn pack_documents(
+    client: &Client,
+    backend_name: &str,
+    reranked: Vec<(String, ContextSource)>,
+    budget: usize
+) -> (String, Vec<ContextSource>) {
+    // Counted with their separator, that's what goes into the prompt
+    let framed: Vec<String> = reranked
+        .iter()
+        .map(|(document, _)| format!("{}{}", CONTEXT_SEPARATOR, document))
+        .collect();
+    let token_counts = count_tokens_batch(client, backend_name, &framed).await;
+
+    pack_counted_documents(reranked, &token_counts, budget)
+}
+
+/// Packs documents whose tokens (separator included) are already counted. A document that
+/// doesn't fit is truncated to the room left if that's still worth it, else skipped for
+/// smaller ones further down.
+fn pack_counted_documents(
+    reranked: Vec<(String, ContextSource)>,
+    token_counts: &[usize],
+    budget: usize
+) -> (String, Vec<ContextSource>) {
+    let mut remaining = budget;
+    let mut documents: Vec<String> = Vec::new();
+    let mut sources: Vec<ContextSource> = Vec::new();
+
+    for ((document, mut source), &tokens) in reranked.into_iter().zip(token_counts) {
+        if remaining == 0 {
+            break;
+        }
+        if tokens <= remaining {
+            remaining -= tokens;
+            documents.push(document);
+        } else if remaining >= MIN_TRUNCATED_TOKENS {
+            documents.push(truncate_to_tokens(&document, tokens, remaining));
+            source.truncated = true;
+            remaining = 0;
+        } else {
+            continue;
+        }
+        sources.push(source);
+    }
+
+    (documents.join(CONTEXT_SEPARATOR), sources)
+}
+
 /// The main function to generate the context for a given session.
 ///
 /// # Arguments
+/// * `client` - Used to reach the model's tokenizer.
+/// * `backend_name` - The backend that will answer, see `token_budget::count_tokens`.
 /// * `session_id` - The session ID.
+/// * `user_id` - The caller, see `MemoryScope`.
 /// * `prompt` - The user prompt.
-/// * `top_n` - The number of top documents to include in the final context.
+/// * `token_budget` - Tokens the context may take, see `token_budget::context_budget`.
+/// * `scope` - Whether memories come from this session, all of the user's or all sessions.
 ///
 /// # Returns
-/// The full context string or an error.
+/// The full context string with the items it was built from, or an error.
 pub async fn make_context(
+    client: &Client,
+    backend_name: &str,
     session_id: &str,
+    user_id: &str,
     prompt: &str,
-    top_n: usize
-) -> Result<String, Box<dyn Error>> {
+    token_budget: usize,
+    scope: MemoryScope
+) -> Result<RetrievedContext, Box<dyn Error>> {
     let last_chats = get_last_chats(session_id, 4).await?;
 
     let embeddings = generate_prompt_embeddings(prompt).await?;
 
-    //SQLITE vector embeddings doesnt support anyother colums execptet tor_id and embeddings
-    // as a result we are fetching around 100 nearest do cuments in all the user history
-    //and then filtering on the basis of the session_id
-    let query_context = query_nearest_chat_embeddings(embeddings.clone(), 100).await?
-        .into_iter()
-        .filter(|(_, _, _, _, sid)| sid == session_id)
-        .collect::<Vec<_>>();
-    let rag_context = query_session_context(session_id, embeddings, 10).await?;
+    // Chat embeddings are partitioned by session, the session scope only searches this one
+    let query_context = query_nearest_chat_embeddings(
+        session_id,
+        user_id,
+        scope,
+        embeddings.clone(),
+        CHAT_MEMORY_LIMIT
+    ).await?;
+    let sessions = indexed_sessions(session_id, user_id, scope).await?;
+    let definitions = mentioned_definitions(sessions.clone(), prompt, MAX_MENTIONED_DEFINITIONS).await;
+    let rag_context = query_session_context(sessions, prompt, embeddings, 10).await?;
+
+    // Memories from other sessions are labelled with the title of the session they came from
+    let other_sessions: HashSet<String> = query_context
+        .iter()
+        .map(|memory| memory.session_id.clone())
+        .chain(rag_context.iter().map(|chunk| chunk.session_id.clone()))
+        .chain(definitions.iter().map(|definition| definition.session_id.clone()))
+        .filter(|sid| sid != session_id)
+        .collect();
+    let session_labels = run_db(move |db| session_labels(db, other_sessions)).await?;
+
+    let all_context = combine_contexts(
+        last_chats.clone(),
+        rag_context,
+        definitions,
+        query_context,
+        session_id,
+        &session_labels
+    );
 
-    let all_context_set = combine_contexts(last_chats.clone(), rag_context, query_context);
-    let all_context: Vec<String> = all_context_set.into_iter().collect();
+    let reranked = filter_reranked_documents(prompt, all_context).await?;
 
-    let only_pos_distance_documents = filter_reranked_documents(prompt, all_context, top_n).await?;
+    // The rolling summary covers the whole session, a fresh session only has its last chat
+    let summary_session_id = session_id.to_string();
+    let (mut prior_conversation, mut prior_source) = match
+        run_db(move |db| db.get_session_summary(&summary_session_id)).await?
+    {
+        Ok((Some(summary), _)) if !summary.is_empty() => {
+            (format!("session_summary: {}", summary), Some(ContextSource::session_summary(session_id)))
+        }
+        _ =>
+            match last_chats.first() {
+                Some((chat_id, compressed)) => {
+                    (format!("prior_chat: {}", compressed), Some(ContextSource::chat(session_id, chat_id)))
+                }
+                None => ("prior_chat: ".to_string(), None),
+            }
+    };
+
+    // The prior conversation always goes in, the documents get what's left of the budget
+    let prior_tokens = count_tokens(client, backend_name, &prior_conversation).await;
+    if prior_tokens > token_budget {
+        prior_conversation = truncate_to_tokens(&prior_conversation, prior_tokens, token_budget);
+        if let Some(source) = prior_source.as_mut() {
+            source.truncated = true;
+        }
+    }
+    let (only_pos_distance_documents, mut sources) = pack_documents(
+        client,
+        backend_name,
+        reranked,
+        token_budget.saturating_sub(prior_tokens)
+    ).await;
     // info!("Reranked documents {:?}", only_pos_distance_documents);
+    sources.extend(prior_source);
 
     let result = if only_pos_distance_documents.is_empty() {
-        format!("prior_chat: {}", last_chats.get(0).unwrap_or(&String::new()))
+        prior_conversation
     } else {
-        format!(
-            "----------CONTEXT----------\n{}\nprior_chat: {}",
-            only_pos_distance_documents,
-            last_chats.get(0).unwrap_or(&String::new())
-        )
+        format!("{}{}\n{}", CONTEXT_SEPARATOR, only_pos_distance_documents, prior_conversation)
     };
     // info!("Context being fed {}", result);
 
-    Ok(result)
+    Ok(RetrievedContext { text: result, sources })
+}
+
+/// "title (id)" of each session, or just the id while it has no title
+fn session_labels(db: &DBConfig, session_ids: HashSet<String>) -> HashMap<String, String> {
+    session_ids
+        .into_iter()
+        .map(|sid| {
+            let title = match db.get_session(&sid) {
+                Ok(Some(session)) => session["title"].as_str().unwrap_or_default().to_string(),
+                _ => String::new(),
+            };
+            let label = if title.is_empty() { sid.clone() } else { format!("{} ({})", title, sid) };
+            (sid, label)
+        })
+        .collect()
 }
 
 /// Measures the time taken to execute an asynchronous function.
@@ -283,3 +627,57 @@ pub async fn measure_time_async<T, F, Fut>(func: F) -> (T, Duration)
     let duration = start.elapsed();
     (result, duration)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ pack_counted_documents, ContextSource, CONTEXT_SEPARATOR };
+    use crate::context::token_budget::MIN_TRUNCATED_TOKENS;
+
+    fn documents(names: &[&str]) -> Vec<(String, ContextSource)> {
+        names
+            .iter()
+            .map(|name| (name.repeat(40), ContextSource::chat("session", name)))
+            .collect()
+    }
+
+    fn chat_ids(sources: &[ContextSource]) -> Vec<String> {
+        sources
+            .iter()
+            .map(|source| source.chat_id.clone().unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn packs_everything_that_fits() {
+        let (text, sources) = pack_counted_documents(documents(&["a", "b"]), &[10, 20], 100);
+        assert_eq!(chat_ids(&sources), vec!["a", "b"]);
+        assert_eq!(text, format!("{}{}{}", "a".repeat(40), CONTEXT_SEPARATOR, "b".repeat(40)));
+        assert!(sources.iter().all(|source| !source.truncated));
+    }
+
+    #[test]
+    fn skips_a_document_too_big_to_truncate_for_a_smaller_one() {
+        // 30 left after "a", too little to truncate "b" into, "c" still fits
+        let budget = 50;
+        let (_, sources) = pack_counted_documents(documents(&["a", "b", "c"]), &[20, 500, 25], budget);
+        assert!(budget - 20 < MIN_TRUNCATED_TOKENS);
+        assert_eq!(chat_ids(&sources), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn truncates_into_the_room_left_and_stops() {
+        let budget = 20 + MIN_TRUNCATED_TOKENS;
+        let (text, sources) = pack_counted_documents(documents(&["a", "b", "c"]), &[20, 500, 5], budget);
+        assert_eq!(chat_ids(&sources), vec!["a", "b"]);
+        assert!(!sources[0].truncated);
+        assert!(sources[1].truncated);
+        assert!(text.ends_with("...(truncated)"));
+    }
+
+    #[test]
+    fn zero_budget_packs_nothing() {
+        let (text, sources) = pack_counted_documents(documents(&["a", "b"]), &[1, 1], 0);
+        assert!(text.is_empty());
+        assert!(sources.is_empty());
+    }
+}
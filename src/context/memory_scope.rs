@@ -0,0 +1,38 @@
+use serde::{ Deserialize, Serialize };
+use serde_json::json;
+use crate::authentication::authorization::User;
+
+// Upper bound on the code indexes searched for one request outside the session scope
+pub const MAX_SCOPE_SESSIONS: usize = 50;
+
+/// Where `make_context` looks for memories: the current session, every session of the
+/// caller, or every session on the server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryScope {
+    #[default]
+    Session,
+    User,
+    Global,
+}
+
+impl MemoryScope {
+    /// Global memory reads other users' chats and code, only admins (and the local user) get it
+    pub fn authorize(self, user: &User) -> Result<Self, actix_web::Error> {
+        if self == MemoryScope::Global && !user.is_admin {
+            return Err(
+                actix_web::error::ErrorForbidden(json!({"error": "memory_scope global requires an admin API key"}))
+            );
+        }
+        Ok(self)
+    }
+
+    /// (user_id, session_id) filters for chat memory, None means any
+    pub fn chat_filters<'a>(self, user_id: &'a str, session_id: &'a str) -> (Option<&'a str>, Option<&'a str>) {
+        match self {
+            MemoryScope::Session => (None, Some(session_id)),
+            MemoryScope::User => (Some(user_id), None),
+            MemoryScope::Global => (None, None),
+        }
+    }
+}
@@ -1,2 +1,6 @@
+pub mod index_filter;
 pub mod make_context;
-pub mod store_text_context;
\ No newline at end of file
+pub mod memory_scope;
+pub mod store_text_context;
+pub mod symbol_context;
+pub mod token_budget;
\ No newline at end of file
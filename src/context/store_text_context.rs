@@ -4,19 +4,32 @@ use std::fs;
 use url::Url;
 use tempfile::TempDir;
 use reqwest::Client;
-use std::io::{ self };
 use std::path::Path;
 use git2::Repository;
 use log::{ info, error, warn };
 use crate::parser::parse_code::{ ParseCode, Chunk, ChunkWithCompressedData };
-use crate::database::db_config::DB_INSTANCE;
+use crate::parser::symbols::{ Symbol, SymbolReference };
+use super::index_filter::{ FileFilter, IndexOptions, PYANO_IGNORE_FILE };
+use ignore::WalkBuilder;
+use crate::database::db_config::{ run_db, DB_INSTANCE };
 use crate::embeddings::text_embeddings::generate_text_embedding;
 use crate::prompt_compression::compress::get_attention_scores;
 use crate::similarity_index::index::{ add_to_index, remove_from_index };
 use rand::Rng;
-use std::collections::HashSet;
-use chrono::{ DateTime, Utc };
-use std::time::SystemTime;
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ Arc, Mutex };
+use once_cell::sync::Lazy;
+use tokio::sync::Mutex as AsyncMutex;
+use serde::Serialize;
+use sha2::{ Digest, Sha256 };
+
+// One lock per (session_id, path), see `index_lock`
+type IndexLocks = HashMap<(String, String), Arc<AsyncMutex<()>>>;
+
+static INDEX_LOCKS: Lazy<Mutex<IndexLocks>> = Lazy::new(||
+    Mutex::new(HashMap::new())
+);
+
 #[derive(Debug)]
 struct InvalidGitURLError(String);
 
@@ -76,7 +89,7 @@ async fn download_github_repo(
     Ok(repo_dir.to_string_lossy().to_string())
 }
 
-fn is_excluded_directory(dir_name: &str) -> bool {
+pub fn is_excluded_directory(dir_name: &str) -> bool {
     // List of common directories to exclude
     let excluded_dirs = vec![
         "node_modules",
@@ -98,45 +111,51 @@ fn is_excluded_directory(dir_name: &str) -> bool {
     excluded_dirs.contains(&dir_name)
 }
 
-/// Recursively traverses the given directory path and appends all file paths to a list.
+/// Recursively traverses the given directory path and appends the file paths to index to a
+/// list, with whether the include globs asked for each one. Honours .gitignore (of the
+/// directory and its parents), .ignore and .pyanoignore files, the excluded directories and
+/// the filter's globs and size limit.
 ///
 /// # Arguments
 /// * `dir_path` - The path of the directory to traverse.
-/// * `file_paths` - A mutable reference to a Vec<String> that will store the file paths.
-///
-/// # Returns
-/// A `Result` indicating success or failure.
-fn traverse_directory(dir_path: &str, file_paths: &mut Vec<String>) -> io::Result<()> {
+/// * `filter` - The indexing options compiled for this directory.
+/// * `file_paths` - A mutable reference to a Vec that will store the file paths.
+fn traverse_directory(dir_path: &str, filter: &FileFilter, file_paths: &mut Vec<(String, bool)>) {
     let path = Path::new(dir_path);
+    if !path.is_dir() {
+        info!("Couldnt find this directory {:?}", path);
+        return;
+    }
 
-    // Check if the given path is a directory
-    if path.is_dir() {
-        // Read the directory entries (files and subdirectories)
-        for entry in fs::read_dir(path)? {
-            let entry = entry?;
-            let path = entry.path();
-
-            // Skip excluded directories
-            if path.is_dir() {
-                if let Some(dir_name) = path.file_name() {
-                    if is_excluded_directory(&dir_name.to_string_lossy()) {
-                        continue; // Skip this directory
-                    }
-                }
-                // Recurse into subdirectories
-                traverse_directory(&path.to_string_lossy(), file_paths)?;
-            }
-
-            // If the path is a file, append it to the list
-            if path.is_file() {
-                file_paths.push(path.to_string_lossy().to_string());
+    let mut walker = WalkBuilder::new(path);
+    walker
+        // Dotfiles were always indexed, only the ignore files decide now
+        .hidden(false)
+        // A directory that isn't a git checkout still has its .gitignore honoured
+        .require_git(false)
+        .add_custom_ignore_filename(PYANO_IGNORE_FILE)
+        .max_filesize(filter.max_file_size)
+        .filter_entry(|entry| {
+            let is_dir = entry.file_type().is_some_and(|file_type| file_type.is_dir());
+            !(is_dir && is_excluded_directory(&entry.file_name().to_string_lossy()))
+        });
+
+    for entry in walker.build() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Skipping an entry under {}: {}", dir_path, e);
+                continue;
             }
+        };
+        if !entry.file_type().is_some_and(|file_type| file_type.is_file()) {
+            continue;
+        }
+        if filter.accepts(entry.path()) {
+            let asked_for = filter.asks_for(entry.path());
+            file_paths.push((entry.path().to_string_lossy().to_string(), asked_for));
         }
-    } else {
-        info!("Couldnt find this directory {:?}", path);
     }
-
-    Ok(())
 }
 
 /// Checks if the given path is a local directory.
@@ -160,19 +179,154 @@ async fn is_remote_repo(path: &str) -> Result<bool, Box<dyn Error>> {
     Ok(false)
 }
 
+/// What an indexing run did. Files are counted for local files and directories, which are
+/// indexed incrementally, and reported as added for remote ones.
+#[derive(Debug, Default, Serialize)]
+pub struct IndexReport {
+    #[serde(skip)]
+    pub chunks: Vec<Chunk>,
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+fn content_hash(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
+}
+
+/// A file to chunk in this run, with the hash to record once its chunks are stored. Its
+/// symbols come from the same read, the file may be gone by the time they are stored.
+struct ChangedFile {
+    file_path: String,
+    content_hash: String,
+    chunks: Vec<Chunk>,
+    symbols: Vec<Symbol>,
+    references: Vec<SymbolReference>,
+}
+
+/// Compares `file_paths`, everything currently under `parent_path` with whether docs and
+/// configs were asked for, with what was indexed for it. New and changed files are chunked,
+/// unchanged ones skipped, and the old chunks of changed files and of files that are gone
+/// (or no longer indexable) are removed from the database and the usearch index.
+async fn index_changed_files(
+    parse_code: &ParseCode,
+    user_id: &str,
+    session_id: &str,
+    parent_path: &str,
+    file_paths: &[(String, bool)],
+    report: &mut IndexReport
+) -> Result<Vec<ChangedFile>, Box<dyn Error>> {
+    let (owner, session, parent) = (user_id.to_string(), session_id.to_string(), parent_path.to_string());
+    let mut known = run_db(move |db| db.fetch_indexed_files(&owner, &session, &parent)).await??;
+
+    let mut changed: Vec<ChangedFile> = Vec::new();
+    let mut stale: Vec<String> = Vec::new();
+    for (file_path, include_docs) in file_paths {
+        // Left in `known` so an indexed file that is skipped now loses its chunks
+        if ParseCode::is_skipped_file(Path::new(file_path), *include_docs) {
+            continue;
+        }
+        let content = match fs::read(file_path) {
+            Ok(content) => content,
+            Err(e) => {
+                warn!("Couldn't read {} for indexing: {}", file_path, e);
+                continue;
+            }
+        };
+        let content_hash = content_hash(&content);
+        // Whatever is left in `known` after the walk is gone from the tree
+        let previous = known.remove(file_path);
+        if let Some(Some(previous_hash)) = &previous {
+            if *previous_hash == content_hash {
+                report.unchanged += 1;
+                continue;
+            }
+        }
+        if previous.is_some() {
+            stale.push(file_path.clone());
+        }
+
+        let chunks = match parse_code.process_local_file(file_path, *include_docs) {
+            Some(chunks) => chunks,
+            None => {
+                // Indexed before but skipped now, e.g. it became binary
+                if previous.is_some() {
+                    report.removed += 1;
+                }
+                continue;
+            }
+        };
+        if previous.is_some() {
+            report.updated += 1;
+        } else {
+            report.added += 1;
+        }
+        let (symbols, references) = parse_code.extract_symbols(&content, Path::new(file_path));
+        changed.push(ChangedFile { file_path: file_path.clone(), content_hash, chunks, symbols, references });
+    }
+
+    report.removed += known.len();
+    stale.extend(known.into_keys());
+    if !stale.is_empty() {
+        info!("Removing the chunks of {} changed or deleted files under {}", stale.len(), parent_path);
+        let (owner, session) = (user_id.to_string(), session_id.to_string());
+        run_db(move |_| delete_index_only_files(&owner, &session, stale)).await?;
+    }
+    Ok(changed)
+}
+
+/// The lock of one indexed (session, path). Indexing runs of the same path, e.g. a watcher's
+/// and a manual one, would diff against the same `context_files` rows and store every changed
+/// chunk twice, so they take turns.
+fn index_lock(session_id: &str, path: &str) -> Arc<AsyncMutex<()>> {
+    INDEX_LOCKS.lock()
+        .unwrap()
+        .entry((session_id.to_string(), path.to_string()))
+        .or_default()
+        .clone()
+}
+
 pub async fn index_code(
     user_id: &str,
     session_id: &str,
-    path: &str
-) -> Result<Vec<Chunk>, Box<dyn Error>> {
-    let mut file_paths = Vec::new();
+    path: &str,
+    options: Option<IndexOptions>
+) -> Result<IndexReport, Box<dyn Error>> {
+    let lock = index_lock(session_id, path);
+    let _indexing = lock.lock().await;
+    index_path(user_id, session_id, path, options).await
+}
+
+async fn index_path(
+    user_id: &str,
+    session_id: &str,
+    path: &str,
+    options: Option<IndexOptions>
+) -> Result<IndexReport, Box<dyn Error>> {
+    let mut file_paths: Vec<(String, bool)> = Vec::new();
     let parse_code = ParseCode::new();
+    let mut report = IndexReport::default();
     let mut all_chunks: Vec<Chunk> = Vec::new();
     let mut chunks_with_compressed_data: Vec<ChunkWithCompressedData> = Vec::new();
+    // Symbol tables of the local files chunked in this run
+    let mut file_symbols: Vec<(String, Vec<Symbol>, Vec<SymbolReference>)> = Vec::new();
+    // Content hashes of the local files chunked in this run
+    let mut file_hashes: Vec<(String, String)> = Vec::new();
 
     //if this is empty which means the path is being indexed for the first time,
     // if not, then the path have been indexed earlier
-    let if_already_index = DB_INSTANCE.fetch_path_session(user_id, session_id, path);
+    let (owner, session, parent) = (user_id.to_string(), session_id.to_string(), path.to_string());
+    let if_already_index = run_db(move |db| db.fetch_path_session(&owner, &session, &parent)).await??;
+    // Without options of its own, a path is indexed again with the ones it was indexed with
+    let options = match options {
+        Some(options) => options,
+        None =>
+            if_already_index
+                .as_ref()
+                .and_then(|indexed| serde_json::from_value(indexed["index_options"].clone()).ok())
+                .unwrap_or_default(),
+    };
     //Storing parent files in the database, before storing individual chunks for parent in
     //another table
     // DB_INSTANCE.store_parent_context(user_id, session_id, path);
@@ -180,56 +334,43 @@ pub async fn index_code(
 
     let mut filetype = "";
     let mut category = "";
-    if is_local_directory(path) {
-        warn!("Path has already been indexed {}", path);
-        filetype = "local_directory";
-        category = "directories";
-        match if_already_index {
-            Some(value) => {
-                if let Some(timestamp) = value.get("timestamp") {
-                    //all the files that has been changed since the last time the repo has been indexed
-                    let modified_files = get_modified_files_since(
-                        path,
-                        timestamp.as_str().unwrap()
-                    )?;
-                    warn!(
-                        "files that are modified since last indexed {:?} {}",
-                        modified_files,
-                        path
-                    );
-
-                    delete_index_only_files(user_id, session_id, modified_files.clone());
-                    for file_path in &modified_files {
-                        let chunks = parse_code.process_local_file(file_path);
-                        all_chunks.extend(chunks.into_iter().flatten());
-                    }
-                    // You can now use `timestamp` for further processing here
-                } else {
-                    println!("Timestamp not found in the JSON value");
-                }
+    if is_local_directory(path) || Path::new(path).is_file() {
+        if is_local_directory(path) {
+            filetype = "local_directory";
+            category = "directories";
+            let filter = FileFilter::new(Path::new(path), &options)?;
+            traverse_directory(path, &filter, &mut file_paths);
+        } else {
+            filetype = "local";
+            category = "files";
+            // A file asked for by name is indexed even if it's docs or config
+            let filter = FileFilter::new(Path::new(path), &options)?;
+            if filter.within_size(Path::new(path)) {
+                file_paths.push((path.to_string(), true));
+            } else {
+                warn!("{} is larger than max_file_size, not indexing it", path);
             }
-            None => {
-                info!("Path is being indexed for the first time {}", path);
+        }
+        if if_already_index.is_some() {
+            info!("Path has already been indexed, re-indexing the files changed since {}", path);
+        } else {
+            info!("Path is being indexed for the first time {}", path);
+        }
 
-                //index all the files if this is the first time that the path is being indexed.
-                traverse_directory(path, &mut file_paths)?;
-                for file_path in &file_paths {
-                    let chunks = parse_code.process_local_file(file_path);
-                    all_chunks.extend(chunks.into_iter().flatten());
-                }
-            }
+        // Files are compared by content, an unchanged file keeps its chunks and embeddings
+        let changed = index_changed_files(
+            &parse_code,
+            user_id,
+            session_id,
+            path,
+            &file_paths,
+            &mut report
+        ).await?;
+        for file in changed {
+            all_chunks.extend(file.chunks);
+            file_symbols.push((file.file_path.clone(), file.symbols, file.references));
+            file_hashes.push((file.file_path, file.content_hash));
         }
-    } else if
-        // Check if it's a local file
-        Path::new(path).is_file()
-    {
-        filetype = "local";
-        category = "files";
-        // Add the file path directly to the list
-        file_paths.push(path.to_string());
-        info!("The path = {} is a local file.", path);
-        let chunks = parse_code.process_local_file(path);
-        all_chunks.extend(chunks.into_iter().flatten());
     } else if
         // Check if it's a remote repository
         is_remote_repo(path).await?
@@ -240,12 +381,24 @@ pub async fn index_code(
 
         let repo_dir_path = download_github_repo(path, &temp_dir).await?;
         info!("The repo is downloaded at {}", repo_dir_path);
-        traverse_directory(&repo_dir_path, &mut file_paths)?;
+        let filter = FileFilter::new(Path::new(&repo_dir_path), &options)?;
+        traverse_directory(&repo_dir_path, &filter, &mut file_paths);
         info!("The path is a remote repository.");
-        for file_path in &file_paths {
-            let chunks = parse_code.process_local_file(file_path);
-            all_chunks.extend(chunks.into_iter().flatten());
+        for (file_path, include_docs) in &file_paths {
+            let chunks = match parse_code.process_local_file(file_path, *include_docs) {
+                Some(chunks) => chunks,
+                None => {
+                    continue;
+                }
+            };
+            all_chunks.extend(chunks);
+            // Read while the checkout still exists, it's deleted with `temp_dir`
+            if let Ok(content) = fs::read(file_path) {
+                let (symbols, references) = parse_code.extract_symbols(&content, Path::new(file_path));
+                file_symbols.push((file_path.clone(), symbols, references));
+            }
         }
+        report.added = file_paths.len();
     } else if
         // Check if it's a remote file
         path.starts_with("http://") ||
@@ -253,12 +406,13 @@ pub async fn index_code(
     {
         filetype = "remote";
         category = "files";
-        file_paths.push(path.to_string());
+        file_paths.push((path.to_string(), true));
         let result = parse_code.process_remote_file(path).await?;
         // Check if the result is Some(Vec<Chunk>)
         if let Some(chunks) = result {
             // Extend `all_chunks` with the actual chunks
             all_chunks.extend(chunks.into_iter());
+            report.added = 1;
         }
     } else {
         // If none of the conditions are met
@@ -271,12 +425,25 @@ pub async fn index_code(
     // Filter out duplicate chunks based on `content`, keeping the original `Chunk`
     all_chunks.retain(|chunk| unique_chunks.insert(chunk.content.clone()));
 
-    DB_INSTANCE.store_parent_context(user_id, session_id, path, filetype, category);
+    // Re-indexing keeps the parent row, its timestamp is bumped at the end
+    if if_already_index.is_none() {
+        let (owner, session, parent) = (user_id.to_string(), session_id.to_string(), path.to_string());
+        run_db(move |db| db.store_parent_context(&owner, &session, &parent, filetype, category)).await??;
+    }
+    let index_options = serde_json::to_string(&options)?;
+    let (owner, session, parent) = (user_id.to_string(), session_id.to_string(), path.to_string());
+    run_db(move |db| db.update_parent_index_options(&owner, &session, &parent, &index_options)).await??;
 
+    // Rows are written in one go once every chunk is compressed, not one blocking call per chunk
+    let mut children: Vec<(Chunk, String, u64)> = Vec::new();
     for chunk in &all_chunks {
-        let tokens: Option<Vec<String>> = compress_chunk_content(chunk).await;
-        let unwrapped_token = tokens.unwrap();
-        let compressed_content = unwrapped_token.join(" ");
+        let compressed_content = match compress_chunk_content(chunk).await {
+            Some(tokens) => tokens.join(" "),
+            None => {
+                // File hashes aren't stored yet, the next run chunks these files again
+                return Err(format!("Failed to compress a chunk of {}", chunk.file_path).into());
+            }
+        };
         info!(
             "content_tokens = {}, compressed_content_tokens={}",
             &chunk.content.len(),
@@ -299,24 +466,60 @@ pub async fn index_code(
             error!("Failed to get embeddings for chunk: {:?}", chunk);
         }
 
-        DB_INSTANCE.store_children_context(
-            user_id,
-            session_id,
-            path,
-            &chunk.chunk_type,
-            &chunk.content,
-            &compressed_content,
-            chunk.start_line,
-            chunk.end_line,
-            &chunk.file_path,
-            chunk_id
-        );
+        children.push((chunk.clone(), compressed_content, chunk_id));
     }
 
+    let (owner, session, parent) = (user_id.to_string(), session_id.to_string(), path.to_string());
+    run_db(move |db| -> Result<(), rusqlite::Error> {
+        for (chunk, compressed_content, chunk_id) in &children {
+            db.store_children_context(
+                &owner,
+                &session,
+                &parent,
+                &chunk.chunk_type,
+                chunk.symbol_name.as_deref(),
+                &chunk.content,
+                compressed_content,
+                chunk.start_line,
+                chunk.end_line,
+                &chunk.file_path,
+                *chunk_id
+            )?;
+        }
+        Ok(())
+    }).await??;
+
     add_to_index(session_id, chunks_with_compressed_data);
+
+    // Definitions and references per file, replacing what an earlier run stored for it
+    let (owner, session, parent) = (user_id.to_string(), session_id.to_string(), path.to_string());
+    run_db(move |db| {
+        for (file_path, symbols, references) in &file_symbols {
+            if let Err(e) = db.replace_file_symbols(&owner, &session, &parent, file_path, symbols, references) {
+                error!("Failed to store the symbols of {}: {}", file_path, e);
+            }
+        }
+    }).await?;
+
+    // Recorded last, a run that fails before this chunks the files again next time
+    if !file_hashes.is_empty() {
+        let (owner, session, parent) = (user_id.to_string(), session_id.to_string(), path.to_string());
+        run_db(move |db| db.store_file_hashes(&owner, &session, &parent, &file_hashes)).await??;
+    }
+
     info!("Updating the session context with path = {} with the latest timestamp", path);
-    let _ = DB_INSTANCE.update_session_context_timestamp(user_id, session_id, path);
-    Ok(all_chunks)
+    let (owner, session, parent) = (user_id.to_string(), session_id.to_string(), path.to_string());
+    let _ = run_db(move |db| db.update_session_context_timestamp(&owner, &session, &parent)).await?;
+    info!(
+        "Indexed {}: {} added, {} updated, {} removed, {} unchanged files",
+        path,
+        report.added,
+        report.updated,
+        report.removed,
+        report.unchanged
+    );
+    report.chunks = all_chunks;
+    Ok(report)
 }
 
 pub fn generate_rowid() -> u64 {
@@ -349,43 +552,9 @@ async fn compress_chunk_content(chunk: &Chunk) -> Option<Vec<String>> {
     Some(tokens)
 }
 
-fn get_modified_files_since(dir: &str, timestamp_str: &str) -> io::Result<Vec<String>> {
-    let mut modified_files = Vec::new();
-
-    // Parse the RFC3339 timestamp string to a DateTime<Utc> and then to SystemTime
-    let timestamp: SystemTime = DateTime::parse_from_rfc3339(timestamp_str)
-        .expect("Failed to parse timestamp")
-        .with_timezone(&Utc)
-        .into();
-
-    // Convert the string to a Path
-    let path = Path::new(dir);
-
-    // Traverse the directory
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        let file_path = entry.path();
-
-        // Check if it's a file (not a directory)
-        if file_path.is_file() {
-            // Get the file metadata
-            let metadata = fs::metadata(&file_path)?;
-
-            // Get the last modified time
-            if let Ok(modified) = metadata.modified() {
-                // Compare with the parsed timestamp
-                if modified > timestamp {
-                    modified_files.push(file_path.to_string_lossy().to_string());
-                }
-            }
-        }
-    }
-    Ok(modified_files)
-}
-
 pub fn delete_index(user_id: &str, session_id: &str, files: Vec<String>) {
     for file_path in files {
-        match DB_INSTANCE.delete_parent_context(&file_path) {
+        match DB_INSTANCE.delete_parent_context(user_id, session_id, &file_path) {
             Ok(_) => {
                 info!("Successfully deleted parent context for file: {:?}", file_path);
             }
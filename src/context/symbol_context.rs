@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use log::error;
+use crate::database::db_config::run_db;
+use crate::database::symbol_db::SymbolDefinition;
+
+// Definitions pulled into a prompt for the symbols it mentions
+pub const MAX_MENTIONED_DEFINITIONS: usize = 8;
+// Names looked up per prompt, a pasted file can mention hundreds
+const MAX_MENTIONED_NAMES: usize = 32;
+
+static IDENTIFIER: Lazy<Regex> = Lazy::new(|| Regex::new(r"[A-Za-z_][A-Za-z0-9_]*").unwrap());
+
+/// Names in `text` that look like code rather than prose: snake_case, camelCase, or
+/// written as a call, a path, a field or in backticks. In order of first mention.
+pub fn mentioned_identifiers(text: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for found in IDENTIFIER.find_iter(text) {
+        let name = found.as_str();
+        if name.len() < 3 || names.iter().any(|known| known == name) {
+            continue;
+        }
+        let before = text[..found.start()].chars().next_back();
+        let after = text[found.end()..].chars().next();
+        let code_like =
+            name.contains('_') ||
+            name.chars().skip(1).any(|c| c.is_ascii_uppercase()) ||
+            matches!(before, Some('`' | '.' | ':')) ||
+            matches!(after, Some('(' | '`' | '.' | ':' | '!' | '<'));
+        if code_like {
+            names.push(name.to_string());
+            if names.len() == MAX_MENTIONED_NAMES {
+                break;
+            }
+        }
+    }
+    names
+}
+
+/// Definitions, in the sessions' symbol tables, of the names `text` mentions. Fewer names
+/// come first, so one popular name like `new` doesn't crowd out the rest.
+pub async fn mentioned_definitions(session_ids: Vec<String>, text: &str, limit: usize) -> Vec<SymbolDefinition> {
+    let names = mentioned_identifiers(text);
+    if names.is_empty() {
+        return Vec::new();
+    }
+
+    let lookup = names.clone();
+    let definitions = match
+        run_db(move |db| db.symbol_definitions(&session_ids, &lookup, limit * 4)).await
+    {
+        Ok(Ok(definitions)) => definitions,
+        Ok(Err(e)) => {
+            error!("Failed to look up symbol definitions: {}", e);
+            return Vec::new();
+        }
+        Err(e) => {
+            error!("Failed to look up symbol definitions: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut per_name: HashMap<String, usize> = HashMap::new();
+    for definition in &definitions {
+        *per_name.entry(definition.name.clone()).or_insert(0) += 1;
+    }
+    let mention = |name: &str| names.iter().position(|known| known == name).unwrap_or(usize::MAX);
+    let mut definitions = definitions;
+    definitions.sort_by_key(|definition| (per_name[&definition.name], mention(&definition.name)));
+    definitions.truncate(limit);
+    definitions
+}
+
+/// The code of a definition, its signature when it isn't a chunk of its own
+pub fn definition_code(definition: &SymbolDefinition) -> &str {
+    definition.content.as_deref().unwrap_or(&definition.signature)
+}
+
+/// `file_path\ncode` entries for the pair programmer prompts: definitions of the symbols
+/// `text` mentions that aren't among the retrieved `chunks` already
+pub async fn mentioned_definition_entries(
+    session_id: &str,
+    text: &str,
+    chunks: &[(String, String, String, String)]
+) -> Vec<String> {
+    mentioned_definitions(vec![session_id.to_string()], text, MAX_MENTIONED_DEFINITIONS).await
+        .iter()
+        .filter(|definition| {
+            !chunks
+                .iter()
+                .any(|(file_path, _, content, _)| {
+                    *file_path == definition.file_path && content == definition_code(definition)
+                })
+        })
+        .map(|definition| format!("{}\n{}", definition.file_path, definition_code(definition)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ mentioned_identifiers, MAX_MENTIONED_NAMES };
+
+    #[test]
+    fn picks_code_like_names() {
+        let names = mentioned_identifiers(
+            "Why does parse_config fail when ServerConfig calls load() on `settings`?"
+        );
+        assert_eq!(names, vec!["parse_config", "ServerConfig", "load", "settings"]);
+    }
+
+    #[test]
+    fn skips_prose_and_short_names() {
+        assert!(mentioned_identifiers("Please explain what this function does").is_empty());
+        assert!(mentioned_identifiers("call f() and go() on x_").is_empty());
+    }
+
+    #[test]
+    fn paths_fields_and_macros() {
+        let names = mentioned_identifiers("use std::fmt then self.cache and println! with Vec<u8>");
+        assert_eq!(names, vec!["std", "fmt", "self", "cache", "println", "Vec"]);
+    }
+
+    #[test]
+    fn dedups_in_first_mention_order() {
+        assert_eq!(mentioned_identifiers("run_db(a) then index_code() then run_db(b)"), vec![
+            "run_db",
+            "index_code",
+        ]);
+    }
+
+    #[test]
+    fn caps_the_number_of_names() {
+        let text: String = (0..100).map(|i| format!("name_{} ", i)).collect();
+        let names = mentioned_identifiers(&text);
+        assert_eq!(names.len(), MAX_MENTIONED_NAMES);
+        assert_eq!(names[0], "name_0");
+    }
+}
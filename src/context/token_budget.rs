@@ -0,0 +1,162 @@
+use std::time::Duration;
+use futures::future::join_all;
+use log::debug;
+use reqwest::Client;
+use serde_json::{ json, Value };
+use crate::database::db_config::run_db;
+use crate::llm_stream::generation::{ GenerationParams, resolve_generation_params };
+use crate::llm_stream::usage::CHARS_PER_TOKEN;
+use crate::utils::get_local_url;
+
+// What run_llama_server starts a model with when its config has no ctx_size
+const DEFAULT_CTX_SIZE: usize = 8192;
+// Room kept for the answer when neither the request nor the route sets max_tokens
+const DEFAULT_ANSWER_TOKENS: usize = 1024;
+// The instructions wrapped around the context and the question, and the chat template
+const PROMPT_TEMPLATE_TOKENS: usize = 128;
+// A document cut shorter than this isn't worth including
+pub const MIN_TRUNCATED_TOKENS: usize = 64;
+// llama.cpp answers /tokenize and /props right away, a slow answer means it's busy or gone
+const LLAMACPP_TIMEOUT: Duration = Duration::from_secs(2);
+// Only llama.cpp has /props and /tokenize, the other backends are sized from config and estimates
+const LLAMACPP_BACKEND: &str = "llamacpp";
+// /tokenize calls made at once when packing documents, documents past these are estimated
+const MAX_TOKENIZE_REQUESTS: usize = 16;
+
+/// Context window of the model `backend_name` answers with: what llama.cpp reports, else
+/// the stored model config, else the llama-server default
+pub async fn context_window(client: &Client, backend_name: &str) -> usize {
+    if backend_name == LLAMACPP_BACKEND {
+        let props = client.get(format!("{}/props", get_local_url())).timeout(LLAMACPP_TIMEOUT).send().await;
+        if let Ok(response) = props {
+            if let Ok(body) = response.json::<Value>().await {
+                if let Some(n_ctx) = body["default_generation_settings"]["n_ctx"].as_u64() {
+                    return n_ctx as usize;
+                }
+            }
+        }
+    }
+
+    match run_db(|db| db.get_model_config().map(|config| config.ctx_size)).await {
+        Ok(Ok(ctx_size)) if ctx_size > 0 => ctx_size as usize,
+        _ => DEFAULT_CTX_SIZE,
+    }
+}
+
+/// Tokens of `text` estimated from its length
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+/// Tokens of `text` with the model's tokenizer (llama.cpp /tokenize), estimated from its
+/// length for other backends or when llama.cpp isn't reachable
+pub async fn count_tokens(client: &Client, backend_name: &str, text: &str) -> usize {
+    if text.is_empty() {
+        return 0;
+    }
+    if backend_name != LLAMACPP_BACKEND {
+        return estimate_tokens(text);
+    }
+    let response = client
+        .post(format!("{}/tokenize", get_local_url()))
+        .json(&json!({ "content": text }))
+        .timeout(LLAMACPP_TIMEOUT)
+        .send().await;
+    if let Ok(response) = response {
+        if let Ok(body) = response.json::<Value>().await {
+            if let Some(tokens) = body["tokens"].as_array() {
+                return tokens.len();
+            }
+        }
+    }
+    debug!("llama.cpp /tokenize unavailable, estimating token count");
+    estimate_tokens(text)
+}
+
+/// Tokens of each of `texts`. The first `MAX_TOKENIZE_REQUESTS` are counted concurrently,
+/// the rest are estimated so a long candidate list doesn't cost a round trip each.
+pub async fn count_tokens_batch(client: &Client, backend_name: &str, texts: &[String]) -> Vec<usize> {
+    let tokenized = texts.len().min(MAX_TOKENIZE_REQUESTS);
+    let mut counts = join_all(
+        texts[..tokenized].iter().map(|text| count_tokens(client, backend_name, text))
+    ).await;
+    counts.extend(texts[tokenized..].iter().map(|text| estimate_tokens(text)));
+    counts
+}
+
+/// Tokens left for retrieved context once the system prompt, the user's prompt and the
+/// answer (`max_tokens` of the request or route) are accounted for
+pub async fn context_budget(
+    client: &Client,
+    backend_name: &str,
+    route: &str,
+    system_prompt: &str,
+    user_prompt: &str,
+    generation: Option<&GenerationParams>
+) -> usize {
+    let ctx_size = context_window(client, backend_name).await;
+
+    let answer_tokens = resolve_generation_params(route, generation, GenerationParams::default()).await
+        .max_tokens
+        .filter(|max_tokens| *max_tokens > 0)
+        .map(|max_tokens| max_tokens as usize)
+        .unwrap_or(DEFAULT_ANSWER_TOKENS);
+
+    let prompt_tokens =
+        count_tokens(client, backend_name, system_prompt).await + count_tokens(client, backend_name, user_prompt).await;
+    let budget = ctx_size.saturating_sub(prompt_tokens + answer_tokens + PROMPT_TEMPLATE_TOKENS);
+    debug!(
+        "Context budget {} tokens (ctx {}, prompt {}, answer {})",
+        budget,
+        ctx_size,
+        prompt_tokens,
+        answer_tokens
+    );
+    budget
+}
+
+/// Cuts `text`, which is `tokens` long, down to about `budget` tokens
+pub fn truncate_to_tokens(text: &str, tokens: usize, budget: usize) -> String {
+    if tokens <= budget {
+        return text.to_string();
+    }
+    // Tokens aren't evenly spread over the characters, keep a margin
+    let keep_chars = (text.chars().count() * budget * 9) / (tokens * 10);
+    let truncated: String = text.chars().take(keep_chars).collect();
+    format!("{}\n...(truncated)", truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ estimate_tokens, truncate_to_tokens };
+
+    #[test]
+    fn estimates_four_chars_per_token_rounding_up() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcd"), 1);
+        assert_eq!(estimate_tokens("abcde"), 2);
+        // Characters, not bytes
+        assert_eq!(estimate_tokens("ääää"), 1);
+    }
+
+    #[test]
+    fn leaves_text_within_budget_alone() {
+        assert_eq!(truncate_to_tokens("fn main() {}", 4, 4), "fn main() {}");
+        assert_eq!(truncate_to_tokens("fn main() {}", 4, 100), "fn main() {}");
+    }
+
+    #[test]
+    fn cuts_text_over_budget_with_a_margin() {
+        // A prior conversation of 1000 tokens in a budget of 100
+        let prior = "x".repeat(4000);
+        let truncated = truncate_to_tokens(&prior, 1000, 100);
+        assert!(truncated.ends_with("\n...(truncated)"));
+        // 90% of the proportional share
+        assert_eq!(truncated.chars().filter(|c| *c == 'x').count(), 360);
+    }
+
+    #[test]
+    fn zero_budget_keeps_only_the_marker() {
+        assert_eq!(truncate_to_tokens("some text", 3, 0), "\n...(truncated)");
+    }
+}
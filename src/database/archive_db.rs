@@ -0,0 +1,195 @@
+use crate::database::db_config::DBConfig;
+use rusqlite::{ params, Connection, OptionalExtension };
+use rusqlite::types::{ Value as SqlValue, ValueRef };
+use serde_json::{ Map, Value };
+use zerocopy::AsBytes;
+use bytemuck::cast_slice;
+use std::error::Error;
+use log::info;
+
+/// Tables that make up a session, in the order they are exported and restored.
+/// (table, lives in pair_programmer.db, column holding the session id)
+pub const SESSION_TABLES: &[(&str, bool, &str)] = &[
+    ("sessions", false, "id"),
+    ("chats", false, "session_id"),
+    ("context_parent", false, "session_id"),
+    ("context_children", false, "session_id"),
+    ("pair_programmer", true, "session_id"),
+    ("pp_steps", true, "session_id"),
+];
+
+/// A row of a session table: (table, column values)
+pub type SessionRow = (String, Map<String, Value>);
+
+/// A session row to import, with the embedding of a chat or code chunk if the archive has one
+pub type ImportRow = (String, Map<String, Value>, Option<Vec<f32>>);
+
+fn sql_to_json(value: ValueRef) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => Value::from(i),
+        ValueRef::Real(f) => Value::from(f),
+        ValueRef::Text(t) => Value::from(String::from_utf8_lossy(t).to_string()),
+        // None of the session tables have blob columns, embeddings are exported separately
+        ValueRef::Blob(_) => Value::Null,
+    }
+}
+
+fn json_to_sql(value: &Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(b) => SqlValue::Integer(*b as i64),
+        Value::Number(n) =>
+            match n.as_i64() {
+                Some(i) => SqlValue::Integer(i),
+                None => SqlValue::Real(n.as_f64().unwrap_or_default()),
+            }
+        Value::String(s) => SqlValue::Text(s.clone()),
+        other => SqlValue::Text(other.to_string()),
+    }
+}
+
+fn table_rows(
+    connection: &Connection,
+    table: &str,
+    session_column: &str,
+    session_id: &str
+) -> Result<Vec<Map<String, Value>>, rusqlite::Error> {
+    // Table and column names only ever come from SESSION_TABLES
+    let mut stmt = connection.prepare(
+        &format!("SELECT * FROM {} WHERE {} = ? ORDER BY rowid", table, session_column)
+    )?;
+    let columns: Vec<String> = stmt
+        .column_names()
+        .iter()
+        .map(|c| c.to_string())
+        .collect();
+
+    let rows = stmt
+        .query_map(params![session_id], |row| {
+            let mut map = Map::new();
+            for (i, column) in columns.iter().enumerate() {
+                map.insert(column.clone(), sql_to_json(row.get_ref(i)?));
+            }
+            Ok(map)
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+fn table_columns(connection: &Connection, table: &str) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = connection.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(columns)
+}
+
+// Only the columns the local schema knows about are written, so archives from an older
+// or newer server still import and missing columns fall back to their defaults
+fn insert_row(
+    connection: &Connection,
+    table: &str,
+    known_columns: &[String],
+    row: &Map<String, Value>
+) -> Result<(), rusqlite::Error> {
+    let (columns, values): (Vec<&String>, Vec<SqlValue>) = row
+        .iter()
+        .filter(|(column, _)| known_columns.contains(column))
+        .map(|(column, value)| (column, json_to_sql(value)))
+        .unzip();
+
+    let sql = format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        table,
+        columns
+            .iter()
+            .map(|c| c.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        vec!["?"; columns.len()].join(", ")
+    );
+    connection.execute(&sql, rusqlite::params_from_iter(values))?;
+    Ok(())
+}
+
+impl DBConfig {
+    /// Every row belonging to the session, as (table, row) in SESSION_TABLES order
+    pub fn export_session_rows(
+        &self,
+        session_id: &str
+    ) -> Result<Vec<SessionRow>, Box<dyn Error>> {
+        let mut rows = Vec::new();
+        for (table, pair_programmer_db, session_column) in SESSION_TABLES {
+            let connection = if *pair_programmer_db {
+                self.pair_programmer_connection.get().map_err(|_| "Failed to get a database connection")?
+            } else {
+                self.connection.get().map_err(|_| "Failed to get a database connection")?
+            };
+            for row in table_rows(&connection, table, session_column, session_id)? {
+                rows.push((table.to_string(), row));
+            }
+        }
+        Ok(rows)
+    }
+
+    pub fn get_chat_embedding(&self, vec_row_id: i64) -> Result<Option<Vec<f32>>, Box<dyn Error>> {
+        let connection = self.connection.get().map_err(|_| "Failed to get a database connection")?;
+        let bytes: Option<Vec<u8>> = connection
+            .query_row(
+                "SELECT embeddings FROM chat_embeddings WHERE rowid = ?",
+                params![vec_row_id],
+                |row| row.get(0)
+            )
+            .optional()?;
+        Ok(bytes.map(|b| cast_slice::<u8, f32>(&b).to_vec()))
+    }
+
+    /// Writes already re-keyed session rows. chats.db and pair_programmer.db each get one
+    /// transaction, chat embeddings are stored alongside their chat row.
+    pub fn import_session_rows(
+        &self,
+        rows: &[ImportRow]
+    ) -> Result<(), Box<dyn Error>> {
+        for pair_programmer_db in [false, true] {
+            let mut connection = if pair_programmer_db {
+                self.pair_programmer_connection.get().map_err(|_| "Failed to get a database connection")?
+            } else {
+                self.connection.get().map_err(|_| "Failed to get a database connection")?
+            };
+            let tx = connection.transaction()?;
+
+            for (table, _, _) in SESSION_TABLES.iter().filter(|(_, pp, _)| *pp == pair_programmer_db) {
+                let known_columns = table_columns(&tx, table)?;
+                let mut count = 0;
+                for (_, row, embeddings) in rows.iter().filter(|(t, _, _)| t == table) {
+                    insert_row(&tx, table, &known_columns, row).map_err(|e|
+                        format!("Failed to insert {} record: {}", table, e)
+                    )?;
+
+                    if *table == "chats" {
+                        if let (Some(embeddings), Some(vec_row_id)) = (embeddings, row.get("vec_row_id")) {
+                            let vec_row_id = vec_row_id
+                                .as_str()
+                                .and_then(|id| id.parse::<i64>().ok())
+                                .ok_or("Chat record without a valid vec_row_id")?;
+                            tx.execute(
+                                "INSERT INTO chat_embeddings (rowid, session_id, user_id, embeddings) VALUES (?, ?, ?, ?)",
+                                params![
+                                    vec_row_id,
+                                    row.get("session_id").and_then(Value::as_str),
+                                    row.get("user_id").and_then(Value::as_str),
+                                    embeddings.as_bytes()
+                                ]
+                            ).map_err(|e| format!("Failed to insert chat embeddings record: {}", e))?;
+                        }
+                    }
+                    count += 1;
+                }
+                info!("Imported {} {} records", count, table);
+            }
+            tx.commit()?;
+        }
+        Ok(())
+    }
+}
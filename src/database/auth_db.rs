@@ -0,0 +1,176 @@
+use crate::database::db_config::{ pool_exhausted, DBConfig };
+use crate::authentication::authorization::User;
+use chrono::Utc;
+use serde_json::{ json, Value };
+use rusqlite::{ params, OptionalExtension, Row };
+use uuid::Uuid;
+
+fn user_from_row(row: &Row) -> Result<Value, rusqlite::Error> {
+    Ok(
+        json!({
+        "id": row.get::<_, String>(0)?,
+        "name": row.get::<_, String>(1)?,
+        "is_admin": row.get::<_, i32>(2)? != 0,
+        "created_at": row.get::<_, Option<String>>(3)?,
+        "daily_token_quota": row.get::<_, Option<i64>>(4)?,
+        "monthly_token_quota": row.get::<_, Option<i64>>(5)?,
+    })
+    )
+}
+
+// Never includes the hash, the prefix is enough to tell keys apart
+fn api_key_from_row(row: &Row) -> Result<Value, rusqlite::Error> {
+    Ok(
+        json!({
+        "id": row.get::<_, String>(0)?,
+        "user_id": row.get::<_, String>(1)?,
+        "prefix": row.get::<_, String>(2)?,
+        "label": row.get::<_, Option<String>>(3)?,
+        "created_at": row.get::<_, Option<String>>(4)?,
+        "last_used_at": row.get::<_, Option<String>>(5)?,
+        "revoked_at": row.get::<_, Option<String>>(6)?,
+    })
+    )
+}
+
+impl DBConfig {
+    pub fn create_user(&self, name: &str, is_admin: bool) -> Result<Value, rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        let user_id = Uuid::new_v4().to_string();
+        let timestamp = Utc::now().to_rfc3339();
+
+        connection.execute(
+            "INSERT INTO users (id, name, is_admin, created_at) VALUES (?, ?, ?, ?)",
+            params![user_id, name, is_admin as i32, timestamp]
+        )?;
+
+        Ok(
+            json!({
+            "id": user_id,
+            "name": name,
+            "is_admin": is_admin,
+            "created_at": timestamp,
+            "daily_token_quota": null,
+            "monthly_token_quota": null,
+        })
+        )
+    }
+
+    pub fn get_user(&self, user_id: &str) -> Result<Option<Value>, rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        connection
+            .query_row(
+                "SELECT id, name, is_admin, created_at, daily_token_quota, monthly_token_quota FROM users WHERE id = ?",
+                params![user_id],
+                user_from_row
+            )
+            .optional()
+    }
+
+    pub fn list_users(&self) -> Result<Vec<Value>, rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        let mut stmt = connection.prepare(
+            "SELECT id, name, is_admin, created_at, daily_token_quota, monthly_token_quota FROM users ORDER BY created_at"
+        )?;
+        let users = stmt.query_map([], user_from_row)?.collect::<Result<Vec<Value>, rusqlite::Error>>()?;
+        Ok(users)
+    }
+
+    pub fn create_api_key(
+        &self,
+        user_id: &str,
+        key_hash: &str,
+        prefix: &str,
+        label: Option<&str>
+    ) -> Result<Value, rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        let key_id = Uuid::new_v4().to_string();
+        let timestamp = Utc::now().to_rfc3339();
+
+        connection.execute(
+            "INSERT INTO api_keys (id, user_id, key_hash, prefix, label, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)",
+            params![key_id, user_id, key_hash, prefix, label, timestamp]
+        )?;
+
+        Ok(
+            json!({
+            "id": key_id,
+            "user_id": user_id,
+            "prefix": prefix,
+            "label": label,
+            "created_at": timestamp,
+            "last_used_at": Value::Null,
+            "revoked_at": Value::Null,
+        })
+        )
+    }
+
+    /// Keys of one user, or of everybody when user_id is None
+    pub fn list_api_keys(&self, user_id: Option<&str>) -> Result<Vec<Value>, rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        let mut stmt = connection.prepare(
+            "SELECT id, user_id, prefix, label, created_at, last_used_at, revoked_at
+            FROM api_keys
+            WHERE (?1 IS NULL OR user_id = ?1)
+            ORDER BY created_at DESC"
+        )?;
+        let keys = stmt
+            .query_map(params![user_id], api_key_from_row)?
+            .collect::<Result<Vec<Value>, rusqlite::Error>>()?;
+        Ok(keys)
+    }
+
+    /// Revokes a key, restricted to the keys of `user_id` unless it is None (admins).
+    /// Returns false if there was no such active key.
+    pub fn revoke_api_key(&self, key_id: &str, user_id: Option<&str>) -> Result<bool, rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        let updated = connection.execute(
+            "UPDATE api_keys SET revoked_at = ?1
+            WHERE id = ?2 AND revoked_at IS NULL AND (?3 IS NULL OR user_id = ?3)",
+            params![Utc::now().to_rfc3339(), key_id, user_id]
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Owner of an active key, also records when the key was last used
+    pub fn find_user_by_api_key(&self, key_hash: &str) -> Result<Option<User>, rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        let user = connection
+            .query_row(
+                "SELECT u.id, u.name, u.is_admin
+                FROM api_keys k
+                JOIN users u ON u.id = k.user_id
+                WHERE k.key_hash = ? AND k.revoked_at IS NULL",
+                params![key_hash],
+                |row| {
+                    Ok(User {
+                        user_id: row.get(0)?,
+                        name: row.get(1)?,
+                        is_admin: row.get::<_, i32>(2)? != 0,
+                    })
+                }
+            )
+            .optional()?;
+
+        if user.is_some() {
+            connection.execute(
+                "UPDATE api_keys SET last_used_at = ? WHERE key_hash = ?",
+                params![Utc::now().to_rfc3339(), key_hash]
+            )?;
+        }
+        Ok(user)
+    }
+
+    pub fn count_active_admin_keys(&self) -> Result<usize, rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        let count: i64 = connection.query_row(
+            "SELECT COUNT(*) FROM api_keys k
+            JOIN users u ON u.id = k.user_id
+            WHERE u.is_admin = 1 AND k.revoked_at IS NULL",
+            [],
+            |row| row.get(0)
+        )?;
+        Ok(count as usize)
+    }
+}
@@ -1,12 +1,83 @@
-use crate::database::db_config::DBConfig;
+use crate::database::db_config::{ pool_exhausted, DBConfig };
 use uuid::Uuid;
-use rusqlite::params;
+use rusqlite::{ params, Connection, ToSql };
 use zerocopy::AsBytes;
 use chrono::Utc; // For getting the current UTC timestamp
 use serde_json::{ json, Value };
 use std::error::Error;
 use bytemuck::cast_slice;
 
+/// Optional filters for the chat history search, dates are RFC3339 and compared as text
+#[derive(Debug, Default)]
+pub struct ChatSearchFilters {
+    pub user_id: String,
+    pub session_id: Option<String>,
+    pub request_type: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+/// A chat of the keyword search: (chat id, bm25 score, snippet)
+pub type KeywordHit = (String, f64, String);
+
+/// A chat recalled from memory by its embedding
+#[derive(Debug, Clone)]
+pub struct ChatMemory {
+    pub chat_id: String,
+    pub compressed_prompt_response: String,
+    pub session_id: String,
+}
+
+// Same filter block for the keyword and the semantic search, NULL means "don't filter"
+const CHAT_SEARCH_FILTERS: &str =
+    "(?1 IS NULL OR c.session_id = ?1)
+    AND (?2 IS NULL OR c.request_type = ?2)
+    AND (?3 IS NULL OR c.timestamp >= ?3)
+    AND (?4 IS NULL OR c.timestamp <= ?4)
+    AND c.user_id = ?5";
+
+/// KNN over chat_embeddings, closest first: (rowid, distance). A session is a vec0 partition,
+/// so it is searched on its own instead of being filtered out of a global top-k.
+fn nearest_chat_embeddings(
+    connection: &Connection,
+    query_embeddings: &[f32],
+    limit: usize,
+    user_id: Option<&str>,
+    session_id: Option<&str>
+) -> Result<Vec<(i64, f64)>, Box<dyn Error>> {
+    // vec0 only pushes plain equality constraints down, so only add the ones we have
+    let query_embedding_bytes: &[u8] = cast_slice(query_embeddings);
+    let k = limit as i64;
+    let mut sql = String::from("SELECT rowid, distance FROM chat_embeddings WHERE embeddings MATCH ? AND k = ?");
+    let mut values: Vec<&dyn ToSql> = vec![&query_embedding_bytes, &k];
+    if let Some(user_id) = &user_id {
+        sql.push_str(" AND user_id = ?");
+        values.push(user_id);
+    }
+    if let Some(session_id) = &session_id {
+        sql.push_str(" AND session_id = ?");
+        values.push(session_id);
+    }
+    sql.push_str(" ORDER BY distance");
+
+    let mut stmt = connection.prepare(&sql).map_err(|e| format!("Failed to prepare query: {}", e))?;
+    let nearest = stmt
+        .query_map(values.as_slice(), |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?)) // rowid and distance
+        })
+        .map_err(|e| format!("Failed to execute query: {}", e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed to collect nearest embeddings: {}", e))?;
+    Ok(nearest)
+}
+
+// Chats stored before context sources were recorded have NULL, returned as null
+fn context_sources_value(context_sources: Option<String>) -> Value {
+    context_sources
+        .and_then(|sources| serde_json::from_str(&sources).ok())
+        .unwrap_or(Value::Null)
+}
+
 impl DBConfig {
     // Function to store a new chat record with embeddings, timestamp, and compressed prompt
     pub fn store_chats(
@@ -17,12 +88,14 @@ impl DBConfig {
         compressed_prompt_response: &str,
         response: &str,
         embeddings: &[f32],
-        request_type: &str
+        request_type: &str,
+        cancelled: bool,
+        context_sources: &str
     ) -> Result<(), Box<dyn Error>> {
-        // Lock the mutex to access the connection
+        // Check out a connection from the pool
         let connection = self.connection
-            .lock()
-            .map_err(|_| "Failed to acquire lock for connection")?;
+            .get()
+            .map_err(|_| "Failed to get a database connection")?;
         let uuid = Uuid::new_v4().to_string();
         let vec_row_id = Self::generate_rowid();
 
@@ -30,8 +103,8 @@ impl DBConfig {
         let timestamp = Utc::now().to_rfc3339();
         connection
             .execute(
-                "INSERT INTO chats (id, user_id, session_id, vec_row_id, prompt, compressed_prompt_response, response, timestamp, request_type)
-                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                "INSERT INTO chats (id, user_id, session_id, vec_row_id, prompt, compressed_prompt_response, response, timestamp, request_type, cancelled, context_sources)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
                 params![
                     uuid,
                     user_id,
@@ -41,40 +114,41 @@ impl DBConfig {
                     compressed_prompt_response,
                     response,
                     timestamp.as_str(),
-                    request_type // Store UTC timestamp as TEXT
+                    request_type, // Store UTC timestamp as TEXT
+                    cancelled as i32, // partial answer, the client cancelled or disconnected
+                    context_sources // JSON list of the context items the answer was built from
                 ]
             )
             .map_err(|e| format!("Failed to insert chat record: {}", e))?;
 
         connection
             .execute(
-                "INSERT INTO chat_embeddings (rowid,  embeddings)
-                VALUES (?, ?)",
-                params![vec_row_id, embeddings.as_bytes()]
+                "INSERT INTO chat_embeddings (rowid, session_id, user_id, embeddings)
+                VALUES (?, ?, ?, ?)",
+                params![vec_row_id, session_id, user_id, embeddings.as_bytes()]
             )
             .map_err(|e| format!("Failed to insert chat api embeddings record: {}", e))?;
         Ok(())
     }
 
-    pub fn fetch_chats_all(&self) -> Vec<Value> {
-        // Lock the mutex to access the connection
-        let connection = self.connection.lock().unwrap();
+    pub fn fetch_chats_all(&self, user_id: &str) -> Result<Vec<Value>, rusqlite::Error> {
+        // Check out a connection from the pool
+        let connection = self.connection.get().map_err(pool_exhausted)?;
 
         // Prepare a SQL query to fetch all the chats for a specific session_id and user_id, sorted by timestamp
         let mut stmt = connection
             .prepare(
-                "SELECT id, user_id, session_id, prompt, compressed_prompt_response, response, timestamp, request_type
+                "SELECT id, user_id, session_id, prompt, compressed_prompt_response, response, timestamp, request_type, cancelled, context_sources
                 FROM chats
                 WHERE user_id = ?
                 ORDER BY timestamp ASC"
-            )
-            .unwrap();
+            )?;
 
         // Create a vector to hold the chat entries in JSON format
         let mut chats: Vec<Value> = Vec::new();
         // Execute the query and iterate over the rows, collecting them into the vector
         let chat_iter = stmt
-            .query_map(["user_id"], |row| {
+            .query_map([user_id], |row| {
                 Ok(
                     json!({
                     "id": row.get::<_, String>(0)?,  // id
@@ -85,35 +159,35 @@ impl DBConfig {
                     "response": row.get::<_, String>(5)?,  // response
                     "timestamp": row.get::<_, String>(6)?,  // timestamp
                     "request_type": row.get::<_, String>(7)?,  // timestamp
-                    
+                    "cancelled": row.get::<_, i32>(8)? != 0,  // partial answer
+                    "context_sources": context_sources_value(row.get(9)?),
+
                 })
                 )
-            })
-            .unwrap();
+            })?;
 
         // Collect all rows into the `chats` vector
         for chat in chat_iter {
-            chats.push(chat.unwrap());
+            chats.push(chat?);
         }
 
-        chats
+        Ok(chats)
     }
 
-    pub fn fetch_chats_for_session(&self, session_id: &str, skip: u32, limit: u32) -> Vec<Value> {
-        // Lock the mutex to access the connection
-        let connection = self.connection.lock().unwrap();
+    pub fn fetch_chats_for_session(&self, session_id: &str, skip: u32, limit: u32) -> Result<Vec<Value>, rusqlite::Error> {
+        // Check out a connection from the pool
+        let connection = self.connection.get().map_err(pool_exhausted)?;
 
         // Prepare a SQL query to fetch all the chats for a specific session_id and user_id, sorted by timestamp
         let mut stmt = connection
             .prepare(
-                "SELECT id, user_id, session_id, prompt, response, timestamp, request_type
+                "SELECT id, user_id, session_id, prompt, response, timestamp, request_type, cancelled, context_sources
                  FROM chats 
                  WHERE session_id = ?
                  ORDER BY timestamp DESC
                  LIMIT ?
                  OFFSET ?"
-            )
-            .unwrap();
+            )?;
 
         // Create a vector to hold the chat entries
         let mut chats: Vec<Value> = Vec::new();
@@ -130,46 +204,47 @@ impl DBConfig {
                     "response": row.get::<_, String>(4)?,  // response
                     "timestamp": row.get::<_, String>(5)?,  // timestamp
                     "request_type": row.get::<_, String>(6)?,  // timestamp
+                    "cancelled": row.get::<_, i32>(7)? != 0,  // partial answer
+                    "context_sources": context_sources_value(row.get(8)?),
                 })
                 )
-            })
-            .unwrap();
+            })?;
 
         // Collect all rows into the `chats` vector
         for chat in chat_iter {
-            chats.push(chat.unwrap());
+            chats.push(chat?);
         }
 
-        chats
+        Ok(chats)
     }
 
     pub fn fetch_chats_for_request_type(
         &self,
+        user_id: &str,
         request_type: &str,
         skip: u32,
         limit: u32
-    ) -> Vec<Value> {
-        // Lock the mutex to access the connection
-        let connection = self.connection.lock().unwrap();
+    ) -> Result<Vec<Value>, rusqlite::Error> {
+        // Check out a connection from the pool
+        let connection = self.connection.get().map_err(pool_exhausted)?;
 
         // Prepare a SQL query to fetch all the chats for a specific session_id and user_id, sorted by timestamp
         let mut stmt = connection
             .prepare(
-                "SELECT id, user_id, session_id, prompt, response, timestamp, request_type
+                "SELECT id, user_id, session_id, prompt, response, timestamp, request_type, cancelled, context_sources
                  FROM chats 
-                 WHERE request_type = ?
+                 WHERE user_id = ? AND request_type = ?
                  ORDER BY timestamp DESC
                  LIMIT ?
                  OFFSET ?"
-            )
-            .unwrap();
+            )?;
 
         // Create a vector to hold the chat entries
         let mut chats: Vec<Value> = Vec::new();
 
         // Execute the query and iterate over the rows, collecting them into the vector
         let chat_iter = stmt
-            .query_map([request_type, &limit.to_string(), &skip.to_string()], |row| {
+            .query_map([user_id, request_type, &limit.to_string(), &skip.to_string()], |row| {
                 Ok(
                     json!({
                     "id": row.get::<_, String>(0)?,  // id
@@ -179,27 +254,29 @@ impl DBConfig {
                     "response": row.get::<_, String>(4)?,  // response
                     "timestamp": row.get::<_, String>(5)?,  // timestamp
                     "request_type": row.get::<_, String>(6)?,  // timestamp
+                    "cancelled": row.get::<_, i32>(7)? != 0,  // partial answer
+                    "context_sources": context_sources_value(row.get(8)?),
                 })
                 )
-            })
-            .unwrap();
+            })?;
 
         // Collect all rows into the `chats` vector
         for chat in chat_iter {
-            chats.push(chat.unwrap());
+            chats.push(chat?);
         }
 
-        chats
+        Ok(chats)
     }
     
-    pub fn get_last_n_chats(&self, session_id: &str, n: usize) -> Result<Vec<String>, Box<dyn Error>> {
-        // Lock the mutex to access the connection
-        let connection = self.connection.lock()
-            .map_err(|_| "Failed to acquire lock for connection")?;
+    /// Last `n` chats of the session, newest first: (chat id, compressed_prompt_response)
+    pub fn get_last_n_chats(&self, session_id: &str, n: usize) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+        // Check out a connection from the pool
+        let connection = self.connection.get()
+            .map_err(|_| "Failed to get a database connection")?;
     
         // Prepare the SQL statement
         let mut stmt = connection.prepare(
-            "SELECT compressed_prompt_response 
+            "SELECT id, compressed_prompt_response
              FROM chats
              WHERE session_id = ?
              ORDER BY timestamp DESC
@@ -210,55 +287,40 @@ impl DBConfig {
         let chats_iter = stmt.query_map(
             params![session_id, n as i64],  // Cast 'n' to i64 for SQLite
             |row| {
-                let compressed_prompt_response: String = row.get(0)?;
-                Ok(compressed_prompt_response)
+                let id: String = row.get(0)?;
+                let compressed_prompt_response: String = row.get(1)?;
+                Ok((id, compressed_prompt_response))
             }
         ).map_err(|e| format!("Failed to query last {} chats: {}", n, e))?;
     
-        // Collect the results into a vector of strings
-        let chats: Vec<String> = chats_iter
-            .collect::<Result<Vec<String>, _>>()  // Collect into Vec<String>
+        // Collect the results into a vector of (id, compressed) pairs
+        let chats: Vec<(String, String)> = chats_iter
+            .collect::<Result<Vec<(String, String)>, _>>()
             .map_err(|e| format!("Failed to collect chat results: {}", e))?;
     
         Ok(chats)
     }
 
-    pub fn query_nearest_embeddings(&self, query_embeddings: Vec<f32>, limit: usize) -> Result<Vec<(i64, f64, String, String, String)>, Box<dyn std::error::Error>> {
-        let connection = self.connection.lock().unwrap();
-        let query_embedding_bytes = cast_slice(&query_embeddings);
-        // Prepare the SQL statement to find the nearest embeddings
-        let mut stmt = connection.prepare(
-            r#"
-            SELECT
-                rowid,
-                distance
-            FROM chat_embeddings
-            WHERE embeddings MATCH ?
-            ORDER BY distance
-            LIMIT ?
-            "#,
-        ).map_err(|e| format!("Failed to prepare query: {}", e))?;
-
-        // Execute the query and collect the nearest embeddings
-        let nearest_embeddings: Vec<(i64, f64)> = stmt
-            .query_map(params![query_embedding_bytes, limit as i64], |row| {
-                Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?)) // rowid and distance
-            })
-            .map_err(|e| format!("Failed to execute query: {}", e))?
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|e| format!("Failed to collect nearest embeddings: {}", e))?;
-
-
+    /// Nearest chats of a session, of a user or of everybody (both filters None), closest first
+    pub fn query_chat_memory(
+        &self,
+        user_id: Option<&str>,
+        session_id: Option<&str>,
+        query_embeddings: Vec<f32>,
+        limit: usize
+    ) -> Result<Vec<ChatMemory>, Box<dyn std::error::Error>> {
+        let connection = self.connection.get()?;
+        let nearest_embeddings = nearest_chat_embeddings(&connection, &query_embeddings, limit, user_id, session_id)?;
 
         // Step 2: For each rowid, collect content and file_path from context_children table, and convert to JSON.
-        let mut query_context: Vec<(i64, f64, String, String, String)> = Vec::new();
+        let mut query_context: Vec<ChatMemory> = Vec::new();
     
-           // For each nearest embedding, fetch the prompt and compressed_prompt
-        for (rowid, distance) in nearest_embeddings {
+           // For each nearest embedding, fetch the chat and its compressed prompt and response
+        for (rowid, _) in nearest_embeddings {
             let mut stmt = connection.prepare(
                 r#"
                 SELECT
-                    prompt, compressed_prompt_response, session_id
+                    id, compressed_prompt_response, session_id
                 FROM chats
                 WHERE vec_row_id = ?
                 "#,
@@ -267,11 +329,11 @@ impl DBConfig {
 
             let context_iter = stmt
                 .query_map(params![rowid], |row| {
-                    let prompt: String = row.get(0)?;
-                    let compressed_prompt: String = row.get(1)?;
-                    let session_id: String = row.get(2)?;
-
-                    Ok((rowid, distance, prompt, compressed_prompt, session_id))
+                    Ok(ChatMemory {
+                        chat_id: row.get(0)?,
+                        compressed_prompt_response: row.get(1)?,
+                        session_id: row.get(2)?,
+                    })
                 })
                 .map_err(|e| format!("Failed to execute context query: {}", e))?;
 
@@ -281,4 +343,120 @@ impl DBConfig {
         }
         Ok(query_context)
     }
+
+    /// BM25 ranked chats matching an FTS5 query, best first
+    pub fn keyword_search_chats(
+        &self,
+        fts_query: &str,
+        filters: &ChatSearchFilters,
+        limit: usize
+    ) -> Result<Vec<KeywordHit>, Box<dyn Error>> {
+        let connection = self.connection.get().map_err(|_| "Failed to get a database connection")?;
+        let mut stmt = connection
+            .prepare(
+                &format!(
+                    "SELECT c.id, bm25(chats_fts) AS score, snippet(chats_fts, -1, '[', ']', '...', 16)
+                    FROM chats_fts
+                    JOIN chats c ON c.id = chats_fts.chat_id
+                    WHERE chats_fts MATCH ?6 AND {}
+                    ORDER BY score
+                    LIMIT ?7",
+                    CHAT_SEARCH_FILTERS
+                )
+            )
+            .map_err(|e| format!("Failed to prepare search query: {}", e))?;
+
+        let results = stmt
+            .query_map(
+                params![
+                    filters.session_id,
+                    filters.request_type,
+                    filters.from,
+                    filters.to,
+                    filters.user_id,
+                    fts_query,
+                    limit as i64
+                ],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?, row.get::<_, String>(2)?))
+            )
+            .map_err(|e| format!("Failed to run search query: {}", e))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to collect search results: {}", e))?;
+        Ok(results)
+    }
+
+    /// Nearest chats to an embedding that pass the filters, closest first: (chat id, distance)
+    pub fn semantic_search_chats(
+        &self,
+        query_embeddings: Vec<f32>,
+        filters: &ChatSearchFilters,
+        limit: usize
+    ) -> Result<Vec<(String, f64)>, Box<dyn Error>> {
+        let connection = self.connection.get().map_err(|_| "Failed to get a database connection")?;
+        // Owner and session are filtered inside vec0, request type and dates on the chats table
+        let nearest = nearest_chat_embeddings(
+            &connection,
+            &query_embeddings,
+            limit * 5,
+            Some(&filters.user_id),
+            filters.session_id.as_deref()
+        )?;
+
+        let mut stmt = connection
+            .prepare(&format!("SELECT c.id FROM chats c WHERE c.vec_row_id = ?6 AND {}", CHAT_SEARCH_FILTERS))
+            .map_err(|e| format!("Failed to prepare search query: {}", e))?;
+
+        let mut results = Vec::new();
+        for (rowid, distance) in nearest {
+            let ids = stmt
+                .query_map(
+                    params![
+                        filters.session_id,
+                        filters.request_type,
+                        filters.from,
+                        filters.to,
+                        filters.user_id,
+                        rowid
+                    ],
+                    |row| row.get::<_, String>(0)
+                )
+                .map_err(|e| format!("Failed to run search query: {}", e))?;
+            for id in ids.filter_map(Result::ok) {
+                results.push((id, distance));
+            }
+            if results.len() >= limit {
+                break;
+            }
+        }
+        Ok(results)
+    }
+
+    pub fn fetch_chat_by_id(&self, chat_id: &str) -> Result<Option<Value>, Box<dyn Error>> {
+        let connection = self.connection.get().map_err(|_| "Failed to get a database connection")?;
+        let mut stmt = connection.prepare(
+            "SELECT id, user_id, session_id, prompt, response, timestamp, request_type, cancelled, context_sources
+             FROM chats WHERE id = ?"
+        )?;
+
+        let mut rows = stmt.query(params![chat_id])?;
+        match rows.next()? {
+            Some(row) =>
+                Ok(
+                    Some(
+                        json!({
+                    "id": row.get::<_, String>(0)?,
+                    "user_id": row.get::<_, String>(1)?,
+                    "session_id": row.get::<_, String>(2)?,
+                    "prompt": row.get::<_, String>(3)?,
+                    "response": row.get::<_, String>(4)?,
+                    "timestamp": row.get::<_, String>(5)?,
+                    "request_type": row.get::<_, String>(6)?,
+                    "cancelled": row.get::<_, i32>(7)? != 0,
+                    "context_sources": context_sources_value(row.get(8)?),
+                })
+                    )
+                ),
+            None => Ok(None),
+        }
+    }
 }
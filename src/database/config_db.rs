@@ -1,10 +1,12 @@
-use crate::database::db_config::DBConfig;
+use crate::database::db_config::{ pool_exhausted, DBConfig };
 use rusqlite::params;
 use crate::model_state::state::ConfigSection;
+use crate::llm_stream::generation::GenerationParams;
+use chrono::Utc;
 
 impl DBConfig {
-    pub fn update_system_prompt(&self, model_name: &str, system_prompt: &str) {
-        let connection = self.common_connection.lock().unwrap();
+    pub fn update_system_prompt(&self, model_name: &str, system_prompt: &str) -> Result<(), rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
         connection
             .execute(
                 "
@@ -16,12 +18,12 @@ impl DBConfig {
                 system_prompt = excluded.system_prompt;
             ",
                 params![model_name, "none", system_prompt]
-            )
-            .unwrap();
+            )?;
+        Ok(())
     }
 
-    pub fn update_model_config(&self, config: &ConfigSection) {
-        let connection = self.common_connection.lock().unwrap();
+    pub fn update_model_config(&self, config: &ConfigSection) -> Result<(), rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
         connection
             .execute(
                 "
@@ -49,12 +51,12 @@ impl DBConfig {
                     config.mmap as i32,
                     config.system_prompt
                 ]
-            )
-            .unwrap();
+            )?;
+        Ok(())
     }
 
     pub fn get_model_config(&self) -> Result<ConfigSection, rusqlite::Error> {
-        let connection = self.common_connection.lock().unwrap();
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
         let mut stmt = connection.prepare(
             "
             SELECT
@@ -90,7 +92,7 @@ impl DBConfig {
 
     pub fn get_system_prompt(&self) -> Result<String, rusqlite::Error> {
         // Lock the common database connection to ensure safe access across threads
-        let connection = self.common_connection.lock().unwrap();
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
 
         // Prepare the SQL statement to select the system_prompt from the config table
         let mut stmt = connection.prepare(
@@ -110,4 +112,68 @@ impl DBConfig {
         // Return the system_prompt or propagate any error encountered
         system_prompt
     }
+
+    pub fn get_generation_defaults(
+        &self,
+        route: &str
+    ) -> Result<Option<GenerationParams>, rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        let mut stmt = connection.prepare(
+            "SELECT params FROM generation_defaults WHERE route = ?1;"
+        )?;
+
+        let mut rows = stmt.query(params![route.to_uppercase()])?;
+        match rows.next()? {
+            Some(row) => {
+                let params_json: String = row.get(0)?;
+                // A bad row shouldn't break generation, treat it as no defaults
+                Ok(serde_json::from_str(&params_json).ok())
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_generation_defaults(
+        &self,
+        route: &str,
+        generation: &GenerationParams
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let connection = self.common_connection.get()?;
+        let params_json = serde_json::to_string(generation)?;
+        connection.execute(
+            "
+            INSERT INTO generation_defaults (route, params, updated_at)
+            VALUES (?1, ?2, ?3)
+            ON CONFLICT(route) DO UPDATE SET
+                params = excluded.params,
+                updated_at = excluded.updated_at;
+            ",
+            params![route.to_uppercase(), params_json, Utc::now().to_rfc3339()]
+        )?;
+        Ok(())
+    }
+
+    pub fn list_generation_defaults(
+        &self
+    ) -> Result<Vec<(String, GenerationParams)>, rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        let mut stmt = connection.prepare(
+            "SELECT route, params FROM generation_defaults ORDER BY route;"
+        )?;
+
+        let rows = stmt.query_map([], |row| {
+            let route: String = row.get(0)?;
+            let params_json: String = row.get(1)?;
+            Ok((route, params_json))
+        })?;
+
+        let mut defaults = Vec::new();
+        for row in rows {
+            let (route, params_json) = row?;
+            if let Ok(generation) = serde_json::from_str::<GenerationParams>(&params_json) {
+                defaults.push((route, generation));
+            }
+        }
+        Ok(defaults)
+    }
 }
@@ -0,0 +1,73 @@
+use actix_web::{ get, post, web, HttpResponse, Error };
+use serde_json::{ json, Value };
+use log::info;
+use crate::authentication::authorization::{ require_admin, User };
+use super::db_config::{ run_db, DB_INSTANCE };
+use super::migrations::{ backup_database, backup_file, schema_version, DATABASES };
+
+pub fn register_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(database_status).service(backup_databases);
+}
+
+// Schema version of every database of the active profile, and what migrating did at startup
+#[get("/admin/db")]
+async fn database_status(user: User) -> Result<HttpResponse, Error> {
+    if let Some(response) = require_admin(&user) {
+        return Ok(response);
+    }
+
+    let databases = run_db(|db| {
+        DATABASES.iter()
+        .map(|(database, migrations)| {
+            let file = db.database_dir.join(format!("{}.db", database));
+            let pool = db.connection_for(database);
+            let schema_version = pool
+                .get()
+                .map_err(|e| e.to_string())
+                .and_then(|connection| schema_version(&connection).map_err(|e| e.to_string()));
+            let startup = db.migration_reports.iter().find(|report| report.database == *database);
+            json!({
+                "database": database,
+                "file": file,
+                "size_bytes": std::fs::metadata(&file).map(|m| m.len()).ok(),
+                "schema_version": schema_version.as_ref().ok(),
+                "latest_version": migrations.len(),
+                "up_to_date": schema_version.as_ref().map(|v| *v == migrations.len()).unwrap_or(false),
+                "error": schema_version.as_ref().err(),
+                "startup_migration": startup,
+                "pool": {"max_size": pool.max_size(), "open": pool.state().connections, "idle": pool.state().idle_connections},
+            })
+        })
+        .collect::<Vec<Value>>()
+    }).await?;
+
+    Ok(HttpResponse::Ok().json(json!({"database_dir": DB_INSTANCE.database_dir, "databases": databases})))
+}
+
+// Copies every database into <profile>/database/backups, e.g. before a manual upgrade
+#[post("/admin/db/backup")]
+async fn backup_databases(user: User) -> Result<HttpResponse, Error> {
+    if let Some(response) = require_admin(&user) {
+        return Ok(response);
+    }
+
+    // VACUUM INTO copies the whole file, keep it off the async workers
+    let backed_up = run_db(move |db| {
+        let backup_dir = db.database_dir.join("backups");
+        let mut backups = Vec::new();
+        for (database, _) in DATABASES {
+            let connection = db.connection_for(database).get().map_err(|e| (e.to_string(), backups.clone()))?;
+            let version = schema_version(&connection).unwrap_or_default();
+            let target = backup_file(&backup_dir, database, version);
+            backup_database(&connection, &target).map_err(|e| (e, backups.clone()))?;
+            info!("Backed up {} to {}", database, target.display());
+            backups.push(json!({"database": database, "schema_version": version, "file": target}));
+        }
+        Ok::<_, (String, Vec<Value>)>(backups)
+    }).await?;
+
+    match backed_up {
+        Ok(backups) => Ok(HttpResponse::Ok().json(json!({"backups": backups}))),
+        Err((e, backups)) => Ok(HttpResponse::InternalServerError().json(json!({"error": e, "backups": backups}))),
+    }
+}
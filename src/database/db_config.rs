@@ -1,140 +1,167 @@
 use std::fs;
-use log::{ info, error };
-use lazy_static::lazy_static;
-use std::sync::Mutex;
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+use log::{ error, info };
 use once_cell::sync::Lazy;
+use actix_web::{ error::BlockingError, web };
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
 use sqlite_vec::sqlite3_vec_init;
-use rusqlite::{ ffi::sqlite3_auto_extension, Connection };
-use rusqlite_migration::{ Migrations, M };
+use rusqlite::ffi::sqlite3_auto_extension;
+use crate::server_config::{ profile_data_dir, server_config };
+use super::migrations::{ migrate, MigrationReport, DATABASES };
+
+pub type DbPool = Pool<SqliteConnectionManager>;
+
+// How long a writer waits for another writer before SQLITE_BUSY, and a request for a free connection
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+const CHECKOUT_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct DBConfig {
-    pub connection: Mutex<Connection>, // Wrapping the connection in Mutex for thread-safe access
-    pub pair_programmer_connection: Mutex<Connection>,
-    pub common_connection: Mutex<Connection>,
+    pub connection: DbPool, // Pooled connections, each request checks one out with get()
+    pub pair_programmer_connection: DbPool,
+    pub common_connection: DbPool,
+    pub database_dir: PathBuf,
+    /// What migrating each database did when it was opened
+    pub migration_reports: Vec<MigrationReport>,
+}
+
+/// Makes the sqlite-vec functions and vec0 tables available on every connection opened after
+pub fn register_sqlite_vec() {
+    // Registering the same entry point again is a no-op
+    unsafe {
+        sqlite3_auto_extension(Some(std::mem::transmute(sqlite3_vec_init as *const ())));
+    }
+}
+
+/// Pool of connections to one database file. WAL lets readers run next to the single
+/// writer, busy_timeout makes writers wait for each other instead of failing.
+fn open_pool(file: &Path, pool_size: u32) -> DbPool {
+    let manager = SqliteConnectionManager::file(file).with_init(|connection| {
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        connection.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")
+    });
+    Pool::builder()
+        .max_size(pool_size)
+        .connection_timeout(CHECKOUT_TIMEOUT)
+        .build(manager)
+        .unwrap_or_else(|e| panic!("Can't open {}: {}", file.display(), e))
 }
 
-lazy_static! {
-    static ref MIGRATIONS: Migrations<'static> = Migrations::new(
-        vec![M::up("ALTER TABLE chats ADD COLUMN vec_row_id TEXT NOT NULL;")]
-    );
-    // TODO can add MIGRATIONS_PAIR_PROGRAMMING etc for respective dbs and move migrations to separate folder
+/// No connection came free within CHECKOUT_TIMEOUT, reported as a busy database so the
+/// caller gets an error instead of a panicked blocking thread
+pub fn pool_exhausted(e: r2d2::Error) -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(
+        rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+        Some(format!("No database connection available: {}", e))
+    )
+}
+
+/// Runs blocking database work on actix's blocking thread pool, so a slow query holds up
+/// its own request and not the async worker serving everyone else
+pub async fn run_db<F, T>(f: F) -> Result<T, BlockingError>
+    where F: FnOnce(&'static DBConfig) -> T + Send + 'static, T: Send + 'static
+{
+    web::block(move || f(&DB_INSTANCE)).await
 }
 
 impl DBConfig {
-    // Function to create a new database connection (or open existing one)
+    // Opens the databases of the active profile, migrating them if needed
     pub fn new() -> Self {
-        let home_directory = dirs::home_dir().unwrap();
-        let root_pyano_dir = home_directory.join(".pyano");
-        let pyano_data_dir = root_pyano_dir.join("database");
-        if !pyano_data_dir.exists() {
-            fs::create_dir_all(&pyano_data_dir).unwrap();
+        let database_dir = profile_data_dir("database");
+        let backup_dir = database_dir.join("backups");
+        let backup_dir = if server_config().backup_before_migrate { Some(backup_dir.as_path()) } else { None };
+
+        let db_config = DBConfig::open(&database_dir, backup_dir, server_config().db_pool_size);
+        for report in &db_config.migration_reports {
+            // Writing to it with an older schema in mind could lose data, better not start at all
+            if report.is_newer_than_supported() {
+                panic!("{}", report.error.clone().unwrap_or_default());
+            }
         }
-        let pyano_db_file = pyano_data_dir.join("chats.db");
-        let pair_programmer_db_file = pyano_data_dir.join("pair_programmer.db");
-        let common_db_file = pyano_data_dir.join("common.db");
+        db_config
+    }
 
-        // Register the sqlite-vec extension to support vector operations
-        unsafe {
-            sqlite3_auto_extension(Some(std::mem::transmute(sqlite3_vec_init as *const ())));
+    /// Opens (or creates) chats.db, pair_programmer.db and common.db in `database_dir`
+    /// and brings them to the latest schema
+    pub fn open(database_dir: &Path, backup_dir: Option<&Path>, pool_size: u32) -> Self {
+        if !database_dir.exists() {
+            fs::create_dir_all(database_dir).unwrap();
         }
-        let connection: Connection = Connection::open(&pyano_db_file).unwrap();
-        let pair_programmer_connection: Connection = Connection::open(
-            &pair_programmer_db_file
-        ).unwrap();
-        let common_connection = Connection::open(&common_db_file).unwrap();
-
-        let db_config = DBConfig {
-            connection: Mutex::new(connection), // Wrapping the connection
-            pair_programmer_connection: Mutex::new(pair_programmer_connection),
-            common_connection: Mutex::new(common_connection),
+        let pyano_db_file = database_dir.join("chats.db");
+        let pair_programmer_db_file = database_dir.join("pair_programmer.db");
+        let common_db_file = database_dir.join("common.db");
+
+        register_sqlite_vec();
+        let mut db_config = DBConfig {
+            connection: open_pool(&pyano_db_file, pool_size),
+            pair_programmer_connection: open_pool(&pair_programmer_db_file, pool_size),
+            common_connection: open_pool(&common_db_file, pool_size),
+            database_dir: database_dir.to_path_buf(),
+            migration_reports: Vec::new(),
         };
 
-        db_config.create_chat_table();
-        db_config.create_chat_embeddings();
-        db_config.create_parent_context_table();
-        db_config.create_children_context_table();
-        db_config.create_context_embeddings();
-        db_config.create_pair_programmer_steps_table();
-        db_config.create_pair_programmer_table();
-        db_config.create_config_table();
-
-        if let Err(e) = db_config.run_migrations() {
-            error!("Failed to run migrations: {:?}", e);
-            // Handle the error appropriately, maybe exit the program or take alternative action
-        } else {
+        if let Err(e) = db_config.create_tables() {
+            error!("Failed to create the database tables: {}", e);
+        }
+
+        db_config.migration_reports = db_config.run_migrations(backup_dir);
+        if db_config.migration_reports.iter().all(|report| report.error.is_none()) {
             info!("Migrations completed successfully");
         }
 
         db_config
     }
 
-    pub fn run_migrations(&self) -> Result<(), rusqlite_migration::Error> {
-        let mut connection = self.connection.lock().unwrap();
-        match MIGRATIONS.to_latest(&mut connection) {
-            Ok(_) => {
-
-                // Begin transaction for safety
-                connection.execute("BEGIN TRANSACTION;", []).unwrap();
-            
-                // Step 1: Create a new table with the correct schema
-                connection
-                    .execute(
-                        "
-                        CREATE TABLE IF NOT EXISTS context_children_new (
-                            id TEXT PRIMARY KEY,
-                            user_id TEXT NOT NULL,
-                            session_id TEXT NOT NULL,
-                            parent_path TEXT,
-                            chunk_type TEXT,
-                            content TEXT,
-                            compressed_content TEXT,
-                            end_line INTEGER,
-                            file_path TEXT,
-                            start_line INTEGER,
-                            vec_row_id INTEGER NOT NULL,  -- INTEGER type for row ID reference
-                            timestamp TEXT
-                        );
-                        ",
-                        [],
-                    )
-                    .unwrap();
-            
-                // Step 2: Copy data from old table to new table, casting `vec_row_id` to INTEGER
-                connection
-                    .execute(
-                        "
-                        INSERT INTO context_children_new (id, user_id, session_id, parent_path, chunk_type,
-                                                          content, compressed_content, end_line, file_path,
-                                                          start_line, vec_row_id, timestamp)
-                        SELECT id, user_id, session_id, parent_path, chunk_type, content, compressed_content,
-                               end_line, file_path, start_line, CAST(vec_row_id AS INTEGER), timestamp
-                        FROM context_children;
-                        ",
-                        [],
-                    )
-                    .unwrap();
-            
-                // Step 3: Drop the old table
-                connection.execute("DROP TABLE context_children;", []).unwrap();
-            
-                // Step 4: Rename the new table to the original name
-                connection
-                    .execute("ALTER TABLE context_children_new RENAME TO context_children;", [])
-                    .unwrap();
-            
-                // Commit transaction
-                connection.execute("COMMIT;", []).unwrap();
-                Ok(())
-            }
-            Err(e) => {
-                // error!("Migration failed: {:?}", e);
-                Err(e)
-            }
+    // Tables of a fresh install, the migrations bring them up to date
+    fn create_tables(&self) -> Result<(), rusqlite::Error> {
+        self.create_chat_table()?;
+        self.create_sessions_table()?;
+        self.create_chat_embeddings()?;
+        self.create_parent_context_table()?;
+        self.create_children_context_table()?;
+        self.create_context_embeddings()?;
+        self.create_pair_programmer_steps_table()?;
+        self.create_pair_programmer_table()?;
+        self.create_config_table()?;
+        self.create_generation_defaults_table()?;
+        self.create_users_table()?;
+        self.create_api_keys_table()?;
+        self.create_usage_table()
+    }
+
+    /// Migrates every database, see `database::migrations`
+    fn run_migrations(&self, backup_dir: Option<&Path>) -> Vec<MigrationReport> {
+        DATABASES.iter()
+            .map(|(database, migrations)| {
+                match self.connection_for(database).get() {
+                    Ok(mut connection) => migrate(&mut connection, database, migrations, backup_dir),
+                    Err(e) =>
+                        MigrationReport {
+                            database: database.to_string(),
+                            from_version: 0,
+                            to_version: 0,
+                            latest_version: migrations.len(),
+                            backup: None,
+                            dry_run: false,
+                            error: Some(pool_exhausted(e).to_string()),
+                        },
+                }
+            })
+            .collect()
+    }
+
+    /// Connection pool of one of the `DATABASES`
+    pub fn connection_for(&self, database: &str) -> &DbPool {
+        match database {
+            "pair_programmer" => &self.pair_programmer_connection,
+            "common" => &self.common_connection,
+            _ => &self.connection,
         }
     }
 
-    pub fn create_config_table(&self) {
-        let connection = self.common_connection.lock().unwrap();
+    pub fn create_config_table(&self) -> Result<(), rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
         info!("Checking for <config> Table in common connection");
         connection
             .execute(
@@ -153,12 +180,115 @@ impl DBConfig {
             );
             ",
                 [] // Empty array for parameters since none are needed
-            )
-            .unwrap();
+            )?;
+        Ok(())
+    }
+
+    pub fn create_generation_defaults_table(&self) -> Result<(), rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        info!("Checking for <generation_defaults> Table in common connection");
+        connection
+            .execute(
+                "
+            CREATE TABLE IF NOT EXISTS generation_defaults (
+                route TEXT PRIMARY KEY,  -- CHAT, FIND_BUGS, INFILL, PAIR_PROGRAMMER ...
+                params TEXT NOT NULL,    -- GenerationParams as JSON
+                updated_at TEXT
+            );
+            ",
+                []
+            )?;
+        Ok(())
+    }
+
+    pub fn create_users_table(&self) -> Result<(), rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        info!("Checking for <users> Table in common connection");
+        connection
+            .execute(
+                "
+            CREATE TABLE IF NOT EXISTS users (
+                id TEXT PRIMARY KEY,  -- UUID, the user_id stored on sessions/chats/context rows
+                name TEXT NOT NULL,
+                is_admin INTEGER NOT NULL DEFAULT 0,
+                daily_token_quota INTEGER,  -- NULL falls back to DAILY_TOKEN_QUOTA
+                monthly_token_quota INTEGER,  -- NULL falls back to MONTHLY_TOKEN_QUOTA
+                created_at TEXT
+            );
+            ",
+                []
+            )?;
+        Ok(())
+    }
+
+    pub fn create_api_keys_table(&self) -> Result<(), rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        info!("Checking for <api_keys> Table in common connection");
+        connection
+            .execute(
+                "
+            CREATE TABLE IF NOT EXISTS api_keys (
+                id TEXT PRIMARY KEY,  -- UUID, used to revoke the key
+                user_id TEXT NOT NULL,
+                key_hash TEXT NOT NULL UNIQUE,  -- sha256 of the key, the key itself is never stored
+                prefix TEXT NOT NULL,  -- first characters of the key so users can tell them apart
+                label TEXT,
+                created_at TEXT,
+                last_used_at TEXT,
+                revoked_at TEXT
+            );
+            ",
+                []
+            )?;
+        Ok(())
+    }
+
+    pub fn create_usage_table(&self) -> Result<(), rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        info!("Checking for <usage> Table in common connection");
+        connection
+            .execute_batch(
+                "
+            CREATE TABLE IF NOT EXISTS usage (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id TEXT NOT NULL,
+                route TEXT NOT NULL,  -- CHAT, FIND_BUGS, PAIR_PROGRAMMER, TITLE ...
+                backend TEXT NOT NULL,
+                model TEXT NOT NULL,
+                request_id TEXT,
+                prompt_tokens INTEGER NOT NULL,
+                completion_tokens INTEGER NOT NULL,
+                estimated INTEGER NOT NULL DEFAULT 0,  -- no timings from the backend, counted by us
+                created_at TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS usage_user_created_at ON usage (user_id, created_at);
+            "
+            )?;
+        Ok(())
     }
 
-    pub fn create_chat_table(&self) {
-        let connection = self.connection.lock().unwrap();
+    pub fn create_sessions_table(&self) -> Result<(), rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        info!("Checking for <sessions> Table");
+        connection
+            .execute(
+                "
+            CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY,  -- UUID as primary key, same id the chats/context rows use
+                user_id TEXT NOT NULL,
+                title TEXT,
+                archived INTEGER NOT NULL DEFAULT 0,
+                created_at TEXT,
+                updated_at TEXT
+            );
+            ",
+                []
+            )?;
+        Ok(())
+    }
+
+    pub fn create_chat_table(&self) -> Result<(), rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
         info!("Checking for <chats> Table");
         connection
             .execute(
@@ -175,14 +305,14 @@ impl DBConfig {
             );
             ",
                 [] // Empty array for parameters since none are needed
-            )
-            .unwrap();
+            )?;
+        Ok(())
     }
 
-    pub fn create_pair_programmer_table(&self) {
+    pub fn create_pair_programmer_table(&self) -> Result<(), rusqlite::Error> {
         info!("Checking for <pair_programmer> Table");
 
-        let connection = self.pair_programmer_connection.lock().unwrap();
+        let connection = self.pair_programmer_connection.get().map_err(pool_exhausted)?;
         connection
             .execute(
                 "
@@ -196,14 +326,14 @@ impl DBConfig {
             );
             ",
                 [] // Empty array for parameters since none are needed
-            )
-            .unwrap();
+            )?;
+        Ok(())
     }
 
-    pub fn create_pair_programmer_steps_table(&self) {
+    pub fn create_pair_programmer_steps_table(&self) -> Result<(), rusqlite::Error> {
         info!("Checking for <pair_programmer_steps> Table");
 
-        let connection = self.pair_programmer_connection.lock().unwrap();
+        let connection = self.pair_programmer_connection.get().map_err(pool_exhausted)?;
         connection
             .execute(
                 "
@@ -222,15 +352,15 @@ impl DBConfig {
             );
             ",
                 [] // Empty array for parameters since none are needed
-            )
-            .unwrap();
+            )?;
+        Ok(())
     }
 
     //Saves the individual chunks in the table
-    pub fn create_parent_context_table(&self) {
+    pub fn create_parent_context_table(&self) -> Result<(), rusqlite::Error> {
         info!("Checking for <context_parent> Table");
 
-        let connection = self.connection.lock().unwrap();
+        let connection = self.connection.get().map_err(pool_exhausted)?;
         connection
             .execute(
                 "
@@ -245,15 +375,15 @@ impl DBConfig {
             );
             ",
                 [] // Empty array for parameters since none are needed
-            )
-            .unwrap();
+            )?;
+        Ok(())
     }
 
     //Saves the individual chunks in the table
-    pub fn create_children_context_table(&self) {
+    pub fn create_children_context_table(&self) -> Result<(), rusqlite::Error> {
         info!("Checking for <context_children> Table");
 
-        let connection = self.connection.lock().unwrap();
+        let connection = self.connection.get().map_err(pool_exhausted)?;
         connection
             .execute(
                 "
@@ -273,14 +403,14 @@ impl DBConfig {
                 );
             ",
                 [] // Empty array for parameters since none are needed
-            )
-            .unwrap();
+            )?;
+        Ok(())
     }
 
-    pub fn create_context_embeddings(&self) {
+    pub fn create_context_embeddings(&self) -> Result<(), rusqlite::Error> {
         info!("Checking for <context_embeddings> Table");
 
-        let connection = self.connection.lock().unwrap();
+        let connection = self.connection.get().map_err(pool_exhausted)?;
         let table_exists: bool =
             connection
                 .query_row(
@@ -298,15 +428,15 @@ impl DBConfig {
             CREATE VIRTUAL TABLE context_embeddings USING vec0 (embeddings float[384]);
             ",
                     []
-                )
-                .unwrap();
+                )?;
         }
+        Ok(())
     }
 
-    pub fn create_chat_embeddings(&self) {
+    pub fn create_chat_embeddings(&self) -> Result<(), rusqlite::Error> {
         info!("Checking for <chat_embeddings> Table");
 
-        let connection = self.connection.lock().unwrap();
+        let connection = self.connection.get().map_err(pool_exhausted)?;
         let table_exists: bool =
             connection
                 .query_row(
@@ -324,9 +454,9 @@ impl DBConfig {
             CREATE VIRTUAL TABLE chat_embeddings USING vec0 (embeddings float[384]);
             ",
                     []
-                )
-                .unwrap();
+                )?;
         }
+        Ok(())
     }
 }
 // Create a singleton instance of the database connection
@@ -0,0 +1,193 @@
+use rusqlite_migration::M;
+
+/// chats.db, on top of the tables created by `DBConfig::create_*_table`.
+/// Append only: a released migration is never edited or reordered.
+pub const CHATS_MIGRATIONS: &[M<'static>] = &[
+    // 1
+    M::up("ALTER TABLE chats ADD COLUMN vec_row_id TEXT NOT NULL;"),
+    // 2
+    M::up("ALTER TABLE chats ADD COLUMN cancelled INTEGER NOT NULL DEFAULT 0;"),
+    // 3. Sessions used to live only in the client, register the ones we already have data for
+    M::up(
+        "INSERT OR IGNORE INTO sessions (id, user_id, title, archived, created_at, updated_at)
+        SELECT session_id, MIN(user_id), NULL, 0, MIN(timestamp), MAX(timestamp)
+        FROM chats GROUP BY session_id;
+        INSERT OR IGNORE INTO sessions (id, user_id, title, archived, created_at, updated_at)
+        SELECT session_id, MIN(user_id), NULL, 0, MIN(timestamp), MAX(timestamp)
+        FROM context_parent GROUP BY session_id;"
+    ),
+    // 4. Rolling summary, and how many chats of the session it already covers
+    M::up(
+        "ALTER TABLE sessions ADD COLUMN summary TEXT;
+        ALTER TABLE sessions ADD COLUMN summary_chat_count INTEGER NOT NULL DEFAULT 0;"
+    ),
+    // 5. Full text index over chats, kept in sync by triggers. Keyed on the chat id rather than
+    // the implicit rowid of `chats`, which VACUUM is free to renumber.
+    M::up(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS chats_fts USING fts5(chat_id UNINDEXED, prompt, response);
+        CREATE TRIGGER IF NOT EXISTS chats_fts_insert AFTER INSERT ON chats BEGIN
+            INSERT INTO chats_fts (chat_id, prompt, response) VALUES (new.id, new.prompt, new.response);
+        END;
+        CREATE TRIGGER IF NOT EXISTS chats_fts_delete AFTER DELETE ON chats BEGIN
+            DELETE FROM chats_fts WHERE chat_id = old.id;
+        END;
+        CREATE TRIGGER IF NOT EXISTS chats_fts_update AFTER UPDATE OF prompt, response ON chats BEGIN
+            DELETE FROM chats_fts WHERE chat_id = old.id;
+            INSERT INTO chats_fts (chat_id, prompt, response) VALUES (new.id, new.prompt, new.response);
+        END;
+        INSERT INTO chats_fts (chat_id, prompt, response) SELECT id, prompt, response FROM chats;"
+    ),
+    // 6. context_children.vec_row_id is the usearch key, store it as INTEGER. SQLite can't
+    // change a column type in place, so the table is rebuilt and existing ids are cast.
+    M::up(
+        "CREATE TABLE context_children_new (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            session_id TEXT NOT NULL,
+            parent_path TEXT,
+            chunk_type TEXT,
+            content TEXT,
+            compressed_content TEXT,
+            end_line INTEGER,
+            file_path TEXT,
+            start_line INTEGER,
+            vec_row_id INTEGER NOT NULL,
+            timestamp TEXT
+        );
+        INSERT INTO context_children_new (id, user_id, session_id, parent_path, chunk_type, content,
+                                          compressed_content, end_line, file_path, start_line, vec_row_id, timestamp)
+        SELECT id, user_id, session_id, parent_path, chunk_type, content, compressed_content,
+               end_line, file_path, start_line, CAST(vec_row_id AS INTEGER), timestamp
+        FROM context_children;
+        DROP TABLE context_children;
+        ALTER TABLE context_children_new RENAME TO context_children;"
+    ),
+    // 7. Every session scoped query filters on these
+    M::up(
+        "CREATE INDEX IF NOT EXISTS chats_session_id ON chats (session_id);
+        CREATE INDEX IF NOT EXISTS context_parent_session_id ON context_parent (session_id, parent_path);
+        CREATE INDEX IF NOT EXISTS context_children_session_id ON context_children (session_id, parent_path);"
+    ),
+    // 8. Chat embeddings partitioned by session with the owner as metadata, so chat memory
+    // is searched per session instead of filtering a global top-k. vec0 tables can't be
+    // altered, the embeddings are copied out and back. Embeddings without a chat are dropped.
+    M::up(
+        "CREATE TABLE chat_embeddings_old AS
+            SELECT e.rowid AS vec_row_id, c.session_id, c.user_id, e.embeddings
+            FROM chat_embeddings e JOIN chats c ON c.vec_row_id = e.rowid;
+        DROP TABLE chat_embeddings;
+        CREATE VIRTUAL TABLE chat_embeddings USING vec0 (
+            session_id TEXT PARTITION KEY,
+            user_id TEXT,
+            embeddings float[384]
+        );
+        INSERT INTO chat_embeddings (rowid, session_id, user_id, embeddings)
+            SELECT vec_row_id, session_id, user_id, embeddings FROM chat_embeddings_old;
+        DROP TABLE chat_embeddings_old;"
+    ),
+    // 9. The context items each answer was built from, as JSON. Also swaps back the line
+    // ranges of code chunks, which were stored with start_line and end_line reversed.
+    M::up(
+        "ALTER TABLE chats ADD COLUMN context_sources TEXT;
+        UPDATE context_children SET start_line = end_line, end_line = start_line
+        WHERE start_line > end_line;"
+    ),
+    // 10. Full text index over the indexed code, for exact identifiers and error strings the
+    // embeddings of the compressed chunks miss. Keyed on the usearch key like the vector index.
+    M::up(
+        "ALTER TABLE context_children ADD COLUMN symbol_name TEXT;
+        CREATE VIRTUAL TABLE IF NOT EXISTS context_children_fts USING fts5(
+            vec_row_id UNINDEXED, session_id UNINDEXED, file_path, symbol_name, content
+        );
+        CREATE TRIGGER IF NOT EXISTS context_children_fts_insert AFTER INSERT ON context_children BEGIN
+            INSERT INTO context_children_fts (vec_row_id, session_id, file_path, symbol_name, content)
+            VALUES (new.vec_row_id, new.session_id, new.file_path, new.symbol_name, new.content);
+        END;
+        CREATE TRIGGER IF NOT EXISTS context_children_fts_delete AFTER DELETE ON context_children BEGIN
+            DELETE FROM context_children_fts WHERE vec_row_id = old.vec_row_id;
+        END;
+        CREATE TRIGGER IF NOT EXISTS context_children_fts_update
+        AFTER UPDATE OF session_id, file_path, symbol_name, content ON context_children BEGIN
+            DELETE FROM context_children_fts WHERE vec_row_id = old.vec_row_id;
+            INSERT INTO context_children_fts (vec_row_id, session_id, file_path, symbol_name, content)
+            VALUES (new.vec_row_id, new.session_id, new.file_path, new.symbol_name, new.content);
+        END;
+        INSERT INTO context_children_fts (vec_row_id, session_id, file_path, symbol_name, content)
+        SELECT vec_row_id, session_id, file_path, symbol_name, content FROM context_children;"
+    ),
+    // 11. Symbol tables of the indexed code: definitions and the references to names, so
+    // definitions, references and callers can be looked up by name. caller_id is the
+    // definition a reference sits in.
+    M::up(
+        "CREATE TABLE IF NOT EXISTS symbols (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            session_id TEXT NOT NULL,
+            parent_path TEXT NOT NULL,
+            file_path TEXT NOT NULL,
+            name TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            signature TEXT NOT NULL,
+            scope TEXT,
+            start_line INTEGER NOT NULL,
+            end_line INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS symbols_session_name ON symbols (session_id, name);
+        CREATE INDEX IF NOT EXISTS symbols_session_file ON symbols (session_id, file_path);
+        CREATE TABLE IF NOT EXISTS symbol_references (
+            id INTEGER PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            session_id TEXT NOT NULL,
+            parent_path TEXT NOT NULL,
+            file_path TEXT NOT NULL,
+            name TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            caller_id TEXT,
+            line INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS symbol_references_session_name ON symbol_references (session_id, name);
+        CREATE INDEX IF NOT EXISTS symbol_references_session_file ON symbol_references (session_id, file_path);"
+    ),
+    // 12. Content hash of every indexed local file, so re-indexing only chunks the files that
+    // changed and drops the chunks of files that are gone
+    M::up(
+        "CREATE TABLE IF NOT EXISTS context_files (
+            user_id TEXT NOT NULL,
+            session_id TEXT NOT NULL,
+            parent_path TEXT NOT NULL,
+            file_path TEXT NOT NULL,
+            content_hash TEXT NOT NULL,
+            timestamp TEXT NOT NULL,
+            PRIMARY KEY (session_id, parent_path, file_path)
+        );"
+    ),
+    // 13. Include/exclude globs and size limit a path was indexed with, reused when it's re-indexed
+    M::up("ALTER TABLE context_parent ADD COLUMN index_options TEXT;"),
+    // 14. Key the code index of 10 on its rowid, set to the usearch key. Deleting by the
+    // UNINDEXED vec_row_id column scanned the whole index for every chunk of a re-indexed file.
+    M::up(
+        "DROP TRIGGER IF EXISTS context_children_fts_insert;
+        DROP TRIGGER IF EXISTS context_children_fts_delete;
+        DROP TRIGGER IF EXISTS context_children_fts_update;
+        DROP TABLE IF EXISTS context_children_fts;
+        CREATE VIRTUAL TABLE context_children_fts USING fts5(
+            session_id UNINDEXED, file_path, symbol_name, content
+        );
+        CREATE TRIGGER context_children_fts_insert AFTER INSERT ON context_children BEGIN
+            INSERT INTO context_children_fts (rowid, session_id, file_path, symbol_name, content)
+            VALUES (new.vec_row_id, new.session_id, new.file_path, new.symbol_name, new.content);
+        END;
+        CREATE TRIGGER context_children_fts_delete AFTER DELETE ON context_children BEGIN
+            DELETE FROM context_children_fts WHERE rowid = old.vec_row_id;
+        END;
+        CREATE TRIGGER context_children_fts_update
+        AFTER UPDATE OF vec_row_id, session_id, file_path, symbol_name, content ON context_children BEGIN
+            DELETE FROM context_children_fts WHERE rowid = old.vec_row_id;
+            INSERT INTO context_children_fts (rowid, session_id, file_path, symbol_name, content)
+            VALUES (new.vec_row_id, new.session_id, new.file_path, new.symbol_name, new.content);
+        END;
+        INSERT INTO context_children_fts (rowid, session_id, file_path, symbol_name, content)
+        SELECT vec_row_id, session_id, file_path, symbol_name, content FROM context_children
+        WHERE rowid IN (SELECT MIN(rowid) FROM context_children GROUP BY vec_row_id);"
+    ),
+];
@@ -0,0 +1,8 @@
+use rusqlite_migration::M;
+
+/// common.db, on top of the tables created by `DBConfig::create_*_table`.
+/// Append only: a released migration is never edited or reordered.
+pub const COMMON_MIGRATIONS: &[M<'static>] = &[
+    // 1. Key listings are per user
+    M::up("CREATE INDEX IF NOT EXISTS api_keys_user_id ON api_keys (user_id);"),
+];
@@ -0,0 +1,298 @@
+pub mod chats;
+pub mod pair_programmer;
+pub mod common;
+
+use std::fs;
+use std::path::{ Path, PathBuf };
+use chrono::Utc;
+use log::{ error, info };
+use rusqlite::{ params, Connection };
+use rusqlite_migration::{ Migrations, M };
+use serde::Serialize;
+use crate::database::db_config::{ register_sqlite_vec, DBConfig };
+use self::chats::CHATS_MIGRATIONS;
+use self::pair_programmer::PAIR_PROGRAMMER_MIGRATIONS;
+use self::common::COMMON_MIGRATIONS;
+
+/// Every database of a profile with its migrations, `<name>.db` in the database directory
+pub const DATABASES: &[(&str, &[M<'static>])] = &[
+    ("chats", CHATS_MIGRATIONS),
+    ("pair_programmer", PAIR_PROGRAMMER_MIGRATIONS),
+    ("common", COMMON_MIGRATIONS),
+];
+
+/// What migrating one database did, or would do in a dry run
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationReport {
+    pub database: String,
+    pub from_version: usize,
+    pub to_version: usize,
+    pub latest_version: usize,
+    pub backup: Option<PathBuf>,
+    pub dry_run: bool,
+    pub error: Option<String>,
+}
+
+impl MigrationReport {
+    /// The database was written by a newer server, this one must not touch it
+    pub fn is_newer_than_supported(&self) -> bool {
+        self.from_version > self.latest_version
+    }
+}
+
+/// Version stored in the database header, the number of migrations applied
+pub fn schema_version(connection: &Connection) -> Result<usize, rusqlite::Error> {
+    connection.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0)).map(|version| version as usize)
+}
+
+pub fn backup_file(backup_dir: &Path, database: &str, version: usize) -> PathBuf {
+    backup_dir.join(format!("{}-v{}-{}.db", database, version, Utc::now().format("%Y%m%dT%H%M%S")))
+}
+
+/// Consistent copy of the database, safe while the connection is in use
+pub fn backup_database(connection: &Connection, target: &Path) -> Result<(), String> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Can't create {}: {}", parent.display(), e))?;
+    }
+    connection
+        .execute("VACUUM INTO ?", params![target.display().to_string()])
+        .map_err(|e| format!("Backup to {} failed: {}", target.display(), e))?;
+    Ok(())
+}
+
+/// Applies the pending migrations of one database. They run in a single transaction, so a
+/// failing migration leaves the database exactly as it was. With `backup_dir` set the
+/// database is copied there first.
+pub fn migrate(
+    connection: &mut Connection,
+    database: &str,
+    migrations: &'static [M<'static>],
+    backup_dir: Option<&Path>
+) -> MigrationReport {
+    let mut report = MigrationReport {
+        database: database.to_string(),
+        from_version: 0,
+        to_version: 0,
+        latest_version: migrations.len(),
+        backup: None,
+        dry_run: false,
+        error: None,
+    };
+
+    let from_version = match schema_version(connection) {
+        Ok(version) => version,
+        Err(e) => {
+            report.error = Some(e.to_string());
+            return report;
+        }
+    };
+    report.from_version = from_version;
+    report.to_version = from_version;
+
+    if report.is_newer_than_supported() {
+        report.error = Some(
+            format!(
+                "{} is at schema v{} but this server only knows v{}, upgrade the server",
+                database,
+                from_version,
+                report.latest_version
+            )
+        );
+        return report;
+    }
+    if from_version == report.latest_version {
+        return report;
+    }
+
+    if let Some(backup_dir) = backup_dir {
+        let target = backup_file(backup_dir, database, from_version);
+        if let Err(e) = backup_database(connection, &target) {
+            report.error = Some(format!("Not migrating {}: {}", database, e));
+            return report;
+        }
+        report.backup = Some(target);
+    }
+
+    match Migrations::new(migrations.to_vec()).to_latest(connection) {
+        Ok(_) => {
+            report.to_version = report.latest_version;
+            info!("Migrated {} from v{} to v{}", database, from_version, report.latest_version);
+        }
+        Err(e) => {
+            error!("Migrating {} from v{} failed, nothing was changed: {}", database, from_version, e);
+            report.error = Some(e.to_string());
+        }
+    }
+    report
+}
+
+/// Runs the migrations against copies of the databases in `database_dir` and reports what
+/// would happen. The real databases are only read.
+pub fn dry_run(database_dir: &Path) -> Result<Vec<MigrationReport>, String> {
+    register_sqlite_vec();
+    let scratch = tempfile::tempdir().map_err(|e| format!("Can't create a scratch directory: {}", e))?;
+
+    for (database, _) in DATABASES {
+        let file = database_dir.join(format!("{}.db", database));
+        if !file.exists() {
+            continue;
+        }
+        let connection = Connection::open(&file).map_err(|e| format!("Can't open {}: {}", file.display(), e))?;
+        backup_database(&connection, &scratch.path().join(format!("{}.db", database)))?;
+    }
+
+    let copy = DBConfig::open(scratch.path(), None, 1);
+    Ok(
+        copy.migration_reports
+            .iter()
+            .cloned()
+            .map(|mut report| {
+                report.dry_run = true;
+                report
+            })
+            .collect()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use r2d2::Pool;
+    use r2d2_sqlite::SqliteConnectionManager;
+    use rusqlite::{ params, Connection };
+    use crate::database::db_config::{ register_sqlite_vec, DBConfig };
+    use super::{ dry_run, migrate, schema_version, CHATS_MIGRATIONS };
+
+    // Before the table rebuilds of migrations 6, 8, 10 and 14
+    const BEFORE_REBUILDS: usize = 5;
+
+    /// chats.db in `dir` with the tables of a fresh install, migrated only up to `version`
+    fn chats_db_at(dir: &Path, version: usize) -> Connection {
+        register_sqlite_vec();
+        let pool = |manager: SqliteConnectionManager| Pool::builder().max_size(1).build(manager).unwrap();
+        let db = DBConfig {
+            connection: pool(SqliteConnectionManager::file(dir.join("chats.db"))),
+            pair_programmer_connection: pool(SqliteConnectionManager::memory()),
+            common_connection: pool(SqliteConnectionManager::memory()),
+            database_dir: dir.to_path_buf(),
+            migration_reports: Vec::new(),
+        };
+        db.create_chat_table().unwrap();
+        db.create_sessions_table().unwrap();
+        db.create_chat_embeddings().unwrap();
+        db.create_parent_context_table().unwrap();
+        db.create_children_context_table().unwrap();
+        db.create_context_embeddings().unwrap();
+        drop(db);
+
+        let mut connection = Connection::open(dir.join("chats.db")).unwrap();
+        let report = migrate(&mut connection, "chats", &CHATS_MIGRATIONS[..version], None);
+        assert_eq!(report.error, None);
+        connection
+    }
+
+    fn embedding(value: f32) -> String {
+        format!("[{}]", vec![value.to_string(); 384].join(","))
+    }
+
+    #[test]
+    fn migrates_a_fresh_install_to_the_latest_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DBConfig::open(dir.path(), None, 1);
+        for report in &db.migration_reports {
+            assert_eq!(report.error, None, "{}", report.database);
+            assert_eq!(report.to_version, report.latest_version, "{}", report.database);
+        }
+        let connection = db.connection.get().unwrap();
+        assert_eq!(schema_version(&connection).unwrap(), CHATS_MIGRATIONS.len());
+    }
+
+    #[test]
+    fn table_rebuilds_keep_existing_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut connection = chats_db_at(dir.path(), BEFORE_REBUILDS);
+        connection
+            .execute_batch(
+                &format!(
+                    "INSERT INTO chats (id, user_id, session_id, prompt, response, timestamp, vec_row_id)
+                    VALUES ('chat-1', 'user-1', 'session-1', 'hello there', 'general kenobi', '2024-01-01', '7');
+                    INSERT INTO chat_embeddings (rowid, embeddings) VALUES (7, '{}');
+                    INSERT INTO chat_embeddings (rowid, embeddings) VALUES (8, '{}');
+                    INSERT INTO context_children (id, user_id, session_id, parent_path, content, file_path,
+                                                  start_line, end_line, vec_row_id)
+                    VALUES ('chunk-1', 'user-1', 'session-1', '/work', 'fn load_settings() {{}}', 'src/lib.rs',
+                            20, 10, '42');",
+                    embedding(0.5),
+                    embedding(0.25)
+                )
+            )
+            .unwrap();
+
+        let backup_dir = dir.path().join("backups");
+        let report = migrate(&mut connection, "chats", CHATS_MIGRATIONS, Some(&backup_dir));
+        assert_eq!(report.error, None);
+        assert_eq!((report.from_version, report.to_version), (BEFORE_REBUILDS, CHATS_MIGRATIONS.len()));
+        assert_eq!(schema_version(&connection).unwrap(), CHATS_MIGRATIONS.len());
+
+        // The backup is the database as it was before migrating
+        let backup = report.backup.expect("no backup was made");
+        assert_eq!(schema_version(&Connection::open(&backup).unwrap()).unwrap(), BEFORE_REBUILDS);
+
+        // 5: the chat is searchable
+        let chat_id: String = connection
+            .query_row("SELECT chat_id FROM chats_fts WHERE chats_fts MATCH 'kenobi'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(chat_id, "chat-1");
+
+        // 8: the embedding of the chat moved into its session, the one without a chat is gone
+        let embeddings: Vec<(i64, String, String)> = connection
+            .prepare("SELECT rowid, session_id, user_id FROM chat_embeddings")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(embeddings, vec![(7, "session-1".to_string(), "user-1".to_string())]);
+
+        // 6 and 9: the usearch key is an integer and the line range is in order
+        let (vec_row_id, start_line, end_line): (i64, i64, i64) = connection
+            .query_row("SELECT vec_row_id, start_line, end_line FROM context_children WHERE id = 'chunk-1'", [], |row|
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            )
+            .unwrap();
+        assert_eq!((vec_row_id, start_line, end_line), (42, 10, 20));
+
+        // 10 and 14: the chunk is indexed under its usearch key, and deleting it unindexes it
+        let fts_row_id: i64 = connection
+            .query_row(
+                "SELECT rowid FROM context_children_fts WHERE context_children_fts MATCH 'settings'",
+                [],
+                |row| row.get(0)
+            )
+            .unwrap();
+        assert_eq!(fts_row_id, 42);
+        connection.execute("DELETE FROM context_children WHERE vec_row_id = ?", params![42]).unwrap();
+        let indexed: i64 = connection
+            .query_row("SELECT count(*) FROM context_children_fts", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(indexed, 0);
+    }
+
+    #[test]
+    fn dry_run_leaves_the_databases_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        drop(chats_db_at(dir.path(), BEFORE_REBUILDS));
+
+        let reports = dry_run(dir.path()).unwrap();
+        let chats = reports
+            .iter()
+            .find(|report| report.database == "chats")
+            .unwrap();
+        assert!(chats.dry_run);
+        assert_eq!(chats.error, None);
+        assert_eq!((chats.from_version, chats.to_version), (BEFORE_REBUILDS, CHATS_MIGRATIONS.len()));
+
+        let connection = Connection::open(dir.path().join("chats.db")).unwrap();
+        assert_eq!(schema_version(&connection).unwrap(), BEFORE_REBUILDS);
+    }
+}
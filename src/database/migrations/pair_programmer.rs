@@ -0,0 +1,11 @@
+use rusqlite_migration::M;
+
+/// pair_programmer.db, on top of the tables created by `DBConfig::create_*_table`.
+/// Append only: a released migration is never edited or reordered.
+pub const PAIR_PROGRAMMER_MIGRATIONS: &[M<'static>] = &[
+    // 1. Steps are always loaded per plan, plans per session
+    M::up(
+        "CREATE INDEX IF NOT EXISTS pp_steps_pair_programmer_id ON pp_steps (pair_programmer_id);
+        CREATE INDEX IF NOT EXISTS pair_programmer_session_id ON pair_programmer (session_id);"
+    ),
+];
@@ -2,4 +2,11 @@ pub mod db_config;
 pub mod chat_db;
 pub mod rag_db;
 pub mod pair_programmer_db;
-pub mod config_db;
\ No newline at end of file
+pub mod config_db;
+pub mod session_db;
+pub mod archive_db;
+pub mod auth_db;
+pub mod usage_db;
+pub mod symbol_db;
+pub mod migrations;
+pub mod db_api;
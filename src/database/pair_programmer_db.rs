@@ -1,9 +1,9 @@
 
 
 use chrono::Utc; // For getting the current UTC timestamp
-use rusqlite::params;
+use rusqlite::{params, OptionalExtension};
 use serde_json::{json, Value};
-use crate::database::db_config::DBConfig;
+use crate::database::db_config::{ pool_exhausted, DBConfig };
 use crate::pair_programmer::types::{StepChat, PairProgrammerStep, PairProgrammerStepRaw};
 use log::info;
 use std::collections::HashMap;
@@ -15,10 +15,10 @@ impl DBConfig{
         pair_programmer_id: &str,
         step_number: &str,
     ) -> Result<PairProgrammerStep, Box<dyn Error>> {
-        // Lock the mutex to access the connection
-         // Lock the mutex to access the connection
-         let connection = self.pair_programmer_connection.lock()
-         .map_err(|_| "Failed to acquire lock for connection")?;
+        // Check out a connection from the pool
+         // Check out a connection from the pool
+         let connection = self.pair_programmer_connection.get()
+         .map_err(|_| "Failed to get a database connection")?;
 
         // Construct the step_id from pair_programmer_id and step_number
         let step_id = format!("{}_{}", pair_programmer_id, step_number);
@@ -78,9 +78,9 @@ impl DBConfig{
         steps: &Vec<PairProgrammerStepRaw>
     ) -> Result<(), Box<dyn Error>> {
         
-        // Lock the mutex to access the connection
-        let connection = self.pair_programmer_connection.lock()
-            .map_err(|_| "Failed to acquire lock for connection")?;
+        // Check out a connection from the pool
+        let connection = self.pair_programmer_connection.get()
+            .map_err(|_| "Failed to get a database connection")?;
         
         let serialized_steps = serde_json::to_string(&steps)
             .map_err(|_| "Failed to serialize steps")?;
@@ -137,9 +137,9 @@ impl DBConfig{
         pair_programmer_id: &str
     ) -> Result<String, Box<dyn Error>> {
         
-        // Lock the mutex to access the connection
-        let connection = self.pair_programmer_connection.lock()
-            .map_err(|_| "Failed to acquire lock for connection")?;
+        // Check out a connection from the pool
+        let connection = self.pair_programmer_connection.get()
+            .map_err(|_| "Failed to get a database connection")?;
         
         // Query to fetch only the task from pair_programmer table
         let mut stmt = connection.prepare(
@@ -153,9 +153,20 @@ impl DBConfig{
         Ok(task)
     }
 
-    pub fn fetch_steps(&self, pair_programmer_id: &str) -> Vec<Value> {
-        // Lock the mutex to access the connection
-        let connection = self.pair_programmer_connection.lock().unwrap();
+    pub fn fetch_pair_programmer_owner(&self, pair_programmer_id: &str) -> Result<Option<String>, rusqlite::Error> {
+        let connection = self.pair_programmer_connection.get().map_err(pool_exhausted)?;
+        connection
+            .query_row(
+                "SELECT user_id FROM pair_programmer WHERE id = ?",
+                params![pair_programmer_id],
+                |row| row.get(0)
+            )
+            .optional()
+    }
+
+    pub fn fetch_steps(&self, pair_programmer_id: &str) -> Result<Vec<Value>, rusqlite::Error> {
+        // Check out a connection from the pool
+        let connection = self.pair_programmer_connection.get().map_err(pool_exhausted)?;
         
         // Prepare a SQL query to fetch all the steps for a specific pair_programming_id
         let mut stmt = connection
@@ -163,8 +174,7 @@ impl DBConfig{
                 "SELECT id, user_id, session_id, heading, action, details, executed, response, chat, timestamp 
                  FROM pp_steps 
                  WHERE pair_programmer_id = ?",
-            )
-            .unwrap();
+            )?;
         
         // Create a vector to hold the steps in JSON format
         let mut steps: Vec<Value> = Vec::new();
@@ -186,21 +196,20 @@ impl DBConfig{
                     "chat": row.get::<_, String>(8)?,            // chat (assuming it's serialized as JSON or a string)
                     "timestamp": row.get::<_, String>(9)?,       // timestamp
                 }))
-            })
-            .unwrap();
+            })?;
         
         // Collect all rows into the `steps` vector
         for step in step_iter {
-            steps.push(step.unwrap());
+            steps.push(step?);
         }
         
-        steps
+        Ok(steps)
     }
 
     pub fn update_step_execution(&self, pair_programmer_id: &str, step_number: &str, response: &str) ->Result<(), rusqlite::Error>  {
             
-        // Lock the mutex to access the connection
-        let connection = self.pair_programmer_connection.lock().unwrap();
+        // Check out a connection from the pool
+        let connection = self.pair_programmer_connection.get().map_err(pool_exhausted)?;
         let step_id = format!("{}_{}", pair_programmer_id, step_number);
 
         let sql = "UPDATE pp_steps SET response = ?1, executed = 1 WHERE id = ?2";
@@ -212,8 +221,8 @@ impl DBConfig{
 
     // pub fn update_step_chat(&self, pair_programmer_id: &str, step_number: &str, prompt: &str, response: &str) ->Result<(), rusqlite::Error>  {
             
-    //     // Lock the mutex to access the connection
-    //     let connection = self.pair_programmer_connection.lock().unwrap();
+    //     // Check out a connection from the pool
+    //     let connection = self.pair_programmer_connection.get().unwrap();
     //     let step_id = format!("{}_{}", pair_programmer_id, step_number);
 
     //     // Fetch the current chat from the step
@@ -237,8 +246,8 @@ impl DBConfig{
     // }
 
     // pub fn step_chat_string(&self, pair_programmer_id: &str, step_number: &str) -> Result<String, Box<dyn std::error::Error>> {
-    //     // Lock the mutex to access the connection
-    //     let connection = self.pair_programmer_connection.lock().unwrap();
+    //     // Check out a connection from the pool
+    //     let connection = self.pair_programmer_connection.get().unwrap();
     //     let step_id = format!("{}_{}", pair_programmer_id, step_number);
     
     //     // Fetch the current chat from the step
@@ -268,9 +277,9 @@ impl DBConfig{
         response: &str
     ) -> Result<(), Box<dyn Error>> {
         
-        // Lock the mutex to access the connection
-        let connection = self.pair_programmer_connection.lock()
-            .map_err(|_| "Failed to acquire lock for connection")?;
+        // Check out a connection from the pool
+        let connection = self.pair_programmer_connection.get()
+            .map_err(|_| "Failed to get a database connection")?;
         let step_id = format!("{}_{}", pair_programmer_id, step_number);
     
         // Fetch the existing chat history for the step
@@ -319,9 +328,9 @@ impl DBConfig{
         response: &str
     ) -> Result<(), Box<dyn Error>> {
         
-        // Lock the mutex to access the connection
-        let connection = self.pair_programmer_connection.lock()
-            .map_err(|_| "Failed to acquire lock for connection")?;
+        // Check out a connection from the pool
+        let connection = self.pair_programmer_connection.get()
+            .map_err(|_| "Failed to get a database connection")?;
         let step_id = format!("{}_{}", pair_programmer_id, step_number);
     
         // Fetch the existing chat history for the step
@@ -363,8 +372,8 @@ impl DBConfig{
     }
 
     // pub fn get_step_chat(&self, pair_programmer_id: &str, step_number: &str) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
-    //     // Lock the mutex to access the connection
-    //     let connection = self.pair_programmer_connection.lock().unwrap();
+    //     // Check out a connection from the pool
+    //     let connection = self.pair_programmer_connection.get().unwrap();
     //     let step_id = format!("{}_{}", pair_programmer_id, step_number);
     
     //     // Fetch the current chat from the step
@@ -1,11 +1,29 @@
-use crate::database::db_config::DBConfig;
+use crate::database::db_config::{ pool_exhausted, DBConfig };
 use uuid::Uuid;
 use chrono::Utc; // For getting the current UTC timestamp
 use serde_json::{ json, Value };
 use rand::Rng;
-use rusqlite::params;
+use rusqlite::{ params, OptionalExtension, ToSql };
 use std::error::Error;
+use std::collections::HashMap;
 use log::info;
+
+/// One indexed code chunk, lines are zero based as tree-sitter reports them
+#[derive(Debug, Clone)]
+pub struct ContextChunk {
+    pub file_path: String,
+    pub chunk_type: String,
+    pub content: String,
+    pub session_id: String,
+    pub start_line: Option<i64>,
+    pub end_line: Option<i64>,
+}
+
+// The stored `index_options` column, null for paths indexed before it existed
+fn index_options(stored: Option<String>) -> Value {
+    stored.and_then(|options| serde_json::from_str(&options).ok()).unwrap_or(Value::Null)
+}
+
 impl DBConfig {
     pub fn generate_rowid() -> u64 {
         let mut rng = rand::thread_rng();
@@ -20,9 +38,9 @@ impl DBConfig {
         parent_path: &str,
         filetype: &str,
         category: &str
-    ) {
-        // Lock the mutex to access the connection
-        let connection = self.connection.lock().unwrap();
+    ) -> Result<(), rusqlite::Error> {
+        // Check out a connection from the pool
+        let connection = self.connection.get().map_err(pool_exhausted)?;
         let uuid = Uuid::new_v4().to_string();
 
         // Get the current UTC timestamp
@@ -40,18 +58,23 @@ impl DBConfig {
                     category,
                     timestamp.as_str()
                 ]
-            )
-            .unwrap();
+            )?;
+        Ok(())
     }
 
-    pub fn delete_parent_context(&self, parent_path: &str) -> Result<(), rusqlite::Error> {
-        // Lock the mutex to access the connection
-        let connection = self.connection.lock().map_err(|_| rusqlite::Error::InvalidQuery)?;
+    pub fn delete_parent_context(
+        &self,
+        user_id: &str,
+        session_id: &str,
+        parent_path: &str
+    ) -> Result<(), rusqlite::Error> {
+        // Check out a connection from the pool
+        let connection = self.connection.get().map_err(pool_exhausted)?;
 
         // Execute the DELETE query and return the result
         connection.execute(
-            "DELETE FROM context_parent WHERE parent_path = ?",
-            params![parent_path]
+            "DELETE FROM context_parent WHERE user_id = ? AND session_id = ? AND parent_path = ?",
+            params![user_id, session_id, parent_path]
         )?;
 
         Ok(())
@@ -63,15 +86,16 @@ impl DBConfig {
         session_id: &str,
         parent_path: &str,
         chunk_type: &str,
+        symbol_name: Option<&str>,
         content: &str,
         compressed_content: &str,
-        end_line: usize,
         start_line: usize,
+        end_line: usize,
         file_path: &str,
         vec_row_id: u64
-    ) {
-        // Lock the mutex to access the connection
-        let connection = self.connection.lock().unwrap();
+    ) -> Result<(), rusqlite::Error> {
+        // Check out a connection from the pool
+        let connection = self.connection.get().map_err(pool_exhausted)?;
 
         // Generate UUIDs for the child and the vector embedding
         let uuid = Uuid::new_v4().to_string();
@@ -89,15 +113,16 @@ impl DBConfig {
         connection
             .execute(
                 "INSERT INTO context_children (
-                id, user_id, session_id, parent_path, chunk_type, content, compressed_content,
+                id, user_id, session_id, parent_path, chunk_type, symbol_name, content, compressed_content,
                 end_line, file_path, start_line, vec_row_id, timestamp
-            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
                 params![
                     uuid,
                     user_id,
                     session_id,
                     parent_path,
                     chunk_type,
+                    symbol_name,
                     content,
                     compressed_content,
                     end_line,
@@ -106,8 +131,7 @@ impl DBConfig {
                     vec_row_id,
                     timestamp.as_str()
                 ]
-            )
-            .unwrap();
+            )?;
 
         // // Insert into context_embeddings
         // connection.execute(
@@ -117,6 +141,7 @@ impl DBConfig {
         //         embeddings.as_bytes(),  // You can pass the float array directly in rusqlite
         //     ],
         // ).unwrap();
+        Ok(())
     }
 
     pub fn delete_children_context_by_parent_path(
@@ -125,8 +150,8 @@ impl DBConfig {
         session_id: &str,
         parent_path: &str
     ) -> Result<Vec<u64>, rusqlite::Error> {
-        // Lock the mutex to access the connection
-        let mut connection = self.connection.lock().unwrap();
+        // Check out a connection from the pool
+        let mut connection = self.connection.get().map_err(pool_exhausted)?;
 
         // Begin a transaction to ensure both deletions are atomic
         let tx = connection.transaction()?;
@@ -151,6 +176,19 @@ impl DBConfig {
              WHERE user_id = ? AND session_id = ? AND parent_path = ?",
             params![user_id, session_id, parent_path]
         )?;
+        // Symbols go with the code they were extracted from
+        tx.execute(
+            "DELETE FROM symbols WHERE user_id = ? AND session_id = ? AND parent_path = ?",
+            params![user_id, session_id, parent_path]
+        )?;
+        tx.execute(
+            "DELETE FROM symbol_references WHERE user_id = ? AND session_id = ? AND parent_path = ?",
+            params![user_id, session_id, parent_path]
+        )?;
+        tx.execute(
+            "DELETE FROM context_files WHERE user_id = ? AND session_id = ? AND parent_path = ?",
+            params![user_id, session_id, parent_path]
+        )?;
 
         // // Delete from `context_embeddings` for the corresponding vec_row_ids
         // for row_id in &vec_row_ids {
@@ -172,8 +210,8 @@ impl DBConfig {
         session_id: &str,
         file_path: &str
     ) -> Result<Vec<u64>, rusqlite::Error> {
-        // Lock the mutex to access the connection
-        let mut connection = self.connection.lock().unwrap();
+        // Check out a connection from the pool
+        let mut connection = self.connection.get().map_err(pool_exhausted)?;
 
         // Begin a transaction to ensure both deletions are atomic
         let tx = connection.transaction()?;
@@ -198,6 +236,19 @@ impl DBConfig {
              WHERE user_id = ? AND session_id = ? AND file_path = ?",
             params![user_id, session_id, file_path]
         )?;
+        // Symbols go with the code they were extracted from
+        tx.execute(
+            "DELETE FROM symbols WHERE user_id = ? AND session_id = ? AND file_path = ?",
+            params![user_id, session_id, file_path]
+        )?;
+        tx.execute(
+            "DELETE FROM symbol_references WHERE user_id = ? AND session_id = ? AND file_path = ?",
+            params![user_id, session_id, file_path]
+        )?;
+        tx.execute(
+            "DELETE FROM context_files WHERE user_id = ? AND session_id = ? AND file_path = ?",
+            params![user_id, session_id, file_path]
+        )?;
 
         // // Delete from `context_embeddings` for the corresponding vec_row_ids
         // for row_id in &vec_row_ids {
@@ -213,19 +264,47 @@ impl DBConfig {
         Ok(vec_row_ids)
     }
 
-    pub fn fetch_session_context_files(&self, user_id: &str, session_id: &str) -> Vec<Value> {
-        // Lock the mutex to access the connection
-        let connection = self.connection.lock().unwrap();
+    /// Sessions with indexed code, most recently indexed first. `user_id` None covers everybody.
+    pub fn fetch_indexed_sessions(&self, user_id: Option<&str>, limit: usize) -> Result<Vec<String>, rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let mut stmt = connection.prepare(
+            "SELECT session_id FROM context_parent
+             WHERE ?1 IS NULL OR user_id = ?1
+             GROUP BY session_id
+             ORDER BY MAX(timestamp) DESC
+             LIMIT ?2"
+        )?;
+        let sessions = stmt
+            .query_map(params![user_id, limit as i64], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+        Ok(sessions)
+    }
+
+    /// Indexed local directories of every session as (user_id, session_id, parent_path)
+    pub fn fetch_local_directories(&self) -> Result<Vec<(String, String, String)>, rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let mut stmt = connection.prepare(
+            "SELECT DISTINCT user_id, session_id, parent_path FROM context_parent
+             WHERE filetype = 'local_directory'"
+        )?;
+        let directories = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(directories)
+    }
+
+    pub fn fetch_session_context_files(&self, user_id: &str, session_id: &str) -> Result<Vec<Value>, rusqlite::Error> {
+        // Check out a connection from the pool
+        let connection = self.connection.get().map_err(pool_exhausted)?;
 
         // Prepare a SQL query to fetch all the chats for a specific session_id and user_id, sorted by timestamp
         let mut stmt = connection
             .prepare(
-                "SELECT user_id, session_id, parent_path, filetype, category, timestamp 
+                "SELECT user_id, session_id, parent_path, filetype, category, timestamp, index_options 
                  FROM context_parent 
                  WHERE user_id = ? and session_id = ?
                  ORDER BY timestamp ASC"
-            )
-            .unwrap();
+            )?;
 
         // Create a vector to hold the chat entries in JSON format
         let mut context_files: Vec<Value> = Vec::new();
@@ -241,17 +320,17 @@ impl DBConfig {
                     "filetype": row.get::<_, String>(3)?,  // prompt
                     "category": row.get::<_, String>(4)?,  // prompt
                     "timestamp": row.get::<_, String>(5)?,  // timestamp
+                    "index_options": index_options(row.get(6)?),
                 })
                 )
-            })
-            .unwrap();
+            })?;
 
         // Collect all rows into the `chats` vector
         for chat in context_iter {
-            context_files.push(chat.unwrap());
+            context_files.push(chat?);
         }
 
-        context_files
+        Ok(context_files)
     }
 
     //fetch a filepath for a paritcular session if present
@@ -260,19 +339,18 @@ impl DBConfig {
         user_id: &str,
         session_id: &str,
         parent_path: &str
-    ) -> Option<Value> {
-        // Lock the mutex to access the connection
-        let connection = self.connection.lock().unwrap();
+    ) -> Result<Option<Value>, rusqlite::Error> {
+        // Check out a connection from the pool
+        let connection = self.connection.get().map_err(pool_exhausted)?;
 
         // Prepare a SQL query to fetch all the chats for a specific session_id and user_id, sorted by timestamp
         let mut stmt = connection
             .prepare(
-                "SELECT user_id, session_id, parent_path, filetype, category, timestamp 
+                "SELECT user_id, session_id, parent_path, filetype, category, timestamp, index_options 
                  FROM context_parent 
                  WHERE user_id = ? and session_id = ? and parent_path = ?
                  ORDER BY timestamp ASC"
-            )
-            .unwrap();
+            )?;
 
         // Execute the query and iterate over the rows, collecting them into the vector
         let result = stmt.query_row([user_id, session_id, parent_path], |row| {
@@ -284,12 +362,13 @@ impl DBConfig {
                     "filetype": row.get::<_, String>(3)?,  // filetype
                     "category": row.get::<_, String>(4)?,  // category
                     "timestamp": row.get::<_, String>(5)?,  // timestamp
+                    "index_options": index_options(row.get(6)?),
                 })
             )
         });
 
-        // Return the result as an Option, with None if no row is found
-        result.ok()
+        // None if no row is found
+        result.optional()
     }
 
     pub fn update_session_context_timestamp(
@@ -298,8 +377,8 @@ impl DBConfig {
         session_id: &str,
         parent_path: &str
     ) -> Result<(), rusqlite::Error> {
-        // Lock the mutex to access the connection
-        let connection = self.connection.lock().unwrap();
+        // Check out a connection from the pool
+        let connection = self.connection.get().map_err(pool_exhausted)?;
         let timestamp = Utc::now().to_rfc3339();
 
         // Prepare the SQL query to update the timestamp for the specified entry
@@ -314,15 +393,135 @@ impl DBConfig {
         Ok(())
     }
 
+    /// Files indexed under a parent path, with their content hash when it was recorded. Files
+    /// chunked before hashes were kept have none.
+    pub fn fetch_indexed_files(
+        &self,
+        user_id: &str,
+        session_id: &str,
+        parent_path: &str
+    ) -> Result<HashMap<String, Option<String>>, rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let mut stmt = connection.prepare(
+            "SELECT file_path, content_hash FROM context_files
+            WHERE user_id = ?1 AND session_id = ?2 AND parent_path = ?3
+            UNION ALL
+            SELECT DISTINCT file_path, NULL FROM context_children
+            WHERE user_id = ?1 AND session_id = ?2 AND parent_path = ?3"
+        )?;
+
+        let mut files: HashMap<String, Option<String>> = HashMap::new();
+        let rows = stmt.query_map(params![user_id, session_id, parent_path], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+        })?;
+        for row in rows {
+            let (file_path, content_hash) = row?;
+            let known = files.entry(file_path).or_insert(None);
+            if content_hash.is_some() {
+                *known = content_hash;
+            }
+        }
+        Ok(files)
+    }
+
+    /// Stores the `IndexOptions` (as JSON) a path was last indexed with
+    pub fn update_parent_index_options(
+        &self,
+        user_id: &str,
+        session_id: &str,
+        parent_path: &str,
+        index_options: &str
+    ) -> Result<(), rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        connection.execute(
+            "UPDATE context_parent SET index_options = ?
+             WHERE user_id = ? AND session_id = ? AND parent_path = ?",
+            params![index_options, user_id, session_id, parent_path]
+        )?;
+        Ok(())
+    }
+
+    /// Records the content hashes of files that were just chunked, as (file_path, hash)
+    pub fn store_file_hashes(
+        &self,
+        user_id: &str,
+        session_id: &str,
+        parent_path: &str,
+        hashes: &[(String, String)]
+    ) -> Result<(), rusqlite::Error> {
+        let mut connection = self.connection.get().map_err(pool_exhausted)?;
+        let tx = connection.transaction()?;
+        let timestamp = Utc::now().to_rfc3339();
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO context_files (
+                    user_id, session_id, parent_path, file_path, content_hash, timestamp
+                ) VALUES (?, ?, ?, ?, ?, ?)"
+            )?;
+            for (file_path, content_hash) in hashes {
+                stmt.execute(
+                    params![user_id, session_id, parent_path, file_path, content_hash, timestamp]
+                )?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// BM25 ranked chunks of the sessions matching an FTS5 query, best first, as usearch keys.
+    /// Symbol names count the most, then file paths, then the code itself.
+    pub fn keyword_search_context(
+        &self,
+        fts_query: &str,
+        session_ids: &[String],
+        limit: usize
+    ) -> Result<Vec<u64>, rusqlite::Error> {
+        if session_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let placeholders = vec!["?"; session_ids.len()].join(", ");
+        let mut stmt = connection.prepare(
+            &format!(
+                "SELECT rowid FROM context_children_fts
+                WHERE context_children_fts MATCH ? AND session_id IN ({})
+                ORDER BY bm25(context_children_fts, 0.0, 2.0, 5.0, 1.0)
+                LIMIT ?",
+                placeholders
+            )
+        )?;
+
+        let limit = limit as i64;
+        let mut values: Vec<&dyn ToSql> = vec![&fts_query];
+        values.extend(session_ids.iter().map(|id| id as &dyn ToSql));
+        values.push(&limit);
+        let keys = stmt
+            .query_map(values.as_slice(), |row| row.get::<_, i64>(0))?
+            .map(|key| key.map(|key| key as u64))
+            .collect::<Result<Vec<u64>, _>>()?;
+        Ok(keys)
+    }
+
+    /// Indexed chunks by usearch key: (file_path, chunk_type, content, session_id)
     pub fn get_row_ids(
         &self,
         row_ids: Vec<u64>
     ) -> Result<Vec<(String, String, String, String)>, Box<dyn Error>> {
+        let chunks = self.get_context_chunks(row_ids)?;
+        Ok(
+            chunks
+                .into_iter()
+                .map(|chunk| (chunk.file_path, chunk.chunk_type, chunk.content, chunk.session_id))
+                .collect()
+        )
+    }
+
+    /// Same as `get_row_ids`, plus the line range of each chunk
+    pub fn get_context_chunks(&self, row_ids: Vec<u64>) -> Result<Vec<ContextChunk>, Box<dyn Error>> {
         // Collect context data for each nearest embedding
-        let mut chunks: Vec<(String, String, String, String)> = Vec::new();
+        let mut chunks: Vec<ContextChunk> = Vec::new();
         let connection = self.connection
-            .lock()
-            .map_err(|e| { format!("Failed to acquire lock: {}", e) })?;
+            .get()
+            .map_err(|e| { format!("Failed to get a database connection: {}", e) })?;
         for rowid in row_ids {
             let mut stmt = connection.prepare(
                 r#"
@@ -330,19 +529,23 @@ impl DBConfig {
                     file_path,
                     chunk_type,
                     content,
-                    session_id
+                    session_id,
+                    start_line,
+                    end_line
                 FROM context_children
                 WHERE vec_row_id = ?
                 "#
             )?;
 
             let context_iter = stmt.query_map(params![rowid], |row| {
-                let file_path: String = row.get(0)?;
-                let chunk_type: String = row.get(1)?;
-                let content: String = row.get(2)?;
-                let session_id: String = row.get(3)?;
-
-                Ok((file_path, chunk_type, content, session_id))
+                Ok(ContextChunk {
+                    file_path: row.get(0)?,
+                    chunk_type: row.get(1)?,
+                    content: row.get(2)?,
+                    session_id: row.get(3)?,
+                    start_line: row.get(4)?,
+                    end_line: row.get(5)?,
+                })
             })?;
 
             for context in context_iter {
@@ -0,0 +1,247 @@
+use crate::database::db_config::{ pool_exhausted, DBConfig };
+use chrono::Utc;
+use serde_json::{ json, Value };
+use rusqlite::{ params, OptionalExtension, Row };
+use std::error::Error;
+use log::info;
+
+fn session_from_row(row: &Row) -> Result<Value, rusqlite::Error> {
+    Ok(
+        json!({
+        "id": row.get::<_, String>(0)?,
+        "user_id": row.get::<_, String>(1)?,
+        "title": row.get::<_, Option<String>>(2)?,
+        "archived": row.get::<_, i32>(3)? != 0,
+        "created_at": row.get::<_, Option<String>>(4)?,
+        "updated_at": row.get::<_, Option<String>>(5)?,
+        "summary": row.get::<_, Option<String>>(6)?,
+    })
+    )
+}
+
+impl DBConfig {
+    pub fn create_session(
+        &self,
+        session_id: &str,
+        user_id: &str,
+        title: Option<&str>
+    ) -> Result<Value, Box<dyn Error>> {
+        let connection = self.connection.get()?;
+        let timestamp = Utc::now().to_rfc3339();
+
+        connection.execute(
+            "INSERT INTO sessions (id, user_id, title, archived, created_at, updated_at)
+            VALUES (?, ?, ?, 0, ?, ?)",
+            params![session_id, user_id, title, timestamp, timestamp]
+        )?;
+
+        Ok(
+            json!({
+            "id": session_id,
+            "user_id": user_id,
+            "title": title,
+            "archived": false,
+            "created_at": timestamp,
+            "updated_at": timestamp,
+            "summary": Value::Null,
+        })
+        )
+    }
+
+    pub fn get_session(&self, session_id: &str) -> Result<Option<Value>, rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        connection
+            .query_row(
+                "SELECT id, user_id, title, archived, created_at, updated_at, summary
+                FROM sessions WHERE id = ?",
+                params![session_id],
+                session_from_row
+            )
+            .optional()
+    }
+
+    pub fn list_sessions(
+        &self,
+        user_id: &str,
+        include_archived: bool,
+        skip: u32,
+        limit: u32
+    ) -> Result<Vec<Value>, rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let mut stmt = connection.prepare(
+            "SELECT id, user_id, title, archived, created_at, updated_at, summary
+            FROM sessions
+            WHERE user_id = ? AND (archived = 0 OR ?)
+            ORDER BY updated_at DESC
+            LIMIT ?
+            OFFSET ?"
+        )?;
+
+        let sessions = stmt
+            .query_map(params![user_id, include_archived, limit, skip], session_from_row)?
+            .collect::<Result<Vec<Value>, rusqlite::Error>>()?;
+        Ok(sessions)
+    }
+
+    /// Sessions `list_sessions` pages through
+    pub fn count_sessions(&self, user_id: &str, include_archived: bool) -> Result<usize, rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let count: i64 = connection.query_row(
+            "SELECT COUNT(*) FROM sessions WHERE user_id = ? AND (archived = 0 OR ?)",
+            params![user_id, include_archived],
+            |row| row.get(0)
+        )?;
+        Ok(count as usize)
+    }
+
+    /// Updates the title and/or archived flag, returns false if the session doesn't exist
+    pub fn update_session(
+        &self,
+        session_id: &str,
+        title: Option<&str>,
+        archived: Option<bool>
+    ) -> Result<bool, rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let updated = connection.execute(
+            "UPDATE sessions SET
+                title = COALESCE(?, title),
+                archived = COALESCE(?, archived),
+                updated_at = ?
+            WHERE id = ?",
+            params![title, archived.map(|a| a as i32), Utc::now().to_rfc3339(), session_id]
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Bumps updated_at so the most recently used sessions list first
+    pub fn touch_session(&self, session_id: &str) -> Result<(), rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        connection.execute(
+            "UPDATE sessions SET updated_at = ? WHERE id = ?",
+            params![Utc::now().to_rfc3339(), session_id]
+        )?;
+        Ok(())
+    }
+
+    /// Deletes the session with its chats, chat embeddings and indexed context in one transaction
+    pub fn delete_session(&self, session_id: &str) -> Result<bool, Box<dyn Error>> {
+        let mut connection = self.connection.get()?;
+        let tx = connection.transaction()?;
+
+        // vec0 tables are keyed by rowid, collect them before the parent rows are gone
+        let chat_row_ids: Vec<i64> = {
+            let mut stmt = tx.prepare("SELECT vec_row_id FROM chats WHERE session_id = ?")?;
+            let ids = stmt
+                .query_map(params![session_id], |row| row.get::<_, String>(0))?
+                .filter_map(Result::ok)
+                .filter_map(|id| id.parse::<i64>().ok())
+                .collect();
+            ids
+        };
+        let context_row_ids: Vec<i64> = {
+            let mut stmt = tx.prepare(
+                "SELECT vec_row_id FROM context_children WHERE session_id = ?"
+            )?;
+            let ids = stmt
+                .query_map(params![session_id], |row| row.get::<_, i64>(0))?
+                .filter_map(Result::ok)
+                .collect();
+            ids
+        };
+
+        for row_id in &chat_row_ids {
+            tx.execute("DELETE FROM chat_embeddings WHERE rowid = ?", params![row_id])?;
+        }
+        for row_id in &context_row_ids {
+            tx.execute("DELETE FROM context_embeddings WHERE rowid = ?", params![row_id])?;
+        }
+        tx.execute("DELETE FROM chats WHERE session_id = ?", params![session_id])?;
+        tx.execute("DELETE FROM context_children WHERE session_id = ?", params![session_id])?;
+        tx.execute("DELETE FROM symbols WHERE session_id = ?", params![session_id])?;
+        tx.execute("DELETE FROM symbol_references WHERE session_id = ?", params![session_id])?;
+        tx.execute("DELETE FROM context_files WHERE session_id = ?", params![session_id])?;
+        tx.execute("DELETE FROM context_parent WHERE session_id = ?", params![session_id])?;
+        let deleted = tx.execute("DELETE FROM sessions WHERE id = ?", params![session_id])?;
+
+        tx.commit()?;
+        info!(
+            "Deleted session {} with {} chats and {} context chunks",
+            session_id,
+            chat_row_ids.len(),
+            context_row_ids.len()
+        );
+        Ok(deleted > 0)
+    }
+
+    /// Only sets the title when the session doesn't have one yet, so a rename by the user wins
+    pub fn set_session_title_if_empty(&self, session_id: &str, title: &str) -> Result<bool, rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let updated = connection.execute(
+            "UPDATE sessions SET title = ? WHERE id = ? AND (title IS NULL OR title = '')",
+            params![title, session_id]
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// (summary, number of chats the summary covers)
+    pub fn get_session_summary(&self, session_id: &str) -> Result<(Option<String>, usize), rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let summary = connection
+            .query_row(
+                "SELECT summary, summary_chat_count FROM sessions WHERE id = ?",
+                params![session_id],
+                |row| Ok((row.get::<_, Option<String>>(0)?, row.get::<_, i64>(1)? as usize))
+            )
+            .optional()?;
+        Ok(summary.unwrap_or((None, 0)))
+    }
+
+    pub fn update_session_summary(
+        &self,
+        session_id: &str,
+        summary: &str,
+        summary_chat_count: usize
+    ) -> Result<(), rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        connection.execute(
+            "UPDATE sessions SET summary = ?, summary_chat_count = ? WHERE id = ?",
+            params![summary, summary_chat_count as i64, session_id]
+        )?;
+        Ok(())
+    }
+
+    pub fn count_session_chats(&self, session_id: &str) -> Result<usize, rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let count: i64 = connection.query_row(
+            "SELECT COUNT(*) FROM chats WHERE session_id = ?",
+            params![session_id],
+            |row| row.get(0)
+        )?;
+        Ok(count as usize)
+    }
+
+    /// (prompt, response) of the session's chats in the order they happened, skipping the first `skip`
+    pub fn fetch_session_exchanges(
+        &self,
+        session_id: &str,
+        skip: usize
+    ) -> Result<Vec<(String, String)>, rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let mut stmt = connection.prepare(
+            "SELECT prompt, response FROM chats
+            WHERE session_id = ?
+            ORDER BY timestamp ASC
+            LIMIT -1 OFFSET ?"
+        )?;
+
+        let exchanges = stmt
+            .query_map(params![session_id, skip as i64], |row| {
+                Ok((
+                    row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+                    row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                ))
+            })?
+            .collect::<Result<Vec<(String, String)>, rusqlite::Error>>()?;
+        Ok(exchanges)
+    }
+}
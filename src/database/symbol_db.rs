@@ -0,0 +1,263 @@
+use crate::database::db_config::{ pool_exhausted, DBConfig };
+use crate::parser::symbols::{ Symbol, SymbolReference };
+use uuid::Uuid;
+use serde_json::{ json, Value };
+use rusqlite::{ params, OptionalExtension, Row, ToSql };
+use log::info;
+
+const SYMBOL_COLUMNS: &str =
+    "s.id, s.session_id, s.file_path, s.name, s.kind, s.signature, s.scope, s.start_line, s.end_line";
+
+fn symbol_from_row(row: &Row) -> Result<Value, rusqlite::Error> {
+    Ok(
+        json!({
+        "id": row.get::<_, String>(0)?,
+        "session_id": row.get::<_, String>(1)?,
+        "file_path": row.get::<_, String>(2)?,
+        "name": row.get::<_, String>(3)?,
+        "kind": row.get::<_, String>(4)?,
+        "signature": row.get::<_, String>(5)?,
+        "scope": row.get::<_, Option<String>>(6)?,
+        "start_line": row.get::<_, i64>(7)?,
+        "end_line": row.get::<_, i64>(8)?,
+    })
+    )
+}
+
+/// A definition with the indexed chunk that holds it, for prompts
+#[derive(Debug, Clone)]
+pub struct SymbolDefinition {
+    pub session_id: String,
+    pub file_path: String,
+    pub name: String,
+    pub kind: String,
+    pub signature: String,
+    pub start_line: i64,
+    pub end_line: i64,
+    // None when the definition isn't a chunk of its own, e.g. a Rust const
+    pub content: Option<String>,
+}
+
+impl DBConfig {
+    /// Replaces what is stored for a file with its latest symbols and references.
+    /// `SymbolReference::enclosing` indexes into `symbols`.
+    pub fn replace_file_symbols(
+        &self,
+        user_id: &str,
+        session_id: &str,
+        parent_path: &str,
+        file_path: &str,
+        symbols: &[Symbol],
+        references: &[SymbolReference]
+    ) -> Result<(), rusqlite::Error> {
+        let mut connection = self.connection.get().map_err(pool_exhausted)?;
+        let tx = connection.transaction()?;
+
+        tx.execute(
+            "DELETE FROM symbols WHERE session_id = ? AND file_path = ?",
+            params![session_id, file_path]
+        )?;
+        tx.execute(
+            "DELETE FROM symbol_references WHERE session_id = ? AND file_path = ?",
+            params![session_id, file_path]
+        )?;
+
+        let ids: Vec<String> = symbols
+            .iter()
+            .map(|_| Uuid::new_v4().to_string())
+            .collect();
+        {
+            let mut insert_symbol = tx.prepare(
+                "INSERT INTO symbols (
+                    id, user_id, session_id, parent_path, file_path, name, kind, signature, scope,
+                    start_line, end_line
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            )?;
+            for (id, symbol) in ids.iter().zip(symbols) {
+                insert_symbol.execute(
+                    params![
+                        id,
+                        user_id,
+                        session_id,
+                        parent_path,
+                        file_path,
+                        symbol.name,
+                        symbol.kind,
+                        symbol.signature,
+                        symbol.scope,
+                        symbol.start_line as i64,
+                        symbol.end_line as i64
+                    ]
+                )?;
+            }
+
+            let mut insert_reference = tx.prepare(
+                "INSERT INTO symbol_references (
+                    user_id, session_id, parent_path, file_path, name, kind, caller_id, line
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+            )?;
+            for reference in references {
+                let caller_id = reference.enclosing.and_then(|index| ids.get(index));
+                insert_reference.execute(
+                    params![
+                        user_id,
+                        session_id,
+                        parent_path,
+                        file_path,
+                        reference.name,
+                        reference.kind,
+                        caller_id,
+                        reference.line as i64
+                    ]
+                )?;
+            }
+        }
+
+        tx.commit()?;
+        info!(
+            "Stored {} symbols and {} references for {}",
+            symbols.len(),
+            references.len(),
+            file_path
+        );
+        Ok(())
+    }
+
+    /// Definitions named `name` in a session, optionally of one kind
+    pub fn find_symbols(
+        &self,
+        session_id: &str,
+        name: &str,
+        kind: Option<&str>,
+        limit: usize
+    ) -> Result<Vec<Value>, rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let mut stmt = connection.prepare(
+            &format!(
+                "SELECT {} FROM symbols s
+                WHERE s.session_id = ? AND s.name = ? AND (?3 IS NULL OR s.kind = ?3)
+                ORDER BY s.file_path, s.start_line
+                LIMIT ?",
+                SYMBOL_COLUMNS
+            )
+        )?;
+        let symbols = stmt
+            .query_map(params![session_id, name, kind, limit as i64], symbol_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(symbols)
+    }
+
+    pub fn get_symbol(&self, symbol_id: &str) -> Result<Option<Value>, rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        connection
+            .query_row(
+                &format!("SELECT {} FROM symbols s WHERE s.id = ?", SYMBOL_COLUMNS),
+                params![symbol_id],
+                symbol_from_row
+            )
+            .optional()
+    }
+
+    /// Uses of a symbol's name in its session, with the definition each one sits in
+    pub fn symbol_references(&self, symbol_id: &str, limit: usize) -> Result<Vec<Value>, rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let mut stmt = connection.prepare(
+            "SELECT r.file_path, r.line, r.kind, c.id, c.name
+            FROM symbols s
+            JOIN symbol_references r ON r.session_id = s.session_id AND r.name = s.name
+            LEFT JOIN symbols c ON c.id = r.caller_id
+            WHERE s.id = ?
+            ORDER BY r.file_path, r.line
+            LIMIT ?"
+        )?;
+        let references = stmt
+            .query_map(params![symbol_id, limit as i64], |row| {
+                Ok(
+                    json!({
+                    "file_path": row.get::<_, String>(0)?,
+                    "line": row.get::<_, i64>(1)?,
+                    "kind": row.get::<_, String>(2)?,
+                    "caller_id": row.get::<_, Option<String>>(3)?,
+                    "caller_name": row.get::<_, Option<String>>(4)?,
+                })
+                )
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(references)
+    }
+
+    /// Definitions that call a symbol, by name, in its session
+    pub fn symbol_callers(&self, symbol_id: &str, limit: usize) -> Result<Vec<Value>, rusqlite::Error> {
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let mut stmt = connection.prepare(
+            &format!(
+                "SELECT DISTINCT {} FROM symbols callee
+                JOIN symbol_references r
+                    ON r.session_id = callee.session_id AND r.name = callee.name AND r.kind = 'call'
+                JOIN symbols s ON s.id = r.caller_id
+                WHERE callee.id = ?
+                ORDER BY s.file_path, s.start_line
+                LIMIT ?",
+                SYMBOL_COLUMNS
+            )
+        )?;
+        let callers = stmt
+            .query_map(params![symbol_id, limit as i64], symbol_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(callers)
+    }
+
+    /// Definitions of any of `names` in the sessions, with the code of the chunk starting
+    /// where the definition starts
+    pub fn symbol_definitions(
+        &self,
+        session_ids: &[String],
+        names: &[String],
+        limit: usize
+    ) -> Result<Vec<SymbolDefinition>, rusqlite::Error> {
+        if session_ids.is_empty() || names.is_empty() {
+            return Ok(Vec::new());
+        }
+        let connection = self.connection.get().map_err(pool_exhausted)?;
+        let session_placeholders = vec!["?"; session_ids.len()].join(", ");
+        let name_placeholders = vec!["?"; names.len()].join(", ");
+        let mut stmt = connection.prepare(
+            &format!(
+                "SELECT s.session_id, s.file_path, s.name, s.kind, s.signature, s.start_line, s.end_line,
+                    (SELECT c.content FROM context_children c
+                     WHERE c.session_id = s.session_id AND c.file_path = s.file_path
+                        AND c.start_line = s.start_line
+                     ORDER BY length(c.content) LIMIT 1)
+                FROM symbols s
+                WHERE s.session_id IN ({}) AND s.name IN ({})
+                ORDER BY s.name, s.file_path, s.start_line
+                LIMIT ?",
+                session_placeholders,
+                name_placeholders
+            )
+        )?;
+
+        let limit = limit as i64;
+        let mut values: Vec<&dyn ToSql> = session_ids
+            .iter()
+            .map(|id| id as &dyn ToSql)
+            .collect();
+        values.extend(names.iter().map(|name| name as &dyn ToSql));
+        values.push(&limit);
+        let definitions = stmt
+            .query_map(values.as_slice(), |row| {
+                Ok(SymbolDefinition {
+                    session_id: row.get(0)?,
+                    file_path: row.get(1)?,
+                    name: row.get(2)?,
+                    kind: row.get(3)?,
+                    signature: row.get(4)?,
+                    start_line: row.get(5)?,
+                    end_line: row.get(6)?,
+                    content: row.get(7)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(definitions)
+    }
+}
@@ -0,0 +1,150 @@
+use crate::database::db_config::{ pool_exhausted, DBConfig };
+use chrono::Utc;
+use serde_json::{ json, Value };
+use rusqlite::params;
+
+/// Token counts of one LLM request
+#[derive(Debug, Clone)]
+pub struct UsageRecord {
+    pub user_id: String,
+    pub route: String,
+    pub backend: String,
+    pub model: String,
+    pub request_id: Option<String>,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub estimated: bool,
+}
+
+// Optional filters shared by all the breakdowns, NULL means "don't filter"
+const USAGE_FILTERS: &str =
+    "(?1 IS NULL OR user_id = ?1)
+    AND (?2 IS NULL OR created_at >= ?2)
+    AND (?3 IS NULL OR created_at <= ?3)";
+
+impl DBConfig {
+    pub fn record_usage(&self, usage: &UsageRecord) -> Result<(), rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        connection.execute(
+            "INSERT INTO usage (user_id, route, backend, model, request_id, prompt_tokens, completion_tokens, estimated, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                usage.user_id,
+                usage.route,
+                usage.backend,
+                usage.model,
+                usage.request_id,
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.estimated as i32,
+                Utc::now().to_rfc3339()
+            ]
+        )?;
+        Ok(())
+    }
+
+    /// Prompt plus completion tokens the user spent since `since` (RFC3339)
+    pub fn tokens_used_since(&self, user_id: &str, since: &str) -> Result<i64, rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        connection.query_row(
+            "SELECT COALESCE(SUM(prompt_tokens + completion_tokens), 0) FROM usage
+            WHERE user_id = ? AND created_at >= ?",
+            params![user_id, since],
+            |row| row.get(0)
+        )
+    }
+
+    /// (daily, monthly) quota overrides of a user, None means the server default applies
+    pub fn get_user_quotas(&self, user_id: &str) -> Result<(Option<i64>, Option<i64>), rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        let quotas = connection.query_row(
+            "SELECT daily_token_quota, monthly_token_quota FROM users WHERE id = ?",
+            params![user_id],
+            |row| Ok((row.get(0)?, row.get(1)?))
+        );
+        match quotas {
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok((None, None)),
+            other => other,
+        }
+    }
+
+    /// Overrides the quotas of a user, None resets to the server default. False if the user doesn't exist.
+    pub fn set_user_quotas(
+        &self,
+        user_id: &str,
+        daily: Option<i64>,
+        monthly: Option<i64>
+    ) -> Result<bool, rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        let updated = connection.execute(
+            "UPDATE users SET daily_token_quota = ?, monthly_token_quota = ? WHERE id = ?",
+            params![daily, monthly, user_id]
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Totals grouped by `group_by`, which is one of the usage columns, biggest consumer first
+    fn usage_grouped_by(
+        &self,
+        group_by: &str,
+        user_id: Option<&str>,
+        from: Option<&str>,
+        to: Option<&str>
+    ) -> Result<Vec<Value>, rusqlite::Error> {
+        let connection = self.common_connection.get().map_err(pool_exhausted)?;
+        let mut stmt = connection.prepare(
+            &format!(
+                "SELECT {group}, COUNT(*), SUM(prompt_tokens), SUM(completion_tokens), SUM(estimated)
+                FROM usage
+                WHERE {filters}
+                GROUP BY {group}
+                ORDER BY SUM(prompt_tokens + completion_tokens) DESC",
+                group = group_by,
+                filters = USAGE_FILTERS
+            )
+        )?;
+
+        let rows = stmt
+            .query_map(params![user_id, from, to], |row| {
+                let prompt_tokens: i64 = row.get(2)?;
+                let completion_tokens: i64 = row.get(3)?;
+                Ok(
+                    json!({
+                    group_by: row.get::<_, String>(0)?,
+                    "requests": row.get::<_, i64>(1)?,
+                    "prompt_tokens": prompt_tokens,
+                    "completion_tokens": completion_tokens,
+                    "total_tokens": prompt_tokens + completion_tokens,
+                    "estimated_requests": row.get::<_, i64>(4)?,
+                })
+                )
+            })?
+            .collect::<Result<Vec<Value>, rusqlite::Error>>()?;
+        Ok(rows)
+    }
+
+    /// Usage totals plus per-route, per-model and per-user breakdowns. `user_id` None covers everybody.
+    pub fn usage_summary(
+        &self,
+        user_id: Option<&str>,
+        from: Option<&str>,
+        to: Option<&str>
+    ) -> Result<Value, rusqlite::Error> {
+        let by_route = self.usage_grouped_by("route", user_id, from, to)?;
+        let by_model = self.usage_grouped_by("model", user_id, from, to)?;
+        let by_user = self.usage_grouped_by("user_id", user_id, from, to)?;
+
+        let sum = |key: &str| by_route.iter().filter_map(|r| r[key].as_i64()).sum::<i64>();
+        Ok(
+            json!({
+            "requests": sum("requests"),
+            "prompt_tokens": sum("prompt_tokens"),
+            "completion_tokens": sum("completion_tokens"),
+            "total_tokens": sum("total_tokens"),
+            "by_route": by_route,
+            "by_model": by_model,
+            "by_user": by_user,
+        })
+        )
+    }
+}
@@ -1,5 +1,4 @@
 use std::error::Error;
-use dirs;
 use std::path::{Path, PathBuf};
 use rust_bert::pipelines::sentence_embeddings::{SentenceEmbeddingsBuilder, SentenceEmbeddingsModel};
 use tch::Device;
@@ -9,7 +8,7 @@ use once_cell::sync::Lazy;
 use reqwest::blocking::Client;
 use std::io::Cursor;
 use log::{info, error};
-use dirs::home_dir;
+use crate::server_config::shared_data_dir;
 const BASE_URL: &str = "https://huggingface.co/sentence-transformers/all-MiniLM-L6-v2/resolve/main/";
 
 const FILES: &[&str] = &[
@@ -30,10 +29,7 @@ pub struct EmbeddingsManager {
 }
 
 impl EmbeddingsManager {
-    pub fn new(save_path: &str) -> Self {
-        let home_dir = dirs::home_dir().expect("Unable to get home directory");
-        let save_path = home_dir.join(save_path);
-
+    pub fn new(save_path: PathBuf) -> Self {
         Self {
             save_path,
         }
@@ -107,19 +103,13 @@ impl EmbeddingsManager {
 
 // The Lazy initialization will ensure that the model is loaded only once during the application's lifecycle.
 static EMBEDDINGS_MODEL: Lazy<Result<Arc<Mutex<SentenceEmbeddingsModel>>, Box<dyn Error + Send + Sync>>> = Lazy::new(|| {
-    let home_dir = home_dir().ok_or_else(|| anyhow::anyhow!("Failed to retrieve home directory"))?;
-    let models_dir = home_dir.join(".pyano/models/embed_model");
+    let models_dir = shared_data_dir("models").join("embed_model");
 
     // Ensure the model directory exists
     fs::create_dir_all(&models_dir)?;
 
     // Ensure the model is downloaded
-    let models_dir_str = models_dir
-        .to_str()
-        .ok_or_else(|| anyhow::anyhow!("Failed to convert PathBuf to str"))?
-        .to_string();
-
-    let manager = EmbeddingsManager::new(&models_dir_str);
+    let manager = EmbeddingsManager::new(models_dir);
     let model = manager.initialize_model()?;
     Ok(Arc::new(Mutex::new(model)))
 });
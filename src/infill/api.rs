@@ -5,14 +5,40 @@ use std::sync::Arc ;
 use reqwest::Client;
 use super::stream_utils::stream_infill_request;
 use serde_json::json;
+use crate::llm_stream::generation::{ GenerationParams, resolve_generation_params };
+use crate::llm_stream::usage::UsageContext;
+use crate::authentication::authorization::is_request_allowed;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InfillRequest {
     pub code_before: String,
     pub code_after: String,
     pub infill_id: String,
+    pub generation: Option<GenerationParams>,
 }
 
+const INFILL_STOP_TOKENS: [&str; 19] = [
+    "<|endoftext|>",
+    "<|fim_prefix|>",
+    "<|fim_middle|>",
+    "<|fim_suffix|>",
+    "<|fim_pad|>",
+    "<|repo_name|>",
+    "<|file_sep|>",
+    "<|im_start|>",
+    "<|im_end|>",
+    "\n\n",
+    "\r\n\r\n",
+    "/src/",
+    "#- coding: utf-8",
+    "```",
+    "\nfunction",
+    "\nclass",
+    "\nmodule",
+    "\nexport",
+    "\nimport",
+];
+
 pub fn register_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(infill); // Register the correct route handler
 }
@@ -22,9 +48,11 @@ pub async fn infill(
     data: web::Json<InfillRequest>,
     infill_model_state: web::Data<Arc<InfillModelState>>,
     client: web::Data<Client>,
-    _req: HttpRequest
+    req: HttpRequest
 ) -> Result<HttpResponse, Error> {
     let infill_id = &data.infill_id;
+    let usage = UsageContext::from_request(&req, Some(infill_id.clone()));
+    is_request_allowed(&usage.user_id).await?;
 
     let model_process_guard = infill_model_state.infill_model_process.lock().await;
 
@@ -42,42 +70,32 @@ pub async fn infill(
         code_after_cursor = &data.code_after
     );
     // adjust below keys according to how model is loaded and the type of model is being used
-    // settings for model: Qwen2.5 Coder 7b instruct
-    let infill_req_body =
+    // settings for model: Qwen2.5 Coder 7b instruct, used unless the request or the INFILL route defaults say otherwise
+    let infill_defaults = GenerationParams {
+        max_tokens: Some(2048),
+        temperature: Some(0.8),
+        stop: Some(
+            INFILL_STOP_TOKENS.iter()
+                .map(|stop| stop.to_string())
+                .collect()
+        ),
+        ..Default::default()
+    };
+    let generation = resolve_generation_params("INFILL", data.generation.as_ref(), infill_defaults).await;
+
+    let mut infill_req_body =
         json!({
-        "max_tokens": 2048,
-        "temperature": 0.8,
         // "t_max_predict_ms": 2500,
         "stream": true,
-        "stop": [
-            "<|endoftext|>",
-            "<|fim_prefix|>",
-            "<|fim_middle|>",
-            "<|fim_suffix|>",
-            "<|fim_pad|>",
-            "<|repo_name|>",
-            "<|file_sep|>",
-            "<|im_start|>",
-            "<|im_end|>",
-            "\n\n",
-            "\r\n\r\n",
-            "/src/",
-            "#- coding: utf-8",
-            "```",
-            "\nfunction",
-            "\nclass",
-            "\nmodule",
-            "\nexport",
-            "\nimport"
-        ],
         "prompt": infill_prompt
     });
+    generation.apply_to_llamacpp_body(&mut infill_req_body, "max_tokens");
 
     let (tx, _rx) = tokio::sync::oneshot::channel::<()>();
 
     // Adding context for the infill will increase generation time
 
-    let response = stream_infill_request(&client, &infill_id, infill_req_body, tx).await?;
+    let response = stream_infill_request(&client, &infill_id, infill_req_body, usage, tx).await?;
 
     Ok(response)
 }
@@ -1,5 +1,5 @@
 use log::{info, error};
-use dirs::home_dir;
+use crate::server_config::shared_data_dir;
 use tokio::io::{ AsyncBufReadExt, BufReader };
 use tokio::process::Command as tokio_command;
 use tokio::task::JoinHandle;
@@ -9,17 +9,14 @@ use std::fs::create_dir_all;
 pub async fn run_infill_server<F>(callback: F, reset_state_callback: impl Fn() -> JoinHandle<()>)
     where F: FnOnce(Option<u32>) + Send + 'static
 {
-    // Retrieves the user's home directory or exits if it fails
-    let home_dir = home_dir().expect("Failed to retrieve home directory");
-
-    // Constructs the path to the `.pyano/configs` directory inside the home directory
-    let config_dir = home_dir.join(".pyano/configs"); // Spawn a new thread for downloading the model and initialization
+    // Constructs the path to the `configs` directory inside the data directory
+    let config_dir = shared_data_dir("configs");
 
     // Ensures that the model directory exists by creating all directories in the path if they don't exist
     create_dir_all(&config_dir).expect("Failed to create config directory");
 
     // Retrieves the current working directory of the process
-    let scripts_dir = home_dir.join(".pyano/scripts");
+    let scripts_dir = shared_data_dir("scripts");
     
     let script_path = scripts_dir.join("run-infill-model.sh");
 
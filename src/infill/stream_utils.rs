@@ -16,15 +16,20 @@ use async_stream::stream;
 use serde_json::Value;
 use actix_web::Error as ActixError;
 use crate::llm_stream::types::AccumulatedStream;
-use crate::llm_stream::local::format_local_llm_response;
+use crate::llm_stream::local::{ format_local_llm_events, tokens_only };
+use crate::llm_stream::usage::{ meter_events, UsageContext };
+
+// The infill server runs whatever model its script loads, usage rows name it by route
+const INFILL_MODEL: &str = "infill";
 
 pub async fn stream_infill_request(
     client: &Client, // Pass the client here
     infill_id: &str,
     infill_req_body: Value,
+    usage: UsageContext,
     tx: tokio::sync::oneshot::Sender<()>
 ) -> Result<HttpResponse, Error> {
-    let stream_result = handle_infill_request(client, infill_req_body).await;
+    let stream_result = handle_infill_request(client, infill_req_body, usage).await;
     let mut stream = match stream_result {
         Ok(s) => s,
         Err(e) => {
@@ -76,9 +81,10 @@ pub async fn stream_infill_request(
 
 pub async fn handle_infill_request(
     client: &Client,
-    infill_req_body: Value
+    infill_req_body: Value,
+    usage: UsageContext
 ) -> Result<AccumulatedStream, ActixError> {
-    let stream: AccumulatedStream = infill_agent_execution(client, infill_req_body).await.map_err(
+    let stream: AccumulatedStream = infill_agent_execution(client, infill_req_body, usage).await.map_err(
         |e| ActixError::from(actix_web::error::ErrorInternalServerError(e.to_string()))
     )?;
     // Shared state using Arc<Mutex<_>>
@@ -99,15 +105,22 @@ pub async fn handle_infill_request(
 
 pub async fn infill_agent_execution(
     client: &Client,
-    infill_req_body: Value
+    infill_req_body: Value,
+    usage: UsageContext
 ) -> Result<
     Pin<Box<dyn Stream<Item = Result<Bytes, ReqwestError>> + Send>>,
     Box<dyn StdError + Send + Sync + 'static>
 > {
+    let prompt_chars = infill_req_body
+        .get("prompt")
+        .and_then(|prompt| prompt.as_str())
+        .map(|prompt| prompt.chars().count())
+        .unwrap_or(0);
     match send_infill_request(client, infill_req_body).await {
         Ok(stream) => {
-            let formatted_stream = format_local_llm_response(stream).await;
-            Ok(Box::pin(formatted_stream)) // Pin the stream here using Box::pin
+            let events = Box::pin(format_local_llm_events(stream).await);
+            let metered = meter_events(events, "INFILL", "llamacpp", INFILL_MODEL.to_string(), prompt_chars, usage);
+            Ok(Box::pin(tokens_only(metered))) // Pin the stream here using Box::pin
         }
         Err(e) => {
             error!("Infill execution error: {}", e);
@@ -0,0 +1,77 @@
+use async_trait::async_trait;
+use actix_web::{ HttpRequest, Error as ActixError };
+use std::error::Error as StdError;
+use reqwest::Client;
+use log::debug;
+use crate::utils::{
+    get_llm_backend,
+    get_local_url,
+    get_remote_url,
+    get_cloud_api_key,
+    get_remote_model,
+    get_ollama_url,
+    get_ollama_model,
+};
+use super::types::EventStream;
+use super::generation::GenerationParams;
+use super::local::LlamaCppBackend;
+use super::remote::OpenAiBackend;
+use super::ollama::OllamaBackend;
+
+/// Header a client can send to pick the backend for a single request
+pub const LLM_BACKEND_HEADER: &str = "X-LLM-Backend";
+
+/// A server that can turn a system prompt and a user prompt into a stream of tokens
+#[async_trait]
+pub trait LlmBackend: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Model the backend will answer with, used for usage accounting
+    async fn model(&self) -> String;
+
+    async fn stream_events(
+        &self,
+        client: &Client,
+        system_prompt: &str,
+        prompt_with_context: &str,
+        generation: &GenerationParams
+    ) -> Result<EventStream, Box<dyn StdError + Send + Sync + 'static>>;
+}
+
+/// Builds a backend from its configured name, e.g. `llamacpp`, `openai` or `ollama`.
+pub fn backend_from_name(backend_name: &str) -> Result<Box<dyn LlmBackend>, ActixError> {
+    match backend_name {
+        "llamacpp" => Ok(Box::new(LlamaCppBackend::new(get_local_url()))),
+        "openai" =>
+            Ok(Box::new(OpenAiBackend::new(get_remote_url(), get_cloud_api_key(), get_remote_model()))),
+        "ollama" => Ok(Box::new(OllamaBackend::new(get_ollama_url(), get_ollama_model()))),
+        _ => Err(actix_web::error::ErrorBadRequest(format!("Unknown LLM backend: {}", backend_name))),
+    }
+}
+
+/// Name of the backend a route will use. A backend requested by the client wins over the
+/// per-route configuration, which wins over the global default.
+pub fn resolve_backend_name(route: &str, requested_backend: Option<&str>) -> String {
+    match requested_backend {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => get_llm_backend(route),
+    }
+}
+
+/// Picks the backend for a route, see `resolve_backend_name`
+pub fn resolve_backend(
+    route: &str,
+    requested_backend: Option<&str>
+) -> Result<Box<dyn LlmBackend>, ActixError> {
+    let backend = backend_from_name(&resolve_backend_name(route, requested_backend))?;
+    debug!("Using LLM backend {} for route {}", backend.name(), route);
+    Ok(backend)
+}
+
+/// Reads the per-request backend override from the request headers
+pub fn requested_backend(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(LLM_BACKEND_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex };
+use lazy_static::lazy_static;
+use actix_web::HttpRequest;
+use tokio::sync::{ oneshot, Notify };
+use uuid::Uuid;
+use log::debug;
+
+/// Header carrying the id a client can later pass to `POST /chat/cancel/{request_id}`
+pub const REQUEST_ID_HEADER: &str = "X-Request-ID";
+
+lazy_static! {
+    // request_id -> notifier for every generation that is currently streaming
+    static ref ACTIVE_REQUESTS: Mutex<HashMap<String, ActiveEntry>> = Mutex::new(HashMap::new());
+}
+
+struct ActiveEntry {
+    // Only the user who started a generation can cancel it
+    user_id: String,
+    notify: Arc<Notify>,
+}
+
+/// How a streamed answer ended, sent to the task that persists the chat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamOutcome {
+    Completed,
+    Cancelled,
+}
+
+/// A generation that can be cancelled by id. Unregisters itself when dropped.
+pub struct ActiveRequest {
+    pub request_id: String,
+    notify: Arc<Notify>,
+}
+
+impl ActiveRequest {
+    /// Resolves once someone calls `cancel_request` with this id
+    pub async fn cancelled(&self) {
+        self.notify.notified().await
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        let mut active = ACTIVE_REQUESTS.lock().unwrap();
+        // Only its own entry, never one registered under the same id after it
+        let own = active.get(&self.request_id).is_some_and(|entry| Arc::ptr_eq(&entry.notify, &self.notify));
+        if own {
+            active.remove(&self.request_id);
+        }
+    }
+}
+
+/// Uses the client's `X-Request-ID` when it sent one, otherwise makes a new id
+pub fn request_id_for(req: &HttpRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Registers a generation of `user_id` under `request_id`, or under a new id when a running
+/// generation already uses that one. The id it ended up with is `ActiveRequest::request_id`.
+pub fn register_request(request_id: &str, user_id: &str) -> ActiveRequest {
+    let mut active = ACTIVE_REQUESTS.lock().unwrap();
+    let request_id = if active.contains_key(request_id) {
+        let fresh = Uuid::new_v4().to_string();
+        debug!("Request id {} is already running, using {}", request_id, fresh);
+        fresh
+    } else {
+        request_id.to_string()
+    };
+    let notify = Arc::new(Notify::new());
+    active.insert(request_id.clone(), ActiveEntry { user_id: user_id.to_string(), notify: Arc::clone(&notify) });
+    ActiveRequest { request_id, notify }
+}
+
+/// Returns false when no generation of `user_id` with this id is running
+pub fn cancel_request(request_id: &str, user_id: &str) -> bool {
+    match ACTIVE_REQUESTS.lock().unwrap().get(request_id) {
+        Some(entry) if entry.user_id == user_id => {
+            debug!("Cancelling request {}", request_id);
+            // notify_one keeps a permit, so a cancel between two chunks isn't lost
+            entry.notify.notify_one();
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Wraps the oneshot that tells `handle_stream_completion` the stream is over.
+/// If the response stream is dropped early (client went away) it reports `Cancelled`
+/// so the partial answer still gets saved.
+pub struct CompletionSignal {
+    tx: Option<oneshot::Sender<StreamOutcome>>,
+}
+
+impl CompletionSignal {
+    pub fn new(tx: oneshot::Sender<StreamOutcome>) -> Self {
+        CompletionSignal { tx: Some(tx) }
+    }
+
+    pub fn send(&mut self, outcome: StreamOutcome) {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(outcome);
+        }
+    }
+}
+
+impl Drop for CompletionSignal {
+    fn drop(&mut self) {
+        self.send(StreamOutcome::Cancelled);
+    }
+}
@@ -0,0 +1,48 @@
+use bytes::Bytes;
+use async_stream::stream;
+use futures::{ Stream, StreamExt };
+use reqwest::Error as ReqwestError;
+use super::types::StreamEvent;
+
+/// Splits a raw response into lines and hands every complete line to `parse_line`.
+/// Local, remote and ollama responses all go through here, so a line (or a multi-byte
+/// character) cut in half by the network is stitched back together before parsing.
+pub fn format_llm_events<'a, S, F>(
+    stream: S,
+    mut parse_line: F
+) -> impl Stream<Item = Result<StreamEvent, ReqwestError>> + 'a
+    where
+        S: Stream<Item = Result<Bytes, ReqwestError>> + 'a,
+        F: FnMut(&str) -> Vec<StreamEvent> + 'a
+{
+    stream! {
+        let mut stream = Box::pin(stream);
+        let mut buffer: Vec<u8> = Vec::new();
+
+        while let Some(chunk_result) = stream.next().await {
+            match chunk_result {
+                Ok(chunk) => {
+                    buffer.extend_from_slice(&chunk);
+                    while let Some(newline) = buffer.iter().position(|b| *b == b'\n') {
+                        let line: Vec<u8> = buffer.drain(..=newline).collect();
+                        for event in parse_line(String::from_utf8_lossy(&line).trim()) {
+                            yield Ok(event);
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error receiving chunk: {}", e);
+                    yield Err(e);
+                    break;
+                }
+            }
+        }
+
+        // Whatever is left over when the server closes without a trailing newline
+        if !buffer.is_empty() {
+            for event in parse_line(String::from_utf8_lossy(&buffer).trim()) {
+                yield Ok(event);
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use serde::{ Deserialize, Serialize };
+use log::error;
+use serde_json::{ json, Value };
+use crate::database::db_config::run_db;
+use crate::utils::{ get_llm_temperature, get_top_k, get_top_p };
+
+/// Sampling knobs a request can send in its `generation` object.
+/// Every field is optional, whatever is missing comes from the route defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GenerationParams {
+    pub temperature: Option<f64>,
+    pub top_k: Option<i64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<i64>,
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<i64>,
+    pub repeat_penalty: Option<f64>,
+    pub grammar: Option<String>,
+}
+
+impl GenerationParams {
+    /// Fills the fields that are not set on `self` from `defaults`
+    pub fn or(self, defaults: GenerationParams) -> GenerationParams {
+        GenerationParams {
+            temperature: self.temperature.or(defaults.temperature),
+            top_k: self.top_k.or(defaults.top_k),
+            top_p: self.top_p.or(defaults.top_p),
+            max_tokens: self.max_tokens.or(defaults.max_tokens),
+            stop: self.stop.or(defaults.stop),
+            seed: self.seed.or(defaults.seed),
+            repeat_penalty: self.repeat_penalty.or(defaults.repeat_penalty),
+            grammar: self.grammar.or(defaults.grammar),
+        }
+    }
+
+    /// Writes the params that are set into a llama.cpp `/completion` body,
+    /// whatever is left unset keeps the llama.cpp server default
+    pub fn apply_to_llamacpp_body(&self, body: &mut Value, max_tokens_key: &str) {
+        if let Some(temperature) = self.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if let Some(top_k) = self.top_k {
+            body["top_k"] = json!(top_k);
+        }
+        if let Some(top_p) = self.top_p {
+            body["top_p"] = json!(top_p);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            body[max_tokens_key] = json!(max_tokens);
+        }
+        if let Some(stop) = &self.stop {
+            body["stop"] = json!(stop);
+        }
+        if let Some(seed) = self.seed {
+            body["seed"] = json!(seed);
+        }
+        if let Some(repeat_penalty) = self.repeat_penalty {
+            body["repeat_penalty"] = json!(repeat_penalty);
+        }
+        if let Some(grammar) = &self.grammar {
+            body["grammar"] = json!(grammar);
+        }
+    }
+
+    /// What every route used before per-route defaults existed
+    pub fn from_env() -> GenerationParams {
+        GenerationParams {
+            temperature: Some(get_llm_temperature()),
+            top_k: Some(get_top_k()),
+            top_p: Some(get_top_p()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Params for a route: the request's `generation` object wins, then the defaults stored
+/// for the route in the config db, then `fallback`.
+pub async fn resolve_generation_params(
+    route: &str,
+    requested: Option<&GenerationParams>,
+    fallback: GenerationParams
+) -> GenerationParams {
+    let stored_route = route.to_string();
+    let route_defaults = match run_db(move |db| db.get_generation_defaults(&stored_route)).await {
+        Ok(Ok(defaults)) => defaults.unwrap_or_default(),
+        Ok(Err(e)) => {
+            error!("Failed to load generation defaults for {}: {}", route, e);
+            GenerationParams::default()
+        }
+        Err(e) => {
+            error!("Failed to load generation defaults for {}: {}", route, e);
+            GenerationParams::default()
+        }
+    };
+
+    requested.cloned().unwrap_or_default().or(route_defaults).or(fallback)
+}
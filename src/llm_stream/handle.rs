@@ -1,30 +1,111 @@
-use actix_web::{ web, HttpResponse, Error };
+use actix_web::{ web, HttpRequest, HttpResponse, Error };
 use async_stream::stream;
 
 use futures::StreamExt; // Ensure StreamExt is imported
 use actix_web::Error as ActixError;
-use crate::utils::is_cloud_execution_mode;
+use serde_json::{ json, Value };
 
 use std::sync::{ Arc, Mutex };
 
-use super::types::AccumulatedStream;
-use super::remote::remote_agent_execution;
-use super::local::local_agent_execution;
+use super::types::{ AccumulatedStream, EventStream, StreamEvent };
+use super::cancel::{ register_request, request_id_for, CompletionSignal, StreamOutcome, REQUEST_ID_HEADER };
+use super::backend::{ resolve_backend, requested_backend };
+use super::generation::{ GenerationParams, resolve_generation_params };
+use super::local::{ tokens_only, calculate_tokens_per_second };
+use super::usage::{ metered_stream_events, UsageContext };
 use reqwest::Client;
 use crate::chats::chat_types::RequestType;
+use crate::context::make_context::ContextSource;
+
+/// JSON list of the context items an answer was built from, for clients reading plain text
+pub const CONTEXT_SOURCES_HEADER: &str = "X-Context-Sources";
+/// How many items there were, `CONTEXT_SOURCES_HEADER` only carries the first ones that fit
+pub const CONTEXT_SOURCES_TOTAL_HEADER: &str = "X-Context-Sources-Total";
+// Proxies commonly reject responses with headers over 4-8 KB
+const MAX_CONTEXT_SOURCES_HEADER_BYTES: usize = 4096;
+
+/// Returns true when the client asked for typed Server-Sent Events instead of raw text
+pub fn wants_event_stream(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(actix_web::http::header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map(|accept| accept.contains("text/event-stream"))
+        .unwrap_or(false)
+}
+
+/// The first sources that fit in `MAX_CONTEXT_SOURCES_HEADER_BYTES` as header safe JSON,
+/// anything outside ASCII (file paths) is \u escaped. The full list is in the response body.
+pub fn context_sources_header(sources: &[ContextSource]) -> String {
+    let mut header = String::from("[");
+    for source in sources {
+        let item = ascii_json(&serde_json::to_string(source).unwrap_or_default());
+        // Room for the comma and the closing bracket
+        if header.len() + item.len() + 2 > MAX_CONTEXT_SOURCES_HEADER_BYTES {
+            break;
+        }
+        if header.len() > 1 {
+            header.push(',');
+        }
+        header.push_str(&item);
+    }
+    header.push(']');
+    header
+}
+
+fn ascii_json(json: &str) -> String {
+    json.chars()
+        .map(|c| {
+            if c.is_ascii() {
+                c.to_string()
+            } else {
+                c.encode_utf16(&mut [0; 2])
+                    .iter()
+                    .map(|unit| format!("\\u{:04x}", unit))
+                    .collect()
+            }
+        })
+        .collect()
+}
+
+/// Formats a single SSE frame
+pub fn sse_event(event: &str, data: &Value) -> web::Bytes {
+    web::Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
 
 pub async fn stream_to_chat_client(
     request_type: RequestType,
     client: &Client,  // Pass the client here
+    req: &HttpRequest,
     session_id: &str,
     system_prompt: &str,
     full_user_prompt: &str,
+    context_used: &str,
+    context_sources: &[ContextSource],
+    generation: Option<&GenerationParams>,
     accumulated_content_clone: Arc<Mutex<String>>,
-    tx: tokio::sync::oneshot::Sender<()>
+    tx: tokio::sync::oneshot::Sender<StreamOutcome>
 ) -> Result<HttpResponse, Error> {
-    let stream_result = handle_request(request_type, client, system_prompt, full_user_prompt).await;
+    let mut usage = UsageContext::from_request(req, None);
+    // Registered until the response stream is dropped, so /chat/cancel can find it
+    let active_request = register_request(&request_id_for(req), &usage.user_id);
+    let request_id = active_request.request_id.clone();
+    usage.request_id = Some(request_id.clone());
+    let backend_name = requested_backend(req);
+    let stream_result = handle_request_events(
+        request_type,
+        client,
+        backend_name.as_deref(),
+        generation,
+        system_prompt,
+        full_user_prompt,
+        usage
+    ).await;
     let mut stream = match stream_result {
         Ok(s) => s,
+        // Over quota, pass the 429 through
+        Err(e) if e.as_response_error().status_code() == actix_web::http::StatusCode::TOO_MANY_REQUESTS => {
+            return Err(e);
+        }
         Err(e) => {
             return Ok(
                 HttpResponse::InternalServerError().json(
@@ -36,77 +117,267 @@ pub async fn stream_to_chat_client(
         }
     };
 
-    // Stream chunks to the client in real-time and accumulate
-    let response_stream = stream! {
-        while let Some(chunk_result) = stream.next().await {
-            match chunk_result {
-                Ok(chunk) => {
-                    if let Ok(chunk_str) = std::str::from_utf8(&chunk) {
-                        // Accumulate the content in memory
-                        {
-                            let mut accumulated = accumulated_content_clone.lock().unwrap();
-                            accumulated.push_str(chunk_str);
+    // Reports Cancelled on its own if the client disconnects and the stream gets dropped
+    let mut completion = CompletionSignal::new(tx);
+
+    if !wants_event_stream(req) {
+        let mut stream = tokens_only(stream).boxed();
+
+        // Stream chunks to the client in real-time and accumulate
+        let response_stream = stream! {
+            let mut outcome = StreamOutcome::Completed;
+            loop {
+                // Dropping `stream` on cancel closes the upstream request, which stops llama.cpp
+                let chunk_result = tokio::select! {
+                    chunk = stream.next() => chunk,
+                    _ = active_request.cancelled() => {
+                        outcome = StreamOutcome::Cancelled;
+                        None
+                    }
+                };
+                let chunk_result = match chunk_result {
+                    Some(chunk_result) => chunk_result,
+                    None => break,
+                };
+                match chunk_result {
+                    Ok(chunk) => {
+                        if let Ok(chunk_str) = std::str::from_utf8(&chunk) {
+                            // Accumulate the content in memory
+                            {
+                                let mut accumulated = accumulated_content_clone.lock().unwrap();
+                                accumulated.push_str(chunk_str);
+                            }
+
+                            // Yield each chunk to the stream
+                            yield Ok::<_, Error>(web::Bytes::from(chunk_str.to_owned()));
                         }
+                    }
+                    Err(e) => {
+                        yield Err(
+                            actix_web::error::ErrorInternalServerError(
+                                format!("Error while streaming: {}", e)
+                            )
+                        );
+                    }
+                }
+            }
+            drop(stream);
+
+            // Notify that streaming is complete
+            completion.send(outcome);
+        };
 
-                        // Yield each chunk to the stream
-                        yield Ok::<_, Error>(web::Bytes::from(chunk_str.to_owned()));
+        // Return the response as a streaming body
+        let response = HttpResponse::Ok()
+            .content_type("application/json")
+            .append_header(("X-Session-ID", session_id)) // Add the header here
+            .append_header((REQUEST_ID_HEADER, request_id))
+            .append_header((CONTEXT_SOURCES_HEADER, context_sources_header(context_sources)))
+            .append_header((CONTEXT_SOURCES_TOTAL_HEADER, context_sources.len()))
+            .streaming(response_stream);
+
+        return Ok(response);
+    }
+
+    let context_event = sse_event("context_used", &json!({ "context": context_used, "sources": context_sources }));
+    let session_id_owned = session_id.to_string();
+    let request_id_owned = request_id.clone();
+
+    // Same stream as above, framed as typed SSE events so the UI can tell the parts apart
+    let response_stream = stream! {
+        yield Ok::<_, Error>(context_event);
+
+        let mut outcome = StreamOutcome::Completed;
+        let mut failed = false;
+        loop {
+            let event_result = tokio::select! {
+                event = stream.next() => event,
+                _ = active_request.cancelled() => {
+                    outcome = StreamOutcome::Cancelled;
+                    None
+                }
+            };
+            let event_result = match event_result {
+                Some(event_result) => event_result,
+                None => break,
+            };
+            match event_result {
+                Ok(StreamEvent::Token(content)) => {
+                    if content.is_empty() {
+                        continue;
+                    }
+                    {
+                        let mut accumulated = accumulated_content_clone.lock().unwrap();
+                        accumulated.push_str(&content);
                     }
+                    yield Ok(sse_event("token", &json!({ "content": content })));
                 }
-                Err(e) => {
-                    yield Err(
-                        actix_web::error::ErrorInternalServerError(
-                            format!("Error while streaming: {}", e)
+                Ok(StreamEvent::Timings(timings)) => {
+                    let tokens_per_second = calculate_tokens_per_second(
+                        timings.predicted_n,
+                        timings.predicted_ms
+                    );
+                    yield Ok(
+                        sse_event(
+                            "timings",
+                            &json!({ "timings": timings, "tokens_per_second": tokens_per_second })
                         )
                     );
                 }
+                Err(e) => {
+                    yield Ok(sse_event("error", &json!({ "error": format!("Error while streaming: {}", e) })));
+                    failed = true;
+                    break;
+                }
             }
         }
+        drop(stream);
+
+        // `error` is the last event of a failed stream, `done` only ends one that finished or was cancelled
+        if !failed {
+            yield Ok(
+                sse_event(
+                    "done",
+                    &json!({
+                        "session_id": session_id_owned,
+                        "request_id": request_id_owned,
+                        "cancelled": outcome == StreamOutcome::Cancelled
+                    })
+                )
+            );
+        }
 
         // Notify that streaming is complete
-        let _ = tx.send(());
+        completion.send(outcome);
     };
 
-    // Return the response as a streaming body
     let response = HttpResponse::Ok()
-        .content_type("application/json")
-        .append_header(("X-Session-ID", session_id)) // Add the header here
+        .content_type("text/event-stream")
+        .append_header(("Cache-Control", "no-cache"))
+        .append_header(("X-Session-ID", session_id))
+        .append_header((REQUEST_ID_HEADER, request_id))
         .streaming(response_stream);
 
     Ok(response)
 }
 
 pub async fn handle_request(
-    _request_type: RequestType,
+    request_type: RequestType,
     client: &Client,  // Pass the client here
+    requested_backend: Option<&str>,
+    generation: Option<&GenerationParams>,
     system_prompt: &str,
-    full_user_prompt: &str
+    full_user_prompt: &str,
+    usage: UsageContext
 ) -> Result<AccumulatedStream, ActixError> {
-    let stream: AccumulatedStream = if is_cloud_execution_mode() {
-        // Remote agent execution for cloud mode
-        remote_agent_execution(system_prompt, full_user_prompt).await.map_err(|e|
-            ActixError::from(actix_web::error::ErrorInternalServerError(e.to_string()))
-        )?
-    } else {
-        // Local execution mode with different handling for INFILL and other request types
-                // All other request types use local_agent_execution
-                local_agent_execution(client, system_prompt, full_user_prompt).await.map_err(|e|
-                    ActixError::from(actix_web::error::ErrorInternalServerError(e.to_string()))
-                )?
+    let events = handle_request_events(
+        request_type,
+        client,
+        requested_backend,
+        generation,
+        system_prompt,
+        full_user_prompt,
+        usage
+    ).await?;
+    Ok(Box::pin(tokens_only(events)))
+}
 
-    };
+pub async fn handle_request_events(
+    request_type: RequestType,
+    client: &Client,  // Pass the client here
+    requested_backend: Option<&str>,
+    generation: Option<&GenerationParams>,
+    system_prompt: &str,
+    full_user_prompt: &str,
+    usage: UsageContext
+) -> Result<EventStream, ActixError> {
+    let route = request_type.to_string();
+    // Header override first, then LLM_BACKEND_<ROUTE>, then LLM_BACKEND
+    let backend = resolve_backend(route, requested_backend)?;
+    let generation = resolve_generation_params(route, generation, GenerationParams::from_env()).await;
+
+    metered_stream_events(route, backend.as_ref(), client, system_prompt, full_user_prompt, &generation, usage).await
+}
+
+/// Runs a prompt to the end and returns the whole answer, for server side jobs
+/// (titles, summaries) where nobody is waiting on a stream
+pub async fn complete_text(
+    route: &str,
+    client: &Client,
+    system_prompt: &str,
+    prompt: &str,
+    fallback_generation: GenerationParams,
+    usage: UsageContext
+) -> Result<String, ActixError> {
+    let backend = resolve_backend(route, None)?;
+    let generation = resolve_generation_params(route, None, fallback_generation).await;
+
+    let mut stream = metered_stream_events(
+        route,
+        backend.as_ref(),
+        client,
+        system_prompt,
+        prompt,
+        &generation,
+        usage
+    ).await?;
 
-    // Shared state using Arc<Mutex<_>>
-    let accumulated_content = Arc::new(Mutex::new(String::new()));
-    let accumulated_content_clone = Arc::clone(&accumulated_content);
-    // Apply inspect on the stream to accumulate content
-    let accumulated_stream = stream.inspect(move |chunk_result| {
-        if let Ok(chunk) = chunk_result {
-            if let Ok(chunk_str) = std::str::from_utf8(chunk) {
-                let mut accumulated = accumulated_content_clone.lock().unwrap();
-                accumulated.push_str(chunk_str);
+    let mut answer = String::new();
+    while let Some(event) = stream.next().await {
+        match event {
+            Ok(StreamEvent::Token(content)) => answer.push_str(&content),
+            Ok(StreamEvent::Timings(_)) => {}
+            Err(e) => {
+                return Err(actix_web::error::ErrorInternalServerError(e.to_string()));
             }
         }
-    });
-    // Return the stream directly wrapped in a Pin
-    Ok(Box::pin(accumulated_stream))
-}
\ No newline at end of file
+    }
+    Ok(answer.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ context_sources_header, MAX_CONTEXT_SOURCES_HEADER_BYTES };
+    use crate::context::make_context::{ ContextSource, SourceKind };
+
+    fn code_source(file_path: &str) -> ContextSource {
+        ContextSource {
+            kind: SourceKind::Code,
+            session_id: "session".to_string(),
+            file_path: Some(file_path.to_string()),
+            chunk_type: Some("function".to_string()),
+            start_line: Some(1),
+            end_line: Some(20),
+            chat_id: None,
+            symbol: None,
+            rerank_score: Some(0.5),
+            truncated: false,
+        }
+    }
+
+    #[test]
+    fn escapes_non_ascii_paths() {
+        let header = context_sources_header(&[code_source("src/größe.rs")]);
+        assert!(header.is_ascii());
+        let parsed: Vec<ContextSource> = serde_json::from_str(&header).unwrap();
+        assert_eq!(parsed[0].file_path.as_deref(), Some("src/größe.rs"));
+    }
+
+    #[test]
+    fn keeps_the_first_sources_that_fit() {
+        let sources: Vec<ContextSource> = (0..500)
+            .map(|i| code_source(&format!("src/module_{}/file.rs", i)))
+            .collect();
+        let header = context_sources_header(&sources);
+        assert!(header.len() <= MAX_CONTEXT_SOURCES_HEADER_BYTES);
+
+        let parsed: Vec<ContextSource> = serde_json::from_str(&header).unwrap();
+        assert!(!parsed.is_empty() && parsed.len() < sources.len());
+        assert_eq!(parsed[0].file_path.as_deref(), Some("src/module_0/file.rs"));
+    }
+
+    #[test]
+    fn empty_list() {
+        assert_eq!(context_sources_header(&[]), "[]");
+    }
+}
@@ -1,72 +1,74 @@
 use log::{ info, error };
-use std::pin::Pin;
 use bytes::Bytes;
+use async_trait::async_trait;
 
 use serde_json::json;
-use serde::Deserialize;
 
 use futures::{ Stream, StreamExt }; // Ensure StreamExt is imported
 use std::error::Error as StdError; // Importing the correct trait
 use reqwest::Error as ReqwestError;
 use futures_util::stream::TryStreamExt;
 use tokio_stream::wrappers::ReceiverStream;
-use crate::utils::{ get_llm_temperature, get_local_url, get_top_k, get_top_p };
 use crate::platform_variables::get_default_prompt_template;
 use reqwest::Client;
 use tokio::sync::mpsc;
-use futures::stream::unfold;
 use serde_json::Value;
+use super::types::{ EventStream, LLMGenerattionTimings, StreamEvent };
+use super::backend::LlmBackend;
+use super::generation::GenerationParams;
+use super::chunks::format_llm_events;
+use crate::database::db_config::run_db;
+
+pub struct LlamaCppBackend {
+    llm_server_url: String,
+}
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize)]
-struct LLMGenerattionTimings {
-    predicted_ms: f64,
-    predicted_n: f64,
-    predicted_per_second: f64,
-    predicted_per_token_ms: f64,
-    prompt_ms: f64,
-    prompt_n: f64,
-    prompt_per_second: f64,
-    prompt_per_token_ms: f64,
+impl LlamaCppBackend {
+    pub fn new(llm_server_url: String) -> Self {
+        LlamaCppBackend { llm_server_url }
+    }
 }
 
-pub async fn local_agent_execution(
-    client: &Client, // Pass the client here
-    system_prompt: &str,
-    prompt_with_context: &str
-) -> Result<
-    Pin<Box<dyn Stream<Item = Result<Bytes, ReqwestError>> + Send>>,
-    Box<dyn StdError + Send + Sync + 'static>
-> {
-    let llm_temperature = get_llm_temperature();
-    let top_k = get_top_k();
-    let top_p = get_top_p();
-
-    match local_llm_request(client, system_prompt, prompt_with_context, llm_temperature, top_k, top_p).await {
-        Ok(stream) => {
-            let formatted_stream = format_local_llm_response(stream).await;
-            Ok(Box::pin(formatted_stream)) // Pin the stream here using Box::pin
-        }
-        Err(e) => {
-            error!("Local LLM execution error in Pair programmer: {}", e);
-            Err(e.into()) // Use `into()` to convert the error directly into `Box<dyn StdError>`
+#[async_trait]
+impl LlmBackend for LlamaCppBackend {
+    fn name(&self) -> &str {
+        "llamacpp"
+    }
+
+    // The configured local model, llama.cpp serves one model at a time
+    async fn model(&self) -> String {
+        match run_db(|db| db.get_model_config().map(|config| config.model_name)).await {
+            Ok(Ok(model_name)) => model_name,
+            _ => "llamacpp".to_string(),
         }
     }
-}
 
-async fn local_llm_request(
-    client: &Client,
-    system_prompt: &str,
-    prompt_with_context: &str,
-    temperature: f64,
-    top_k: i64,
-    top_p: f64
-) -> Result<
-    impl Stream<Item = Result<bytes::Bytes, reqwest::Error>>,
-    Box<dyn StdError + Send + Sync + 'static>
-> {
-    let llm_server_url = get_local_url();
-    send_llm_request(client, &llm_server_url, system_prompt, prompt_with_context, temperature,  top_k, top_p).await
+    async fn stream_events(
+        &self,
+        client: &Client,
+        system_prompt: &str,
+        prompt_with_context: &str,
+        generation: &GenerationParams
+    ) -> Result<EventStream, Box<dyn StdError + Send + Sync + 'static>> {
+        match
+            send_llm_request(
+                client,
+                &self.llm_server_url,
+                system_prompt,
+                prompt_with_context,
+                generation
+            ).await
+        {
+            Ok(stream) => {
+                let formatted_stream = format_local_llm_events(stream).await;
+                Ok(Box::pin(formatted_stream)) // Pin the stream here using Box::pin
+            }
+            Err(e) => {
+                error!("Local LLM execution error: {}", e);
+                Err(e.into()) // Use `into()` to convert the error directly into `Box<dyn StdError>`
+            }
+        }
+    }
 }
 
 async fn send_llm_request(
@@ -74,9 +76,7 @@ async fn send_llm_request(
     llm_server_url: &str,
     system_prompt: &str,
     prompt_with_context: &str,
-    temperature: f64,
-    top_k: i64,
-    top_p: f64
+    generation: &GenerationParams
 ) -> Result<
     impl Stream<Item = Result<bytes::Bytes, reqwest::Error>>,
     Box<dyn StdError + Send + Sync + 'static>
@@ -90,18 +90,17 @@ async fn send_llm_request(
 
     // info!("{} with temperature {}", full_prompt, temperature);
 
+    let mut request_body =
+        json!({
+        "prompt": full_prompt,
+        "stream": true,
+        "cache_prompt": true
+    });
+    generation.apply_to_llamacpp_body(&mut request_body, "n_predict");
+
     let resp = client
         .post(format!("{}/completion", llm_server_url))
-        .json(
-            &json!({
-            "prompt": full_prompt,
-            "stream": true,
-            "temperature": temperature,
-            "top_k": top_k,
-            "top_p": top_p,
-            "cache_prompt": true
-        })
-        )
+        .json(&request_body)
         .send().await?
         .error_for_status()?; // Handle HTTP errors automatically
 
@@ -111,85 +110,84 @@ async fn send_llm_request(
     tokio::spawn(async move {
         let mut stream = resp.bytes_stream();
 
-        while let Ok(Some(bytes)) = stream.try_next().await {
-            if tx.send(Ok(bytes)).await.is_err() {
-                eprintln!("Receiver dropped");
-                break;
+        loop {
+            // Stop as soon as the receiver goes away (client disconnected or request cancelled),
+            // dropping `stream` closes the connection and llama.cpp stops generating
+            let next = tokio::select! {
+                next = stream.try_next() => next,
+                _ = tx.closed() => {
+                    info!("Receiver dropped, aborting llama.cpp generation");
+                    break;
+                }
+            };
+            match next {
+                Ok(Some(bytes)) => {
+                    if tx.send(Ok(bytes)).await.is_err() {
+                        eprintln!("Receiver dropped");
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    break;
+                }
             }
         }
-
-        if let Err(e) = stream.try_next().await {
-            let _ = tx.send(Err(e)).await;
-        }
     });
 
     Ok(ReceiverStream::new(rx))
 }
 
-pub async fn format_local_llm_response<'a>(
+/// Parses the llama.cpp `data: {...}` lines into tokens and timings events
+pub async fn format_local_llm_events<'a>(
     stream: impl Stream<Item = Result<Bytes, ReqwestError>> + Unpin + 'a
-) -> impl Stream<Item = Result<Bytes, ReqwestError>> + 'a {
-    let acc = String::new(); // Initialize accumulator
-
-    unfold((stream, acc), move |(mut stream, acc)| {
-        async move {
-            if let Some(chunk_result) = stream.next().await {
-                match chunk_result {
-                    Ok(chunk) => {
-                        if let Ok(chunk_str) = std::str::from_utf8(&chunk) {
-                            let content_to_stream = process_chunk(chunk_str).await;
-
-                            if !content_to_stream.is_empty() {
-                                return Some((Ok(Bytes::from(content_to_stream)), (stream, acc)));
-                            }
-                        } else {
-                            eprintln!("Failed to parse chunk as UTF-8");
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Error receiving chunk: {}", e);
-                        return Some((Err(e), (stream, acc)));
-                    }
-                }
-            } else {
-                // End of stream, mark the stream as done
-                return None; // Stream is done
-            }
+) -> impl Stream<Item = Result<StreamEvent, ReqwestError>> + 'a {
+    format_llm_events(stream, process_line)
+}
 
-            Some((Ok(Bytes::new()), (stream, acc)))
+/// Drops everything but the generated text from an event stream
+pub fn tokens_only<'a>(
+    events: impl Stream<Item = Result<StreamEvent, ReqwestError>> + 'a
+) -> impl Stream<Item = Result<Bytes, ReqwestError>> + 'a {
+    events.filter_map(|event| async move {
+        match event {
+            Ok(StreamEvent::Token(content)) if !content.is_empty() => Some(Ok(Bytes::from(content))),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
         }
     })
 }
-/// Process each chunk of the stream, extracting content and accumulating it
-async fn process_chunk(chunk_str: &str) -> String {
-    let mut content_to_stream = String::new();
-
-    for line in chunk_str.lines() {
-        if line.starts_with("data: ") {
-            if let Ok(json_data) = serde_json::from_str::<Value>(&line[6..]) {
-                if let Some(content) = json_data.get("content").and_then(|c| c.as_str()) {
-                    content_to_stream.push_str(content); // Stream content
-                }
-                if let Some(timings) = json_data.get("timings") {
-                    if
-                        let Ok(timing_struct) = serde_json::from_value::<LLMGenerattionTimings>(
-                            timings.clone()
-                        )
-                    {
-                        let tokens_per_second = calculate_tokens_per_second(
-                            timing_struct.predicted_n,
-                            timing_struct.predicted_ms
-                        );
-                        info!("Tokens generated per second: {:.2}", tokens_per_second);
-                    }
+
+/// Process each line of the stream, extracting content and timings
+fn process_line(line: &str) -> Vec<StreamEvent> {
+    let mut events = Vec::new();
+
+    if line.starts_with("data: ") {
+        if let Ok(json_data) = serde_json::from_str::<Value>(&line[6..]) {
+            if let Some(content) = json_data.get("content").and_then(|c| c.as_str()) {
+                events.push(StreamEvent::Token(content.to_string())); // Stream content
+            }
+            if let Some(timings) = json_data.get("timings") {
+                if
+                    let Ok(timing_struct) = serde_json::from_value::<LLMGenerattionTimings>(
+                        timings.clone()
+                    )
+                {
+                    let tokens_per_second = calculate_tokens_per_second(
+                        timing_struct.predicted_n,
+                        timing_struct.predicted_ms
+                    );
+                    info!("Tokens generated per second: {:.2}", tokens_per_second);
+                    events.push(StreamEvent::Timings(timing_struct));
                 }
             }
         }
     }
-    content_to_stream
+    events
 }
 
-fn calculate_tokens_per_second(predicted_n: f64, predicted_ms: f64) -> f64 {
+pub fn calculate_tokens_per_second(predicted_n: f64, predicted_ms: f64) -> f64 {
     let predicted_seconds = predicted_ms / 1000.0;
     predicted_n / predicted_seconds
 }
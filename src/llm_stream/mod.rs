@@ -1,4 +1,10 @@
 pub mod local;
 pub mod remote;
+pub mod ollama;
+pub mod backend;
 pub mod handle;
-pub mod types;
\ No newline at end of file
+pub mod types;
+pub mod chunks;
+pub mod generation;
+pub mod cancel;
+pub mod usage;
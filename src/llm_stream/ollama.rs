@@ -0,0 +1,120 @@
+use log::{ info, error };
+use async_trait::async_trait;
+
+use serde_json::{ json, Value };
+
+use std::error::Error as StdError;
+use reqwest::Client;
+use super::types::{ EventStream, LLMGenerattionTimings, StreamEvent };
+use super::backend::LlmBackend;
+use super::generation::GenerationParams;
+use super::local::calculate_tokens_per_second;
+use super::chunks::format_llm_events;
+
+pub struct OllamaBackend {
+    ollama_url: String,
+    model: String,
+}
+
+impl OllamaBackend {
+    pub fn new(ollama_url: String, model: String) -> Self {
+        OllamaBackend { ollama_url, model }
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OllamaBackend {
+    fn name(&self) -> &str {
+        "ollama"
+    }
+
+    async fn model(&self) -> String {
+        self.model.clone()
+    }
+
+    async fn stream_events(
+        &self,
+        client: &Client,
+        system_prompt: &str,
+        prompt_with_context: &str,
+        generation: &GenerationParams
+    ) -> Result<EventStream, Box<dyn StdError + Send + Sync + 'static>> {
+        // Ollama takes the sampling knobs under `options`, null entries fall back to the modelfile
+        let mut request_body =
+            json!({
+            "model": self.model,
+            "system": system_prompt,
+            "prompt": prompt_with_context,
+            "stream": true,
+            "options": {
+                "temperature": generation.temperature,
+                "top_k": generation.top_k,
+                "top_p": generation.top_p,
+                "num_predict": generation.max_tokens,
+                "stop": generation.stop,
+                "seed": generation.seed,
+                "repeat_penalty": generation.repeat_penalty
+            }
+        });
+        if let Some(options) = request_body["options"].as_object_mut() {
+            options.retain(|_, value| !value.is_null());
+        }
+
+        let resp = client
+            .post(format!("{}/api/generate", self.ollama_url))
+            .json(&request_body)
+            .send().await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|e| {
+                error!("Ollama execution error: {}", e);
+                e
+            })?;
+
+        // Ollama answers with one JSON object per line
+        let events = format_llm_events(resp.bytes_stream(), process_line);
+
+        Ok(Box::pin(events))
+    }
+}
+
+/// Turns one line of the Ollama response into tokens and, on the final line, timings
+fn process_line(line: &str) -> Vec<StreamEvent> {
+    let mut events = Vec::new();
+    if line.is_empty() {
+        return events;
+    }
+
+    let json_data = match serde_json::from_str::<Value>(line) {
+        Ok(value) => value,
+        Err(_) => {
+            eprintln!("Failed to parse Ollama line: {}", line);
+            return events;
+        }
+    };
+
+    if let Some(content) = json_data.get("response").and_then(|c| c.as_str()) {
+        events.push(StreamEvent::Token(content.to_string()));
+    }
+
+    if json_data.get("done").and_then(|d| d.as_bool()).unwrap_or(false) {
+        let timings = ollama_timings(&json_data);
+        info!(
+            "Tokens generated per second: {:.2}",
+            calculate_tokens_per_second(timings.predicted_n, timings.predicted_ms)
+        );
+        events.push(StreamEvent::Timings(timings));
+    }
+    events
+}
+
+/// Ollama reports counts and durations in nanoseconds, convert them to the llama.cpp shape
+fn ollama_timings(json_data: &Value) -> LLMGenerattionTimings {
+    let number = |key: &str| json_data.get(key).and_then(|v| v.as_f64()).unwrap_or(0.0);
+
+    LLMGenerattionTimings::from_counts(
+        number("prompt_eval_count"),
+        number("prompt_eval_duration") / 1_000_000.0,
+        number("eval_count"),
+        number("eval_duration") / 1_000_000.0
+    )
+}
@@ -1,29 +1,46 @@
 
 
-use log::error;
-use std::pin::Pin;
-use bytes::Bytes;
+use log::{ info, error };
+use async_trait::async_trait;
+use std::time::Instant;
 
-use serde_json::json;
+use serde_json::{ json, Value };
 
 use futures::{Stream, StreamExt}; // Ensure StreamExt is imported
 use std::error::Error as StdError;  // Importing the correct trait
-use reqwest::Error as ReqwestError;
 use tokio_stream::wrappers::ReceiverStream;
 use reqwest::Client;
 use tokio::sync::mpsc;
-use crate::utils::{get_remote_url, get_cloud_api_key};
+use super::types::{ EventStream, LLMGenerattionTimings, StreamEvent };
+use super::backend::LlmBackend;
+use super::generation::GenerationParams;
+use super::chunks::format_llm_events;
+use super::local::calculate_tokens_per_second;
+
+pub struct OpenAiBackend {
+    api_url: String,
+    api_key: String,
+    model: String,
+}
+
+impl OpenAiBackend {
+    pub fn new(api_url: String, api_key: String, model: String) -> Self {
+        OpenAiBackend { api_url, api_key, model }
+    }
+}
 
 async fn cloud_llm_response(
+    client: &Client,
+    api_url: &str,
+    api_key: &str,
+    model: &str,
     system_prompt: &str,
     prompt_with_context: &str,
+    generation: &GenerationParams,
 ) -> Result<impl Stream<Item = Result<bytes::Bytes, reqwest::Error>>,Box<dyn StdError + Send + Sync + 'static>> {
-    let api_url =  get_remote_url();
-
-    let api_key = get_cloud_api_key();
     // Prepare the dynamic JSON body for the request
-    let request_body = json!({
-        "model": "meta-llama/Meta-Llama-3.1-8B-Instruct-Turbo",
+    let mut request_body = json!({
+        "model": model,
         "messages": [
             {
                 "role": "system",
@@ -34,10 +51,26 @@ async fn cloud_llm_response(
                 "content": prompt_with_context
             }
         ],
+        "stream": true,
+        // Ask for a final usage chunk so we can report timings like llama.cpp does
+        "stream_options": { "include_usage": true }
     });
-
-    // Create a new reqwest client
-    let client = Client::new();
+    // top_k, repeat_penalty and grammar have no OpenAI equivalent and are dropped
+    if let Some(temperature) = generation.temperature {
+        request_body["temperature"] = json!(temperature);
+    }
+    if let Some(top_p) = generation.top_p {
+        request_body["top_p"] = json!(top_p);
+    }
+    if let Some(max_tokens) = generation.max_tokens {
+        request_body["max_tokens"] = json!(max_tokens);
+    }
+    if let Some(stop) = &generation.stop {
+        request_body["stop"] = json!(stop);
+    }
+    if let Some(seed) = generation.seed {
+        request_body["seed"] = json!(seed);
+    }
 
     // Make the POST request
     let response = client
@@ -56,7 +89,19 @@ async fn cloud_llm_response(
     tokio::spawn(async move {
         let mut stream = response.bytes_stream();
 
-        while let Some(chunk) = stream.next().await {
+        loop {
+            // Bail out when the receiver is gone instead of waiting for the next chunk to fail
+            let chunk = tokio::select! {
+                chunk = stream.next() => chunk,
+                _ = tx.closed() => {
+                    info!("Receiver dropped, aborting remote generation");
+                    break;
+                }
+            };
+            let chunk = match chunk {
+                Some(chunk) => chunk,
+                None => break,
+            };
             match chunk {
                 Ok(bytes) => {
                     if tx.send(Ok(bytes)).await.is_err() {
@@ -76,17 +121,121 @@ async fn cloud_llm_response(
     Ok(ReceiverStream::new(rx))
 }
 
-pub async fn remote_agent_execution(
-    system_prompt: &str,
-    prompt_with_context: &str,
-) -> Result<Pin<Box<dyn Stream<Item = Result<Bytes, ReqwestError>> + Send>>, Box<dyn StdError + Send + Sync + 'static>> {
-    match cloud_llm_response(system_prompt, prompt_with_context).await {
-        Ok(stream) => {
-            Ok(Box::pin(stream)) // Pin the stream here using Box::pin
+/// Keeps the state needed to turn OpenAI `chat.completion.chunk` lines into events.
+/// OpenAI doesn't report durations, so they are measured on our side.
+struct OpenAiStreamParser {
+    started: Instant,
+    first_token_ms: Option<f64>,
+    streamed_chunks: f64,
+    timings_sent: bool,
+}
+
+impl OpenAiStreamParser {
+    fn new() -> Self {
+        OpenAiStreamParser {
+            started: Instant::now(),
+            first_token_ms: None,
+            streamed_chunks: 0.0,
+            timings_sent: false,
         }
-        Err(e) => {
-            error!("Remote agent execution error in Pair programmer: {}", e);
-            Err(e.into())  // Use `into()` to convert the error directly into `Box<dyn StdError>`
+    }
+
+    fn elapsed_ms(&self) -> f64 {
+        self.started.elapsed().as_secs_f64() * 1000.0
+    }
+
+    fn timings(&mut self, prompt_n: f64, predicted_n: f64) -> StreamEvent {
+        self.timings_sent = true;
+        let prompt_ms = self.first_token_ms.unwrap_or(0.0);
+        let predicted_ms = self.elapsed_ms() - prompt_ms;
+        info!("Tokens generated per second: {:.2}", calculate_tokens_per_second(predicted_n, predicted_ms));
+        StreamEvent::Timings(LLMGenerattionTimings::from_counts(prompt_n, prompt_ms, predicted_n, predicted_ms))
+    }
+
+    fn process_line(&mut self, line: &str) -> Vec<StreamEvent> {
+        let mut events = Vec::new();
+
+        let data = match line.strip_prefix("data:") {
+            Some(data) => data.trim(),
+            None => return events, // blank separators, `event:` lines and keep-alive comments
+        };
+
+        if data == "[DONE]" {
+            // Providers that ignore stream_options never send usage, count the deltas instead
+            if !self.timings_sent {
+                let predicted_n = self.streamed_chunks;
+                events.push(self.timings(0.0, predicted_n));
+            }
+            return events;
+        }
+
+        let json_data = match serde_json::from_str::<Value>(data) {
+            Ok(value) => value,
+            Err(_) => {
+                eprintln!("Failed to parse remote chunk: {}", data);
+                return events;
+            }
+        };
+
+        if let Some(error) = json_data.get("error") {
+            error!("Remote LLM stream error: {}", error);
+            return events;
+        }
+
+        let content = json_data
+            .get("choices")
+            .and_then(|choices| choices.get(0))
+            .and_then(|choice| choice.get("delta"))
+            .and_then(|delta| delta.get("content"))
+            .and_then(|c| c.as_str());
+        if let Some(content) = content {
+            if !content.is_empty() {
+                if self.first_token_ms.is_none() {
+                    self.first_token_ms = Some(self.elapsed_ms());
+                }
+                self.streamed_chunks += 1.0;
+                events.push(StreamEvent::Token(content.to_string()));
+            }
+        }
+
+        if let Some(usage) = json_data.get("usage").filter(|usage| !usage.is_null()) {
+            let count = |key: &str| usage.get(key).and_then(|v| v.as_f64()).unwrap_or(0.0);
+            let (prompt_n, predicted_n) = (count("prompt_tokens"), count("completion_tokens"));
+            events.push(self.timings(prompt_n, predicted_n));
         }
+
+        events
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OpenAiBackend {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    async fn model(&self) -> String {
+        self.model.clone()
     }
-}
\ No newline at end of file
+
+    async fn stream_events(
+        &self,
+        client: &Client,
+        system_prompt: &str,
+        prompt_with_context: &str,
+        generation: &GenerationParams,
+    ) -> Result<EventStream, Box<dyn StdError + Send + Sync + 'static>> {
+        let mut parser = OpenAiStreamParser::new();
+        match cloud_llm_response(client, &self.api_url, &self.api_key, &self.model, system_prompt, prompt_with_context, generation).await {
+            Ok(stream) => {
+                // Same line layer as the local backend, only the line format differs
+                let events = format_llm_events(stream, move |line: &str| parser.process_line(line));
+                Ok(Box::pin(events)) // Pin the stream here using Box::pin
+            }
+            Err(e) => {
+                error!("Remote LLM execution error: {}", e);
+                Err(e.into())  // Use `into()` to convert the error directly into `Box<dyn StdError>`
+            }
+        }
+    }
+}
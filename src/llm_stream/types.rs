@@ -1,7 +1,48 @@
-
 use std::pin::Pin;
 use futures::Stream;
 use bytes::Bytes;
 use reqwest::Error as ReqwestError;
+use serde::{ Deserialize, Serialize };
+
+pub type AccumulatedStream = Pin<Box<dyn Stream<Item = Result<Bytes, ReqwestError>> + Send>>;
+
+pub type EventStream = Pin<Box<dyn Stream<Item = Result<StreamEvent, ReqwestError>> + Send>>;
+
+/// Generation timings reported by llama.cpp on the last chunk of a completion
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LLMGenerattionTimings {
+    pub predicted_ms: f64,
+    pub predicted_n: f64,
+    pub predicted_per_second: f64,
+    pub predicted_per_token_ms: f64,
+    pub prompt_ms: f64,
+    pub prompt_n: f64,
+    pub prompt_per_second: f64,
+    pub prompt_per_token_ms: f64,
+}
+
+impl LLMGenerattionTimings {
+    /// Builds timings from raw counts for backends that don't report the llama.cpp shape
+    pub fn from_counts(prompt_n: f64, prompt_ms: f64, predicted_n: f64, predicted_ms: f64) -> Self {
+        let per_second = |n: f64, ms: f64| if ms > 0.0 { n / (ms / 1000.0) } else { 0.0 };
+        let per_token_ms = |n: f64, ms: f64| if n > 0.0 { ms / n } else { 0.0 };
+
+        LLMGenerattionTimings {
+            predicted_ms,
+            predicted_n,
+            predicted_per_second: per_second(predicted_n, predicted_ms),
+            predicted_per_token_ms: per_token_ms(predicted_n, predicted_ms),
+            prompt_ms,
+            prompt_n,
+            prompt_per_second: per_second(prompt_n, prompt_ms),
+            prompt_per_token_ms: per_token_ms(prompt_n, prompt_ms),
+        }
+    }
+}
 
-pub type AccumulatedStream = Pin<Box<dyn Stream<Item = Result<Bytes, ReqwestError>> + Send>>;
\ No newline at end of file
+/// A parsed piece of an LLM response stream
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    Token(String),
+    Timings(LLMGenerattionTimings),
+}
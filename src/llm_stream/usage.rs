@@ -0,0 +1,160 @@
+use actix_web::{ HttpMessage, HttpRequest, Error as ActixError };
+use futures::StreamExt;
+use log::{ debug, error };
+use reqwest::Client;
+
+use super::backend::LlmBackend;
+use super::generation::GenerationParams;
+use super::types::{ EventStream, StreamEvent };
+use crate::authentication::authorization::{ is_request_allowed, User, DEFAULT_USER_ID };
+use crate::database::db_config::DB_INSTANCE;
+use crate::database::usage_db::UsageRecord;
+
+// Rough chars per token, only used when the backend doesn't report counts
+pub const CHARS_PER_TOKEN: usize = 4;
+
+/// Who an LLM call is billed to
+#[derive(Debug, Clone)]
+pub struct UsageContext {
+    pub user_id: String,
+    pub request_id: Option<String>,
+}
+
+impl UsageContext {
+    pub fn new(user_id: &str, request_id: Option<String>) -> Self {
+        UsageContext {
+            user_id: user_id.to_string(),
+            request_id,
+        }
+    }
+
+    /// The caller the auth middleware put on the request
+    pub fn from_request(req: &HttpRequest, request_id: Option<String>) -> Self {
+        let user_id = req
+            .extensions()
+            .get::<User>()
+            .map(|user| user.user_id.clone())
+            .unwrap_or_else(|| DEFAULT_USER_ID.to_string());
+        UsageContext { user_id, request_id }
+    }
+}
+
+/// Counts the tokens of one stream and writes the usage row when the stream is dropped,
+/// so finished, failed and cancelled generations are all accounted for
+struct UsageRecorder {
+    record: UsageRecord,
+    prompt_chars: usize,
+    token_events: i64,
+    reported: bool,
+}
+
+impl UsageRecorder {
+    fn observe(&mut self, event: &StreamEvent) {
+        match event {
+            StreamEvent::Token(content) => {
+                if !content.is_empty() {
+                    self.token_events += 1;
+                }
+            }
+            StreamEvent::Timings(timings) => {
+                self.record.prompt_tokens = timings.prompt_n as i64;
+                self.record.completion_tokens = timings.predicted_n as i64;
+                self.reported = true;
+            }
+        }
+    }
+}
+
+impl Drop for UsageRecorder {
+    fn drop(&mut self) {
+        if !self.reported {
+            // Backends stream about one token per event
+            self.record.prompt_tokens = self.prompt_chars.div_ceil(CHARS_PER_TOKEN) as i64;
+            self.record.completion_tokens = self.token_events;
+            self.record.estimated = true;
+        }
+        debug!(
+            "Usage for {} on {}: {} prompt, {} completion tokens",
+            self.record.user_id,
+            self.record.route,
+            self.record.prompt_tokens,
+            self.record.completion_tokens
+        );
+        let record = self.record.clone();
+        let write = move || {
+            if let Err(e) = DB_INSTANCE.record_usage(&record) {
+                error!("Failed to record usage for {}: {}", record.user_id, e);
+            }
+        };
+        // Dropped on an async worker, the insert goes to the blocking pool
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(write);
+            }
+            Err(_) => write(),
+        }
+    }
+}
+
+/// Starts a generation on `backend` after checking the user's quota. The returned stream
+/// records the tokens it used once it's dropped.
+pub async fn metered_stream_events(
+    route: &str,
+    backend: &dyn LlmBackend,
+    client: &Client,
+    system_prompt: &str,
+    prompt: &str,
+    generation: &GenerationParams,
+    usage: UsageContext
+) -> Result<EventStream, ActixError> {
+    is_request_allowed(&usage.user_id).await?;
+
+    let events = backend
+        .stream_events(client, system_prompt, prompt, generation).await
+        .map_err(|e| ActixError::from(actix_web::error::ErrorInternalServerError(e.to_string())))?;
+
+    Ok(
+        meter_events(
+            events,
+            route,
+            backend.name(),
+            backend.model().await,
+            system_prompt.chars().count() + prompt.chars().count(),
+            usage
+        )
+    )
+}
+
+/// Records the usage of an event stream that was started without `metered_stream_events`,
+/// e.g. the infill model's completions. The quota has to be checked by the caller.
+pub fn meter_events(
+    events: EventStream,
+    route: &str,
+    backend_name: &str,
+    model: String,
+    prompt_chars: usize,
+    usage: UsageContext
+) -> EventStream {
+    let mut recorder = UsageRecorder {
+        record: UsageRecord {
+            user_id: usage.user_id,
+            route: route.to_string(),
+            backend: backend_name.to_string(),
+            model,
+            request_id: usage.request_id,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            estimated: false,
+        },
+        prompt_chars,
+        token_events: 0,
+        reported: false,
+    };
+
+    let metered = events.inspect(move |event| {
+        if let Ok(event) = event {
+            recorder.observe(event);
+        }
+    });
+    Box::pin(metered)
+}
@@ -1,14 +1,13 @@
 use actix_cors::Cors;
-use actix_web::{ get, post, web, App, HttpServer, HttpResponse, Responder };
+use actix_web::{ get, post, web, middleware, App, HttpServer, HttpResponse, Responder };
 use serde::{ Deserialize, Serialize };
 use serde_json::json;
-use env_logger::Env;
 use log::info;
 use dotenv::dotenv;
+use env_logger::Env;
 use std::sync::{ Arc, Mutex };
 use tokio::sync::Mutex as TokioMutex; // Import tokio's async mutex
 use reqwest::Client;
-use std::env;
 mod chats; // Import the chats module
 mod authentication;
 mod utils;
@@ -27,8 +26,11 @@ mod llm_stream;
 mod context;
 mod infill;
 mod similarity_index;
+mod server_config;
 use crate::model_state::state::ModelState;
 use crate::infill::state::InfillModelState;
+use crate::server_config::{ Cli, ServerConfig, init_server_config };
+use clap::Parser;
 
 #[get("/")]
 async fn hello() -> impl Responder {
@@ -70,10 +72,31 @@ async fn json_handler(info: web::Json<Info>) -> impl Responder {
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
+    let cli = Cli::parse();
+    let config = match ServerConfig::load(&cli) {
+        Ok(config) => init_server_config(config),
+        Err(e) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e));
+        }
+    };
+    // RUST_LOG, when set, still wins over the configured level
+    env_logger::Builder::from_env(Env::default().default_filter_or(&config.log_level)).init();
+
+    if cli.migrate_dry_run {
+        let database_dir = server_config::profile_data_dir("database");
+        return match database::migrations::dry_run(&database_dir) {
+            Ok(reports) => {
+                println!("{}", serde_json::to_string_pretty(&reports).unwrap_or_default());
+                if reports.iter().any(|report| report.error.is_some()) {
+                    Err(std::io::Error::other("Migration dry run failed"))
+                } else {
+                    Ok(())
+                }
+            }
+            Err(e) => Err(std::io::Error::other(e)),
+        };
+    }
     let client = Client::new();
-    let log_level = "info";
-    info!("Setting LOG_LEVEL : {}", log_level);
-    std::env::set_var("RUST_LOG", log_level); // Adjust the log level as needed
 
     let model_state = Arc::new(ModelState {
         model_pid: Arc::new(Mutex::new(None)),
@@ -86,15 +109,17 @@ async fn main() -> std::io::Result<()> {
         infill_model_process: Arc::new(TokioMutex::new(None)),
     });
 
-    // Access the environment variables
-    let llm_server_url = crate::utils::get_local_url();
-    let temperature = crate::utils::get_llm_temperature();
-    let cloud_execution_mode = crate::utils::is_cloud_execution_mode();
-
-    info!("LLM Server URL: {}", llm_server_url);
-    info!("Temperature: {}", temperature);
-    info!("Cloud Execution Mode: {}", cloud_execution_mode);
-    env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
+    match &config.config_file {
+        Some(path) => info!("Loaded server config from {}", path.display()),
+        None => info!("No server config file, using defaults and environment"),
+    }
+    info!("Data directory: {}", config.data_dir.display());
+    info!("LLM Server URL: {}", config.llm.local_url);
+    info!("Temperature: {}", config.llm.temperature);
+    info!("Cloud Execution Mode: {}", config.cloud_execution_mode);
+    authentication::authorization::bootstrap_admin_key();
+    // Keeps indexed directories in sync with the disk, off unless watch_directories is set
+    actix_web::rt::spawn(rag::watcher::start_watchers());
 
     //TODO: This is meant just for testing the Parsers for indexing code, Delete it
     //when the rag will be live
@@ -103,6 +128,8 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || {
         let expose_headers = [
             "X-Session-ID",
+            "X-Request-ID",
+            "X-Context-Sources",
             "X-Pair-Programmer-id",
             "access-control-allow-origin",
             "content-type",
@@ -117,7 +144,8 @@ async fn main() -> std::io::Result<()> {
             .app_data(web::Data::new(model_state.clone()))
             .app_data(web::Data::new(infill_model_state.clone()))
             .app_data(web::Data::new(client.clone())) // Add client to state
-            .wrap(cors)
+            .wrap(middleware::from_fn(authentication::middleware::require_api_key))
+            .wrap(cors) // Outermost, so preflights and 401s still get CORS headers
             .service(hello) // Register the GET route
             .service(echo) // Register the POST route
             .service(json_handler) // Register the POST route for JSON
@@ -132,9 +160,16 @@ async fn main() -> std::io::Result<()> {
             .configure(chats::chat_findbugs_routes) // Add chatfindbugs routes
             .configure(chats::chat_docstring_routes) // Add docstring routes
             .configure(chats::chat_history_routes) // Add docstring routes
+            .configure(chats::chat_completions_routes) // Add OpenAI compatible routes
+            .configure(chats::chat_cancel_routes) // Add chat cancel route
+            .configure(session_manager::session_api::register_routes) // Add session routes
+            .configure(authentication::auth_api::register_routes) // Add user and API key routes
+            .configure(authentication::usage_api::register_routes) // Add token usage routes
+            .configure(server_config::server_config_api::register_routes) // Add config route
+            .configure(database::db_api::register_routes) // Add database admin routes
             .configure(rag::code_rag_api::register_routes) // Add chat explain routes
             .configure(pair_programmer::pair_programmer_api::register_routes) // Add chat explain routes
     })
-        .bind("localhost:52556")?
+        .bind(config.address())?
         .run().await
 }
@@ -1,5 +1,5 @@
 use log::{ debug, error };
-use dirs::home_dir;
+use crate::server_config::shared_data_dir;
 // use psutil::host::info;
 use tokio::io::{ AsyncBufReadExt, BufReader };
 use tokio::process::Command as tokio_command;
@@ -35,10 +35,8 @@ use crate::database::db_config::DB_INSTANCE;
 // }
 
 pub fn get_app_config_json() -> String {
-    let home_dir = home_dir().expect("Failed to retrieve home directory");
-
-    // Constructs the path to the `.pyano/configs` directory inside the home directory
-    let config_dir = home_dir.join(".pyano/configs"); // Spawn a new thread for downloading the model and initialization
+    // Constructs the path to the `configs` directory inside the data directory
+    let config_dir = shared_data_dir("configs");
 
     // TODO move this to dev scripts
     // Ensures that the model directory exists by creating all directories in the path if they don't exist
@@ -119,8 +117,7 @@ pub async fn run_llama_server<F>(model_type: String, callback: F)
             }
         }
     }
-    let home_dir = home_dir().expect("Failed to retrieve home directory");
-    let model_path = home_dir.join(".pyano/models").join(model_name);
+    let model_path = shared_data_dir("models").join(model_name);
 
     // check if model path exists
     debug!("Model path: {:#?}", model_path.to_str());
@@ -129,7 +126,7 @@ pub async fn run_llama_server<F>(model_type: String, callback: F)
         std::process::exit(1);
     }
 
-    let scripts_dir = home_dir.join(".pyano/scripts");
+    let scripts_dir = shared_data_dir("scripts");
 
     let script_path = scripts_dir.join("run-model.sh");
     // info!("Scripts path from where run_models.hs i sbeing loaded {:?}", script_path);
@@ -151,8 +148,10 @@ pub async fn run_llama_server<F>(model_type: String, callback: F)
     {
         Ok(child) => {
             println!("Model process started successfully.");
-            if system_prompt.is_some() {
-                DB_INSTANCE.update_system_prompt(model_name, system_prompt.unwrap());
+            if let Some(system_prompt) = system_prompt {
+                if let Err(e) = DB_INSTANCE.update_system_prompt(model_name, system_prompt) {
+                    eprintln!("Failed to store the system prompt: {}", e);
+                }
             }
             child
         }
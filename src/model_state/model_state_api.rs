@@ -13,9 +13,10 @@ use serde::{ Deserialize, Serialize };
 use tokio::time::{ sleep, Duration, Instant }; // Import sleep and Duration from tokio
 use tokio::process::Command;
 use crate::model_state::state::{ ModelState, AppConfigJson };
-// use crate::database::db_config::DB_INSTANCE;
-use reqwest::Client;
-use dirs::home_dir;
+use crate::database::db_config::run_db;
+use crate::llm_stream::generation::GenerationParams;
+use crate::authentication::authorization::{ require_admin, User };
+use crate::server_config::shared_data_dir;
 
 pub fn model_state_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(mode_state)
@@ -23,7 +24,9 @@ pub fn model_state_routes(cfg: &mut web::ServiceConfig) {
         .service(kill_model)
         .service(restart_model)
         .service(get_model_usage)
-        .service(model_config);
+        .service(model_config)
+        .service(list_generation_defaults)
+        .service(set_generation_defaults);
 }
 
 #[get("/model-state")]
@@ -73,15 +76,18 @@ pub struct SwitchModelRequest {
 async fn run_model(
     data: web::Data<Arc<ModelState>>, // Accepts the shared state (ModelState) wrapped in an Arc and web::Data for thread-safe access.
     post_data: web::Json<SwitchModelRequest>,
-    client: web::Data<Client>
+    user: User
 ) -> Result<HttpResponse, Error> {
+    // There's one model for every user of the server
+    if let Some(response) = require_admin(&user) {
+        return Ok(response);
+    }
     // Acquires an asynchronous lock on the model process state to ensure only one process is running at a time.
     let mut model_process_guard = data.model_process.lock().await;
 
-    // Acquires a synchronous lock on the model PID state, needed to check if a model is already running and to track the PID.
-    let model_pid_guard = data.model_pid.lock().unwrap();
-
-    let mut model_type = data.model_type.lock().unwrap();
+    // Copied out of the synchronous locks, their guards mustn't be held across the kill below
+    let running_pid = *data.model_pid.lock().unwrap();
+    let running_type = data.model_type.lock().unwrap().clone();
     let new_model_type = post_data.model.clone();
     // Check if the model process is already running by verifying if the process handle exists.
     let model_running = model_process_guard.is_some();
@@ -93,8 +99,7 @@ async fn run_model(
         model_file_name = &model.model_name;
     }
 
-    let home_dir = home_dir().expect("Failed to retrieve home directory");
-    let model_path = home_dir.join(".pyano/models").join(model_file_name.clone());
+    let model_path = shared_data_dir("models").join(model_file_name.clone());
 
     // check if model path exists
     // debug!("Model path: {:#?}", model_path.to_str());
@@ -103,41 +108,26 @@ async fn run_model(
     }
 
     if model_running {
-        if *model_type == new_model_type {
+        if running_type == new_model_type {
             return Ok(
                 HttpResponse::BadRequest().json(
-                    json!({"message": "Model is already running", "pid": model_pid_guard.unwrap_or(0), "modelType": new_model_type})
+                    json!({"message": "Model is already running", "pid": running_pid.unwrap_or(0), "modelType": new_model_type})
                 )
             );
         } else {
             println!("Killing old model...");
-            // Release locks before making the request to prevent deadlock
-            drop(model_process_guard);
-            drop(model_pid_guard);
-            drop(model_type);
-            // kill old model
-            let url = "http://localhost:52556/kill-model".to_string();
-            let _resp = match client.get(url).send().await {
-                Ok(response) => {
-                    if response.status() != 200 {
-                        return Ok(
-                            HttpResponse::BadRequest().json(
-                                json!({"message": "Error stopping model"})
-                            )
-                        );
-                    }
-                }
-                Err(_e) => {
+            // Stopped in place like /restart-model, no round trip through our own /kill-model
+            if let Some(pid) = running_pid {
+                if let Err(e) = kill_model_process(pid).await {
+                    debug!("Failed to stop model {}: {}", pid, e);
                     return Ok(
                         HttpResponse::BadRequest().json(json!({"message": "Error stopping model"}))
                     );
                 }
-            };
-
-            // Re-acquire locks after the request
-            model_process_guard = data.model_process.lock().await;
-            // model_pid_guard = data.model_pid.lock().unwrap();
-            model_type = data.model_type.lock().unwrap();
+            }
+            // Re-acquire locks after the kill
+            *data.model_pid.lock().unwrap() = None;
+            *model_process_guard = None;
         }
     }
 
@@ -156,12 +146,12 @@ async fn run_model(
 
     // Store the handle to the running process in the shared state so it can be tracked or stopped later.
     *model_process_guard = Some(handle);
-    *model_type = post_data.model.clone();
+    *data.model_type.lock().unwrap() = post_data.model.clone();
 
     // Log that the script has been triggered
     println!("Main server thread running...");
 
-    Ok(HttpResponse::Ok().json(json!({"message": "Model started", "modelType": *model_type})))
+    Ok(HttpResponse::Ok().json(json!({"message": "Model started", "modelType": post_data.model})))
 }
 
 #[get("/model-config")]
@@ -171,7 +161,7 @@ async fn model_config() -> Result<HttpResponse, Error> {
         ::from_str(&config)
         .map_err(|e| actix_web::error::ErrorBadRequest(e))?;
 
-    let home_dir = home_dir().expect("Failed to retrieve home directory");
+    let models_dir = shared_data_dir("models");
     // Extract all model names from the config
     let models = json_config["models"]
         .as_object()
@@ -186,7 +176,7 @@ async fn model_config() -> Result<HttpResponse, Error> {
     let available_models = models
         .iter()
         .filter(|(_, model_name)| {
-            let model_path = home_dir.join(".pyano").join("models").join(model_name);
+            let model_path = models_dir.join(model_name);
             model_path.exists()
         })
         .map(|(key, _)| key.clone())
@@ -202,7 +192,10 @@ async fn model_config() -> Result<HttpResponse, Error> {
 }
 
 #[get("/kill-model")]
-async fn kill_model(data: web::Data<Arc<ModelState>>) -> Result<HttpResponse, Error> {
+async fn kill_model(data: web::Data<Arc<ModelState>>, user: User) -> Result<HttpResponse, Error> {
+    if let Some(response) = require_admin(&user) {
+        return Ok(response);
+    }
     let mut model_process_guard = data.model_process.lock().await;
     let mut model_pid_guard = data.model_pid.lock().unwrap();
     let mut model_type = data.model_type.lock().unwrap();
@@ -238,7 +231,10 @@ async fn kill_model(data: web::Data<Arc<ModelState>>) -> Result<HttpResponse, Er
 }
 
 #[get("/restart-model")]
-async fn restart_model(data: web::Data<Arc<ModelState>>) -> Result<HttpResponse, Error> {
+async fn restart_model(data: web::Data<Arc<ModelState>>, user: User) -> Result<HttpResponse, Error> {
+    if let Some(response) = require_admin(&user) {
+        return Ok(response);
+    }
     // Kill the model if it's running
 
     let mut model_process_guard = data.model_process.lock().await;
@@ -374,3 +370,47 @@ async fn calculate_cpu_usage(
 
     cpu_usage_percent
 }
+
+#[get("/generation-defaults")]
+async fn list_generation_defaults() -> Result<HttpResponse, Error> {
+    match run_db(|db| db.list_generation_defaults()).await? {
+        Ok(defaults) => {
+            let defaults: serde_json::Map<String, serde_json::Value> = defaults
+                .into_iter()
+                .map(|(route, generation)| (route, json!(generation)))
+                .collect();
+            Ok(HttpResponse::Ok().json(json!({ "defaults": defaults })))
+        }
+        Err(e) =>
+            Ok(
+                HttpResponse::InternalServerError().json(
+                    json!({"error": format!("Failed to fetch generation defaults: {}", e)})
+                )
+            ),
+    }
+}
+
+/// Stores the default generation params for a route, e.g. `/generation-defaults/find_bugs`
+#[post("/generation-defaults/{route}")]
+async fn set_generation_defaults(
+    path: web::Path<String>,
+    data: web::Json<GenerationParams>,
+    user: User
+) -> Result<HttpResponse, Error> {
+    // Shared by every user of the server
+    if let Some(response) = require_admin(&user) {
+        return Ok(response);
+    }
+    let route = path.into_inner().to_uppercase();
+    let (stored_route, generation) = (route.clone(), data.clone());
+    let stored = run_db(move |db| db.set_generation_defaults(&stored_route, &generation).map_err(|e| e.to_string())).await?;
+    match stored {
+        Ok(_) => Ok(HttpResponse::Ok().json(json!({ "route": route, "generation": data.into_inner() }))),
+        Err(e) =>
+            Ok(
+                HttpResponse::InternalServerError().json(
+                    json!({"error": format!("Failed to store generation defaults: {}", e)})
+                )
+            ),
+    }
+}
@@ -2,10 +2,11 @@ use std::sync::{Arc, Mutex};
 use async_trait::async_trait;
 use futures::StreamExt; // Ensure StreamExt is imported
 use actix_web::Error as ActixError;
-use crate::utils::is_cloud_execution_mode;
-use crate::llm_stream::local::local_agent_execution;
-use crate::llm_stream::remote::remote_agent_execution;
+use crate::llm_stream::backend::resolve_backend;
+use crate::llm_stream::local::tokens_only;
+use crate::llm_stream::generation::{ GenerationParams, resolve_generation_params };
 use crate::llm_stream::types::AccumulatedStream;
+use crate::llm_stream::usage::{ metered_stream_events, UsageContext };
 
 use reqwest::Client;
 
@@ -13,17 +14,26 @@ use reqwest::Client;
 #[async_trait]
 pub trait Agent: Send + Sync {
 
-    async fn execute(&self, client: &Client) -> Result<AccumulatedStream, ActixError> {
-
-        let stream: AccumulatedStream = if is_cloud_execution_mode() {
-            remote_agent_execution(&self.get_system_prompt(), &self.get_user_prompt_with_context())
-                .await
-                .map_err(|e| ActixError::from(actix_web::error::ErrorInternalServerError(e.to_string())))?
-        } else {
-            local_agent_execution(&client, &self.get_system_prompt(), &self.get_user_prompt_with_context())
-                .await
-                .map_err(|e| ActixError::from(actix_web::error::ErrorInternalServerError(e.to_string())))?
-        };
+    async fn execute(
+        &self,
+        client: &Client,
+        requested_backend: Option<&str>,
+        generation: Option<&GenerationParams>,
+        usage: UsageContext
+    ) -> Result<AccumulatedStream, ActixError> {
+
+        let backend = resolve_backend("PAIR_PROGRAMMER", requested_backend)?;
+        let generation = resolve_generation_params("PAIR_PROGRAMMER", generation, GenerationParams::from_env()).await;
+        let events = metered_stream_events(
+            "PAIR_PROGRAMMER",
+            backend.as_ref(),
+            &client,
+            &self.get_system_prompt(),
+            &self.get_user_prompt_with_context(),
+            &generation,
+            usage
+        ).await?;
+        let stream: AccumulatedStream = Box::pin(tokens_only(events));
 
         let accumulated_content = Arc::new(Mutex::new(String::new()));
         let accumulated_content_clone = Arc::clone(&accumulated_content);
@@ -9,6 +9,8 @@ use crate::pair_programmer::{agent_planner::PlannerAgent,
 use async_trait::async_trait;
 use actix_web::Error as ActixError;
 use crate::llm_stream::types::AccumulatedStream;
+use crate::llm_stream::generation::GenerationParams;
+use crate::llm_stream::usage::UsageContext;
 use reqwest::Client;
 pub enum AgentEnum {
     GenerateCode(Box<dyn Agent>),
@@ -81,15 +83,21 @@ impl Agent for AgentEnum {
     }
 
 
-    async fn execute(&self, client: &Client) -> Result<AccumulatedStream, ActixError> {
+    async fn execute(
+        &self,
+        client: &Client,
+        requested_backend: Option<&str>,
+        generation: Option<&GenerationParams>,
+        usage: UsageContext
+    ) -> Result<AccumulatedStream, ActixError> {
         match self {
-            AgentEnum::GenerateCode(agent) => agent.execute(&client).await,
-            AgentEnum::NativeLLM(agent) => agent.execute(&client).await,
-            AgentEnum::Planner(agent) => agent.execute(&client).await,
-            AgentEnum::Rethinker(agent) => agent.execute(&client).await,
-            AgentEnum::SystemCode(agent) => agent.execute(&client).await,
-            AgentEnum::ModifyCodeAgent(agent) => agent.execute(&client).await,
-            AgentEnum::ModifyStepAgent(agent) => agent.execute(&client).await
+            AgentEnum::GenerateCode(agent) => agent.execute(&client, requested_backend, generation, usage).await,
+            AgentEnum::NativeLLM(agent) => agent.execute(&client, requested_backend, generation, usage).await,
+            AgentEnum::Planner(agent) => agent.execute(&client, requested_backend, generation, usage).await,
+            AgentEnum::Rethinker(agent) => agent.execute(&client, requested_backend, generation, usage).await,
+            AgentEnum::SystemCode(agent) => agent.execute(&client, requested_backend, generation, usage).await,
+            AgentEnum::ModifyCodeAgent(agent) => agent.execute(&client, requested_backend, generation, usage).await,
+            AgentEnum::ModifyStepAgent(agent) => agent.execute(&client, requested_backend, generation, usage).await
 
         }
     }
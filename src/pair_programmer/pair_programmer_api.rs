@@ -7,16 +7,21 @@ use std::sync::{Arc, Mutex};
 use log::{info, debug, error};
 use serde::{Deserialize, Serialize};
 use crate::context::store_text_context::index_code;
+use crate::context::symbol_context::mentioned_definition_entries;
 use crate::similarity_index::index::search_index;
 use crate::pair_programmer::agent::Agent;
-use crate::database::db_config::DB_INSTANCE;
+use crate::database::db_config::run_db;
 use crate::pair_programmer::agent_enum::AgentEnum;
 use crate::embeddings::text_embeddings::generate_text_embedding;
 use actix_web::{post, web, get, HttpRequest, HttpResponse, Error};
 use crate::pair_programmer::pair_programmer_utils::{parse_steps, parse_step_number, prompt_with_context};
 use futures::StreamExt; // Ensure StreamExt is imported
 use crate::session_manager::check_session;
+use crate::authentication::authorization::User;
 use reqwest::Client;
+use crate::llm_stream::backend::requested_backend;
+use crate::llm_stream::generation::GenerationParams;
+use crate::llm_stream::usage::UsageContext;
 
 #[derive(Debug, Serialize)]
 pub struct ErrorResponse {
@@ -28,9 +33,8 @@ pub struct ErrorResponse {
 pub struct GenerateStepsRequest {
     pub task: String,
     pub session_id: Option<String>,
-    pub user_id: Option<String>,
     pub files: Option<Vec<String>>,
-
+    pub generation: Option<GenerationParams>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -49,18 +53,37 @@ pub struct RethinkRequest {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ExecuteStepRequest {
     pub pair_programmer_id: String,
-    pub step_number: String
+    pub step_number: String,
+    pub generation: Option<GenerationParams>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatStepRequest {
     pub pair_programmer_id: String,
     pub step_number: String,
-    pub prompt: String
+    pub prompt: String,
+    pub generation: Option<GenerationParams>,
 }
 
 
 
+// Plans of other users are reported as missing, same as sessions
+async fn pair_programmer_not_found(pair_programmer_id: &str, user: &User) -> Option<HttpResponse> {
+    let owner_id = pair_programmer_id.to_string();
+    let owner = run_db(move |db| db.fetch_pair_programmer_owner(&owner_id).map_err(|e| e.to_string())).await;
+    match owner {
+        Ok(Ok(Some(owner))) if owner == user.user_id => None,
+        Ok(Ok(_)) =>
+            Some(
+                HttpResponse::NotFound().json(ErrorResponse {
+                    error: format!("Pair programmer {} not found", pair_programmer_id),
+                })
+            ),
+        Ok(Err(e)) => Some(HttpResponse::InternalServerError().json(ErrorResponse { error: e })),
+        Err(e) => Some(HttpResponse::InternalServerError().json(ErrorResponse { error: e.to_string() })),
+    }
+}
+
 pub fn register_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(pair_programmer_generate_steps)
     .service(get_steps)
@@ -155,23 +178,13 @@ steps:
 pub async fn pair_programmer_generate_steps(
     data: web::Json<GenerateStepsRequest>,
     client: web::Data<Client>,
-    _req: HttpRequest,
+    req: HttpRequest,
+    user: User,
 ) -> Result<HttpResponse, Error> {
 
-    let user_id = data.user_id.clone().unwrap_or_else(|| "user_id".to_string());
+    let user_id = user.user_id.clone();
 
-    let session_id = match check_session(data.session_id.clone()) {
-        Ok(id) => id,
-        Err(e) => {
-            return Err(
-                actix_web::error::ErrorInternalServerError(
-                    json!({
-                "error": e.to_string()
-            })
-                )
-            );
-        }
-    };
+    let session_id = check_session(data.session_id.clone(), &user.user_id).await?;
 
     if data.task.is_empty() {
         return Ok(HttpResponse::BadRequest().json(serde_json::json!({
@@ -184,7 +197,7 @@ pub async fn pair_programmer_generate_steps(
     if let Some(files) = &data.files {
         for file_path in files {
 
-            match index_code(&user_id, &session_id, file_path).await {
+            match index_code(&user_id, &session_id, file_path, None).await {
                 Ok(_) => {
                 }
                 Err(e) => {
@@ -214,11 +227,16 @@ pub async fn pair_programmer_generate_steps(
     let chunk_ids = search_index(&session_id, query_embeddings.clone(), 20);
 
     //  let file_path, chunk_type, content, pair_programmer_id;
-    let entries = DB_INSTANCE.get_row_ids(chunk_ids).unwrap();
+    let entries = run_db(move |db| db.get_row_ids(chunk_ids).map_err(|e| e.to_string()))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
     info!("All the matching entries {:?}", entries);
+    // Definitions of the symbols the task names, the embeddings may not surface them
+    let definition_entries = mentioned_definition_entries(&session_id, &data.task, &entries).await;
     let formatted_entries: String = entries
     .iter()
     .map(|(file_path, _, content, _)| format!("{}\n{}", file_path, content))
+    .chain(definition_entries)
     .collect::<Vec<String>>()
     .join("\n\n");
 
@@ -242,6 +260,8 @@ pub async fn pair_programmer_generate_steps(
     // Start streaming and sending data to the client
     let response = stream_to_client(
         &client,
+        &req,
+        data.generation.as_ref(),
         agent,
         pair_programmer_id.clone(),
         accumulated_content_clone,
@@ -265,12 +285,18 @@ pub async fn pair_programmer_generate_steps(
 
 // The correct handler for GET steps
 #[get("/pair-programmer/steps/{pair_programmer_id}")]
-async fn get_steps(path: web::Path<String>) -> Result<HttpResponse, Error> {
+async fn get_steps(path: web::Path<String>, user: User) -> Result<HttpResponse, Error> {
     // Use into_inner to get the inner String from the Path extractors
     let pair_programmer_id = path.into_inner();
+    if let Some(response) = pair_programmer_not_found(&pair_programmer_id, &user).await {
+        return Ok(response);
+    }
 
     // Fetch the steps for the provided pair_programmer_id
-    let steps = DB_INSTANCE.fetch_steps(&pair_programmer_id);
+    let steps_id = pair_programmer_id.to_string();
+    let steps = run_db(move |db| db.fetch_steps(&steps_id).map_err(|e| e.to_string()))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
     let response = json!({
         "steps": steps
     });
@@ -281,7 +307,7 @@ async fn get_steps(path: web::Path<String>) -> Result<HttpResponse, Error> {
 
 
 #[post("/pair-programmer/steps/execute")]
-pub async fn execute_step(payload: web::Payload, client: web::Data<Client>, req: HttpRequest) -> Result<HttpResponse, Error> {
+pub async fn execute_step(payload: web::Payload, client: web::Data<Client>, req: HttpRequest, user: User) -> Result<HttpResponse, Error> {
     let data = web::Json::<ExecuteStepRequest>::from_request(&req, &mut payload.into_inner()).await;
     let valid_data = match data {
         Ok(valid_data) => {
@@ -306,6 +332,9 @@ pub async fn execute_step(payload: web::Payload, client: web::Data<Client>, req:
 
 
     let pair_programmer_id = valid_data.pair_programmer_id.clone();
+    if let Some(response) = pair_programmer_not_found(&pair_programmer_id, &user).await {
+        return Ok(response);
+    }
     let step_number = &valid_data.step_number;
     //fetching the step details that is to be executed
     let step_number_usize = parse_step_number(step_number).map_err(|err| {
@@ -313,7 +342,10 @@ pub async fn execute_step(payload: web::Payload, client: web::Data<Client>, req:
     })?;
 
     //fetching all steps for the pai_programmer_id
-    let steps = DB_INSTANCE.fetch_steps(&pair_programmer_id);
+    let steps_id = pair_programmer_id.to_string();
+    let steps = run_db(move |db| db.fetch_steps(&steps_id).map_err(|e| e.to_string()))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
     let all_steps = steps.iter()
             .enumerate()
             .map(|(index, step)| {
@@ -338,7 +370,8 @@ pub async fn execute_step(payload: web::Payload, client: web::Data<Client>, req:
         .collect::<Vec<String>>()
         .join("\n");
     
-    let step_result = DB_INSTANCE.fetch_single_step(&pair_programmer_id, step_number);
+    let (step_id, step) = (pair_programmer_id.clone(), step_number.to_string());
+    let step_result = run_db(move |db| db.fetch_single_step(&step_id, &step).map_err(|e| e.to_string())).await?;
 
     let step = match step_result {
         Ok(step) => step,
@@ -352,7 +385,10 @@ pub async fn execute_step(payload: web::Payload, client: web::Data<Client>, req:
     };
     
     if step.action != "edit_file"{
-        let db_response = DB_INSTANCE.update_step_execution(&pair_programmer_id.clone(), &step_number.to_string(), "");
+        let (step_id, step) = (pair_programmer_id.clone(), step_number.to_string());
+        if let Err(e) = run_db(move |db| db.update_step_execution(&step_id, &step, "").map_err(|e| e.to_string())).await? {
+            error!("Failed to mark step {} as executed: {}", step_number, e);
+        }
         return Ok(HttpResponse::Ok().json(json!({"message": "Marked as executed"})));
     }
 
@@ -385,11 +421,16 @@ pub async fn execute_step(payload: web::Payload, client: web::Data<Client>, req:
     let chunk_ids = search_index(&pair_programmer_id, query_embeddings.clone(), 20);
 
     //  let file_path, chunk_type, content, session_id;
-    let entries = DB_INSTANCE.get_row_ids(chunk_ids).unwrap();
+    let entries = run_db(move |db| db.get_row_ids(chunk_ids).map_err(|e| e.to_string()))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
     info!("All the matching entries {:?}", entries);
+    // Definitions of the symbols the step names, the embeddings may not surface them
+    let definition_entries = mentioned_definition_entries(&pair_programmer_id, &step.heading, &entries).await;
     let formatted_entries: String = entries
         .iter()
         .map(|(file_path, _, content, _)| format!("{}\n{}", file_path, content))
+        .chain(definition_entries)
         .collect::<Vec<String>>()
         .join("\n\n");
 
@@ -420,7 +461,9 @@ pub async fn execute_step(payload: web::Payload, client: web::Data<Client>, req:
 
     // Start streaming and sending data to the client
     let response = stream_to_client(
-        &client, 
+        &client,
+        &req,
+        valid_data.generation.as_ref(),
         agent,
         pair_programmer_id.clone(),
         accumulated_content_clone,
@@ -505,7 +548,7 @@ pub async fn execute_step(payload: web::Payload, client: web::Data<Client>, req:
 // }
 
 #[post("/pair-programmer/steps/chat")]
-pub async fn chat_step(payload: web::Payload, client: web::Data<Client>, req: HttpRequest) -> Result<HttpResponse, Error> {
+pub async fn chat_step(payload: web::Payload, client: web::Data<Client>, req: HttpRequest, user: User) -> Result<HttpResponse, Error> {
     let data: Result<web::Json<ChatStepRequest>, Error> = web::Json::<ChatStepRequest>::from_request(&req, &mut payload.into_inner()).await;
     let valid_data = match data {
         Ok(valid_data) => {
@@ -530,6 +573,9 @@ pub async fn chat_step(payload: web::Payload, client: web::Data<Client>, req: Ht
 
 
     let pair_programmer_id = valid_data.pair_programmer_id.clone();
+    if let Some(response) = pair_programmer_not_found(&pair_programmer_id, &user).await {
+        return Ok(response);
+    }
     let step_number = &valid_data.step_number;
     // let step_number = parse_step_number(step_number).map_err(|err| {
     //     actix_web::error::ErrorBadRequest(format!("Invalid step number: {}", err))
@@ -539,7 +585,8 @@ pub async fn chat_step(payload: web::Payload, client: web::Data<Client>, req: Ht
     //Prompt by the user to make changes to the code
     let prompt = valid_data.prompt.clone();
 
-    let step_result = DB_INSTANCE.fetch_single_step(&pair_programmer_id, step_number);
+    let (step_id, step) = (pair_programmer_id.clone(), step_number.to_string());
+    let step_result = run_db(move |db| db.fetch_single_step(&step_id, &step).map_err(|e| e.to_string())).await?;
 
     let step = match step_result {
         Ok(step) => step,
@@ -574,18 +621,30 @@ pub async fn chat_step(payload: web::Payload, client: web::Data<Client>, req: Ht
     //  let file_path, chunk_type, content, session_id;
     // chunk_ids will give the u64 unique ids of the code chunks stored in the index, This
     //step will fetch the actual code snippets from the database
-    let entries = DB_INSTANCE.get_row_ids(chunk_ids).unwrap();
+    let entries = run_db(move |db| db.get_row_ids(chunk_ids).map_err(|e| e.to_string()))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
     info!("All the matching entries {:?}", entries);
+    // Definitions of the symbols the step or the user's prompt name
+    let definition_entries = mentioned_definition_entries(
+        &pair_programmer_id,
+        &format!("{}\n{}", step.heading, prompt),
+        &entries
+    ).await;
     let formatted_entries: String = entries
         .iter()
         .map(|(file_path, _, content, _)| format!("{}\n{}", file_path, content))
+        .chain(definition_entries)
         .collect::<Vec<String>>()
         .join("\n\n");
 
     info!("Formatted entries:\n{}", formatted_entries);
 
     //fetching all steps for the pai_programmer_id
-    let steps = DB_INSTANCE.fetch_steps(&pair_programmer_id);
+    let steps_id = pair_programmer_id.to_string();
+    let steps = run_db(move |db| db.fetch_steps(&steps_id).map_err(|e| e.to_string()))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
     let all_steps = steps.iter()
             .enumerate()
             .map(|(index, step)| {
@@ -622,6 +681,8 @@ pub async fn chat_step(payload: web::Payload, client: web::Data<Client>, req: Ht
     // Start streaming and sending data to the client
     let response = stream_to_client(
         &client,
+        &req,
+        valid_data.generation.as_ref(),
         agent,
         pair_programmer_id.clone(),
         accumulated_content_clone,
@@ -702,14 +763,21 @@ pub async fn chat_step(payload: web::Payload, client: web::Data<Client>, req: Ht
 
 async fn stream_to_client(
     client: &Client,
+    req: &HttpRequest,
+    generation: Option<&GenerationParams>,
     agent: AgentEnum,
     pair_programmer_id: String,
     accumulated_content_clone: Arc<Mutex<String>>,
     tx: tokio::sync::oneshot::Sender<()>
 ) -> Result<HttpResponse, Error> {
-    let stream_result = agent.execute(&client).await;
+    let usage = UsageContext::from_request(req, None);
+    let stream_result = agent.execute(&client, requested_backend(req).as_deref(), generation, usage).await;
     let mut stream = match stream_result {
         Ok(s) => s,
+        // Over quota, pass the 429 through
+        Err(e) if e.as_response_error().status_code() == actix_web::http::StatusCode::TOO_MANY_REQUESTS => {
+            return Err(e);
+        }
         Err(e) => {
             return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
                 "error": format!("Local LLM response error: {}", e)
@@ -802,7 +870,11 @@ async fn handle_stream_completion_execute(
     // Print the accumulated content after streaming is completed
     println!("Final accumulated content: {}", accumulated_content_final);
 
-    let db_response = DB_INSTANCE.update_step_execution(&pair_programmer_id.clone(), &step_number.to_string(), &accumulated_content_final);
+    let (step_id, step, response) = (pair_programmer_id.clone(), step_number.clone(), accumulated_content_final.clone());
+    let db_response = run_db(move |db| db.update_step_execution(&step_id, &step, &response).map_err(|e| e.to_string()))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|stored| stored);
     match  db_response {
         Ok(_) => {debug!("DB Update successful for executing pair_programmer_id {} and  step {}", pair_programmer_id, step_number)},
         Err(err) => {error!("Error updating executing pair_programmer_id {} and  step {}: {:?}",  pair_programmer_id, step_number, err);}
@@ -844,10 +916,16 @@ async fn handle_stream_completion_planner(
     // Print the accumulated content after streaming is completed
     println!("Steps = {:?}", steps);
 
-    let db_response= DB_INSTANCE.store_new_pair_programming_session(user_id, session_id, &pair_programmer_id, task, &steps);
+    let (owner, session, planned_id, planned_task) = (user_id.to_string(), session_id.to_string(), pair_programmer_id.clone(), task.to_string());
+    let step_count = steps.len();
+    let db_response = run_db(move |db| {
+        db.store_new_pair_programming_session(&owner, &session, &planned_id, &planned_task, &steps).map_err(|e| e.to_string())
+    }).await
+        .map_err(|e| e.to_string())
+        .and_then(|stored| stored);
     match  db_response {
-        Ok(_) => {debug!("DB Update successful for planning the task at id {} and number of steps step {}", pair_programmer_id, steps.len())},
-        Err(err) => {error!("Error inserting for planning the task at id {} and number of steps step {} with error {:?}",  pair_programmer_id, steps.len(), err);}
+        Ok(_) => {debug!("DB Update successful for planning the task at id {} and number of steps step {}", pair_programmer_id, step_count)},
+        Err(err) => {error!("Error inserting for planning the task at id {} and number of steps step {} with error {:?}",  pair_programmer_id, step_count, err);}
     }
 }
 
@@ -871,16 +949,20 @@ async fn handle_stream_completion_chat(
 
     // Print the accumulated content after streaming is completed
     println!("Final accumulated content: {}", accumulated_content_final);
-    let db_response;
-    if response == ""{
-        info!("The task hasnt been executed and hence the task heading will be updated by the LLM response");
-        //if response is not empty that means the modifycode agent has been executed and it updates the repsonse
-        db_response = DB_INSTANCE.update_step_heading(&pair_programmer_id.clone(), &step_number.to_string(), &prompt, &accumulated_content_final);
-
-    }else{
-        //if response is empty that means the modifystep agent has been executed and it updates the task heading
-        db_response = DB_INSTANCE.update_step_response(&pair_programmer_id.clone(), &step_number.to_string(), &prompt, &accumulated_content_final);
-    }
+    let (step_id, step, step_prompt, content) = (pair_programmer_id.clone(), step_number.clone(), prompt.to_string(), accumulated_content_final.clone());
+    let db_response = run_db(move |db| {
+        if response == ""{
+            info!("The task hasnt been executed and hence the task heading will be updated by the LLM response");
+            //if response is not empty that means the modifycode agent has been executed and it updates the repsonse
+            db.update_step_heading(&step_id, &step, &step_prompt, &content).map_err(|e| e.to_string())
+
+        }else{
+            //if response is empty that means the modifystep agent has been executed and it updates the task heading
+            db.update_step_response(&step_id, &step, &step_prompt, &content).map_err(|e| e.to_string())
+        }
+    }).await
+        .map_err(|e| e.to_string())
+        .and_then(|stored| stored);
 
     // let db_response = DB_INSTANCE.update_step_chat(&pair_programmer_id.clone(), &step_number.to_string(), &prompt, &accumulated_content_final);
     match  db_response {
@@ -1,2 +1,3 @@
 pub mod parser;
-pub mod parse_code;
\ No newline at end of file
+pub mod parse_code;
+pub mod symbols;
\ No newline at end of file
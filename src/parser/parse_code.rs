@@ -6,6 +6,7 @@ use std::path::Path;
 use reqwest::Client;
 use std::error::Error;
 use crate::parser::parser::ParserLoader;
+use crate::parser::symbols::{ self, Symbol, SymbolReference };
 
 // Define the struct for IndexCode
 pub struct ParseCode{
@@ -20,6 +21,9 @@ pub struct Chunk {
     pub start_line: usize,
     pub end_line: usize,
     pub file_path: String,
+    // Name of the function, class... the chunk defines, when the grammar has one
+    #[serde(default)]
+    pub symbol_name: Option<String>,
 }
 
 impl Eq for Chunk {}
@@ -108,20 +112,37 @@ impl ParseCode {
     }
 
 
-    pub fn process_local_file(&self, file_path: &str) -> Option<Vec<Chunk>> {
-        let path = Path::new(file_path);
-        // Open the file at the given file path.
-        if Self::is_media_file(path) {
-            debug!("Skipping media file: {:?}", path);
-            return None;
+    /// Whether the file is skipped for its extension: media always, docs and configs unless
+    /// `include_docs` (they were asked for)
+    pub fn is_skipped_file(file_path: &Path, include_docs: bool) -> bool {
+        if Self::is_media_file(file_path) {
+            debug!("Skipping media file: {:?}", file_path);
+            return true;
+        }
+
+        if !include_docs && Self::is_config_file(file_path) {
+            debug!("Skipping config file: {:?}", file_path);
+            return true;
         }
+        false
+    }
 
-        if Self::is_config_file(path) {
-            debug!("Skipping config file: {:?}", path);
+    /// Content of a local file worth indexing, None for media, config and binary files and
+    /// for files that can't be read, e.g. deleted while indexing
+    fn read_local_file(file_path: &str, include_docs: bool) -> Option<Vec<u8>> {
+        let path = Path::new(file_path);
+        // Open the file at the given file path.
+        if Self::is_skipped_file(path, include_docs) {
             return None;
         }
 
-        let file = File::open(path).expect("Failed to open file");
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Couldn't open {:?}: {}", path, e);
+                return None;
+            }
+        };
 
         // Create a buffered reader to efficiently read the file's content.
         let mut reader = BufReader::new(file);
@@ -130,17 +151,44 @@ impl ParseCode {
         let mut content = Vec::new();
 
         // Read the entire content of the file into the `content` vector.
-        reader.read_to_end(&mut content).expect("Failed to read file");
+        if let Err(e) = reader.read_to_end(&mut content) {
+            warn!("Couldn't read {:?}: {}", path, e);
+            return None;
+        }
 
         // Check if the file is binary
         if Self::is_binary_file(&content) {
             debug!("The file is binary and will not be chunked.");
             return None;
         }
+        Some(content)
+    }
+
+    pub fn process_local_file(&self, file_path: &str, include_docs: bool) -> Option<Vec<Chunk>> {
+        let content = Self::read_local_file(file_path, include_docs)?;
 
         // Call the `chunk_code` method to process the content into chunks,
         // passing the file content and the file path to determine the chunking strategy.
-        Some(self.chunk_code(&content, path))
+        Some(self.chunk_code(&content, Path::new(file_path)))
+    }
+
+    /// Symbol table of a file, empty for languages without symbol queries
+    pub fn extract_symbols(&self, file_content: &[u8], file_path: &Path) -> (Vec<Symbol>, Vec<SymbolReference>) {
+        let lang_name = self.get_lang_name(file_path);
+        let mut parser = match self.parse_loader.get_parser(file_path) {
+            Ok(parser) => parser,
+            Err(_) => {
+                return (Vec::new(), Vec::new());
+            }
+        };
+        let (language, tree) = match (parser.language(), parser.parse(file_content, None)) {
+            (Some(language), Some(tree)) => (language, tree),
+            _ => {
+                warn!("Couldn't parse {:?} for symbols", file_path);
+                return (Vec::new(), Vec::new());
+            }
+        };
+        symbols::extract_symbols(language, &lang_name, &tree, file_content, &file_path.to_string_lossy())
     }
 
     // Method to download and process a remote file
@@ -198,6 +246,7 @@ impl ParseCode {
                 start_line: 0,  // Set start line as 0
                 end_line: file_content.iter().filter(|&&c| c == b'\n').count(),  // Count the number of lines by counting newlines
                 file_path: file_path.to_str().unwrap().to_string(),  // Convert file path to a string
+                symbol_name: None,
             }];
         }
     
@@ -225,12 +274,18 @@ impl ParseCode {
 
         if chunk_types.contains(&node.kind().to_string()) {
             let content = String::from_utf8(file_content[node.start_byte()..node.end_byte()].to_vec()).unwrap();
+            // Most grammars put the identifier of a definition in its `name` field
+            let symbol_name = node
+                .child_by_field_name("name")
+                .and_then(|name| name.utf8_text(file_content).ok())
+                .map(String::from);
             chunks.push(Chunk {
                 chunk_type: node.kind().to_string(),
                 content,
                 start_line: node.start_position().row,
                 end_line: node.end_position().row,
                 file_path: file_path.to_str().unwrap().to_string(),
+                symbol_name,
             });
         }
 
@@ -3,7 +3,7 @@ use tree_sitter::{Parser, Language};
 use log::info;
 use std::path::Path;
 use std::ffi::CString;
-use dirs::home_dir;
+use crate::server_config::shared_data_dir;
 use std::fs::create_dir_all;
 
 // Define function signatures for the `tree_sitter_LANGUAGE` functions.
@@ -19,8 +19,7 @@ impl ParserLoader {
         unsafe{
             //let current_dir: PathBuf = env::current_dir().expect("Failed to get current directory");
 
-            let home_dir = home_dir().expect("Failed to retrieve home directory");
-            let parsers_dir = home_dir.join(".pyano/parsers");    // Spawn a new thread for downloading the model and initialization
+            let parsers_dir = shared_data_dir("parsers");
             // Ensure the model directory exists
             create_dir_all(&parsers_dir).expect("Failed to create parsers directory");
             let lib_path = parsers_dir.join("languages.so");
@@ -0,0 +1,264 @@
+use serde::{ Deserialize, Serialize };
+use tree_sitter::{ Language, Node, Query, QueryCursor, Tree };
+use log::error;
+
+// Signatures longer than this are cut, a definition header is rarely that long
+const MAX_SIGNATURE_CHARS: usize = 300;
+
+/// A definition found in a file. Lines are zero based like the chunks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: String,
+    pub signature: String,
+    // Name of the enclosing definition, e.g. the class of a method
+    pub scope: Option<String>,
+    pub file_path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+/// A use of a name. `enclosing` is the index, in the file's symbols, of the definition the
+/// reference sits in, which makes that definition a caller for `call` references.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SymbolReference {
+    pub name: String,
+    pub kind: String,
+    pub file_path: String,
+    pub line: usize,
+    pub enclosing: Option<usize>,
+}
+
+/// tree-sitter queries per language: definitions capture `@name` inside `@definition.<kind>`,
+/// references capture `@name` inside `@reference.<kind>`
+fn symbol_queries(lang_name: &str) -> Option<(&'static str, &'static str)> {
+    match lang_name {
+        "rust" =>
+            Some((
+                r#"
+                (function_item name: (identifier) @name) @definition.function
+                (function_signature_item name: (identifier) @name) @definition.function
+                (struct_item name: (type_identifier) @name) @definition.struct
+                (enum_item name: (type_identifier) @name) @definition.enum
+                (trait_item name: (type_identifier) @name) @definition.trait
+                (type_item name: (type_identifier) @name) @definition.type
+                (const_item name: (identifier) @name) @definition.constant
+                (static_item name: (identifier) @name) @definition.constant
+                (mod_item name: (identifier) @name) @definition.module
+                (macro_definition name: (identifier) @name) @definition.macro
+                "#,
+                r#"
+                (call_expression function: (identifier) @name) @reference.call
+                (call_expression function: (field_expression field: (field_identifier) @name)) @reference.call
+                (call_expression function: (scoped_identifier name: (identifier) @name)) @reference.call
+                (macro_invocation macro: (identifier) @name) @reference.call
+                (struct_expression name: (type_identifier) @name) @reference.type
+                "#,
+            )),
+        "python" =>
+            Some((
+                r#"
+                (function_definition name: (identifier) @name) @definition.function
+                (class_definition name: (identifier) @name) @definition.class
+                "#,
+                r#"
+                (call function: (identifier) @name) @reference.call
+                (call function: (attribute attribute: (identifier) @name)) @reference.call
+                "#,
+            )),
+        "javascript" =>
+            Some((
+                r#"
+                (function_declaration name: (identifier) @name) @definition.function
+                (class_declaration name: (identifier) @name) @definition.class
+                (method_definition name: (property_identifier) @name) @definition.method
+                (variable_declarator name: (identifier) @name value: (arrow_function)) @definition.function
+                "#,
+                r#"
+                (call_expression function: (identifier) @name) @reference.call
+                (call_expression function: (member_expression property: (property_identifier) @name)) @reference.call
+                (new_expression constructor: (identifier) @name) @reference.class
+                "#,
+            )),
+        "typescript" | "tsx" =>
+            Some((
+                r#"
+                (function_declaration name: (identifier) @name) @definition.function
+                (class_declaration name: (type_identifier) @name) @definition.class
+                (interface_declaration name: (type_identifier) @name) @definition.interface
+                (method_definition name: (property_identifier) @name) @definition.method
+                (variable_declarator name: (identifier) @name value: (arrow_function)) @definition.function
+                "#,
+                r#"
+                (call_expression function: (identifier) @name) @reference.call
+                (call_expression function: (member_expression property: (property_identifier) @name)) @reference.call
+                (new_expression constructor: (identifier) @name) @reference.class
+                "#,
+            )),
+        "go" =>
+            Some((
+                r#"
+                (function_declaration name: (identifier) @name) @definition.function
+                (method_declaration name: (field_identifier) @name) @definition.method
+                (type_spec name: (type_identifier) @name) @definition.type
+                "#,
+                r#"
+                (call_expression function: (identifier) @name) @reference.call
+                (call_expression function: (selector_expression field: (field_identifier) @name)) @reference.call
+                "#,
+            )),
+        "java" =>
+            Some((
+                r#"
+                (class_declaration name: (identifier) @name) @definition.class
+                (interface_declaration name: (identifier) @name) @definition.interface
+                (method_declaration name: (identifier) @name) @definition.method
+                "#,
+                r#"
+                (method_invocation name: (identifier) @name) @reference.call
+                (object_creation_expression type: (type_identifier) @name) @reference.class
+                "#,
+            )),
+        "c" =>
+            Some((
+                r#"
+                (function_definition declarator: (function_declarator declarator: (identifier) @name)) @definition.function
+                (struct_specifier name: (type_identifier) @name body: (_)) @definition.struct
+                "#,
+                r#"
+                (call_expression function: (identifier) @name) @reference.call
+                "#,
+            )),
+        "cpp" =>
+            Some((
+                r#"
+                (function_definition declarator: (function_declarator declarator: (identifier) @name)) @definition.function
+                (class_specifier name: (type_identifier) @name body: (_)) @definition.class
+                (struct_specifier name: (type_identifier) @name body: (_)) @definition.struct
+                "#,
+                r#"
+                (call_expression function: (identifier) @name) @reference.call
+                (call_expression function: (field_expression field: (field_identifier) @name)) @reference.call
+                "#,
+            )),
+        _ => None,
+    }
+}
+
+/// The header of a definition: its text up to the body, or its first line
+fn signature(node: &Node, source: &[u8]) -> String {
+    let end = node
+        .child_by_field_name("body")
+        .map(|body| body.start_byte())
+        .unwrap_or_else(|| node.end_byte());
+    let header = String::from_utf8_lossy(&source[node.start_byte()..end]);
+    let header = if node.child_by_field_name("body").is_some() {
+        header.trim().to_string()
+    } else {
+        header.lines().next().unwrap_or("").trim().to_string()
+    };
+    let collapsed = header.split_whitespace().collect::<Vec<_>>().join(" ");
+    collapsed.chars().take(MAX_SIGNATURE_CHARS).collect()
+}
+
+/// Runs `query` and returns (kind, name node, whole node) for every match. The kind is
+/// what follows `prefix` in the outer capture name.
+fn run_query<'tree>(
+    query: &Query,
+    root: Node<'tree>,
+    source: &[u8],
+    prefix: &str
+) -> Vec<(String, Node<'tree>, Node<'tree>)> {
+    let capture_names = query.capture_names();
+    let mut cursor = QueryCursor::new();
+    let mut found = Vec::new();
+
+    for query_match in cursor.matches(query, root, source) {
+        let mut name_node = None;
+        let mut outer = None;
+        for capture in query_match.captures {
+            let capture_name = &capture_names[capture.index as usize];
+            if capture_name == "name" {
+                name_node = Some(capture.node);
+            } else if let Some(kind) = capture_name.strip_prefix(prefix) {
+                outer = Some((kind.to_string(), capture.node));
+            }
+        }
+        if let (Some(name_node), Some((kind, node))) = (name_node, outer) {
+            found.push((kind, name_node, node));
+        }
+    }
+    found
+}
+
+/// Definitions and references of one parsed file. Names are matched by text only, a
+/// reference to `new` points at every `new` of the session.
+pub fn extract_symbols(
+    language: Language,
+    lang_name: &str,
+    tree: &Tree,
+    source: &[u8],
+    file_path: &str
+) -> (Vec<Symbol>, Vec<SymbolReference>) {
+    let (definitions_query, references_query) = match symbol_queries(lang_name) {
+        Some(queries) => queries,
+        None => {
+            return (Vec::new(), Vec::new());
+        }
+    };
+    // A grammar that doesn't know a node type in the query fails to compile it
+    let (definitions_query, references_query) = match
+        (Query::new(language, definitions_query), Query::new(language, references_query))
+    {
+        (Ok(definitions), Ok(references)) => (definitions, references),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Symbol queries for {} don't compile: {:?}", lang_name, e);
+            return (Vec::new(), Vec::new());
+        }
+    };
+
+    let root = tree.root_node();
+    let definitions = run_query(&definitions_query, root, source, "definition.");
+    let name_of = |node: &Node| node.utf8_text(source).unwrap_or_default().to_string();
+
+    // Smallest definition strictly around a byte range, that's the scope or the caller
+    let enclosing = |start: usize, end: usize, skip: Option<usize>| -> Option<usize> {
+        definitions
+            .iter()
+            .enumerate()
+            .filter(|(i, (_, _, node))| {
+                Some(*i) != skip && node.start_byte() <= start && end <= node.end_byte()
+            })
+            .min_by_key(|(_, (_, _, node))| node.end_byte() - node.start_byte())
+            .map(|(i, _)| i)
+    };
+
+    let symbols: Vec<Symbol> = definitions
+        .iter()
+        .enumerate()
+        .map(|(i, (kind, name_node, node))| Symbol {
+            name: name_of(name_node),
+            kind: kind.clone(),
+            signature: signature(node, source),
+            scope: enclosing(node.start_byte(), node.end_byte(), Some(i)).map(|scope| {
+                name_of(&definitions[scope].1)
+            }),
+            file_path: file_path.to_string(),
+            start_line: node.start_position().row,
+            end_line: node.end_position().row,
+        })
+        .collect();
+
+    let references: Vec<SymbolReference> = run_query(&references_query, root, source, "reference.")
+        .into_iter()
+        .map(|(kind, name_node, node)| SymbolReference {
+            name: name_of(&name_node),
+            kind,
+            file_path: file_path.to_string(),
+            line: name_node.start_position().row,
+            enclosing: enclosing(node.start_byte(), node.end_byte(), None),
+        })
+        .collect();
+
+    (symbols, references)
+}
@@ -15,7 +15,7 @@ use serde_json::{Value, json};
 use std::fs::{File, OpenOptions};
 /// Directory to save the model
 use std::io::{Read, Write};  // Import the required traits
-use dirs::home_dir;
+use crate::server_config::shared_data_dir;
 use std::sync::{Mutex, Arc};
 use once_cell::sync::Lazy;
 pub struct AttentionCalculator {
@@ -244,8 +244,7 @@ fn download_and_save_model(save_path: &str) -> Result<(), Box<dyn std::error::Er
 }
 
 static ATTENTION_MODEL: Lazy<Result<Arc<Mutex<AttentionCalculator>>, Box<dyn Error + Send + Sync>>> = Lazy::new(|| {
-    let home_dir = home_dir().ok_or_else(|| anyhow::anyhow!("Failed to retrieve home directory"))?;
-    let model_dir = home_dir.join(".pyano/models");
+    let model_dir = shared_data_dir("models");
 
     // Ensure the model directory exists
     fs::create_dir_all(&model_dir)?;
@@ -1,26 +1,50 @@
-use actix_web::{ post, get, web, delete, HttpRequest, HttpResponse, Error };
+use actix_web::{ post, get, web, delete, HttpResponse, Error };
 use serde::{ Deserialize, Serialize };
-use crate::authentication::authorization::is_request_allowed;
+use crate::authentication::authorization::User;
 use log::{ info, warn };
-use crate::session_manager::check_session;
+use crate::session_manager::{ check_session, owned_session };
 use serde_json::json;
 use crate::context::store_text_context::index_code;
+use crate::context::index_filter::{ FileFilter, IndexOptions };
 use crate::parser::parse_code::Chunk;
-use crate::database::db_config::DB_INSTANCE;
+use crate::database::db_config::run_db;
 use crate::embeddings::text_embeddings::generate_text_embedding;
-use crate::similarity_index::index::{search_index, remove_from_index};
+use crate::similarity_index::index::remove_from_index;
+use crate::similarity_index::hybrid::hybrid_search_code;
+use crate::rag::watcher::{ list_watchers, unwatch, unwatch_path, watch_directory };
+use crate::server_config::server_config;
+use std::path::Path;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RagRequest {
     pub session_id: Option<String>,
-    pub user_id: Option<String>,
     pub files: Vec<String>,
+    // Globs over paths inside an indexed directory, see `IndexOptions`. Left out, a path is
+    // indexed with the options it was last indexed with.
+    #[serde(default)]
+    pub include: Option<Vec<String>>,
+    #[serde(default)]
+    pub exclude: Option<Vec<String>>,
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+}
+
+impl RagRequest {
+    pub fn index_options(&self) -> Option<IndexOptions> {
+        if self.include.is_none() && self.exclude.is_none() && self.max_file_size.is_none() {
+            return None;
+        }
+        Some(IndexOptions {
+            include: self.include.clone().unwrap_or_default(),
+            exclude: self.exclude.clone().unwrap_or_default(),
+            max_file_size: self.max_file_size,
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeleteRequest {
     pub session_id: Option<String>,
-    pub user_id: Option<String>,
     pub files: Option<Vec<String>>,
 }
 
@@ -28,45 +52,38 @@ pub fn register_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(rag_request)
         .service(get_indexed_context)
         .service(fetch_similar_entries)
-        .service(delete_rag_context); // Register the correct route handler
+        .service(delete_rag_context) // Register the correct route handler
+        .service(search_symbols)
+        .service(get_symbol_references)
+        .service(get_symbol_callers)
+        .service(get_watchers)
+        .service(delete_watcher);
 }
 
 #[post("/rags/index/code")]
 pub async fn rag_request(
     data: web::Json<RagRequest>,
-    req: HttpRequest
+    user: User
 ) -> Result<HttpResponse, Error> {
     info!("Session_id = {:?}", data.session_id.clone());
     info!("Files = {:?}", data.files.clone());
 
     // Check session and extract user ID from the request
-    let session_id = match check_session(data.session_id.clone()) {
-        Ok(id) => id,
-        Err(e) => {
-            return Err(
-                actix_web::error::ErrorInternalServerError(
-                    json!({
-                "error": e.to_string()
-            })
-                )
-            );
-        }
-    };
+    let session_id = check_session(data.session_id.clone(), &user.user_id).await?;
 
-    let user_id: String = match is_request_allowed(req.clone()).await {
-        Ok(Some(user)) => user.user_id.clone(),
-        Ok(None) => data.user_id.clone().unwrap_or_else(|| "user_id".to_string()), // Set default user_id if not present
-        Err(_) => {
-            // Handle the error case by propagating the HttpResponse error
-            return Err(
-                actix_web::error::ErrorInternalServerError(
-                    json!({ "error": "An error occurred during request validation" })
-                )
-            );
+    let user_id = user.user_id.clone();
+
+    let options = data.index_options();
+    if let Some(options) = &options {
+        if let Err(e) = FileFilter::new(Path::new(""), options) {
+            return Ok(HttpResponse::BadRequest().json(json!({ "error": e })));
         }
-    };
+    }
 
-    let entries: Vec<serde_json::Value> = DB_INSTANCE.fetch_session_context_files(&user_id, &session_id);
+    let (owner, session) = (user_id.clone(), session_id.clone());
+    let entries: Vec<serde_json::Value> = run_db(move |db| db.fetch_session_context_files(&owner, &session).map_err(|e| e.to_string()))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
     let indexed_paths: Vec<String> = entries
         .iter()
         .filter_map(|entry| entry.get("path").and_then(|p| p.as_str().map(String::from)))
@@ -74,6 +91,8 @@ pub async fn rag_request(
 
 
     let mut all_indexed_chunks: Vec<Chunk> = Vec::new();
+    // Files added, updated, removed and unchanged per indexed path
+    let mut reports = serde_json::Map::new();
     // Iterate over the files and call `index_code` for each
     for file_path in &data.files {
         // if indexed_paths.contains(file_path) {
@@ -81,9 +100,15 @@ pub async fn rag_request(
         //     continue;
         // }
 
-        match index_code(&user_id, &session_id, file_path).await {
-            Ok(chunks) => {
-                all_indexed_chunks.extend(chunks);
+        match index_code(&user_id, &session_id, file_path, options.clone()).await {
+            Ok(mut report) => {
+                all_indexed_chunks.append(&mut report.chunks);
+                reports.insert(file_path.clone(), json!(report));
+                if server_config().watch_directories && Path::new(file_path).is_dir() {
+                    if let Err(e) = watch_directory(&user_id, &session_id, file_path) {
+                        warn!("{}", e);
+                    }
+                }
             }
             Err(e) => {
                 return Err(
@@ -95,7 +120,7 @@ pub async fn rag_request(
 
     let data =
         json!({
-            "message": { "session_id": session_id, "indexed_files": data.files}
+            "message": { "session_id": session_id, "indexed_files": data.files, "files": reports }
         });
 
     Ok(
@@ -108,7 +133,7 @@ pub async fn rag_request(
 #[delete("/rags/index/code")]
 pub async fn delete_rag_context(
     data: web::Json<DeleteRequest>,
-    req: HttpRequest
+    user: User
 ) -> Result<HttpResponse, Error> {
     // Check if session_id, user_id, or files are missing or empty
     if
@@ -141,42 +166,30 @@ pub async fn delete_rag_context(
     info!("Files = {:?}", data.files.clone());
 
     // Check session and extract user ID from the request
-    let session_id = match check_session(data.session_id.clone()) {
-        Ok(id) => id,
-        Err(e) => {
-            return Err(
-                actix_web::error::ErrorInternalServerError(json!({ "error": e.to_string() }))
-            );
-        }
-    };
+    let session_id = check_session(data.session_id.clone(), &user.user_id).await?;
 
-    let user_id: String = match is_request_allowed(req.clone()).await {
-        Ok(Some(user)) => user.user_id.clone(),
-        Ok(None) => data.user_id.clone().unwrap_or_else(|| "user_id".to_string()), // Set default user_id if not present
-        Err(_) => {
-            // Handle the error case by propagating the HttpResponse error
-            return Err(
-                actix_web::error::ErrorInternalServerError(
-                    json!({ "error": "An error occurred during request validation" })
-                )
-            );
-        }
-    };
+    let user_id = user.user_id.clone();
 
     // Iterate over the files and call `delete_indexed_code` for each file path
     for file_path in data.files.as_ref().unwrap() {
-        match DB_INSTANCE.delete_parent_context(file_path) {
-            Ok(_) => {
+        let (owner, session, parent) = (user_id.clone(), session_id.clone(), file_path.clone());
+        let deleted = run_db(move |db| {
+            db.delete_parent_context(&owner, &session, &parent)
+                .map(|_| db.delete_children_context_by_parent_path(&owner, &session, &parent))
+        }).await?;
+        let deleted = match deleted {
+            Ok(deleted) => {
                 info!("Successfully deleted parent context for file: {:?}", file_path);
+                deleted
             }
             Err(e) => {
                 return Err(
                     actix_web::error::ErrorInternalServerError(json!({"error": e.to_string() }))
                 );
             }
-        }
+        };
 
-        let vec_row_ids = match DB_INSTANCE.delete_children_context_by_parent_path(&user_id, &session_id, file_path) {
+        let vec_row_ids = match deleted {
             Ok(ids) => {
                 info!("Successfully deleted chunks for file: {}", file_path);
                 ids
@@ -189,6 +202,7 @@ pub async fn delete_rag_context(
         };
         info!("vec_row_ids that awere deleted from sqlite {:?}", vec_row_ids);
         remove_from_index(&session_id, vec_row_ids);
+        unwatch_path(&session_id, file_path);
     }
 
     Ok(
@@ -201,11 +215,10 @@ pub async fn delete_rag_context(
 #[derive(Deserialize)]
 struct QueryParams {
     session_id: Option<String>, // Make session_id an Option to handle missing field
-    user_id: Option<String>,
 }
 
 #[get("/rags/index/code")]
-async fn get_indexed_context(query: web::Query<QueryParams>) -> Result<HttpResponse, Error> {
+async fn get_indexed_context(query: web::Query<QueryParams>, user: User) -> Result<HttpResponse, Error> {
     // Check if session_id is empty and return an error if it is
     if query.session_id.is_none() {
         return Ok(
@@ -217,9 +230,13 @@ async fn get_indexed_context(query: web::Query<QueryParams>) -> Result<HttpRespo
         );
     }
 
-    let session_id = query.session_id.as_ref().unwrap(); // Safe
-    let user_id = query.user_id.as_deref().unwrap_or("user_id");
-    let entries = DB_INSTANCE.fetch_session_context_files(user_id, session_id);
+    let session_id = query.session_id.clone().unwrap(); // Safe
+    let owned_session_id = session_id.clone();
+    let entries = run_db(move |db| {
+        db.fetch_session_context_files(&user.user_id, &owned_session_id).map_err(|e| e.to_string())
+    })
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
 
     Ok(
         HttpResponse::Ok()
@@ -237,7 +254,7 @@ struct FetchContextRequest {
 #[post("/rags/index/fetch-context")]
 async fn fetch_similar_entries(
     data: web::Json<FetchContextRequest>,
-    _req: HttpRequest
+    user: User
 ) -> Result<HttpResponse, Error> {
     if data.session_id.is_empty() {
         return Ok(
@@ -259,6 +276,19 @@ async fn fetch_similar_entries(
         );
     }
 
+    let (owned_session_id, user_id) = (data.session_id.clone(), user.user_id.clone());
+    match run_db(move |_| owned_session(&owned_session_id, &user_id)).await? {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return Ok(
+                HttpResponse::NotFound().json(json!({"error": format!("Session {} not found", data.session_id)}))
+            );
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()})));
+        }
+    }
+
     let query = &data.query;
 
     let embeddings_result = generate_text_embedding(&query).await;
@@ -276,9 +306,12 @@ async fn fetch_similar_entries(
         }
     };
 
-    let chunk_ids = search_index(&data.session_id, query_embeddings.clone(), 10);
+    // Nearest chunks and exact matches on identifiers, paths and code, fused
+    let chunk_ids = hybrid_search_code(vec![data.session_id.clone()], query, query_embeddings, 10).await;
 
-    let entries = DB_INSTANCE.get_row_ids(chunk_ids).unwrap();
+    let entries = run_db(move |db| db.get_row_ids(chunk_ids).map_err(|e| e.to_string()))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
     info!("All the matching entries {:?}", entries);
 
 
@@ -293,4 +326,154 @@ async fn fetch_similar_entries(
     })
             )
     )
-}
\ No newline at end of file
+}
+// Symbol lookups return at most this many rows unless the request asks for fewer or more
+const DEFAULT_SYMBOL_LIMIT: usize = 50;
+
+#[derive(Deserialize)]
+struct SymbolSearchParams {
+    session_id: Option<String>,
+    name: Option<String>,
+    kind: Option<String>,
+    limit: Option<usize>,
+}
+
+#[get("/rags/symbols")]
+async fn search_symbols(query: web::Query<SymbolSearchParams>, user: User) -> Result<HttpResponse, Error> {
+    let (session_id, name) = match (query.session_id.clone(), query.name.clone()) {
+        (Some(session_id), Some(name)) if !session_id.is_empty() && !name.is_empty() => (session_id, name),
+        _ => {
+            return Ok(
+                HttpResponse::BadRequest().json(json!({ "error": "session_id and name are required" }))
+            );
+        }
+    };
+
+    let (owned_session_id, user_id) = (session_id.clone(), user.user_id.clone());
+    match run_db(move |_| owned_session(&owned_session_id, &user_id)).await? {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return Ok(
+                HttpResponse::NotFound().json(json!({"error": format!("Session {} not found", session_id)}))
+            );
+        }
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()})));
+        }
+    }
+
+    let (owned_session_id, kind) = (session_id.clone(), query.kind.clone());
+    let limit = query.limit.unwrap_or(DEFAULT_SYMBOL_LIMIT);
+    let symbols = run_db(move |db| db.find_symbols(&owned_session_id, &name, kind.as_deref(), limit))
+        .await?
+        .map_err(|e| actix_web::error::ErrorInternalServerError(json!({ "error": e.to_string() })))?;
+
+    Ok(
+        HttpResponse::Ok()
+            .insert_header(("X-Session-Id", session_id.clone()))
+            .json(json!({ "data": symbols }))
+    )
+}
+
+#[derive(Deserialize)]
+struct SymbolRelationParams {
+    limit: Option<usize>,
+}
+
+/// The symbol, if it exists and its session belongs to the user
+async fn owned_symbol(symbol_id: &str, user_id: &str) -> Result<Option<serde_json::Value>, Error> {
+    let (symbol_id, user_id) = (symbol_id.to_string(), user_id.to_string());
+    run_db(move |db| {
+        let symbol = match db.get_symbol(&symbol_id).map_err(|e| e.to_string())? {
+            Some(symbol) => symbol,
+            None => {
+                return Ok(None);
+            }
+        };
+        let session_id = symbol["session_id"].as_str().unwrap_or_default().to_string();
+        match owned_session(&session_id, &user_id).map_err(|e| e.to_string())? {
+            Some(_) => Ok(Some(symbol)),
+            None => Ok(None),
+        }
+    }).await?
+        .map_err(|e: String| actix_web::error::ErrorInternalServerError(json!({ "error": e })))
+}
+
+#[get("/rags/symbols/{symbol_id}/references")]
+async fn get_symbol_references(
+    path: web::Path<String>,
+    query: web::Query<SymbolRelationParams>,
+    user: User
+) -> Result<HttpResponse, Error> {
+    let symbol_id = path.into_inner();
+    let symbol = match owned_symbol(&symbol_id, &user.user_id).await? {
+        Some(symbol) => symbol,
+        None => {
+            return Ok(
+                HttpResponse::NotFound().json(json!({"error": format!("Symbol {} not found", symbol_id)}))
+            );
+        }
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_SYMBOL_LIMIT);
+    let references = run_db(move |db| db.symbol_references(&symbol_id, limit))
+        .await?
+        .map_err(|e| actix_web::error::ErrorInternalServerError(json!({ "error": e.to_string() })))?;
+
+    Ok(HttpResponse::Ok().json(json!({ "symbol": symbol, "data": references })))
+}
+
+#[get("/rags/symbols/{symbol_id}/callers")]
+async fn get_symbol_callers(
+    path: web::Path<String>,
+    query: web::Query<SymbolRelationParams>,
+    user: User
+) -> Result<HttpResponse, Error> {
+    let symbol_id = path.into_inner();
+    let symbol = match owned_symbol(&symbol_id, &user.user_id).await? {
+        Some(symbol) => symbol,
+        None => {
+            return Ok(
+                HttpResponse::NotFound().json(json!({"error": format!("Symbol {} not found", symbol_id)}))
+            );
+        }
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_SYMBOL_LIMIT);
+    let callers = run_db(move |db| db.symbol_callers(&symbol_id, limit))
+        .await?
+        .map_err(|e| actix_web::error::ErrorInternalServerError(json!({ "error": e.to_string() })))?;
+
+    Ok(HttpResponse::Ok().json(json!({ "symbol": symbol, "data": callers })))
+}
+
+#[get("/rags/watchers")]
+async fn get_watchers(user: User) -> Result<HttpResponse, Error> {
+    Ok(
+        HttpResponse::Ok().json(
+            json!({
+            "enabled": server_config().watch_directories,
+            "data": list_watchers(&user.user_id)
+        })
+        )
+    )
+}
+
+#[delete("/rags/watchers/{watcher_id}")]
+async fn delete_watcher(path: web::Path<String>, user: User) -> Result<HttpResponse, Error> {
+    let watcher_id = path.into_inner();
+    // Other users' watchers look the same as missing ones
+    let owned = list_watchers(&user.user_id)
+        .iter()
+        .any(|watcher| watcher.id == watcher_id);
+    if !owned {
+        return Ok(
+            HttpResponse::NotFound().json(json!({"error": format!("Watcher {} not found", watcher_id)}))
+        );
+    }
+
+    match unwatch(&watcher_id) {
+        Some(watcher) => Ok(HttpResponse::Ok().json(json!({ "message": "Watcher stopped", "watcher": watcher }))),
+        None => Ok(HttpResponse::NotFound().json(json!({"error": format!("Watcher {} not found", watcher_id)}))),
+    }
+}
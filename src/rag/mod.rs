@@ -1 +1,2 @@
-pub mod code_rag_api;
\ No newline at end of file
+pub mod code_rag_api;
+pub mod watcher;
\ No newline at end of file
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::sync::Mutex;
+use std::time::Duration;
+use chrono::Utc;
+use log::{ error, info, warn };
+use notify::{ Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher };
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::mpsc::{ unbounded_channel, UnboundedReceiver };
+use tokio::task::JoinHandle;
+use ignore::gitignore::Gitignore;
+use uuid::Uuid;
+use crate::context::index_filter::{ is_root_ignore_file, root_ignore_matcher };
+use crate::context::store_text_context::{ index_code, is_excluded_directory };
+use crate::database::db_config::run_db;
+use crate::server_config::server_config;
+
+/// A watched directory, as listed by `GET /rags/watchers`
+#[derive(Debug, Clone, Serialize)]
+pub struct WatcherInfo {
+    pub id: String,
+    pub user_id: String,
+    pub session_id: String,
+    pub path: String,
+    pub started_at: String,
+    pub last_indexed_at: Option<String>,
+    // Counts of the last re-index, see `IndexReport`
+    pub last_report: Option<Value>,
+    pub last_error: Option<String>,
+}
+
+struct WatchEntry {
+    info: WatcherInfo,
+    // Dropping the watcher stops the events
+    _watcher: RecommendedWatcher,
+    task: JoinHandle<()>,
+}
+
+static WATCHERS: Lazy<Mutex<HashMap<String, WatchEntry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Whether an event can change what is indexed: reads don't, neither do writes that only
+/// touch excluded directories such as `target` or `.git`, or paths the root ignore files skip
+fn is_relevant(event: &Event, root: &Path, ignored: &Gitignore) -> bool {
+    if matches!(event.kind, EventKind::Access(_)) {
+        return false;
+    }
+    event.paths.iter().any(|path| {
+        let relative = match path.strip_prefix(root) {
+            Ok(relative) => relative,
+            // Not under the root as given, can't tell
+            Err(_) => {
+                return true;
+            }
+        };
+        if relative.components().any(|component| is_excluded_directory(&component.as_os_str().to_string_lossy())) {
+            return false;
+        }
+        !ignored.matched_path_or_any_parents(relative, path.is_dir()).is_ignore()
+    })
+}
+
+/// Starts watching an indexed local directory, or returns the watcher it already has
+pub fn watch_directory(user_id: &str, session_id: &str, path: &str) -> Result<WatcherInfo, String> {
+    let mut watchers = WATCHERS.lock().unwrap();
+    if let Some(entry) = watchers.values().find(|entry| entry.info.session_id == session_id && entry.info.path == path) {
+        return Ok(entry.info.clone());
+    }
+
+    let root = PathBuf::from(path);
+    let (tx, rx) = unbounded_channel();
+    let event_root = root.clone();
+    let mut ignored = root_ignore_matcher(&root);
+    let mut watcher = notify
+        ::recommended_watcher(move |result: notify::Result<Event>| {
+            match result {
+                Ok(event) => {
+                    if event.paths.iter().any(|path| is_root_ignore_file(&event_root, path)) {
+                        ignored = root_ignore_matcher(&event_root);
+                    }
+                    if is_relevant(&event, &event_root, &ignored) {
+                        // Only fails once the re-index task is gone
+                        let _ = tx.send(());
+                    }
+                }
+                Err(e) => error!("Watch error for {}: {}", event_root.display(), e),
+            }
+        })
+        .map_err(|e| format!("Failed to create a watcher for {}: {}", path, e))?;
+    watcher.watch(&root, RecursiveMode::Recursive).map_err(|e| format!("Failed to watch {}: {}", path, e))?;
+
+    let info = WatcherInfo {
+        id: Uuid::new_v4().to_string(),
+        user_id: user_id.to_string(),
+        session_id: session_id.to_string(),
+        path: path.to_string(),
+        started_at: Utc::now().to_rfc3339(),
+        last_indexed_at: None,
+        last_report: None,
+        last_error: None,
+    };
+    let debounce = Duration::from_millis(server_config().watch_debounce_ms);
+    let task = tokio::spawn(reindex_on_changes(info.clone(), rx, debounce));
+    info!("Watching {} for session {}", path, session_id);
+    watchers.insert(info.id.clone(), WatchEntry { info: info.clone(), _watcher: watcher, task });
+    Ok(info)
+}
+
+/// Waits for changes to settle for `debounce`, then re-indexes the directory incrementally.
+/// Changes made while it indexes trigger another pass.
+async fn reindex_on_changes(watched: WatcherInfo, mut changes: UnboundedReceiver<()>, debounce: Duration) {
+    while changes.recv().await.is_some() {
+        loop {
+            match tokio::time::timeout(debounce, changes.recv()).await {
+                Ok(Some(())) => {
+                    continue;
+                }
+                Ok(None) => {
+                    return;
+                }
+                Err(_) => {
+                    break;
+                }
+            }
+        }
+
+        // The directory's index was deleted, nothing left to keep live
+        let (owner, session, parent) = (watched.user_id.clone(), watched.session_id.clone(), watched.path.clone());
+        match run_db(move |db| db.fetch_path_session(&owner, &session, &parent)).await {
+            Ok(Ok(Some(_))) => {}
+            Ok(Ok(None)) => {
+                info!("{} is no longer indexed, stopping its watcher", watched.path);
+                unwatch(&watched.id);
+                return;
+            }
+            Ok(Err(e)) => {
+                error!("Failed to look up the index of {}: {}", watched.path, e);
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to look up the index of {}: {}", watched.path, e);
+                continue;
+            }
+        }
+        if !Path::new(&watched.path).is_dir() {
+            warn!("Watched directory {} is gone, not re-indexing", watched.path);
+            continue;
+        }
+
+        // In a task of its own, so a panic while indexing is reported instead of ending the watcher
+        let (user_id, session_id, path) = (watched.user_id.clone(), watched.session_id.clone(), watched.path.clone());
+        let indexing = tokio::spawn(async move {
+            index_code(&user_id, &session_id, &path, None).await.map_err(|e| e.to_string())
+        });
+        let result = match indexing.await {
+            Ok(result) => result,
+            Err(e) => Err(format!("Re-indexing failed: {}", e)),
+        };
+        let mut watchers = WATCHERS.lock().unwrap();
+        let entry = match watchers.get_mut(&watched.id) {
+            Some(entry) => entry,
+            None => {
+                return;
+            }
+        };
+        entry.info.last_indexed_at = Some(Utc::now().to_rfc3339());
+        match result {
+            Ok(report) => {
+                entry.info.last_report = serde_json::to_value(&report).ok();
+                entry.info.last_error = None;
+            }
+            Err(e) => {
+                error!("Failed to re-index watched directory {}: {}", watched.path, e);
+                entry.info.last_error = Some(e);
+            }
+        }
+    }
+}
+
+/// Stops a watcher, returns it if there was one
+pub fn unwatch(id: &str) -> Option<WatcherInfo> {
+    let entry = WATCHERS.lock().unwrap().remove(id)?;
+    entry.task.abort();
+    info!("Stopped watching {} for session {}", entry.info.path, entry.info.session_id);
+    Some(entry.info)
+}
+
+/// Stops the watcher of a session's directory, if it has one
+pub fn unwatch_path(session_id: &str, path: &str) {
+    let id = WATCHERS.lock()
+        .unwrap()
+        .values()
+        .find(|entry| entry.info.session_id == session_id && entry.info.path == path)
+        .map(|entry| entry.info.id.clone());
+    if let Some(id) = id {
+        unwatch(&id);
+    }
+}
+
+/// Stops every watcher of a session, when it's deleted
+pub fn unwatch_session(session_id: &str) {
+    let ids: Vec<String> = WATCHERS.lock()
+        .unwrap()
+        .values()
+        .filter(|entry| entry.info.session_id == session_id)
+        .map(|entry| entry.info.id.clone())
+        .collect();
+    for id in ids {
+        unwatch(&id);
+    }
+}
+
+pub fn list_watchers(user_id: &str) -> Vec<WatcherInfo> {
+    let mut watchers: Vec<WatcherInfo> = WATCHERS.lock()
+        .unwrap()
+        .values()
+        .filter(|entry| entry.info.user_id == user_id)
+        .map(|entry| entry.info.clone())
+        .collect();
+    watchers.sort_by(|a, b| a.started_at.cmp(&b.started_at));
+    watchers
+}
+
+/// Watches every indexed local directory that still exists, when `watch_directories` is on
+pub async fn start_watchers() {
+    if !server_config().watch_directories {
+        return;
+    }
+    let directories = match run_db(|db| db.fetch_local_directories()).await {
+        Ok(Ok(directories)) => directories,
+        Ok(Err(e)) => {
+            error!("Failed to list indexed directories to watch: {}", e);
+            return;
+        }
+        Err(e) => {
+            error!("Failed to list indexed directories to watch: {}", e);
+            return;
+        }
+    };
+    for (user_id, session_id, path) in directories {
+        if !Path::new(&path).is_dir() {
+            warn!("Indexed directory {} doesn't exist anymore, not watching it", path);
+            continue;
+        }
+        if let Err(e) = watch_directory(&user_id, &session_id, &path) {
+            error!("{}", e);
+        }
+    }
+}
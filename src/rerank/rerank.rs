@@ -1,6 +1,6 @@
 use std::error::Error;
 use log::error;
-use dirs::home_dir;
+use crate::server_config::shared_data_dir;
 use std::path::PathBuf;
 use fastembed::{TextRerank, RerankInitOptions, RerankerModel, RerankResult};
 use std::sync::{Mutex, Arc};
@@ -12,9 +12,7 @@ pub struct RerankManager {
 }
 impl RerankManager {
     // Constructor to create a new instance of EmbeddingsManager
-    pub fn new(save_path: &str) -> Self {
-        let home_dir = home_dir().expect("Unable to get home directory");
-        let save_path = home_dir.join(save_path);
+    pub fn new(save_path: PathBuf) -> Self {
         Self {
             save_path,
             model: None,
@@ -45,18 +43,12 @@ impl RerankManager {
 }
 
 static RERANK_MANAGER: Lazy<Result<Arc<Mutex<RerankManager>>, Box<dyn Error + Send + Sync>>> = Lazy::new(|| {
-    let home_dir = home_dir().expect("Failed to retrieve home directory");
-    let rerank_dir = home_dir.join(".pyano/models/reranker");
+    let rerank_dir = shared_data_dir("models").join("reranker");
 
     // Ensure the model directory exists
-    std::fs::create_dir_all(&rerank_dir).expect("Failed to create model directory");
+    std::fs::create_dir_all(&rerank_dir).map_err(|e| format!("Failed to create model directory: {}", e))?;
 
-    let rerank_dir_str = rerank_dir
-        .to_str()
-        .expect("Failed to convert PathBuf to str")
-        .to_string();
-
-    let mut model_manager: RerankManager = RerankManager::new(&rerank_dir_str);
+    let mut model_manager: RerankManager = RerankManager::new(rerank_dir);
     model_manager.load_model().map_err(|e| format!("Failed to load Reranker model: {}", e))?;
     Ok(Arc::new(Mutex::new(model_manager)))
 });
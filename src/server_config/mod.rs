@@ -0,0 +1,365 @@
+pub mod server_config_api;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use clap::Parser;
+use once_cell::sync::OnceCell;
+use serde::{ Deserialize, Serialize };
+
+static SERVER_CONFIG: OnceCell<ServerConfig> = OnceCell::new();
+
+pub const DEFAULT_PROFILE: &str = "default";
+const PROFILES_DIR: &str = "profiles";
+
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+/// Command line flags, they win over the environment and server.toml
+#[derive(Parser, Debug, Default)]
+#[command(name = "pyano_server", version, about = "Pyano local AI server")]
+pub struct Cli {
+    /// Config file to read instead of ~/.pyano/configs/server.toml
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(long)]
+    pub bind: Option<String>,
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Where databases and indexes are stored
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+    /// Keeps databases and indexes apart from other profiles, e.g. `work` or `oss`
+    #[arg(long)]
+    pub profile: Option<String>,
+    /// off, error, warn, info, debug or trace
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Report which database migrations are pending, try them on copies and exit
+    #[arg(long)]
+    pub migrate_dry_run: bool,
+}
+
+/// LLM servers and sampling defaults
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LlmConfig {
+    /// Default backend, None picks llamacpp locally and openai in cloud mode
+    pub backend: Option<String>,
+    /// Backend per route, e.g. FIND_BUGS = "ollama"
+    pub routes: HashMap<String, String>,
+    pub local_url: String,
+    pub infill_local_url: String,
+    pub remote_url: String,
+    pub remote_model: String,
+    #[serde(skip_serializing)]
+    pub cloud_api_key: String,
+    pub ollama_url: String,
+    pub ollama_model: String,
+    pub temperature: f64,
+    pub top_k: i64,
+    pub top_p: f64,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        LlmConfig {
+            backend: None,
+            routes: HashMap::new(),
+            local_url: "http://localhost:52555".to_string(),
+            infill_local_url: "http://localhost:52554".to_string(),
+            remote_url: "http://localhost:8000".to_string(),
+            remote_model: "meta-llama/Meta-Llama-3.1-8B-Instruct-Turbo".to_string(),
+            cloud_api_key: "none".to_string(),
+            ollama_url: "http://localhost:11434".to_string(),
+            ollama_model: "qwen2.5-coder:7b".to_string(),
+            temperature: 0.7,
+            top_k: 20,
+            top_p: 0.8,
+        }
+    }
+}
+
+/// Everything the server reads at startup. Built from defaults, then server.toml, then
+/// environment variables (the `.env` names the server always used), then CLI flags.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub port: u16,
+    pub data_dir: PathBuf,
+    /// None is the default profile, stored directly under data_dir
+    pub profile: Option<String>,
+    pub log_level: String,
+    pub cloud_execution_mode: bool,
+    pub require_api_key: bool,
+    /// Copy a database to <profile>/database/backups before migrating it
+    pub backup_before_migrate: bool,
+    /// Connections kept open per database, readers run concurrently, writers take turns
+    pub db_pool_size: u32,
+    /// Default token quotas, None or 0 means unlimited
+    pub daily_token_quota: Option<i64>,
+    pub monthly_token_quota: Option<i64>,
+    /// Re-index indexed local directories when their files change
+    pub watch_directories: bool,
+    /// Quiet time after the last change before a watched directory is re-indexed
+    pub watch_debounce_ms: u64,
+    pub llm: LlmConfig,
+    /// File the config was read from, if there was one
+    #[serde(skip_deserializing)]
+    pub config_file: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: "localhost".to_string(),
+            port: 52556,
+            data_dir: default_data_dir(),
+            profile: None,
+            log_level: "info".to_string(),
+            cloud_execution_mode: false,
+            require_api_key: false,
+            backup_before_migrate: true,
+            db_pool_size: 8,
+            daily_token_quota: None,
+            monthly_token_quota: None,
+            watch_directories: false,
+            watch_debounce_ms: 2000,
+            llm: LlmConfig::default(),
+            config_file: None,
+        }
+    }
+}
+
+fn default_data_dir() -> PathBuf {
+    dirs::home_dir().unwrap_or_default().join(".pyano")
+}
+
+pub fn default_config_file() -> PathBuf {
+    default_data_dir().join("configs").join("server.toml")
+}
+
+// Overwrites `target` when the variable is set, remembers values that don't parse
+fn env_override<T: FromStr>(name: &str, target: &mut T, errors: &mut Vec<String>) {
+    if let Ok(value) = env::var(name) {
+        match value.parse::<T>() {
+            Ok(parsed) => {
+                *target = parsed;
+            }
+            Err(_) => errors.push(format!("{}: invalid value '{}'", name, value)),
+        }
+    }
+}
+
+fn env_override_option<T: FromStr>(name: &str, target: &mut Option<T>, errors: &mut Vec<String>) {
+    if let Ok(value) = env::var(name) {
+        match value.parse::<T>() {
+            Ok(parsed) => {
+                *target = Some(parsed);
+            }
+            Err(_) => errors.push(format!("{}: invalid value '{}'", name, value)),
+        }
+    }
+}
+
+// Switches set in the environment before server.toml existed used any of these spellings
+fn env_override_bool(name: &str, target: &mut bool, errors: &mut Vec<String>) {
+    if let Ok(value) = env::var(name) {
+        match value.trim().to_lowercase().as_str() {
+            "true" | "1" | "yes" | "on" => {
+                *target = true;
+            }
+            "false" | "0" | "no" | "off" | "" => {
+                *target = false;
+            }
+            _ => errors.push(format!("{}: invalid value '{}'", name, value)),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the config file, the environment and the flags and validates the result
+    pub fn load(cli: &Cli) -> Result<ServerConfig, String> {
+        let config_file = cli.config.clone().unwrap_or_else(default_config_file);
+        let mut config = if config_file.exists() {
+            let raw = fs
+                ::read_to_string(&config_file)
+                .map_err(|e| format!("Failed to read {}: {}", config_file.display(), e))?;
+            let mut config: ServerConfig = toml
+                ::from_str(&raw)
+                .map_err(|e| format!("Invalid config {}: {}", config_file.display(), e))?;
+            config.config_file = Some(config_file);
+            config
+        } else if cli.config.is_some() {
+            return Err(format!("Config file {} not found", config_file.display()));
+        } else {
+            ServerConfig::default()
+        };
+
+        let mut errors = Vec::new();
+        config.apply_env(&mut errors);
+
+        if let Some(bind) = &cli.bind {
+            config.bind = bind.clone();
+        }
+        if let Some(port) = cli.port {
+            config.port = port;
+        }
+        if let Some(data_dir) = &cli.data_dir {
+            config.data_dir = data_dir.clone();
+        }
+        if let Some(profile) = &cli.profile {
+            config.profile = Some(profile.clone());
+        }
+        if let Some(log_level) = &cli.log_level {
+            config.log_level = log_level.clone();
+        }
+
+        config.validate(&mut errors);
+        if !errors.is_empty() {
+            return Err(format!("Invalid server configuration:\n  {}", errors.join("\n  ")));
+        }
+        Ok(config)
+    }
+
+    fn apply_env(&mut self, errors: &mut Vec<String>) {
+        env_override("PYANO_BIND", &mut self.bind, errors);
+        env_override("PYANO_PORT", &mut self.port, errors);
+        env_override("PYANO_DATA_DIR", &mut self.data_dir, errors);
+        env_override_option("PYANO_PROFILE", &mut self.profile, errors);
+        env_override("PYANO_LOG_LEVEL", &mut self.log_level, errors);
+        env_override_bool("CLOUD_EXECUTION_MODE", &mut self.cloud_execution_mode, errors);
+        env_override_bool("REQUIRE_API_KEY", &mut self.require_api_key, errors);
+        env_override_bool("PYANO_BACKUP_BEFORE_MIGRATE", &mut self.backup_before_migrate, errors);
+        env_override("PYANO_DB_POOL_SIZE", &mut self.db_pool_size, errors);
+        env_override_option("DAILY_TOKEN_QUOTA", &mut self.daily_token_quota, errors);
+        env_override_option("MONTHLY_TOKEN_QUOTA", &mut self.monthly_token_quota, errors);
+        env_override_bool("PYANO_WATCH_DIRECTORIES", &mut self.watch_directories, errors);
+        env_override("PYANO_WATCH_DEBOUNCE_MS", &mut self.watch_debounce_ms, errors);
+        env_override_option("LLM_BACKEND", &mut self.llm.backend, errors);
+        env_override("LOCAL_URL", &mut self.llm.local_url, errors);
+        env_override("INFILL_LOCAL_URL", &mut self.llm.infill_local_url, errors);
+        env_override("REMOTE_URL", &mut self.llm.remote_url, errors);
+        env_override("REMOTE_MODEL", &mut self.llm.remote_model, errors);
+        env_override("CLOUD_API_KEY", &mut self.llm.cloud_api_key, errors);
+        env_override("OLLAMA_URL", &mut self.llm.ollama_url, errors);
+        env_override("OLLAMA_MODEL", &mut self.llm.ollama_model, errors);
+        env_override("TEMPERATURE", &mut self.llm.temperature, errors);
+        env_override("TOP_K", &mut self.llm.top_k, errors);
+        env_override("TOP_P", &mut self.llm.top_p, errors);
+    }
+
+    fn validate(&mut self, errors: &mut Vec<String>) {
+        if self.bind.trim().is_empty() {
+            errors.push("bind: must not be empty".to_string());
+        }
+        if self.port == 0 {
+            errors.push("port: must be between 1 and 65535".to_string());
+        }
+        if self.db_pool_size == 0 {
+            errors.push("db_pool_size: must be at least 1".to_string());
+        }
+        if self.watch_debounce_ms == 0 {
+            errors.push("watch_debounce_ms: must be at least 1".to_string());
+        }
+        self.log_level = self.log_level.to_lowercase();
+        if !LOG_LEVELS.contains(&self.log_level.as_str()) {
+            errors.push(format!("log_level: '{}' is not one of {}", self.log_level, LOG_LEVELS.join(", ")));
+        }
+        if self.profile.as_deref() == Some(DEFAULT_PROFILE) {
+            self.profile = None;
+        }
+        if let Some(profile) = &self.profile {
+            if !is_valid_profile_name(profile) {
+                errors.push(format!("profile: '{}' may only contain letters, digits, '-' and '_'", profile));
+            }
+        }
+        for dir in [self.data_dir.clone(), self.profile_dir()] {
+            if let Err(e) = fs::create_dir_all(&dir) {
+                errors.push(format!("data_dir: can't create {}: {}", dir.display(), e));
+            }
+        }
+        for (name, url) in [
+            ("llm.local_url", &self.llm.local_url),
+            ("llm.infill_local_url", &self.llm.infill_local_url),
+            ("llm.remote_url", &self.llm.remote_url),
+            ("llm.ollama_url", &self.llm.ollama_url),
+        ] {
+            if url::Url::parse(url).is_err() {
+                errors.push(format!("{}: '{}' is not a valid URL", name, url));
+            }
+        }
+        if !(0.0..=2.0).contains(&self.llm.temperature) {
+            errors.push(format!("llm.temperature: {} is not between 0 and 2", self.llm.temperature));
+        }
+        if !(0.0..=1.0).contains(&self.llm.top_p) {
+            errors.push(format!("llm.top_p: {} is not between 0 and 1", self.llm.top_p));
+        }
+        if self.llm.top_k < 0 {
+            errors.push(format!("llm.top_k: {} must not be negative", self.llm.top_k));
+        }
+        // 0 is documented as unlimited, same as leaving it out
+        self.daily_token_quota = self.daily_token_quota.filter(|quota| *quota > 0);
+        self.monthly_token_quota = self.monthly_token_quota.filter(|quota| *quota > 0);
+    }
+
+    pub fn address(&self) -> (String, u16) {
+        (self.bind.clone(), self.port)
+    }
+
+    /// Root of the active profile's databases and indexes
+    pub fn profile_dir(&self) -> PathBuf {
+        match &self.profile {
+            Some(profile) => self.data_dir.join(PROFILES_DIR).join(profile),
+            None => self.data_dir.clone(),
+        }
+    }
+
+    pub fn profile_name(&self) -> &str {
+        self.profile.as_deref().unwrap_or(DEFAULT_PROFILE)
+    }
+}
+
+pub fn is_valid_profile_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Directory under the data root shared by every profile: models, parsers, scripts, configs
+pub fn shared_data_dir(name: &str) -> PathBuf {
+    server_config().data_dir.join(name)
+}
+
+/// Directory of the active profile, used for databases and indexes
+pub fn profile_data_dir(name: &str) -> PathBuf {
+    server_config().profile_dir().join(name)
+}
+
+/// Profiles that have data on disk, the default one always exists
+pub fn list_profiles() -> Vec<String> {
+    let mut profiles = vec![DEFAULT_PROFILE.to_string()];
+    if let Ok(entries) = fs::read_dir(server_config().data_dir.join(PROFILES_DIR)) {
+        let mut named: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| is_valid_profile_name(name))
+            .collect();
+        named.sort();
+        profiles.extend(named);
+    }
+    profiles
+}
+
+/// Makes `config` the one returned by `server_config()`. Only the first call wins.
+pub fn init_server_config(config: ServerConfig) -> &'static ServerConfig {
+    SERVER_CONFIG.get_or_init(|| config)
+}
+
+/// The running server's configuration. Falls back to server.toml and the environment
+/// when main didn't install one, e.g. in tools that only use the database layer.
+pub fn server_config() -> &'static ServerConfig {
+    SERVER_CONFIG.get_or_init(|| ServerConfig::load(&Cli::default()).unwrap_or_default())
+}
@@ -0,0 +1,35 @@
+use actix_web::{ get, web, HttpResponse, Error };
+use crate::authentication::authorization::{ require_admin, User };
+use serde_json::json;
+use super::{ list_profiles, server_config };
+
+pub fn register_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_config).service(get_profiles);
+}
+
+// Read only, changes need a restart. Secrets like the cloud API key are never serialized.
+#[get("/config")]
+async fn get_config(user: User) -> Result<HttpResponse, Error> {
+    if let Some(response) = require_admin(&user) {
+        return Ok(response);
+    }
+    Ok(HttpResponse::Ok().json(server_config()))
+}
+
+// Switching profiles means restarting with --profile or PYANO_PROFILE
+#[get("/profiles")]
+async fn get_profiles(user: User) -> Result<HttpResponse, Error> {
+    if let Some(response) = require_admin(&user) {
+        return Ok(response);
+    }
+    let config = server_config();
+    Ok(
+        HttpResponse::Ok().json(
+            json!({
+            "active": config.profile_name(),
+            "profile_dir": config.profile_dir(),
+            "profiles": list_profiles(),
+        })
+        )
+    )
+}
@@ -0,0 +1,72 @@
+use uuid::Uuid;
+use log::{ debug, error };
+use serde_json::{ json, Value };
+use crate::database::db_config::{ run_db, DB_INSTANCE };
+
+pub mod session_api;
+pub mod session_summary;
+pub mod session_archive;
+
+// Creates and persists a brand new session, returns its id
+pub fn create_new_session(user_id: &str, title: Option<&str>) -> Result<String, actix_web::Error> {
+    let session_id = Uuid::new_v4().to_string();
+    DB_INSTANCE.create_session(&session_id, user_id, title).map_err(|e| {
+        error!("Failed to create session: {}", e);
+        actix_web::error::ErrorInternalServerError(format!("Failed to create session: {}", e))
+    })?;
+    Ok(session_id)
+}
+
+/// The session if it exists and belongs to `user_id`. Someone else's session looks exactly
+/// like a missing one, so ids can't be probed.
+pub fn owned_session(session_id: &str, user_id: &str) -> Result<Option<Value>, rusqlite::Error> {
+    Ok(DB_INSTANCE.get_session(session_id)?.filter(|session| session["user_id"] == user_id))
+}
+
+// Function to check session ID
+// An empty or missing id starts a new session. A known id is bumped to the top of the
+// session list. An id we have never seen (e.g. created by an older client) is adopted
+// so its chats show up under /sessions. Sessions of other users are rejected.
+pub async fn check_session(
+    session_id: Option<String>,
+    user_id: &str
+) -> Result<String, actix_web::Error> {
+    let owner = user_id.to_string();
+    match session_id {
+        Some(id) if !id.is_empty() => {
+            let session_id = id.clone();
+            // false when the session belongs to someone else
+            let owned = run_db(move |db| -> Result<bool, String> {
+                let existing = db
+                    .get_session(&session_id)
+                    .map_err(|e| format!("Failed to load session: {}", e))?;
+
+                match existing {
+                    Some(session) if session["user_id"] != owner => Ok(false),
+                    Some(_) => {
+                        if let Err(e) = db.touch_session(&session_id) {
+                            error!("Failed to update session {}: {}", session_id, e);
+                        }
+                        Ok(true)
+                    }
+                    None => {
+                        debug!("Registering session {} supplied by the client", session_id);
+                        db
+                            .create_session(&session_id, &owner, None)
+                            .map_err(|e| format!("Failed to create session: {}", e))?;
+                        Ok(true)
+                    }
+                }
+            }).await?.map_err(actix_web::error::ErrorInternalServerError)?;
+
+            if !owned {
+                return Err(actix_web::error::ErrorNotFound(json!({"error": format!("Session {} not found", id)})));
+            }
+            Ok(id)
+        }
+        _ =>
+            run_db(move |_| create_new_session(&owner, None).map_err(|e| e.to_string())).await?.map_err(
+                actix_web::error::ErrorInternalServerError
+            ),
+    }
+}
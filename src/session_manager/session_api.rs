@@ -0,0 +1,279 @@
+use actix_web::{ get, post, patch, delete, web, HttpResponse, Error };
+use actix_web::error::ErrorInternalServerError;
+use serde::Deserialize;
+use serde_json::json;
+use log::error;
+use crate::database::db_config::run_db;
+use crate::similarity_index::index::delete_index_file;
+use super::{ create_new_session, owned_session };
+use crate::rag::watcher::unwatch_session;
+use crate::authentication::authorization::User;
+use super::session_summary::refresh_summary;
+use super::session_archive::{ export_session, export_markdown, import_session };
+use reqwest::Client;
+use futures_util::StreamExt;
+
+// Archives carry embeddings for every chat and context chunk, so they can get big
+const MAX_IMPORT_SIZE: usize = 512 * 1024 * 1024;
+
+pub fn register_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_sessions)
+        .service(create_session)
+        .service(get_session)
+        .service(update_session)
+        .service(delete_session)
+        .service(summarize_session)
+        .service(export_session_archive)
+        .service(import_session_archive);
+}
+
+#[derive(Deserialize)]
+struct ListSessionsParams {
+    page: Option<u32>,
+    page_size: Option<u32>,
+    include_archived: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct CreateSessionRequest {
+    title: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UpdateSessionRequest {
+    title: Option<String>,
+    archived: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ExportParams {
+    format: Option<String>,
+}
+
+// Error response when the session is missing or belongs to someone else
+async fn session_not_found(session_id: &str, user: &User) -> Result<Option<HttpResponse>, Error> {
+    let (owner_session_id, user_id) = (session_id.to_string(), user.user_id.clone());
+    Ok(match run_db(move |_| owned_session(&owner_session_id, &user_id)).await? {
+        Ok(Some(_)) => None,
+        Ok(None) =>
+            Some(HttpResponse::NotFound().json(json!({"error": format!("Session {} not found", session_id)}))),
+        Err(e) => Some(HttpResponse::InternalServerError().json(json!({"error": e.to_string()}))),
+    })
+}
+
+#[get("/sessions")]
+async fn list_sessions(query: web::Query<ListSessionsParams>, user: User) -> Result<HttpResponse, Error> {
+    let page = query.page.unwrap_or(1).max(1);
+    let page_size = query.page_size.unwrap_or(10);
+    let skip = (page - 1) * page_size;
+
+    let include_archived = query.include_archived.unwrap_or(false);
+    let listed = run_db(move |db| {
+        let sessions = db.list_sessions(&user.user_id, include_archived, skip, page_size)?;
+        let total = db.count_sessions(&user.user_id, include_archived)?;
+        Ok::<_, rusqlite::Error>((sessions, total))
+    }).await?;
+    match listed {
+        Ok((sessions, total)) =>
+            Ok(
+                HttpResponse::Ok().json(
+                    json!({
+                "result": sessions,
+                "total": total,
+                "page": page,
+                "page_size": page_size
+            })
+                )
+            ),
+        Err(e) => {
+            error!("Failed to list sessions: {}", e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()})))
+        }
+    }
+}
+
+#[post("/sessions")]
+async fn create_session(
+    data: Option<web::Json<CreateSessionRequest>>,
+    user: User
+) -> Result<HttpResponse, Error> {
+    let title = data.and_then(|d| d.into_inner().title);
+    let session_id = run_db(move |_| create_new_session(&user.user_id, title.as_deref()).map_err(|e| e.to_string()))
+        .await?
+        .map_err(ErrorInternalServerError)?;
+
+    let created_id = session_id.clone();
+    match run_db(move |db| db.get_session(&created_id)).await? {
+        Ok(Some(session)) => Ok(HttpResponse::Created().json(session)),
+        Ok(None) => Ok(HttpResponse::Created().json(json!({"id": session_id}))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()}))),
+    }
+}
+
+#[get("/sessions/{session_id}")]
+async fn get_session(path: web::Path<String>, user: User) -> Result<HttpResponse, Error> {
+    let session_id = path.into_inner();
+
+    let owner_session_id = session_id.clone();
+    match run_db(move |_| owned_session(&owner_session_id, &user.user_id)).await? {
+        Ok(Some(session)) => Ok(HttpResponse::Ok().json(session)),
+        Ok(None) =>
+            Ok(HttpResponse::NotFound().json(json!({"error": format!("Session {} not found", session_id)}))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()}))),
+    }
+}
+
+// Rename and/or archive a session
+#[patch("/sessions/{session_id}")]
+async fn update_session(
+    path: web::Path<String>,
+    data: web::Json<UpdateSessionRequest>,
+    user: User
+) -> Result<HttpResponse, Error> {
+    let session_id = path.into_inner();
+    if let Some(response) = session_not_found(&session_id, &user).await? {
+        return Ok(response);
+    }
+
+    let data = data.into_inner();
+    let update_session_id = session_id.clone();
+    let updated = run_db(move |db| {
+        db.update_session(&update_session_id, data.title.as_deref(), data.archived)
+            .map(|updated| updated.then(|| db.get_session(&update_session_id).ok().flatten()))
+    }).await?;
+    match updated {
+        Ok(Some(Some(session))) => Ok(HttpResponse::Ok().json(session)),
+        Ok(Some(None)) => Ok(HttpResponse::Ok().json(json!({"id": session_id}))),
+        Ok(None) =>
+            Ok(HttpResponse::NotFound().json(json!({"error": format!("Session {} not found", session_id)}))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()}))),
+    }
+}
+
+// Deletes the session along with its chats, embeddings, indexed context and index file
+#[delete("/sessions/{session_id}")]
+async fn delete_session(path: web::Path<String>, user: User) -> Result<HttpResponse, Error> {
+    let session_id = path.into_inner();
+    if let Some(response) = session_not_found(&session_id, &user).await? {
+        return Ok(response);
+    }
+
+    let delete_session_id = session_id.clone();
+    match run_db(move |db| db.delete_session(&delete_session_id).map_err(|e| e.to_string())).await? {
+        Ok(true) => {
+            if let Err(e) = delete_index_file(&session_id) {
+                error!("{}", e);
+            }
+            unwatch_session(&session_id);
+            Ok(HttpResponse::Ok().json(json!({"message": "Session deleted", "id": session_id})))
+        }
+        Ok(false) =>
+            Ok(HttpResponse::NotFound().json(json!({"error": format!("Session {} not found", session_id)}))),
+        Err(e) => {
+            error!("Failed to delete session {}: {}", session_id, e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()})))
+        }
+    }
+}
+
+// Folds any chats not yet covered into the rolling summary right away
+#[post("/sessions/{session_id}/summary")]
+async fn summarize_session(
+    path: web::Path<String>,
+    client: web::Data<Client>,
+    user: User
+) -> Result<HttpResponse, Error> {
+    let session_id = path.into_inner();
+    if let Some(response) = session_not_found(&session_id, &user).await? {
+        return Ok(response);
+    }
+
+    match refresh_summary(&client, &session_id, true).await {
+        Ok(summary) => Ok(HttpResponse::Ok().json(json!({"id": session_id, "summary": summary}))),
+        Err(e) => {
+            error!("Failed to summarize session {}: {}", session_id, e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()})))
+        }
+    }
+}
+
+// Portable archive of the session (JSON lines), or a Markdown transcript with ?format=markdown
+#[get("/sessions/{session_id}/export")]
+async fn export_session_archive(
+    path: web::Path<String>,
+    query: web::Query<ExportParams>,
+    user: User
+) -> Result<HttpResponse, Error> {
+    let session_id = path.into_inner();
+    if let Some(response) = session_not_found(&session_id, &user).await? {
+        return Ok(response);
+    }
+
+    let export_session_id = session_id.clone();
+    let (exported, content_type, extension) = match query.format.as_deref() {
+        None | Some("archive") =>
+            (run_db(move |_| export_session(&export_session_id).map_err(|e| e.to_string())).await?, "application/x-ndjson", "pyano.jsonl"),
+        Some("markdown") =>
+            (run_db(move |_| export_markdown(&export_session_id).map_err(|e| e.to_string())).await?, "text/markdown; charset=utf-8", "md"),
+        Some(other) => {
+            return Ok(
+                HttpResponse::BadRequest().json(
+                    json!({"error": format!("Unknown format {}, use archive or markdown", other)})
+                )
+            );
+        }
+    };
+
+    match exported {
+        Ok(Some(body)) =>
+            Ok(
+                HttpResponse::Ok()
+                    .content_type(content_type)
+                    .insert_header((
+                        "Content-Disposition",
+                        format!("attachment; filename=\"{}.{}\"", session_id, extension),
+                    ))
+                    .body(body)
+            ),
+        Ok(None) =>
+            Ok(HttpResponse::NotFound().json(json!({"error": format!("Session {} not found", session_id)}))),
+        Err(e) => {
+            error!("Failed to export session {}: {}", session_id, e);
+            Ok(HttpResponse::InternalServerError().json(json!({"error": e.to_string()})))
+        }
+    }
+}
+
+// Restores an archive produced by /sessions/{id}/export as a new session
+#[post("/sessions/import")]
+async fn import_session_archive(mut payload: web::Payload, user: User) -> Result<HttpResponse, Error> {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > MAX_IMPORT_SIZE {
+            return Ok(HttpResponse::PayloadTooLarge().json(json!({"error": "Archive is too large"})));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let archive = match String::from_utf8(body.to_vec()) {
+        Ok(archive) => archive,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(json!({"error": "Archive is not valid UTF-8"})));
+        }
+    };
+
+    let imported = run_db(move |db| {
+        import_session(&archive, &user.user_id)
+            .map_err(|e| e.to_string())
+            .map(|session_id| (db.get_session(&session_id).ok().flatten(), session_id))
+    }).await?;
+    match imported {
+        Ok((Some(session), _)) => Ok(HttpResponse::Created().json(session)),
+        Ok((None, session_id)) => Ok(HttpResponse::Created().json(json!({"id": session_id}))),
+        Err(e) => {
+            error!("Failed to import session: {}", e);
+            Ok(HttpResponse::BadRequest().json(json!({"error": e})))
+        }
+    }
+}
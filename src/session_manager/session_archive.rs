@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::error::Error;
+use chrono::Utc;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Map, Value };
+use uuid::Uuid;
+use log::{ error, info };
+use crate::database::db_config::{ DBConfig, DB_INSTANCE };
+use crate::database::archive_db::SESSION_TABLES;
+use crate::similarity_index::index::{ add_embeddings_to_index, get_embeddings_from_index };
+
+pub const ARCHIVE_FORMAT: &str = "pyano-session";
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// One line of a session archive. The first line is the header, every other line is a
+/// row of one of the session tables. Chats carry their chat embedding and context
+/// chunks the vector stored for them in the session index.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ArchiveRecord {
+    Header {
+        format: String,
+        version: u32,
+        session_id: String,
+        exported_at: String,
+    },
+    Row {
+        table: String,
+        row: Map<String, Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        embeddings: Option<Vec<f32>>,
+    },
+}
+
+fn vec_row_id(row: &Map<String, Value>) -> Option<u64> {
+    match row.get("vec_row_id") {
+        Some(Value::String(id)) => id.parse().ok(),
+        Some(Value::Number(id)) => id.as_u64(),
+        _ => None,
+    }
+}
+
+/// Serializes the session as JSON lines: header, session, chats, indexed context and
+/// pair programmer plans. Returns None if the session doesn't exist.
+pub fn export_session(session_id: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let rows = DB_INSTANCE.export_session_rows(session_id)?;
+    if !rows.iter().any(|(table, _)| table == "sessions") {
+        return Ok(None);
+    }
+
+    // Context embeddings only live in the usearch index, pull them out by chunk id
+    let chunk_ids: Vec<u64> = rows
+        .iter()
+        .filter(|(table, _)| table == "context_children")
+        .filter_map(|(_, row)| vec_row_id(row))
+        .collect();
+    let context_embeddings: HashMap<u64, Vec<f32>> = get_embeddings_from_index(
+        session_id,
+        &chunk_ids
+    )
+        .into_iter()
+        .collect();
+
+    let header = ArchiveRecord::Header {
+        format: ARCHIVE_FORMAT.to_string(),
+        version: ARCHIVE_VERSION,
+        session_id: session_id.to_string(),
+        exported_at: Utc::now().to_rfc3339(),
+    };
+    let mut lines = vec![serde_json::to_string(&header)?];
+
+    for (table, row) in rows {
+        let embeddings = match table.as_str() {
+            "chats" =>
+                match vec_row_id(&row) {
+                    Some(id) => DB_INSTANCE.get_chat_embedding(id as i64)?,
+                    None => None,
+                }
+            "context_children" => vec_row_id(&row).and_then(|id| context_embeddings.get(&id).cloned()),
+            _ => None,
+        };
+        lines.push(serde_json::to_string(&(ArchiveRecord::Row { table, row, embeddings }))?);
+    }
+
+    info!("Exported session {} as {} archive lines", session_id, lines.len());
+    Ok(Some(lines.join("\n") + "\n"))
+}
+
+/// Restores an archive as a brand new session. Every row gets a fresh id and vector row
+/// id so importing the same archive twice, or on the machine it came from, is safe.
+/// The imported rows belong to `user_id`. Returns the new session id.
+pub fn import_session(archive: &str, user_id: &str) -> Result<String, Box<dyn Error>> {
+    let mut lines = archive.lines().filter(|line| !line.trim().is_empty());
+
+    match lines.next().map(serde_json::from_str::<ArchiveRecord>) {
+        Some(Ok(ArchiveRecord::Header { format, version, .. })) => {
+            if format != ARCHIVE_FORMAT || version > ARCHIVE_VERSION {
+                return Err(format!("Unsupported archive {} v{}", format, version).into());
+            }
+        }
+        Some(Err(e)) => {
+            return Err(format!("Invalid archive header: {}", e).into());
+        }
+        _ => {
+            return Err("Archive is missing its header".into());
+        }
+    }
+
+    let session_id = Uuid::new_v4().to_string();
+    let mut pair_programmer_ids: HashMap<String, String> = HashMap::new();
+    let mut context_embeddings: Vec<(u64, Vec<f32>)> = Vec::new();
+    let mut rows = Vec::new();
+
+    for (n, line) in lines.enumerate() {
+        let (table, mut row, embeddings) = match serde_json::from_str::<ArchiveRecord>(line) {
+            Ok(ArchiveRecord::Row { table, row, embeddings }) => (table, row, embeddings),
+            Ok(ArchiveRecord::Header { .. }) => {
+                return Err(format!("Unexpected header on line {}", n + 2).into());
+            }
+            Err(e) => {
+                return Err(format!("Invalid archive record on line {}: {}", n + 2, e).into());
+            }
+        };
+
+        let session_column = SESSION_TABLES.iter()
+            .find(|(t, _, _)| *t == table)
+            .map(|(_, _, column)| *column)
+            .ok_or_else(|| format!("Unknown table {} on line {}", table, n + 2))?;
+        row.insert(session_column.to_string(), json!(session_id));
+        if row.contains_key("user_id") {
+            row.insert("user_id".to_string(), json!(user_id));
+        }
+
+        match table.as_str() {
+            "chats" => {
+                row.insert("id".to_string(), json!(Uuid::new_v4().to_string()));
+                // chats keep vec_row_id as TEXT
+                row.insert("vec_row_id".to_string(), json!(DBConfig::generate_rowid().to_string()));
+            }
+            "context_parent" => {
+                row.insert("id".to_string(), json!(Uuid::new_v4().to_string()));
+            }
+            "context_children" => {
+                let chunk_id = DBConfig::generate_rowid();
+                row.insert("id".to_string(), json!(Uuid::new_v4().to_string()));
+                row.insert("vec_row_id".to_string(), json!(chunk_id));
+                if let Some(embeddings) = &embeddings {
+                    context_embeddings.push((chunk_id, embeddings.clone()));
+                }
+            }
+            "pair_programmer" => {
+                let new_id = Uuid::new_v4().to_string();
+                if let Some(Value::String(old_id)) = row.get("id") {
+                    pair_programmer_ids.insert(old_id.clone(), new_id.clone());
+                }
+                row.insert("id".to_string(), json!(new_id));
+            }
+            "pp_steps" => {
+                // Step ids are "<pair_programmer_id>_<step number>"
+                let old_pp_id = row.get("pair_programmer_id").and_then(|v| v.as_str()).unwrap_or_default();
+                let new_pp_id = pair_programmer_ids
+                    .get(old_pp_id)
+                    .cloned()
+                    .ok_or_else(|| format!("Step on line {} has no matching plan", n + 2))?;
+                let step_id = row.get("id").and_then(|v| v.as_str()).unwrap_or_default();
+                let step_number = step_id.strip_prefix(old_pp_id).unwrap_or(step_id).trim_start_matches('_');
+                row.insert("id".to_string(), json!(format!("{}_{}", new_pp_id, step_number)));
+                row.insert("pair_programmer_id".to_string(), json!(new_pp_id));
+            }
+            _ => {}
+        }
+        rows.push((table, row, embeddings));
+    }
+
+    if !rows.iter().any(|(table, _, _)| table == "sessions") {
+        return Err("Archive has no session record".into());
+    }
+
+    if let Err(e) = DB_INSTANCE.import_session_rows(&rows) {
+        // Don't leave half a session behind
+        if let Err(cleanup) = DB_INSTANCE.delete_session(&session_id) {
+            error!("Failed to clean up partially imported session {}: {}", session_id, cleanup);
+        }
+        return Err(e);
+    }
+
+    if !context_embeddings.is_empty() {
+        add_embeddings_to_index(&session_id, context_embeddings)?;
+    }
+
+    info!("Imported session {} with {} records", session_id, rows.len());
+    Ok(session_id)
+}
+
+fn row_str<'a>(row: &'a Map<String, Value>, column: &str) -> &'a str {
+    row.get(column)
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+}
+
+/// Human readable transcript of the session's chats, for pasting into reviews
+pub fn export_markdown(session_id: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let rows = DB_INSTANCE.export_session_rows(session_id)?;
+    let session = match rows.iter().find(|(table, _)| table == "sessions") {
+        Some((_, session)) => session,
+        None => {
+            return Ok(None);
+        }
+    };
+
+    let title = match row_str(session, "title") {
+        "" => "Untitled session",
+        title => title,
+    };
+    let mut markdown = format!("# {}\n\n_Session `{}`, exported {}_\n", title, session_id, Utc::now().to_rfc3339());
+
+    let summary = row_str(session, "summary");
+    if !summary.is_empty() {
+        markdown.push_str(&format!("\n> {}\n", summary.replace('\n', "\n> ")));
+    }
+
+    let mut chats: Vec<&Map<String, Value>> = rows
+        .iter()
+        .filter(|(table, _)| table == "chats")
+        .map(|(_, row)| row)
+        .collect();
+    chats.sort_by(|a, b| row_str(a, "timestamp").cmp(row_str(b, "timestamp")));
+
+    for chat in chats {
+        markdown.push_str(
+            &format!(
+                "\n---\n\n## {} · {}\n\n### Prompt\n\n{}\n\n### Response\n\n{}\n",
+                row_str(chat, "timestamp"),
+                row_str(chat, "request_type"),
+                row_str(chat, "prompt").trim(),
+                row_str(chat, "response").trim()
+            )
+        );
+        if chat.get("cancelled").and_then(|v| v.as_i64()).unwrap_or(0) != 0 {
+            markdown.push_str("\n_Generation was cancelled, the response is partial._\n");
+        }
+    }
+    Ok(Some(markdown))
+}
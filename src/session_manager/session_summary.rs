@@ -0,0 +1,177 @@
+use log::{ debug, error, info };
+use reqwest::Client;
+use crate::database::db_config::run_db;
+use crate::llm_stream::handle::complete_text;
+use crate::llm_stream::generation::GenerationParams;
+use crate::llm_stream::usage::UsageContext;
+use crate::authentication::authorization::DEFAULT_USER_ID;
+
+// Re-summarize once this many chats were added since the last summary
+const SUMMARY_EVERY_N_CHATS: usize = 4;
+// Long answers (mostly code) are cut before they go into the summary prompt
+const MAX_EXCHANGE_CHARS: usize = 2000;
+
+const TITLE_SYSTEM_PROMPT: &str =
+    r#"
+        You name conversations between a developer and a coding assistant.
+        Reply with a short title of at most 6 words. No quotes, no punctuation at the end, nothing else.
+        "#;
+
+const SUMMARY_SYSTEM_PROMPT: &str =
+    r#"
+        You keep a running summary of a conversation between a developer and a coding assistant.
+        Merge the previous summary with the new exchanges into one summary of at most 150 words.
+        Keep file names, function names, decisions and open questions. Reply with the summary only.
+        "#;
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let truncated: String = text.chars().take(max_chars).collect();
+    format!("{}...", truncated)
+}
+
+fn clean_title(title: &str) -> String {
+    let first_line = title.lines().next().unwrap_or("").trim();
+    truncate(first_line.trim_matches(|c| c == '"' || c == '\'' || c == '.'), 80)
+}
+
+// Titles and summaries count against the quota of whoever owns the session
+async fn session_usage(session_id: &str) -> UsageContext {
+    let session_id = session_id.to_string();
+    let user_id = match run_db(move |db| db.get_session(&session_id)).await {
+        Ok(Ok(Some(session))) => session["user_id"].as_str().unwrap_or(DEFAULT_USER_ID).to_string(),
+        _ => DEFAULT_USER_ID.to_string(),
+    };
+    UsageContext::new(&user_id, None)
+}
+
+/// Called after every stored chat. Names the session after its first exchange and
+/// refreshes the rolling summary every few chats. Failures are only logged, the
+/// chat itself is already saved.
+pub async fn update_session_metadata(session_id: &str, prompt: &str, response: &str) {
+    let client = Client::new();
+
+    let lookup_session_id = session_id.to_string();
+    let session = match run_db(move |db| db.get_session(&lookup_session_id).map_err(|e| e.to_string())).await {
+        Ok(Ok(Some(session))) => session,
+        Ok(Ok(None)) => {
+            debug!("Session {} not registered, skipping title and summary", session_id);
+            return;
+        }
+        Ok(Err(e)) => {
+            error!("Failed to load session {}: {}", session_id, e);
+            return;
+        }
+        Err(e) => {
+            error!("Failed to load session {}: {}", session_id, e);
+            return;
+        }
+    };
+
+    let has_title = session["title"].as_str().map(|t| !t.is_empty()).unwrap_or(false);
+    if !has_title {
+        generate_title(&client, session_id, prompt, response).await;
+    }
+
+    if let Err(e) = refresh_summary(&client, session_id, false).await {
+        error!("Failed to update summary for session {}: {}", session_id, e);
+    }
+}
+
+async fn generate_title(client: &Client, session_id: &str, prompt: &str, response: &str) {
+    let title_prompt = format!(
+        "User: {}\nAssistant: {}",
+        truncate(prompt, MAX_EXCHANGE_CHARS),
+        truncate(response, MAX_EXCHANGE_CHARS)
+    );
+    let generation = GenerationParams {
+        temperature: Some(0.2),
+        max_tokens: Some(24),
+        ..GenerationParams::from_env()
+    };
+
+    let usage = session_usage(session_id).await;
+    // actix_web::Error isn't Send, so the result must not live across the await below
+    let title = match complete_text("TITLE", client, TITLE_SYSTEM_PROMPT, &title_prompt, generation, usage).await {
+        Ok(title) => clean_title(&title),
+        Err(e) => {
+            error!("Failed to generate title for session {}: {}", session_id, e);
+            return;
+        }
+    };
+    if title.is_empty() {
+        return;
+    }
+
+    let (title_session_id, stored_title) = (session_id.to_string(), title.clone());
+    match run_db(move |db| db.set_session_title_if_empty(&title_session_id, &stored_title).map_err(|e| e.to_string())).await {
+        Ok(Ok(true)) => info!("Session {} titled '{}'", session_id, title),
+        Ok(Ok(false)) => {}
+        Ok(Err(e)) => error!("Failed to store title for session {}: {}", session_id, e),
+        Err(e) => error!("Failed to store title for session {}: {}", session_id, e),
+    }
+}
+
+/// Folds the chats that came after the last summary into it. Unless `force` is set
+/// it waits until SUMMARY_EVERY_N_CHATS new chats have piled up.
+pub async fn refresh_summary(
+    client: &Client,
+    session_id: &str,
+    force: bool
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let owned_session_id = session_id.to_string();
+    let ((previous_summary, summarized_count), total_chats) = run_db(move |db| {
+        Ok::<_, rusqlite::Error>((db.get_session_summary(&owned_session_id)?, db.count_session_chats(&owned_session_id)?))
+    }).await??;
+    let pending = total_chats.saturating_sub(summarized_count);
+
+    if pending == 0 || (!force && pending < SUMMARY_EVERY_N_CHATS) {
+        return Ok(previous_summary);
+    }
+
+    let owned_session_id = session_id.to_string();
+    let exchanges = run_db(move |db| db.fetch_session_exchanges(&owned_session_id, summarized_count)).await??;
+    let new_exchanges = exchanges
+        .iter()
+        .map(|(prompt, response)| {
+            format!(
+                "User: {}\nAssistant: {}",
+                truncate(prompt, MAX_EXCHANGE_CHARS),
+                truncate(response, MAX_EXCHANGE_CHARS)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n");
+
+    let summary_prompt = format!(
+        "Previous summary:\n{}\n\nNew exchanges:\n{}",
+        previous_summary.as_deref().unwrap_or("(none)"),
+        new_exchanges
+    );
+    let generation = GenerationParams {
+        temperature: Some(0.2),
+        max_tokens: Some(300),
+        ..GenerationParams::from_env()
+    };
+
+    let usage = session_usage(session_id).await;
+    let summary = complete_text(
+        "SUMMARY",
+        client,
+        SUMMARY_SYSTEM_PROMPT,
+        &summary_prompt,
+        generation,
+        usage
+    ).await.map_err(|e| e.to_string())?;
+    if summary.is_empty() {
+        return Ok(previous_summary);
+    }
+
+    let (owned_session_id, stored_summary) = (session_id.to_string(), summary.clone());
+    let summarized = summarized_count + exchanges.len();
+    run_db(move |db| db.update_session_summary(&owned_session_id, &stored_summary, summarized)).await??;
+    debug!("Updated summary for session {} ({} chats)", session_id, summarized_count + exchanges.len());
+    Ok(Some(summary))
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use log::{ debug, error };
+use crate::chats::history::fts_query;
+use crate::database::db_config::run_db;
+use super::index::{ search_index, search_session_indexes };
+
+// Constant from the reciprocal rank fusion paper, dampens the weight of the top ranks
+pub const RRF_K: f64 = 60.0;
+
+/// Merges ranked lists, best first, into one by reciprocal rank fusion. Keys with the same
+/// score keep the order they were first seen in, earlier lists first.
+pub fn reciprocal_rank_fusion<K: Eq + Hash + Clone>(ranked_lists: &[Vec<K>], limit: usize) -> Vec<K> {
+    let mut positions: HashMap<K, usize> = HashMap::new();
+    let mut ranked: Vec<(K, f64)> = Vec::new();
+    for list in ranked_lists {
+        for (rank, key) in list.iter().enumerate() {
+            let position = *positions.entry(key.clone()).or_insert_with(|| {
+                ranked.push((key.clone(), 0.0));
+                ranked.len() - 1
+            });
+            ranked[position].1 += 1.0 / (RRF_K + (rank as f64) + 1.0);
+        }
+    }
+
+    // Stable, so ties stay in first-seen order
+    ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    ranked.truncate(limit);
+    ranked
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect()
+}
+
+/// Indexed code of the sessions for a query: nearest chunks from the usearch indexes and
+/// BM25 matches on identifiers, file paths and code, fused with reciprocal rank fusion.
+/// Returns usearch keys, best first. Either side failing leaves the other one's results.
+pub async fn hybrid_search_code(
+    session_ids: Vec<String>,
+    query: &str,
+    query_embedding: Vec<f32>,
+    limit: usize
+) -> Vec<u64> {
+    // Fetch more than we return from each side, so fusion has something to work with
+    let candidates = limit * 3;
+
+    let semantic_hits = if let [session_id] = session_ids.as_slice() {
+        search_index(session_id, query_embedding, candidates)
+    } else {
+        search_session_indexes(&session_ids, &query_embedding, candidates)
+            .into_iter()
+            .map(|(_, chunk_id, _)| chunk_id)
+            .collect()
+    };
+
+    let fts = fts_query(query);
+    let keyword_hits = if fts.is_empty() {
+        Vec::new()
+    } else {
+        match run_db(move |db| db.keyword_search_context(&fts, &session_ids, candidates)).await {
+            Ok(Ok(hits)) => hits,
+            Ok(Err(e)) => {
+                error!("Keyword search over indexed code failed: {}", e);
+                Vec::new()
+            }
+            Err(e) => {
+                error!("Keyword search over indexed code failed: {}", e);
+                Vec::new()
+            }
+        }
+    };
+
+    debug!("Code search: {} semantic and {} keyword hits", semantic_hits.len(), keyword_hits.len());
+    reciprocal_rank_fusion(&[semantic_hits, keyword_hits], limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reciprocal_rank_fusion;
+
+    #[test]
+    fn keys_in_both_lists_rank_first() {
+        let fused = reciprocal_rank_fusion(&[vec!["a", "b", "c"], vec!["c", "d"]], 10);
+        assert_eq!(fused, vec!["c", "a", "b", "d"]);
+    }
+
+    #[test]
+    fn ties_keep_first_seen_order() {
+        let fused = reciprocal_rank_fusion(&[vec!["x", "y"], vec!["y", "x"]], 10);
+        assert_eq!(fused, vec!["x", "y"]);
+
+        let fused = reciprocal_rank_fusion(&[vec![3u64], vec![1u64], vec![2u64]], 10);
+        assert_eq!(fused, vec![3, 1, 2]);
+    }
+
+    #[test]
+    fn truncates_to_limit() {
+        let fused = reciprocal_rank_fusion(&[vec![1u64, 2, 3, 4]], 2);
+        assert_eq!(fused, vec![1, 2]);
+    }
+
+    #[test]
+    fn empty_lists() {
+        let none: Vec<Vec<u64>> = Vec::new();
+        assert!(reciprocal_rank_fusion(&none, 10).is_empty());
+        assert!(reciprocal_rank_fusion(&[Vec::<u64>::new(), Vec::new()], 10).is_empty());
+        assert_eq!(reciprocal_rank_fusion(&[Vec::new(), vec![7u64, 8]], 10), vec![7, 8]);
+    }
+}
@@ -2,6 +2,7 @@ use usearch::{ Index, IndexOptions, MetricKind, ScalarKind, new_index };
 use log::{ info, error };
 use crate::parser::parse_code::ChunkWithCompressedData;
 use std::fs;
+use crate::server_config::profile_data_dir;
 
 // let index: Index = new_index(&options).unwrap();
 
@@ -48,7 +49,69 @@ pub fn add_to_index(session_id: &str, chunks_with_data: Vec<ChunkWithCompressedD
     }
 }
 
-fn load_or_create_index(session_id: &str) -> Index {
+/// Adds raw (key, embedding) pairs, used when a session is restored from an archive
+pub fn add_embeddings_to_index(session_id: &str, embeddings: Vec<(u64, Vec<f32>)>) -> Result<(), String> {
+    let index = load_or_create_index(session_id);
+
+    for (key, embedding) in embeddings {
+        index
+            .add(key, &embedding)
+            .map_err(|err| format!("Failed to add embeddings for chunk ID {}: {:?}", key, err))?;
+    }
+    save_index(&index, session_id)
+}
+
+/// Reads the stored vectors back out of the session index, keys that are missing are skipped
+pub fn get_embeddings_from_index(session_id: &str, keys: &[u64]) -> Vec<(u64, Vec<f32>)> {
+    let index = load_or_create_index(session_id);
+    let mut embeddings = Vec::new();
+
+    for key in keys {
+        let mut embedding = vec![0.0f32; 384];
+        match index.get(*key, &mut embedding) {
+            Ok(found) if found > 0 => embeddings.push((*key, embedding)),
+            Ok(_) => info!("Chunk {} not found in the index of session {}", key, session_id),
+            Err(err) => error!("Failed to read chunk ID {} from index: {:?}", key, err),
+        }
+    }
+    embeddings
+}
+
+/// Nearest chunks across the indexes of several sessions, closest first: (session_id, chunk id, distance).
+/// Sessions without an index file are skipped, nothing is created for them.
+pub fn search_session_indexes(session_ids: &[String], query_embedding: &[f32], items: usize) -> Vec<(String, u64, f32)> {
+    let pyano_data_dir = profile_data_dir("indexes");
+    let mut matches: Vec<(String, u64, f32)> = Vec::new();
+
+    for session_id in session_ids {
+        let index_path = pyano_data_dir.join(format!("{}.usearch", session_id));
+        if !index_path.exists() {
+            continue;
+        }
+        // Only searched, so no need to reserve room for new vectors like load_or_create_index
+        let index = empty_index();
+        if let Err(err) = index.load(&index_path.display().to_string()) {
+            error!("Index load failed for session: {} with error {}", session_id, err);
+            continue;
+        }
+        match index.search(query_embedding, items) {
+            Ok(results) => {
+                for (key, distance) in results.keys.into_iter().zip(results.distances) {
+                    matches.push((session_id.clone(), key, distance));
+                }
+            }
+            Err(err) => {
+                error!("Search failed for session: {} with error: {:?}", session_id, err);
+            }
+        }
+    }
+
+    matches.sort_by(|a, b| a.2.partial_cmp(&b.2).unwrap_or(std::cmp::Ordering::Equal));
+    matches.truncate(items);
+    matches
+}
+
+fn empty_index() -> Index {
     let options = IndexOptions {
         dimensions: 384, // necessary for most metric kinds, should match the dimension of embeddings
         metric: MetricKind::Cos, // or ::L2sq, ::Cos ...
@@ -59,11 +122,13 @@ fn load_or_create_index(session_id: &str) -> Index {
         multi: false,
     };
 
-    let index: Index = new_index(&options).unwrap();
+    new_index(&options).unwrap()
+}
+
+fn load_or_create_index(session_id: &str) -> Index {
+    let index = empty_index();
 
-    let home_directory = dirs::home_dir().unwrap();
-    let root_pyano_dir = home_directory.join(".pyano");
-    let pyano_data_dir = root_pyano_dir.join("indexes");
+    let pyano_data_dir = profile_data_dir("indexes");
 
     if !pyano_data_dir.exists() {
         fs::create_dir_all(&pyano_data_dir).unwrap();
@@ -88,9 +153,7 @@ fn load_or_create_index(session_id: &str) -> Index {
 }
 
 fn save_index(index: &Index, session_id: &str) -> Result<(), String> {
-    let home_directory = dirs::home_dir().unwrap();
-    let root_pyano_dir = home_directory.join(".pyano");
-    let pyano_data_dir = root_pyano_dir.join("indexes");
+    let pyano_data_dir = profile_data_dir("indexes");
 
     let index_name = format!("{}.usearch", session_id);
     let index_path = pyano_data_dir.join(index_name);
@@ -145,3 +208,18 @@ pub fn remove_from_index(session_id: &str, chunk_ids: Vec<u64>) {
         info!("Index successfully updated and saved after removal for session: {}", session_id);
     }
 }
+
+/// Removes the index file of a session, used when the session itself is deleted
+pub fn delete_index_file(session_id: &str) -> Result<(), String> {
+    let pyano_data_dir = profile_data_dir("indexes");
+    let index_path = pyano_data_dir.join(format!("{}.usearch", session_id));
+
+    if !index_path.exists() {
+        return Ok(());
+    }
+    fs::remove_file(&index_path).map_err(|err|
+        format!("Failed to delete the index for session {}: {:?}", session_id, err)
+    )?;
+    info!("Deleted index file for session: {}", session_id);
+    Ok(())
+}
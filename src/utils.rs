@@ -1,91 +1,76 @@
 use std::env;
+use crate::server_config::server_config;
 
 pub fn is_cloud_execution_mode() -> bool {
-    // load_env(); // Load the .env file from the specified path
-    let cloud_mode = env::var("CLOUD_EXECUTION_MODE").unwrap_or_else(|_| "false".to_string());
-    cloud_mode == "true"
+    server_config().cloud_execution_mode
+}
+
+// Shared servers set REQUIRE_API_KEY=true, cloud mode always requires a key. Otherwise
+// requests without a key run as the local default user.
+pub fn is_api_key_required() -> bool {
+    is_cloud_execution_mode() || server_config().require_api_key
+}
+
+// Default token quotas for users without their own, unset or 0 means unlimited
+pub fn get_daily_token_quota() -> Option<i64> {
+    server_config().daily_token_quota
+}
+
+pub fn get_monthly_token_quota() -> Option<i64> {
+    server_config().monthly_token_quota
 }
 
 pub fn get_local_url() -> String {
-    // load_env(); // Load the .env file from the specified path
-    env::var("LOCAL_URL").unwrap_or_else(|_| {
-        // eprintln!(
-        //     "Warning: Environment variable LOCAL_URL is not set. Using default value [http://localhost:52555]"
-        // );
-        "http://localhost:52555".to_string() // Default value for LOCAL_URL
-    })
+    server_config().llm.local_url.clone()
 }
 
 pub fn get_infill_local_url() -> String {
-    // load_env(); // Load the .env file from the specified path
-    env::var("INFILL_LOCAL_URL").unwrap_or_else(|_| {
-        // eprintln!(
-        //     "Warning: Environment variable INFILL_LOCAL_URL is not set. Using default value [http://localhost:52554]"
-        // );
-        "http://localhost:52554".to_string() // Default value for LOCAL_URL
-    })
+    server_config().llm.infill_local_url.clone()
 }
 
 pub fn get_remote_url() -> String {
-    // load_env(); // Load the .env file from the specified path
-    env::var("REMOTE_URL").unwrap_or_else(|_| {
-        eprintln!("Warning: Environment variable LOCAL_URL is not set. Using default value.");
-        "http://localhost:8000".to_string() // Default value for LOCAL_URL
-    })
+    server_config().llm.remote_url.clone()
 }
 
 pub fn get_cloud_api_key() -> String {
-    // load_env(); // Load the .env file from the specified path
-    env::var("CLOUD_API_KEY").unwrap_or_else(|_| {
-        eprintln!("Warning: Environment variable LOCAL_URL is not set. Using default value.");
-        "none".to_string() // Default value for LOCAL_URL
-    })
+    server_config().llm.cloud_api_key.clone()
 }
 
 pub fn get_llm_temperature() -> f64 {
-    // load_env(); // Load the .env file from the specified path
-    env::var("TEMPERATURE")
-        .unwrap_or_else(|_| {
-            eprintln!(
-                "Warning: Environment variable TEMPERATURE is not set. Using default value of 0.7."
-            );
-            "0.7".to_string() // Use default value "0.4" as a string
-        })
-        .parse::<f64>()
-        .unwrap_or_else(|_| {
-            eprintln!("Error: Failed to parse TEMPERATURE as a float. Using default value of 0.7.");
-            0.7 // Default value if parsing fails
-        })
+    server_config().llm.temperature
 }
 
 pub fn get_top_k() -> i64 {
-    // load_env(); // Load the .env file from the specified path
-    env::var("TOP_K")
-        .unwrap_or_else(|_| {
-            eprintln!(
-                "Warning: Environment variable TOP_K is not set. Using default value of 20."
-            );
-            "20".to_string() // Use default value "0.4" as a string
-        })
-        .parse::<i64>()
-        .unwrap_or_else(|_| {
-            eprintln!("Error: Failed to parse TOP_K as a float. Using default value of 20.");
-            20 // Default value if parsing fails
-        })
+    server_config().llm.top_k
 }
 
 pub fn get_top_p() -> f64 {
-    // load_env(); // Load the .env file from the specified path
-    env::var("TOP_P")
-        .unwrap_or_else(|_| {
-            eprintln!(
-                "Warning: Environment variable TOP_P is not set. Using default value of 0.8."
-            );
-            "0.8".to_string() // Use default value "0.4" as a string
-        })
-        .parse::<f64>()
-        .unwrap_or_else(|_| {
-            eprintln!("Error: Failed to parse TOP_P as a float. Using default value of 0.8.");
-            0.8 // Default value if parsing fails
+    server_config().llm.top_p
+}
+
+pub fn get_remote_model() -> String {
+    server_config().llm.remote_model.clone()
+}
+
+pub fn get_ollama_url() -> String {
+    server_config().llm.ollama_url.clone()
+}
+
+pub fn get_ollama_model() -> String {
+    server_config().llm.ollama_model.clone()
+}
+
+/// Backend used for a route, e.g. `LLM_BACKEND_FIND_BUGS=ollama` or `[llm.routes]` in
+/// server.toml, falling back to the default backend and then to the execution mode
+/// (cloud => openai, local => llamacpp)
+pub fn get_llm_backend(route: &str) -> String {
+    let route = route.to_uppercase();
+    let llm = &server_config().llm;
+    env::var(format!("LLM_BACKEND_{}", route))
+        .ok()
+        .or_else(|| llm.routes.get(&route).cloned())
+        .or_else(|| llm.backend.clone())
+        .unwrap_or_else(|| {
+            if is_cloud_execution_mode() { "openai".to_string() } else { "llamacpp".to_string() }
         })
 }